@@ -12,12 +12,15 @@ declare_id!("Perco1ator111111111111111111111111111111111");
 
 // 1. mod constants
 pub mod constants {
-    use crate::state::{MarketConfig, SlabHeader};
+    use crate::state::{MarketConfig, SlabHeader, CONFIG_HEAD_LEN, CONFIG_TAIL_LEN};
     use core::mem::{align_of, size_of};
     use percolator::RiskEngine;
 
     pub const MAGIC: u64 = 0x504552434f4c4154; // "PERCOLAT"
-    pub const VERSION: u32 = 1;
+    /// Slab layout version. 2 stores MarketConfig's fields past the original
+    /// 320 bytes in a tail after the engine; version-1 slabs are upgraded in
+    /// place with MigrateSlab.
+    pub const VERSION: u32 = 2;
 
     pub const HEADER_LEN: usize = size_of::<SlabHeader>();
     pub const CONFIG_LEN: usize = size_of::<MarketConfig>();
@@ -27,9 +30,21 @@ pub mod constants {
         (x + (a - 1)) & !(a - 1)
     }
 
-    pub const ENGINE_OFF: usize = align_up(HEADER_LEN + CONFIG_LEN, ENGINE_ALIGN);
+    // The config head sits before the engine; its tail is stored after it.
+    pub const ENGINE_OFF: usize = align_up(HEADER_LEN + CONFIG_HEAD_LEN, ENGINE_ALIGN);
     pub const ENGINE_LEN: usize = size_of::<RiskEngine>();
-    pub const SLAB_LEN: usize = ENGINE_OFF + ENGINE_LEN;
+    pub const SLAB_LEN: usize = ENGINE_OFF + ENGINE_LEN + CONFIG_TAIL_LEN;
+    /// Slabs created before the Account struct reordering migration are 8 bytes
+    /// shorter; they still load and hold up to MAX_ACCOUNTS - 1 accounts.
+    pub const LEGACY_SLAB_LEN: usize = SLAB_LEN - 8;
+    /// Slab size at layout version 1: the same engine offset, no config tail.
+    pub const SLAB_LEN_V1: usize = ENGINE_OFF + ENGINE_LEN;
+
+    /// Slab size this build expects. It depends on MAX_ACCOUNTS, so a slab sized
+    /// for another build (e.g. the 64-account test build) fails with InvalidSlabLen.
+    pub const fn expected_slab_len() -> usize {
+        SLAB_LEN
+    }
     pub const MATCHER_ABI_VERSION: u32 = 1;
     pub const MATCHER_CONTEXT_PREFIX_LEN: usize = 64;
     pub const MATCHER_CONTEXT_LEN: usize = 320;
@@ -44,6 +59,18 @@ pub mod constants {
     /// unit_scale=1..=1_000_000_000 enables scaling with dust tracking.
     pub const MAX_UNIT_SCALE: u32 = 1_000_000_000;
 
+    /// Insurance donor leaderboard size (attributed TopUpInsurance totals kept in config).
+    pub const MAX_DONORS: usize = 8;
+
+    /// Maximum legs in one MultiTrade instruction.
+    pub const MAX_TRADE_LEGS: usize = 4;
+
+    /// Index feeds a market may configure beyond the primary one.
+    pub const MAX_EXTRA_INDEX_FEEDS: usize = 2;
+
+    /// Maximum slots one KeeperCrankBatch may crank (bounds compute per call).
+    pub const MAX_CRANK_BATCH_ITERS: u16 = 16;
+
     // Default funding parameters (used at init_market, can be changed via update_config)
     pub const DEFAULT_FUNDING_HORIZON_SLOTS: u64 = 500; // ~4 min @ ~2 slots/sec
     pub const DEFAULT_FUNDING_K_BPS: u64 = 100; // 1.00x multiplier
@@ -78,6 +105,11 @@ pub mod constants {
     pub const RET_OFF_ORACLE_PRICE: usize = 48;
     pub const RET_OFF_RESERVED: usize = 56;
 
+    // Matcher context header, written by the matcher's init right after the
+    // return prefix; a context without it was never initialized
+    pub const CTX_OFF_MAGIC: usize = MATCHER_CONTEXT_PREFIX_LEN;
+    pub const MATCHER_CONTEXT_MAGIC: u64 = 0x5045_5243_4d41_5443; // "PERCMATC"
+
     // Default threshold parameters (used at init_market, can be changed via update_config)
     pub const DEFAULT_THRESH_FLOOR: u128 = 0;
     pub const DEFAULT_THRESH_RISK_BPS: u64 = 50; // 0.50%
@@ -393,6 +425,14 @@ pub mod verify {
         owner_ok(user_owner, user_signer) && owner_ok(lp_owner, lp_signer)
     }
 
+    /// Trade signer policy. The user always signs. The LP owner signs TradeNoCpi
+    /// directly; for TradeCpi (`via_matcher`) the LP's bound matcher, invoked
+    /// through the LP PDA, stands in for its signature.
+    #[inline]
+    pub fn trade_signers_ok(via_matcher: bool, user_signed: bool, lp_signed: bool) -> bool {
+        user_signed && (via_matcher || lp_signed)
+    }
+
     // =========================================================================
     // TradeCpi decision logic - models the full wrapper policy
     // =========================================================================
@@ -707,14 +747,19 @@ pub mod verify {
     /// Inversion constant: 1e12 for price_e6 * inverted_e6 = 1e12
     pub const INVERSION_CONSTANT: u128 = 1_000_000_000_000;
 
+    /// Smallest raw price ($0.001) an inverted market accepts. Below it one e6
+    /// tick is over 0.1% of the price, so the inverted value (up to 1e9 here)
+    /// would be dominated by quantization rather than the feed.
+    pub const MIN_INVERTIBLE_PRICE_E6: u64 = 1_000;
+
     /// Invert oracle price: inverted_e6 = 1e12 / raw_e6
-    /// Returns None if raw == 0 or result overflows u64.
+    /// Returns None if raw < MIN_INVERTIBLE_PRICE_E6 or result overflows u64.
     #[inline]
     pub fn invert_price_e6(raw: u64, invert: u8) -> Option<u64> {
         if invert == 0 {
             return Some(raw);
         }
-        if raw == 0 {
+        if raw < MIN_INVERTIBLE_PRICE_E6 {
             return None;
         }
         let inverted = INVERSION_CONSTANT / (raw as u128);
@@ -745,6 +790,95 @@ pub mod verify {
         Some(scaled)
     }
 
+    /// Move `price` against a position by the confidence `conf`:
+    /// long → price - conf, short → price + conf.
+    /// Returns None if the long bound reaches zero or the short bound overflows.
+    #[inline]
+    pub fn conf_adjusted_price(price: u64, conf: u64, long: bool) -> Option<u64> {
+        let adjusted = if long {
+            price.checked_sub(conf)?
+        } else {
+            price.checked_add(conf)?
+        };
+        if adjusted == 0 {
+            return None;
+        }
+        Some(adjusted)
+    }
+
+    /// Median of up to three oracle prices; the mean of the middle pair for two.
+    /// None for an empty slice or more than three prices.
+    #[inline]
+    pub fn median_price(prices: &[u64]) -> Option<u64> {
+        match *prices {
+            [p] => Some(p),
+            [a, b] => Some(((a as u128 + b as u128) / 2) as u64),
+            [a, b, c] => Some(a.max(b).min(a.min(b).max(c))),
+            _ => None,
+        }
+    }
+
+    /// The index median needs a strict majority of its configured feeds fresh
+    /// (2 of 3, 2 of 2), so a single surviving feed cannot set the price once
+    /// extra feeds are configured. With only the primary, that feed suffices.
+    #[inline]
+    pub fn median_quorum_ok(fresh: usize, configured: usize) -> bool {
+        fresh > 0 && fresh * 2 > configured
+    }
+
+    /// Extra index feeds are valid if they are filled in order (no tertiary
+    /// without a secondary) and no feed repeats another or the primary, so each
+    /// source counts once in the median.
+    #[inline]
+    pub fn extra_index_feeds_ok(primary: &[u8; 32], extra: &[[u8; 32]; 2]) -> bool {
+        let [secondary, tertiary] = extra;
+        let unset = [0u8; 32];
+        if *secondary == unset {
+            return *tertiary == unset;
+        }
+        secondary != primary
+            && (*tertiary == unset || (tertiary != primary && tertiary != secondary))
+    }
+
+    // =========================================================================
+    // Funding TWAP accumulator (pure logic)
+    // =========================================================================
+
+    /// Fold `price` into a funding TWAP of (price_sum, dt_total) last updated
+    /// at `last_slot`: sum += price * dt, dt_total += dt. The window restarts
+    /// from this sample (weight 1) when empty or once it would exceed `horizon`
+    /// slots. Same-slot samples are ignored. Returns (price_sum, dt_total).
+    #[inline]
+    pub fn twap_accumulate(
+        price_sum: u128,
+        dt_total: u64,
+        last_slot: u64,
+        price: u64,
+        slot: u64,
+        horizon: u64,
+    ) -> (u128, u64) {
+        let dt = slot.saturating_sub(last_slot);
+        if dt_total == 0 || dt_total.saturating_add(dt) > horizon {
+            return (price as u128, 1);
+        }
+        if dt == 0 {
+            return (price_sum, dt_total);
+        }
+        (
+            price_sum.saturating_add((price as u128).saturating_mul(dt as u128)),
+            dt_total + dt,
+        )
+    }
+
+    /// Time-weighted average of an accumulator; None while it is empty.
+    #[inline]
+    pub fn twap_price(price_sum: u128, dt_total: u64) -> Option<u64> {
+        if dt_total == 0 {
+            return None;
+        }
+        Some((price_sum / dt_total as u128).min(u64::MAX as u128) as u64)
+    }
+
     // =========================================================================
     // Unit scale conversion math (pure logic)
     // =========================================================================
@@ -821,6 +955,527 @@ pub mod verify {
     pub fn init_market_scale_ok(unit_scale: u32) -> bool {
         unit_scale <= crate::constants::MAX_UNIT_SCALE
     }
+
+    // =========================================================================
+    // Position direction classification (pure logic)
+    // =========================================================================
+
+    /// True if applying `delta` to `old_pos` moves the position toward zero
+    /// without flipping sides (a pure reduction or full close).
+    /// A zero delta or a fill on a flat position is never reducing.
+    #[inline]
+    pub fn is_reducing_fill(old_pos: i128, delta: i128) -> bool {
+        if old_pos == 0 || delta == 0 {
+            return false;
+        }
+        if (old_pos > 0) == (delta > 0) {
+            return false;
+        }
+        delta.unsigned_abs() <= old_pos.unsigned_abs()
+    }
+
+    /// Funding owed by `position_size` for a move of the global index from
+    /// `account_index` to `global_index`. Positive = account pays. Mirrors engine
+    /// rounding: payments round up, receipts truncate toward zero.
+    #[inline]
+    pub fn funding_payment(position_size: i128, global_index: i128, account_index: i128) -> i128 {
+        let raw = position_size.saturating_mul(global_index.saturating_sub(account_index));
+        if raw > 0 {
+            raw.saturating_add(999_999) / 1_000_000
+        } else {
+            raw / 1_000_000
+        }
+    }
+
+    /// Signed fill that brings `|pos|` down to `target_abs` without flipping
+    /// sides; 0 if the position is already at or below the target.
+    #[inline]
+    pub fn reduce_to_target_delta(pos: i128, target_abs: u128) -> i128 {
+        let abs = pos.unsigned_abs();
+        if abs <= target_abs {
+            return 0;
+        }
+        // cut <= |pos| never overshoots zero; only |i128::MIN| needs the clamp
+        let cut = (abs - target_abs).min(i128::MAX as u128) as i128;
+        if pos > 0 {
+            -cut
+        } else {
+            cut
+        }
+    }
+
+    /// Warmup schedule of an account at `now_slot`:
+    /// `(total_warming, vested_now, full_vest_slot)`.
+    /// - total_warming: positive PnL not yet converted to capital, net of reserved PnL
+    /// - vested_now: `min(total_warming, slope * elapsed)`, the amount the next
+    ///   settlement would convert
+    /// - full_vest_slot: first slot at which all of it has vested; `started_at_slot`
+    ///   when nothing is warming, `u64::MAX` if no slope has been set yet
+    ///
+    /// `warmup_period_slots == 0` is instant warmup: everything is vested now.
+    pub fn warmup_schedule(
+        pnl: i128,
+        reserved_pnl: u128,
+        slope_per_slot: u128,
+        started_at_slot: u64,
+        warmup_period_slots: u64,
+        now_slot: u64,
+    ) -> (u128, u128, u64) {
+        let total = (pnl.max(0) as u128).saturating_sub(reserved_pnl);
+        if total == 0 {
+            return (0, 0, started_at_slot);
+        }
+        if warmup_period_slots == 0 {
+            return (total, total, now_slot);
+        }
+        if slope_per_slot == 0 {
+            return (total, 0, u64::MAX);
+        }
+        let elapsed = now_slot.saturating_sub(started_at_slot) as u128;
+        let vested = slope_per_slot.saturating_mul(elapsed).min(total);
+        let slots = total.saturating_add(slope_per_slot - 1) / slope_per_slot;
+        let full = if slots > u64::MAX as u128 {
+            u64::MAX
+        } else {
+            started_at_slot.saturating_add(slots as u64)
+        };
+        (total, vested, full)
+    }
+
+    /// Cost-basis entry price after applying `fill` (signed, same sign convention
+    /// for users and LPs) at `fill_price` to a position `old_pos` entered at `old_entry`:
+    /// - open from flat or flip through zero: the fill price
+    /// - reduce (including fill == 0): unchanged
+    /// - increase: size-weighted average of old entry and fill, rounded down
+    /// - close to flat: 0
+    ///
+    /// This is the basis of the open position's fills. The engine's stored
+    /// `entry_price` is its mark-settlement price instead (realized PnL lives in `pnl`).
+    #[inline]
+    pub fn weighted_entry_price(old_pos: i128, old_entry: u64, fill: i128, fill_price: u64) -> u64 {
+        let new_pos = old_pos.saturating_add(fill);
+        if new_pos == 0 {
+            return 0;
+        }
+        if old_pos == 0 || (old_pos > 0) != (new_pos > 0) {
+            return fill_price;
+        }
+        if fill == 0 || (fill > 0) != (old_pos > 0) {
+            return old_entry;
+        }
+        let a = old_pos.unsigned_abs();
+        let b = fill.unsigned_abs();
+        let num = a
+            .saturating_mul(old_entry as u128)
+            .saturating_add(b.saturating_mul(fill_price as u128));
+        core::cmp::min(num / a.saturating_add(b), u64::MAX as u128) as u64
+    }
+
+    // =========================================================================
+    // Trade size limits (pure logic)
+    // =========================================================================
+
+    /// Check a single fill's notional (|size| * price / 1e6) against the cap.
+    /// max_notional_e6 == 0 disables the check.
+    #[inline]
+    pub fn trade_notional_ok(size: i128, price_e6: u64, max_notional_e6: u64) -> bool {
+        if max_notional_e6 == 0 {
+            return true;
+        }
+        let notional = size.unsigned_abs().saturating_mul(price_e6 as u128) / 1_000_000;
+        notional <= max_notional_e6 as u128
+    }
+
+    /// Open-interest cap: a fill that grows `|user| + |lp|` may not take the
+    /// market's total open interest (sum of |position| over all accounts)
+    /// above `max_oi`. Non-increasing fills always pass; max_oi == 0 disables.
+    #[inline]
+    pub fn open_interest_ok(
+        total_oi: u128,
+        user_pos: i128,
+        lp_pos: i128,
+        size: i128,
+        max_oi: u128,
+    ) -> bool {
+        if max_oi == 0 {
+            return true;
+        }
+        let before = user_pos
+            .unsigned_abs()
+            .saturating_add(lp_pos.unsigned_abs());
+        let after = user_pos
+            .saturating_add(size)
+            .unsigned_abs()
+            .saturating_add(lp_pos.saturating_sub(size).unsigned_abs());
+        after <= before || total_oi.saturating_sub(before).saturating_add(after) <= max_oi
+    }
+
+    /// Leverage cap: a fill that grows `|pos|` may not leave a notional
+    /// (`|pos + delta| * price / 1e6`) above `capital * max_leverage_x`.
+    /// Reducing fills always pass; max_leverage_x == 0 disables.
+    #[inline]
+    pub fn leverage_ok(
+        pos: i128,
+        delta: i128,
+        price_e6: u64,
+        capital: u128,
+        max_leverage_x: u64,
+    ) -> bool {
+        if max_leverage_x == 0 || is_reducing_fill(pos, delta) {
+            return true;
+        }
+        let notional = pos
+            .saturating_add(delta)
+            .unsigned_abs()
+            .saturating_mul(price_e6 as u128)
+            / 1_000_000;
+        notional <= capital.saturating_mul(max_leverage_x as u128)
+    }
+
+    /// Trade sizes must be negatable: the engine flips the sign for the LP
+    /// side and takes `.abs()` of positions, both of which overflow on i128::MIN.
+    #[inline]
+    pub fn trade_size_in_range(size: i128) -> bool {
+        size != i128::MIN
+    }
+
+    /// Slippage guard: a buy (size > 0) may not execute above `limit_price_e6`,
+    /// a sell not below it. limit_price_e6 == 0 disables the check.
+    #[inline]
+    pub fn limit_price_ok(size: i128, exec_price_e6: u64, limit_price_e6: u64) -> bool {
+        if limit_price_e6 == 0 {
+            return true;
+        }
+        if size > 0 {
+            exec_price_e6 <= limit_price_e6
+        } else {
+            exec_price_e6 >= limit_price_e6
+        }
+    }
+
+    /// LiquidateCpi: the matcher fill is taken only if it closes exactly what the
+    /// engine would (`exec_size == close_size`) at a price no more than
+    /// `slippage_bps` worse for the target than the liquidation price
+    /// `bound_e6`. Anything else falls back to oracle seizure.
+    #[inline]
+    pub fn liquidation_fill_ok(
+        close_size: i128,
+        exec_size: i128,
+        exec_price_e6: u64,
+        bound_e6: u64,
+        slippage_bps: u64,
+    ) -> bool {
+        if close_size == 0 || exec_size != close_size || exec_price_e6 == 0 || bound_e6 == 0 {
+            return false;
+        }
+        let slack = (bound_e6 as u128 * slippage_bps as u128 / 10_000) as u64;
+        let limit = if close_size > 0 {
+            bound_e6.saturating_add(slack)
+        } else {
+            bound_e6.saturating_sub(slack).max(1)
+        };
+        limit_price_ok(close_size, exec_price_e6, limit)
+    }
+
+    /// MigrateSlab only upgrades (`from_version < to_version`), and only a slab
+    /// no larger than the target layout, so growing it never truncates
+    /// accounts.
+    #[inline]
+    pub fn slab_migration_ok(
+        from_version: u32,
+        to_version: u32,
+        slab_len: usize,
+        target_len: usize,
+    ) -> bool {
+        from_version < to_version && slab_len <= target_len
+    }
+
+    /// Trade idempotency: a non-zero `client_req_id` equal to the account's
+    /// last applied one is a resubmission. 0 disables the check.
+    #[inline]
+    pub fn client_req_id_fresh(last_applied: u64, client_req_id: u64) -> bool {
+        client_req_id == 0 || client_req_id != last_applied
+    }
+
+    /// True if keepers have fallen too far behind for new risk to be opened:
+    /// more than `max_lag` slots since the last completed sweep. max_lag == 0 disables.
+    #[inline]
+    pub fn sweep_backlog_exceeded(now_slot: u64, last_full_sweep_slot: u64, max_lag: u64) -> bool {
+        max_lag != 0 && now_slot.saturating_sub(last_full_sweep_slot) > max_lag
+    }
+
+    // =========================================================================
+    // Account creation fee (pure logic)
+    // =========================================================================
+
+    /// Split an InitUser/InitLP payment into (fee, excess). The fee part is what
+    /// account creation charges; the excess is credited to the new account's capital.
+    /// An underpayment passes through whole so the engine can reject it.
+    #[inline]
+    pub fn split_account_fee(paid_units: u128, new_account_fee: u128) -> (u128, u128) {
+        if paid_units <= new_account_fee {
+            return (paid_units, 0);
+        }
+        (new_account_fee, paid_units - new_account_fee)
+    }
+
+    // =========================================================================
+    // Risk parameter updates (pure logic)
+    // =========================================================================
+
+    /// Post-init margin/fee update bounds: maintenance <= initial <= 100%, fees <= 100%.
+    #[inline]
+    pub fn risk_params_update_ok(
+        maintenance_margin_bps: u64,
+        initial_margin_bps: u64,
+        trading_fee_bps: u64,
+        liquidation_fee_bps: u64,
+    ) -> bool {
+        margins_ok(maintenance_margin_bps, initial_margin_bps)
+            && trading_fee_bps <= 10_000
+            && liquidation_fee_bps <= 10_000
+    }
+
+    /// Margin ordering: maintenance <= initial <= 100%. An initial margin below
+    /// maintenance would let a fill open straight into liquidation.
+    #[inline]
+    pub fn margins_ok(maintenance_margin_bps: u64, initial_margin_bps: u64) -> bool {
+        maintenance_margin_bps <= initial_margin_bps && initial_margin_bps <= 10_000
+    }
+
+    /// SetLiquidationParams bounds: fee <= 100%, and the margin a partial
+    /// liquidation restores (maintenance + buffer) <= 100%.
+    #[inline]
+    pub fn liquidation_params_ok(
+        maintenance_margin_bps: u64,
+        liquidation_fee_bps: u64,
+        liquidation_buffer_bps: u64,
+    ) -> bool {
+        liquidation_fee_bps <= 10_000
+            && maintenance_margin_bps.saturating_add(liquidation_buffer_bps) <= 10_000
+    }
+
+    /// True if `equity` covers `margin_bps` of `notional` (all in engine units).
+    /// Negative equity never covers a non-zero requirement.
+    #[inline]
+    pub fn equity_meets_margin(equity: i128, notional: u128, margin_bps: u64) -> bool {
+        let required = notional.saturating_mul(margin_bps as u128) / 10_000;
+        if equity < 0 {
+            return required == 0;
+        }
+        equity as u128 >= required
+    }
+
+    /// Margin (bps) at which the engine's liquidation sizing,
+    /// floor(equity * 1e10 / (price * bps)), keeps at most `remaining_abs` of
+    /// a position. None without positive equity, price or remainder.
+    #[inline]
+    pub fn partial_liquidation_target_bps(
+        equity: i128,
+        price: u64,
+        remaining_abs: u128,
+    ) -> Option<u64> {
+        if equity <= 0 || price == 0 || remaining_abs == 0 {
+            return None;
+        }
+        let num = (equity as u128).checked_mul(10_000 * 1_000_000)?;
+        let den = (price as u128).checked_mul(remaining_abs)?;
+        u64::try_from(num.div_ceil(den)).ok()
+    }
+
+    // =========================================================================
+    // Hyperp mark/index divergence (pure logic)
+    // =========================================================================
+
+    /// True if |mark - index| / index <= max_bps. max_bps == 0 disables the check.
+    /// A zero index is never within bounds when the check is enabled.
+    #[inline]
+    pub fn mark_index_divergence_ok(mark_e6: u64, index_e6: u64, max_bps: u64) -> bool {
+        if max_bps == 0 {
+            return true;
+        }
+        if index_e6 == 0 {
+            return false;
+        }
+        let diff = mark_e6.abs_diff(index_e6) as u128;
+        diff * 10_000 <= (index_e6 as u128) * (max_bps as u128)
+    }
+
+    // =========================================================================
+    // Insurance backstop capacity (pure logic)
+    // =========================================================================
+
+    /// True if the insurance backstop can absorb `notional_e6` more this slot.
+    /// max_per_slot_e6 == 0 means the backstop is disabled.
+    #[inline]
+    pub fn backstop_capacity_ok(
+        used_in_slot_e6: u64,
+        notional_e6: u128,
+        max_per_slot_e6: u64,
+    ) -> bool {
+        max_per_slot_e6 != 0
+            && (used_in_slot_e6 as u128).saturating_add(notional_e6) <= max_per_slot_e6 as u128
+    }
+
+    // =========================================================================
+    // Admin withdrawal bound (pure logic)
+    // =========================================================================
+
+    /// Admin withdrawals may only move non-user funds: `amount` must fit inside the
+    /// insurance fund AND leave the vault still covering the tracked user capital total.
+    /// All values in engine units.
+    #[inline]
+    pub fn admin_withdraw_allowed(
+        vault: u128,
+        user_capital_total: u128,
+        insurance: u128,
+        amount: u128,
+    ) -> bool {
+        if amount > insurance {
+            return false;
+        }
+        match vault.checked_sub(amount) {
+            Some(remaining) => remaining >= user_capital_total,
+            None => false,
+        }
+    }
+
+    /// A partial insurance withdrawal must fit inside the fund and leave at
+    /// least `floor` behind. All values in engine units.
+    #[inline]
+    pub fn insurance_floor_ok(insurance: u128, amount: u128, floor: u128) -> bool {
+        match insurance.checked_sub(amount) {
+            Some(remaining) => remaining >= floor,
+            None => false,
+        }
+    }
+
+    /// Funding rate bounds: a non-zero horizon, a premium cap within 0..=10_000
+    /// bps (100%) and a per-slot cap within 0..=100 bps.
+    #[inline]
+    pub fn funding_caps_ok(
+        max_premium_bps: i64,
+        max_bps_per_slot: i64,
+        horizon_slots: u64,
+    ) -> bool {
+        horizon_slots != 0
+            && (0..=10_000).contains(&max_premium_bps)
+            && (0..=100).contains(&max_bps_per_slot)
+    }
+
+    /// Keeper reward for one crank: `per_liquidation` per liquidation plus
+    /// `per_sweep` if it completed a sweep, never taking insurance below `floor`.
+    #[inline]
+    pub fn crank_reward(
+        liquidations: u64,
+        sweep_completed: bool,
+        per_liquidation: u128,
+        per_sweep: u128,
+        insurance: u128,
+        floor: u128,
+    ) -> u128 {
+        let sweep = if sweep_completed { per_sweep } else { 0 };
+        per_liquidation
+            .saturating_mul(liquidations as u128)
+            .saturating_add(sweep)
+            .min(insurance.saturating_sub(floor))
+    }
+
+    // =========================================================================
+    // Feature flag updates (pure logic)
+    // =========================================================================
+
+    /// Apply a SetFeatureFlags request to `current`: set bits, then clear bits.
+    /// None if either mask names a bit outside `known` or the masks overlap.
+    #[inline]
+    pub fn feature_flags_update(current: u64, set: u64, clear: u64, known: u64) -> Option<u64> {
+        if (set | clear) & !known != 0 || set & clear != 0 {
+            return None;
+        }
+        Some((current | set) & !clear)
+    }
+
+    // =========================================================================
+    // Native vault rent buffer (pure logic)
+    // =========================================================================
+
+    /// A withdrawal from a native-SOL vault must leave at least the token
+    /// account's rent-exempt reserve in lamports, or the runtime could purge it.
+    #[inline]
+    pub fn vault_rent_buffer_ok(vault_lamports: u64, rent_reserve: u64, amount: u64) -> bool {
+        match vault_lamports.checked_sub(amount) {
+            Some(remaining) => remaining >= rent_reserve,
+            None => false,
+        }
+    }
+
+    // =========================================================================
+    // Position transfer shape (pure logic)
+    // =========================================================================
+
+    /// TransferPosition moves `size` out of `from_pos` into `to_pos`: it must
+    /// shrink the source without flipping it, and the destination must be flat
+    /// or on the same side, so total |position| (open interest) is unchanged.
+    #[inline]
+    pub fn transfer_position_ok(from_pos: i128, to_pos: i128, size: i128) -> bool {
+        if size == 0 || size == i128::MIN || !is_reducing_fill(from_pos, -size) {
+            return false;
+        }
+        to_pos == 0 || (to_pos > 0) == (size > 0)
+    }
+
+    // =========================================================================
+    // Vault conservation (pure logic)
+    // =========================================================================
+
+    /// The engine vault must back all user capital plus the insurance fund;
+    /// anything above that is the reserve behind positive PnL. Engine units.
+    #[inline]
+    pub fn engine_vault_covers(vault: u128, c_tot: u128, insurance: u128) -> bool {
+        match c_tot.checked_add(insurance) {
+            Some(need) => vault >= need,
+            None => false,
+        }
+    }
+
+    /// Share of positive PnL the vault actually backs, in 1e6 units
+    /// (1_000_000 = no haircut): the reserve above `c_tot + insurance`,
+    /// capped at `pnl_pos_tot`, over `pnl_pos_tot`. Engine units.
+    #[inline]
+    pub fn haircut_ratio_e6(vault: u128, c_tot: u128, insurance: u128, pnl_pos_tot: u128) -> u64 {
+        if pnl_pos_tot == 0 {
+            return 1_000_000;
+        }
+        let backed = vault
+            .saturating_sub(c_tot)
+            .saturating_sub(insurance)
+            .min(pnl_pos_tot);
+        let ratio = match backed.checked_mul(1_000_000) {
+            Some(scaled) => scaled / pnl_pos_tot,
+            // backed > u128::MAX / 1e6, so pnl_pos_tot / 1e6 is non-zero
+            None => backed / (pnl_pos_tot / 1_000_000),
+        };
+        ratio.min(1_000_000) as u64
+    }
+
+    /// The vault token account must hold exactly the engine vault (scaled back
+    /// to base units) plus the pooled sub-unit `dust_base`.
+    #[inline]
+    pub fn vault_balance_matches(
+        token_balance: u64,
+        engine_vault: u128,
+        unit_scale: u32,
+        dust_base: u64,
+    ) -> bool {
+        let scale = unit_scale.max(1) as u128;
+        match engine_vault
+            .checked_mul(scale)
+            .and_then(|base| base.checked_add(dust_base as u128))
+        {
+            Some(expected) => expected == token_balance as u128,
+            None => false,
+        }
+    }
 }
 
 // 2. mod zc (Zero-Copy unsafe island)
@@ -898,7 +1553,11 @@ pub mod zc {
 }
 
 pub mod matcher_abi {
-    use crate::constants::MATCHER_ABI_VERSION;
+    use crate::constants::{
+        CTX_OFF_MAGIC, MATCHER_ABI_VERSION, MATCHER_CONTEXT_MAGIC, MATCHER_CONTEXT_PREFIX_LEN,
+        RET_OFF_ABI_VERSION, RET_OFF_EXEC_PRICE, RET_OFF_EXEC_SIZE, RET_OFF_FLAGS,
+        RET_OFF_LP_ACCOUNT_ID, RET_OFF_ORACLE_PRICE, RET_OFF_REQ_ID, RET_OFF_RESERVED,
+    };
     use solana_program::program_error::ProgramError;
 
     /// Matcher return flags
@@ -919,29 +1578,48 @@ pub mod matcher_abi {
         pub reserved: u64,
     }
 
-    pub fn read_matcher_return(ctx: &[u8]) -> Result<MatcherReturn, ProgramError> {
-        if ctx.len() < 64 {
+    /// Copy an N-byte field out of the (already length-checked) return prefix.
+    fn ret_field<const N: usize>(prefix: &[u8], off: usize) -> [u8; N] {
+        let mut out = [0u8; N];
+        out.copy_from_slice(&prefix[off..off + N]);
+        out
+    }
+
+    /// Typed accessor for the matcher return prefix written into the matcher context.
+    ///
+    /// Shared by the TradeCpi handler and clients. Decodes the 64-byte prefix at the
+    /// RET_OFF_* offsets and validates its structure (ABI version, zero reserved word).
+    /// Echo checks against the request (req_id, lp_account_id,
+    /// oracle price, size) remain in `validate_matcher_return` / `verify::abi_ok`.
+    pub fn read_return_from_ctx(ctx: &[u8]) -> Result<MatcherReturn, ProgramError> {
+        let prefix = ctx
+            .get(..MATCHER_CONTEXT_PREFIX_LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let ret = MatcherReturn {
+            abi_version: u32::from_le_bytes(ret_field(prefix, RET_OFF_ABI_VERSION)),
+            flags: u32::from_le_bytes(ret_field(prefix, RET_OFF_FLAGS)),
+            exec_price_e6: u64::from_le_bytes(ret_field(prefix, RET_OFF_EXEC_PRICE)),
+            exec_size: i128::from_le_bytes(ret_field(prefix, RET_OFF_EXEC_SIZE)),
+            req_id: u64::from_le_bytes(ret_field(prefix, RET_OFF_REQ_ID)),
+            lp_account_id: u64::from_le_bytes(ret_field(prefix, RET_OFF_LP_ACCOUNT_ID)),
+            oracle_price_e6: u64::from_le_bytes(ret_field(prefix, RET_OFF_ORACLE_PRICE)),
+            reserved: u64::from_le_bytes(ret_field(prefix, RET_OFF_RESERVED)),
+        };
+
+        if ret.abi_version != MATCHER_ABI_VERSION {
             return Err(ProgramError::InvalidAccountData);
         }
-        let abi_version = u32::from_le_bytes(ctx[0..4].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let flags = u32::from_le_bytes(ctx[4..8].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let exec_price_e6 = u64::from_le_bytes(ctx[8..16].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let exec_size = i128::from_le_bytes(ctx[16..32].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let req_id = u64::from_le_bytes(ctx[32..40].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let lp_account_id = u64::from_le_bytes(ctx[40..48].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let oracle_price_e6 = u64::from_le_bytes(ctx[48..56].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let reserved = u64::from_le_bytes(ctx[56..64].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-
-        Ok(MatcherReturn {
-            abi_version,
-            flags,
-            exec_price_e6,
-            exec_size,
-            req_id,
-            lp_account_id,
-            oracle_price_e6,
-            reserved,
-        })
+        if ret.reserved != 0 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(ret)
+    }
+
+    /// Whether the matcher has initialized this context (magic after the return
+    /// prefix). A zeroed context passes the shape checks but its return prefix
+    /// is whatever bytes happen to be there.
+    pub fn context_initialized(ctx: &[u8]) -> bool {
+        ctx.get(CTX_OFF_MAGIC..CTX_OFF_MAGIC + 8) == Some(&MATCHER_CONTEXT_MAGIC.to_le_bytes()[..])
     }
 
     pub fn validate_matcher_return(
@@ -1040,6 +1718,27 @@ pub mod error {
         InvalidTokenProgram,
         InvalidConfigParam,
         HyperpTradeNoCpiDisabled,
+        TradeTooLarge,
+        KeeperSweepStale,
+        BackstopUnavailable,
+        BackstopCapacityExceeded,
+        MarkIndexDiverged,
+        OracleInvalidExpo,
+        ZeroSizeTrade,
+        VaultNotEmpty,
+        HyperpCapRequired,
+        VaultRentExemptBreach,
+        MarketPaused,
+        NoCpiTradingDisabled,
+        MarketHalted,
+        SelfTrade,
+        SlippageExceeded,
+        DuplicateRequest,
+        OpenInterestCap,
+        MatcherNotInitialized,
+        InvalidRiskParams,
+        LeverageCap,
+        BackstopLpAvailable,
     }
 
     impl From<PercolatorError> for ProgramError {
@@ -1067,6 +1766,7 @@ pub mod error {
 
 // 4. mod ix
 pub mod ix {
+    use crate::constants::MAX_TRADE_LEGS;
     use percolator::{RiskParams, U128};
     use solana_program::{program_error::ProgramError, pubkey::Pubkey};
 
@@ -1113,6 +1813,13 @@ pub mod ix {
             lp_idx: u16,
             user_idx: u16,
             size: i128,
+            /// Worst acceptable execution price (engine price, e6); 0 = no limit.
+            /// Optional trailing field, 0 when absent.
+            limit_price_e6: u64,
+            /// Idempotency key checked against the user's last applied one
+            /// (`DuplicateRequest` on a repeat); 0 = unchecked. Optional
+            /// trailing field after `limit_price_e6`, 0 when absent.
+            client_req_id: u64,
         },
         LiquidateAtOracle {
             target_idx: u16,
@@ -1122,11 +1829,18 @@ pub mod ix {
         },
         TopUpInsurance {
             amount: u64,
+            /// Optional attribution: recorded on the donor leaderboard when present
+            donor: Option<Pubkey>,
         },
         TradeCpi {
             lp_idx: u16,
             user_idx: u16,
             size: i128,
+            /// Worst acceptable matcher execution price (e6); 0 = no limit.
+            /// Optional trailing field, 0 when absent.
+            limit_price_e6: u64,
+            /// Idempotency key, as for TradeNoCpi.
+            client_req_id: u64,
         },
         SetRiskThreshold {
             new_threshold: u128,
@@ -1177,17 +1891,273 @@ pub mod ix {
         /// Resolve market: force-close all positions at admin oracle price, enter withdraw-only mode.
         /// Admin only. Uses authority_price_e6 as settlement price.
         ResolveMarket,
-        /// Withdraw insurance fund balance (admin only, requires RESOLVED flag).
-        WithdrawInsurance,
+        /// Withdraw insurance fund balance (admin only).
+        /// None: the whole fund, after resolution with all positions closed.
+        /// Some(amount): `amount` base tokens, leaving at least `insurance_floor`.
+        WithdrawInsurance {
+            amount: Option<u64>,
+        },
         /// Admin force-close an abandoned account after market resolution.
         /// Requires RESOLVED flag, zero position, admin signer.
         AdminForceCloseAccount {
             user_idx: u16,
         },
-    }
-
-    impl Instruction {
-        pub fn decode(input: &[u8]) -> Result<Self, ProgramError> {
+        /// Declare or lift an emergency exit (admin only).
+        /// While active, the reducing side of a fill, liquidations and CloseAccount
+        /// are fee-free.
+        SetEmergencyExit {
+            enabled: u8,
+        },
+        /// Set the max notional of a single fill (admin only). 0 = disabled.
+        SetMaxTradeNotional {
+            max_trade_notional_e6: u64,
+        },
+        /// Read-only: return the global funding index and last crank slot
+        /// as `return_data::FundingIndex`.
+        GetFundingIndex,
+        /// Set the max keeper sweep lag before opening trades are rejected (admin only).
+        /// 0 = disabled.
+        SetMaxSweepLag {
+            max_sweep_lag_slots: u64,
+        },
+        /// Read-only: return the vault authority PDA, its bump and the vault token
+        /// account as `return_data::VaultInfo`.
+        GetVaultInfo,
+        /// Hyperp: reject opening trades while mark and index diverge by more than
+        /// this many bps (admin only). 0 = disabled.
+        SetMaxMarkIndexDivergence {
+            max_mark_index_divergence_bps: u64,
+        },
+        /// Configure the insurance backstop (admin only).
+        /// max_notional_per_slot_e6 = 0 disables it.
+        SetBackstopParams {
+            penalty_bps: u64,
+            max_notional_per_slot_e6: u64,
+        },
+        /// Close the caller's whole position against the insurance fund at oracle,
+        /// paying `backstop_penalty_bps` of notional to insurance. No LP required.
+        CloseWithBackstop {
+            user_idx: u16,
+        },
+        /// Update margins and fees post-init (admin only). Only
+        /// maintenance_margin_bps, initial_margin_bps, trading_fee_bps and
+        /// liquidation_fee_bps are applied; other fields are ignored.
+        /// Tightening maintenance margin fails if it would make an open position
+        /// undercollateralized at the current oracle price, unless allow_force != 0.
+        SetRiskParams {
+            new_params: RiskParams,
+            allow_force: u8,
+        },
+        /// Read-only: preview how liquidating `target_idx` at the current oracle
+        /// would split, as `return_data::LiquidationPreview`. Keepers use it as
+        /// the health check (liquidatable flag, equity, maintenance requirement).
+        SimulateLiquidation {
+            target_idx: u16,
+        },
+        /// Read-only: return `return_data::AccountState` for `user_idx`.
+        /// Accounts `[slab]` or `[slab, clock]`; warmup progress is measured at
+        /// the clock slot when given, else at the engine's last observed slot.
+        GetAccountState {
+            user_idx: u16,
+        },
+        /// Admin: move residual sub-unit `dust_base` from the vault to the admin ATA.
+        SweepDust,
+        /// Read-only: return `return_data::TopDonors`.
+        GetTopDonors,
+        /// Permissionless: liquidate every underwater account among up to
+        /// `max_scan` slots starting at `cursor`. Returns
+        /// `return_data::LiquidationScan` with the cursor to resume from.
+        LiquidateEligible {
+            cursor: u16,
+            max_scan: u16,
+        },
+        /// Read-only: return `return_data::WarmupStatus` for `user_idx` at the
+        /// clock's slot.
+        GetWarmupStatus {
+            user_idx: u16,
+        },
+        /// Reduce `user_idx`'s position to `|position| <= target_abs` against
+        /// `lp_idx` at the oracle price, never flipping sides. Same accounts and
+        /// checks as TradeNoCpi; a no-op if already at or below the target.
+        ReducePosition {
+            lp_idx: u16,
+            user_idx: u16,
+            target_abs: i128,
+        },
+        /// Atomically fill the first `leg_count` `(lp_idx, size)` legs for one user
+        /// (TradeNoCpi rules per leg). Accounts `[user, slab, clock, oracle,
+        /// lp_owner_0, ..]`; the user's initial margin is checked once on the
+        /// net fill.
+        MultiTrade {
+            user_idx: u16,
+            leg_count: u8,
+            legs: [(u16, i128); MAX_TRADE_LEGS],
+        },
+        /// Set then clear `MarketConfig::feature_flags` bits (admin only). Only
+        /// `state::FEATURE_*` bits are accepted, and a bit may not be in both masks.
+        SetFeatureFlags {
+            set: u64,
+            clear: u64,
+        },
+        /// Read-only: return `return_data::FeatureFlags`.
+        GetFeatureFlags,
+        /// Set the trading mode (admin only): 0 = normal, 1 = reduce-only,
+        /// 2 = halted (see `state::MARKET_MODE_*`).
+        SetMarketMode {
+            mode: u8,
+        },
+        /// KeeperCrank once per slot from the engine's last cranked slot up to
+        /// `min(up_to_slot, clock.slot)`, at most `max_iters` slots
+        /// (<= `MAX_CRANK_BATCH_ITERS`). Returns `return_data::CrankBatch`.
+        KeeperCrankBatch {
+            caller_idx: u16,
+            up_to_slot: u64,
+            max_iters: u16,
+            allow_panic: u8,
+        },
+        /// Move `size` of position from one user account to another owned by the
+        /// same signer, marking both to the current price first.
+        TransferPosition {
+            from_idx: u16,
+            to_idx: u16,
+            size: i128,
+        },
+        /// Update oracle validation (admin only): confidence filter in bps
+        /// (<= 10_000) and max price age in seconds.
+        SetOracleParams {
+            conf_filter_bps: u16,
+            max_staleness_secs: u64,
+        },
+        /// Configure the secondary and tertiary index feeds (admin only, external
+        /// oracle markets). [0; 32] leaves a slot unconfigured.
+        SetIndexFeeds {
+            secondary_feed_id: [u8; 32],
+            tertiary_feed_id: [u8; 32],
+        },
+        /// Crank only accounts `[start, start + count)`. The window at 0 also runs
+        /// the global step (price, funding accrual); later windows must continue
+        /// from the engine's `crank_cursor`. Returns `return_data::CrankOutcome`.
+        KeeperCrankWindow {
+            caller_idx: u16,
+            start: u16,
+            count: u16,
+        },
+        /// Liquidate `close_bps / 10_000` of an underwater position at the
+        /// LiquidateAtOracle price. Falls back to a full close when the remainder
+        /// would still be below maintenance after the fee.
+        LiquidatePartial {
+            target_idx: u16,
+            close_bps: u16,
+        },
+        /// Set the insurance balance (engine units) a partial WithdrawInsurance
+        /// must leave behind (admin only).
+        SetInsuranceFloor {
+            insurance_floor: u128,
+        },
+        /// Native-SOL markets only: wrap `lamports` from the signer into its
+        /// native-mint token account (system transfer + `sync_native`), then
+        /// deposit them as DepositCollateral would.
+        DepositCollateralWrapped {
+            user_idx: u16,
+            lamports: u64,
+        },
+        /// Fund `target_idx` from the signer's token account; unlike
+        /// DepositCollateral the signer need not own the target.
+        DepositTo {
+            target_idx: u16,
+            amount: u64,
+        },
+        /// Read-only, permissionless: recompute the engine aggregates and the
+        /// vault balance and return `return_data::InvariantReport`. Violations
+        /// are reported in the bitmask; the instruction itself does not fail.
+        VerifyInvariants,
+        /// Rebind an LP to a new matcher program and context (LP owner only).
+        /// Accounts `[lp_owner, slab, matcher_program, matcher_context]`; the new
+        /// matcher must pass the same shape checks TradeCpi applies.
+        SetLpMatcher {
+            lp_idx: u16,
+            matcher_program: Pubkey,
+            matcher_context: Pubkey,
+        },
+        /// Read-only: return the lowest-indexed account owned by `owner` as
+        /// `return_data::AccountIndex` (`EngineAccountNotFound` if none).
+        GetAccountIndex {
+            owner: Pubkey,
+        },
+        /// Update the funding caps and horizon (admin only), leaving the rest of
+        /// the UpdateConfig parameters untouched. Bounds: `verify::funding_caps_ok`.
+        SetFundingParams {
+            max_premium_bps: i64,
+            max_bps_per_slot: i64,
+            horizon_slots: u64,
+        },
+        /// Set keeper rewards (admin only, engine units, 0 disables): paid from
+        /// insurance to a self-cranking caller's capital per liquidation and per
+        /// completed sweep (at most one sweep reward per slot).
+        SetCrankRewards {
+            per_liquidation: u128,
+            per_sweep: u128,
+        },
+        /// Read-only: return `return_data::MarketStats` (engine totals, open
+        /// interest by side and the current PnL haircut).
+        GetMarketStats,
+        /// Close an LP account (owner only, CloseAccount's accounts): its
+        /// position must be flat; remaining capital is paid out and the slot freed.
+        CloseLp {
+            lp_idx: u16,
+        },
+        /// Cap total open interest (sum of |position| over all accounts, in
+        /// position units) for fills that grow it (admin only, 0 disables).
+        SetOpenInterestCap {
+            max_open_interest_abs: u128,
+        },
+        /// Liquidate `target_idx` by closing what LiquidateAtOracle would against
+        /// `lp_idx` through the LP's matcher (accounts: LiquidateAtOracle's,
+        /// then matcher program, matcher context, LP PDA as in TradeCpi). The
+        /// fill must land within `liquidation_fee_bps` of the liquidation
+        /// price and carries the engine's liquidation fee; a rejected or
+        /// out-of-bounds quote falls back to LiquidateAtOracle's seizure.
+        LiquidateCpi {
+            target_idx: u16,
+            lp_idx: u16,
+        },
+        /// Upgrade a slab written at layout `from_version` to `VERSION` in place
+        /// (admin only; accounts `[admin, slab]`). Fund the slab's rent for
+        /// `SLAB_LEN` beforehand.
+        MigrateSlab {
+            from_version: u32,
+        },
+        /// Update the liquidation fee, fee cap, post-liquidation buffer and
+        /// minimum liquidation size (admin only, accounts `[admin, slab]`).
+        /// Bounds: `verify::liquidation_params_ok`. None of these enter the
+        /// maintenance test, so no account changes liquidatability.
+        SetLiquidationParams {
+            liquidation_fee_bps: u64,
+            liquidation_fee_cap: u128,
+            liquidation_buffer_bps: u64,
+            min_liquidation_abs: u128,
+        },
+        /// Cap each account's leverage on growing fills: notional at most
+        /// `capital * max_leverage_x` (admin only, 0 disables).
+        SetMaxLeverage {
+            max_leverage_x: u64,
+        },
+        /// Dry run of InitMarket's parameter checks: same payload as InitMarket
+        /// (admin and mint are read and ignored), no accounts, nothing written.
+        /// Returns `return_data::InitParamsReport` with one bit per failed check.
+        ValidateInitParams {
+            index_feed_id: [u8; 32],
+            max_staleness_secs: u64,
+            conf_filter_bps: u16,
+            invert: u8,
+            unit_scale: u32,
+            initial_mark_price_e6: u64,
+            risk_params: RiskParams,
+        },
+    }
+
+    impl Instruction {
+        pub fn decode(input: &[u8]) -> Result<Self, ProgramError> {
             let (&tag, mut rest) = input
                 .split_first()
                 .ok_or(ProgramError::InvalidInstructionData)?;
@@ -1254,14 +2224,26 @@ pub mod ix {
                     })
                 }
                 6 => {
-                    // TradeNoCpi
+                    // TradeNoCpi: trailing limit price and client_req_id are optional
                     let lp_idx = read_u16(&mut rest)?;
                     let user_idx = read_u16(&mut rest)?;
                     let size = read_i128(&mut rest)?;
+                    let limit_price_e6 = if rest.is_empty() {
+                        0
+                    } else {
+                        read_u64(&mut rest)?
+                    };
+                    let client_req_id = if rest.is_empty() {
+                        0
+                    } else {
+                        read_u64(&mut rest)?
+                    };
                     Ok(Instruction::TradeNoCpi {
                         lp_idx,
                         user_idx,
                         size,
+                        limit_price_e6,
+                        client_req_id,
                     })
                 }
                 7 => {
@@ -1277,17 +2259,35 @@ pub mod ix {
                 9 => {
                     // TopUpInsurance
                     let amount = read_u64(&mut rest)?;
-                    Ok(Instruction::TopUpInsurance { amount })
+                    // Trailing donor pubkey is optional (anonymous top-up without it)
+                    let donor = if rest.is_empty() {
+                        None
+                    } else {
+                        Some(read_pubkey(&mut rest)?)
+                    };
+                    Ok(Instruction::TopUpInsurance { amount, donor })
                 }
                 10 => {
-                    // TradeCpi
+                    // TradeCpi: trailing limit price and client_req_id are optional
                     let lp_idx = read_u16(&mut rest)?;
                     let user_idx = read_u16(&mut rest)?;
                     let size = read_i128(&mut rest)?;
+                    let limit_price_e6 = if rest.is_empty() {
+                        0
+                    } else {
+                        read_u64(&mut rest)?
+                    };
+                    let client_req_id = if rest.is_empty() {
+                        0
+                    } else {
+                        read_u64(&mut rest)?
+                    };
                     Ok(Instruction::TradeCpi {
                         lp_idx,
                         user_idx,
                         size,
+                        limit_price_e6,
+                        client_req_id,
                     })
                 }
                 11 => {
@@ -1360,11 +2360,312 @@ pub mod ix {
                     Ok(Instruction::SetOraclePriceCap { max_change_e2bps })
                 }
                 19 => Ok(Instruction::ResolveMarket),
-                20 => Ok(Instruction::WithdrawInsurance),
+                20 => {
+                    // WithdrawInsurance: trailing amount is optional (whole fund without it)
+                    let amount = if rest.is_empty() {
+                        None
+                    } else {
+                        Some(read_u64(&mut rest)?)
+                    };
+                    Ok(Instruction::WithdrawInsurance { amount })
+                }
                 21 => {
                     let user_idx = read_u16(&mut rest)?;
                     Ok(Instruction::AdminForceCloseAccount { user_idx })
                 }
+                22 => {
+                    // SetEmergencyExit
+                    let enabled = read_u8(&mut rest)?;
+                    Ok(Instruction::SetEmergencyExit { enabled })
+                }
+                23 => {
+                    // SetMaxTradeNotional
+                    let max_trade_notional_e6 = read_u64(&mut rest)?;
+                    Ok(Instruction::SetMaxTradeNotional {
+                        max_trade_notional_e6,
+                    })
+                }
+                24 => Ok(Instruction::GetFundingIndex),
+                25 => {
+                    // SetMaxSweepLag
+                    let max_sweep_lag_slots = read_u64(&mut rest)?;
+                    Ok(Instruction::SetMaxSweepLag {
+                        max_sweep_lag_slots,
+                    })
+                }
+                26 => {
+                    // SetBackstopParams
+                    let penalty_bps = read_u64(&mut rest)?;
+                    let max_notional_per_slot_e6 = read_u64(&mut rest)?;
+                    Ok(Instruction::SetBackstopParams {
+                        penalty_bps,
+                        max_notional_per_slot_e6,
+                    })
+                }
+                27 => {
+                    // CloseWithBackstop
+                    let user_idx = read_u16(&mut rest)?;
+                    Ok(Instruction::CloseWithBackstop { user_idx })
+                }
+                28 => Ok(Instruction::GetVaultInfo),
+                29 => {
+                    // SetMaxMarkIndexDivergence
+                    let max_mark_index_divergence_bps = read_u64(&mut rest)?;
+                    Ok(Instruction::SetMaxMarkIndexDivergence {
+                        max_mark_index_divergence_bps,
+                    })
+                }
+                30 => {
+                    // SetRiskParams
+                    let new_params = read_risk_params(&mut rest)?;
+                    let allow_force = read_u8(&mut rest)?;
+                    Ok(Instruction::SetRiskParams {
+                        new_params,
+                        allow_force,
+                    })
+                }
+                31 => {
+                    // SimulateLiquidation
+                    let target_idx = read_u16(&mut rest)?;
+                    Ok(Instruction::SimulateLiquidation { target_idx })
+                }
+                32 => {
+                    // GetAccountState
+                    let user_idx = read_u16(&mut rest)?;
+                    Ok(Instruction::GetAccountState { user_idx })
+                }
+                33 => Ok(Instruction::SweepDust),
+                34 => Ok(Instruction::GetTopDonors),
+                35 => {
+                    // LiquidateEligible
+                    let cursor = read_u16(&mut rest)?;
+                    let max_scan = read_u16(&mut rest)?;
+                    Ok(Instruction::LiquidateEligible { cursor, max_scan })
+                }
+                36 => {
+                    // GetWarmupStatus
+                    let user_idx = read_u16(&mut rest)?;
+                    Ok(Instruction::GetWarmupStatus { user_idx })
+                }
+                37 => {
+                    // ReducePosition
+                    let lp_idx = read_u16(&mut rest)?;
+                    let user_idx = read_u16(&mut rest)?;
+                    let target_abs = read_i128(&mut rest)?;
+                    Ok(Instruction::ReducePosition {
+                        lp_idx,
+                        user_idx,
+                        target_abs,
+                    })
+                }
+                38 => {
+                    // MultiTrade
+                    let user_idx = read_u16(&mut rest)?;
+                    let leg_count = read_u8(&mut rest)?;
+                    if leg_count == 0 || leg_count as usize > MAX_TRADE_LEGS {
+                        return Err(ProgramError::InvalidInstructionData);
+                    }
+                    let mut legs = [(0u16, 0i128); MAX_TRADE_LEGS];
+                    for leg in legs.iter_mut().take(leg_count as usize) {
+                        *leg = (read_u16(&mut rest)?, read_i128(&mut rest)?);
+                    }
+                    Ok(Instruction::MultiTrade {
+                        user_idx,
+                        leg_count,
+                        legs,
+                    })
+                }
+                39 => {
+                    // SetFeatureFlags
+                    let set = read_u64(&mut rest)?;
+                    let clear = read_u64(&mut rest)?;
+                    Ok(Instruction::SetFeatureFlags { set, clear })
+                }
+                40 => Ok(Instruction::GetFeatureFlags),
+                41 => {
+                    // SetMarketMode
+                    let mode = read_u8(&mut rest)?;
+                    Ok(Instruction::SetMarketMode { mode })
+                }
+                42 => {
+                    // KeeperCrankBatch
+                    let caller_idx = read_u16(&mut rest)?;
+                    let up_to_slot = read_u64(&mut rest)?;
+                    let max_iters = read_u16(&mut rest)?;
+                    let allow_panic = read_u8(&mut rest)?;
+                    Ok(Instruction::KeeperCrankBatch {
+                        caller_idx,
+                        up_to_slot,
+                        max_iters,
+                        allow_panic,
+                    })
+                }
+                43 => {
+                    // TransferPosition
+                    let from_idx = read_u16(&mut rest)?;
+                    let to_idx = read_u16(&mut rest)?;
+                    let size = read_i128(&mut rest)?;
+                    Ok(Instruction::TransferPosition {
+                        from_idx,
+                        to_idx,
+                        size,
+                    })
+                }
+                44 => {
+                    // SetOracleParams
+                    let conf_filter_bps = read_u16(&mut rest)?;
+                    let max_staleness_secs = read_u64(&mut rest)?;
+                    Ok(Instruction::SetOracleParams {
+                        conf_filter_bps,
+                        max_staleness_secs,
+                    })
+                }
+                45 => {
+                    // SetIndexFeeds
+                    let secondary_feed_id = read_bytes32(&mut rest)?;
+                    let tertiary_feed_id = read_bytes32(&mut rest)?;
+                    Ok(Instruction::SetIndexFeeds {
+                        secondary_feed_id,
+                        tertiary_feed_id,
+                    })
+                }
+                46 => {
+                    // KeeperCrankWindow
+                    let caller_idx = read_u16(&mut rest)?;
+                    let start = read_u16(&mut rest)?;
+                    let count = read_u16(&mut rest)?;
+                    Ok(Instruction::KeeperCrankWindow {
+                        caller_idx,
+                        start,
+                        count,
+                    })
+                }
+                47 => {
+                    // LiquidatePartial
+                    let target_idx = read_u16(&mut rest)?;
+                    let close_bps = read_u16(&mut rest)?;
+                    Ok(Instruction::LiquidatePartial {
+                        target_idx,
+                        close_bps,
+                    })
+                }
+                48 => {
+                    // SetInsuranceFloor
+                    let insurance_floor = read_u128(&mut rest)?;
+                    Ok(Instruction::SetInsuranceFloor { insurance_floor })
+                }
+                49 => {
+                    // DepositCollateralWrapped
+                    let user_idx = read_u16(&mut rest)?;
+                    let lamports = read_u64(&mut rest)?;
+                    Ok(Instruction::DepositCollateralWrapped { user_idx, lamports })
+                }
+                50 => {
+                    // DepositTo
+                    let target_idx = read_u16(&mut rest)?;
+                    let amount = read_u64(&mut rest)?;
+                    Ok(Instruction::DepositTo { target_idx, amount })
+                }
+                51 => Ok(Instruction::VerifyInvariants),
+                52 => {
+                    // SetLpMatcher
+                    let lp_idx = read_u16(&mut rest)?;
+                    let matcher_program = read_pubkey(&mut rest)?;
+                    let matcher_context = read_pubkey(&mut rest)?;
+                    Ok(Instruction::SetLpMatcher {
+                        lp_idx,
+                        matcher_program,
+                        matcher_context,
+                    })
+                }
+                53 => {
+                    // GetAccountIndex
+                    let owner = read_pubkey(&mut rest)?;
+                    Ok(Instruction::GetAccountIndex { owner })
+                }
+                54 => {
+                    // SetFundingParams
+                    let max_premium_bps = read_i64(&mut rest)?;
+                    let max_bps_per_slot = read_i64(&mut rest)?;
+                    let horizon_slots = read_u64(&mut rest)?;
+                    Ok(Instruction::SetFundingParams {
+                        max_premium_bps,
+                        max_bps_per_slot,
+                        horizon_slots,
+                    })
+                }
+                55 => {
+                    // SetCrankRewards
+                    let per_liquidation = read_u128(&mut rest)?;
+                    let per_sweep = read_u128(&mut rest)?;
+                    Ok(Instruction::SetCrankRewards {
+                        per_liquidation,
+                        per_sweep,
+                    })
+                }
+                56 => Ok(Instruction::GetMarketStats),
+                57 => {
+                    // CloseLp
+                    let lp_idx = read_u16(&mut rest)?;
+                    Ok(Instruction::CloseLp { lp_idx })
+                }
+                58 => {
+                    // SetOpenInterestCap
+                    let max_open_interest_abs = read_u128(&mut rest)?;
+                    Ok(Instruction::SetOpenInterestCap {
+                        max_open_interest_abs,
+                    })
+                }
+                59 => {
+                    // LiquidateCpi
+                    let target_idx = read_u16(&mut rest)?;
+                    let lp_idx = read_u16(&mut rest)?;
+                    Ok(Instruction::LiquidateCpi { target_idx, lp_idx })
+                }
+                60 => {
+                    // MigrateSlab
+                    let from_version = read_u32(&mut rest)?;
+                    Ok(Instruction::MigrateSlab { from_version })
+                }
+                61 => {
+                    // SetLiquidationParams
+                    let liquidation_fee_bps = read_u64(&mut rest)?;
+                    let liquidation_fee_cap = read_u128(&mut rest)?;
+                    let liquidation_buffer_bps = read_u64(&mut rest)?;
+                    let min_liquidation_abs = read_u128(&mut rest)?;
+                    Ok(Instruction::SetLiquidationParams {
+                        liquidation_fee_bps,
+                        liquidation_fee_cap,
+                        liquidation_buffer_bps,
+                        min_liquidation_abs,
+                    })
+                }
+                62 => {
+                    // ValidateInitParams: InitMarket's payload
+                    let _admin = read_pubkey(&mut rest)?;
+                    let _collateral_mint = read_pubkey(&mut rest)?;
+                    let index_feed_id = read_bytes32(&mut rest)?;
+                    let max_staleness_secs = read_u64(&mut rest)?;
+                    let conf_filter_bps = read_u16(&mut rest)?;
+                    let invert = read_u8(&mut rest)?;
+                    let unit_scale = read_u32(&mut rest)?;
+                    let initial_mark_price_e6 = read_u64(&mut rest)?;
+                    let risk_params = read_risk_params(&mut rest)?;
+                    Ok(Instruction::ValidateInitParams {
+                        index_feed_id,
+                        max_staleness_secs,
+                        conf_filter_bps,
+                        invert,
+                        unit_scale,
+                        initial_mark_price_e6,
+                        risk_params,
+                    })
+                }
+                63 => {
+                    // SetMaxLeverage
+                    let max_leverage_x = read_u64(&mut rest)?;
+                    Ok(Instruction::SetMaxLeverage { max_leverage_x })
+                }
                 _ => Err(ProgramError::InvalidInstructionData),
             }
         }
@@ -1520,10 +2821,10 @@ pub mod accounts {
 
 // 6. mod state
 pub mod state {
-    use crate::constants::{CONFIG_LEN, HEADER_LEN};
+    use crate::constants::{HEADER_LEN, MAX_DONORS, MAX_EXTRA_INDEX_FEEDS};
     use bytemuck::{Pod, Zeroable};
     use core::cell::RefMut;
-    use core::mem::offset_of;
+    use core::mem::{offset_of, size_of};
     use solana_program::account_info::AccountInfo;
     use solana_program::program_error::ProgramError;
 
@@ -1615,6 +2916,160 @@ pub mod state {
         /// Last effective oracle price (after clamping), in e6 format.
         /// 0 = no history (first price accepted as-is).
         pub last_effective_price_e6: u64,
+
+        // ========================================
+        // Trade Limits
+        // ========================================
+        /// Max notional of a single fill (|size| * price / 1e6). 0 = disabled.
+        pub max_trade_notional_e6: u64,
+
+        // ========================================
+        // Keeper Sweep Metering
+        // ========================================
+        /// Max slots since the last completed crank sweep before opening trades
+        /// are rejected. 0 = disabled.
+        pub max_sweep_lag_slots: u64,
+        /// Slot at which the crank cursor last completed a full cycle.
+        pub last_full_sweep_slot: u64,
+
+        // ========================================
+        // Insurance Backstop
+        // ========================================
+        /// Penalty charged to insurance on a backstop close (bps of notional)
+        pub backstop_penalty_bps: u64,
+        /// Max notional the backstop absorbs per slot. 0 = disabled.
+        pub backstop_max_notional_per_slot_e6: u64,
+        /// Slot the usage counter below belongs to
+        pub backstop_slot: u64,
+        /// Notional absorbed by the backstop in `backstop_slot`
+        pub backstop_used_notional_e6: u64,
+
+        // ========================================
+        // Hyperp Mark Guard
+        // ========================================
+        /// Hyperp only: max |mark - index| / index (bps) at which opening trades
+        /// are accepted. 0 = disabled.
+        pub max_mark_index_divergence_bps: u64,
+
+        // ========================================
+        // Insurance Donor Leaderboard
+        // ========================================
+        /// Donors of attributed TopUpInsurance calls ([0; 32] = empty slot)
+        pub donor_keys: [[u8; 32]; MAX_DONORS],
+        /// Running base-token total per donor (parallel to `donor_keys`)
+        pub donor_totals: [u64; MAX_DONORS],
+
+        // ========================================
+        // Feature Flags
+        // ========================================
+        /// Admin toggles, one bit each (`FEATURE_*`). Market lifecycle flags
+        /// (resolved, emergency exit) live in the header flags byte instead.
+        pub feature_flags: u64,
+        /// Keeps the fields after it 16-byte aligned (u128 alignment on host
+        /// targets), so `Pod` sees no padding.
+        pub _feature_flags_pad: u64,
+
+        // ========================================
+        // Additional Index Oracles
+        // ========================================
+        /// Secondary and tertiary index feeds ([0; 32] = not configured). When set,
+        /// trades, cranks and withdrawals price off the median of the fresh feeds.
+        pub extra_index_feed_ids: [[u8; 32]; MAX_EXTRA_INDEX_FEEDS],
+
+        // ========================================
+        // Funding TWAP
+        // ========================================
+        /// Sum of price_e6 * slots over the current window (`FEATURE_FUNDING_TWAP`)
+        pub funding_twap_price_sum: u128,
+        /// Slots covered by the window; restarts once it would pass the horizon
+        pub funding_twap_dt_total: u64,
+        /// Slot of the last sample folded in
+        pub funding_twap_last_slot: u64,
+
+        // ========================================
+        // Insurance Withdrawal Floor
+        // ========================================
+        /// Insurance balance (engine units) a partial WithdrawInsurance must leave
+        pub insurance_floor: u128,
+
+        // ========================================
+        // Keeper Rewards
+        // ========================================
+        /// Paid from insurance to a self-cranking caller per liquidation (engine units)
+        pub crank_reward_per_liquidation: u128,
+        /// Paid from insurance to a self-cranking caller per completed sweep
+        pub crank_reward_per_sweep: u128,
+
+        // ========================================
+        // Open Interest Cap
+        // ========================================
+        /// Cap on total open interest (sum of |position|) for growing fills; 0 = off
+        pub max_open_interest_abs: u128,
+
+        // ========================================
+        // Leverage Cap
+        // ========================================
+        /// Per-account cap on notional / capital for growing fills; 0 = off
+        pub max_leverage_x: u64,
+        /// Keeps the reserved tail 16-byte aligned for future u128 fields
+        pub _max_leverage_pad: u64,
+
+        /// Unassigned tail keeping the config at CONFIG_SIZE bytes. New fields are
+        /// carved from the front of it (shrinking CONFIG_RESERVED_LEN) without a
+        /// layout bump, so they must treat all-zero as disabled/default.
+        pub _reserved: [u8; CONFIG_RESERVED_LEN],
+    }
+
+    /// Fixed MarketConfig size.
+    pub const CONFIG_SIZE: usize = 1024;
+    /// Bytes of the config not yet assigned to a field.
+    pub const CONFIG_RESERVED_LEN: usize = 128;
+    /// Config bytes stored between the header and the engine (the original
+    /// 320-byte config, so ENGINE_OFF never moves).
+    pub const CONFIG_HEAD_LEN: usize = offset_of!(MarketConfig, max_trade_notional_e6);
+    /// Config bytes stored after the engine, at the end of the slab.
+    pub const CONFIG_TAIL_LEN: usize = CONFIG_SIZE - CONFIG_HEAD_LEN;
+
+    // Portable compile-time assertion that the config stays CONFIG_SIZE bytes
+    const _: [(); CONFIG_SIZE] = [(); size_of::<MarketConfig>()];
+    // ... and that fields are only ever appended after the original 320 bytes
+    const _: [(); 320] = [(); CONFIG_HEAD_LEN];
+
+    /// Paused: no new risk. Trades, deposits and new accounts are rejected;
+    /// withdrawals, closes, liquidations and cranks still run.
+    pub const FEATURE_PAUSED: u64 = 1 << 0;
+    /// CPI-only trading: TradeNoCpi, ReducePosition and MultiTrade are rejected.
+    pub const FEATURE_CPI_ONLY: u64 = 1 << 1;
+    /// Inventory funding prices off the crank-sampled TWAP over the funding
+    /// horizon instead of the spot oracle price. Hyperp funding is unaffected.
+    pub const FEATURE_FUNDING_TWAP: u64 = 1 << 2;
+    /// Every bit SetFeatureFlags accepts.
+    pub const FEATURE_KNOWN_MASK: u64 = FEATURE_PAUSED | FEATURE_CPI_ONLY | FEATURE_FUNDING_TWAP;
+
+    pub fn feature_enabled(config: &MarketConfig, feature: u64) -> bool {
+        config.feature_flags & feature != 0
+    }
+
+    /// Add `amount` to `donor`'s running total. A new donor takes an empty slot, or
+    /// evicts the smallest total when the board is full and it would outrank it.
+    pub fn record_donation(config: &mut MarketConfig, donor: &[u8; 32], amount: u64) {
+        if amount == 0 || *donor == [0u8; 32] {
+            return;
+        }
+        if let Some(i) = config.donor_keys.iter().position(|k| k == donor) {
+            config.donor_totals[i] = config.donor_totals[i].saturating_add(amount);
+            return;
+        }
+        let mut slot = 0;
+        for i in 1..MAX_DONORS {
+            if config.donor_totals[i] < config.donor_totals[slot] {
+                slot = i;
+            }
+        }
+        if config.donor_keys[slot] == [0u8; 32] || amount > config.donor_totals[slot] {
+            config.donor_keys[slot] = *donor;
+            config.donor_totals[slot] = amount;
+        }
     }
 
     pub fn slab_data_mut<'a, 'b>(
@@ -1688,6 +3143,20 @@ pub mod state {
     /// Flag bit: Market is resolved (withdraw-only mode)
     pub const FLAG_RESOLVED: u8 = 1 << 0;
 
+    /// Flag bit: Emergency exit declared by admin (fees waived on reducing trades and closes)
+    pub const FLAG_EMERGENCY_EXIT: u8 = 1 << 1;
+
+    /// Offset of the trading mode byte in SlabHeader (_padding[1])
+    pub const MODE_OFF: usize = 14;
+
+    /// Trading mode: no restrictions.
+    pub const MARKET_MODE_NORMAL: u8 = 0;
+    /// Trading mode: only position-reducing fills are accepted.
+    pub const MARKET_MODE_REDUCE_ONLY: u8 = 1;
+    /// Trading mode: everything but CloseAccount, cranks and admin
+    /// instructions is rejected.
+    pub const MARKET_MODE_HALTED: u8 = 2;
+
     /// Read market flags from _padding[0].
     pub fn read_flags(data: &[u8]) -> u8 {
         data[FLAGS_OFF]
@@ -1698,6 +3167,16 @@ pub mod state {
         data[FLAGS_OFF] = flags;
     }
 
+    /// Read the trading mode from _padding[1].
+    pub fn read_market_mode(data: &[u8]) -> u8 {
+        data[MODE_OFF]
+    }
+
+    /// Write the trading mode to _padding[1].
+    pub fn write_market_mode(data: &mut [u8], mode: u8) {
+        data[MODE_OFF] = mode;
+    }
+
     /// Check if market is resolved (withdraw-only mode).
     pub fn is_resolved(data: &[u8]) -> bool {
         read_flags(data) & FLAG_RESOLVED != 0
@@ -1709,2686 +3188,8047 @@ pub mod state {
         write_flags(data, flags);
     }
 
+    /// Check if emergency exit is active (fee-free reducing trades and closes).
+    pub fn is_emergency_exit(data: &[u8]) -> bool {
+        read_flags(data) & FLAG_EMERGENCY_EXIT != 0
+    }
+
+    /// Set or clear the emergency exit flag.
+    pub fn set_emergency_exit(data: &mut [u8], active: bool) {
+        let flags = if active {
+            read_flags(data) | FLAG_EMERGENCY_EXIT
+        } else {
+            read_flags(data) & !FLAG_EMERGENCY_EXIT
+        };
+        write_flags(data, flags);
+    }
+
+    /// Read the config: its head follows the header, its tail ends the slab.
     pub fn read_config(data: &[u8]) -> MarketConfig {
         let mut c = MarketConfig::zeroed();
-        let src = &data[HEADER_LEN..HEADER_LEN + CONFIG_LEN];
+        let tail_off = data.len() - CONFIG_TAIL_LEN;
         let dst = bytemuck::bytes_of_mut(&mut c);
-        dst.copy_from_slice(src);
+        dst[..CONFIG_HEAD_LEN].copy_from_slice(&data[HEADER_LEN..HEADER_LEN + CONFIG_HEAD_LEN]);
+        dst[CONFIG_HEAD_LEN..].copy_from_slice(&data[tail_off..]);
         c
     }
 
     pub fn write_config(data: &mut [u8], c: &MarketConfig) {
+        let tail_off = data.len() - CONFIG_TAIL_LEN;
         let src = bytemuck::bytes_of(c);
-        let dst = &mut data[HEADER_LEN..HEADER_LEN + CONFIG_LEN];
-        dst.copy_from_slice(src);
+        data[HEADER_LEN..HEADER_LEN + CONFIG_HEAD_LEN].copy_from_slice(&src[..CONFIG_HEAD_LEN]);
+        data[tail_off..].copy_from_slice(&src[CONFIG_HEAD_LEN..]);
     }
 }
 
-// 7. mod units - base token/units conversion at instruction boundaries
-pub mod units {
-    /// Convert base token amount to units, returning (units, dust).
-    /// Base token is the collateral (e.g., lamports for SOL, satoshis for BTC).
-    /// If scale is 0, returns (base, 0) - no scaling.
-    #[inline]
-    pub fn base_to_units(base: u64, scale: u32) -> (u64, u64) {
-        if scale == 0 {
-            return (base, 0);
-        }
-        let s = scale as u64;
-        (base / s, base % s)
+// 6b. mod return_data - stable little-endian payloads returned via set_return_data
+pub mod return_data {
+    use crate::constants::MAX_DONORS;
+
+    /// Sequential little-endian writer over a fixed-size buffer.
+    pub(crate) struct Writer<'a> {
+        buf: &'a mut [u8],
+        off: usize,
     }
 
-    /// Convert units to base token amount.
-    /// If scale is 0, returns units unchanged - no scaling.
-    #[inline]
-    pub fn units_to_base(units: u64, scale: u32) -> u64 {
-        if scale == 0 {
-            return units;
+    impl<'a> Writer<'a> {
+        pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+            Self { buf, off: 0 }
         }
-        units.saturating_mul(scale as u64)
-    }
 
-    /// Convert units to base token amount with overflow check.
-    /// Returns None if overflow would occur.
-    #[inline]
-    pub fn units_to_base_checked(units: u64, scale: u32) -> Option<u64> {
-        if scale == 0 {
-            return Some(units);
+        pub(crate) fn put(&mut self, bytes: &[u8]) {
+            self.buf[self.off..self.off + bytes.len()].copy_from_slice(bytes);
+            self.off += bytes.len();
         }
-        units.checked_mul(scale as u64)
     }
-}
 
-// 8. mod oracle
-pub mod oracle {
-    use crate::error::PercolatorError;
-    use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
+    /// Sequential little-endian reader; every getter returns None on short input.
+    pub(crate) struct Reader<'a> {
+        buf: &'a [u8],
+        off: usize,
+    }
 
-    // SECURITY (H5): The "devnet" feature disables critical oracle safety checks:
-    // - Staleness validation (stale prices accepted)
-    // - Confidence interval validation (wide confidence accepted)
-    //
-    // WARNING: NEVER deploy to mainnet with the "devnet" feature enabled!
-    // Build for mainnet with: cargo build-sbf (without --features devnet)
+    impl<'a> Reader<'a> {
+        pub(crate) fn new(buf: &'a [u8]) -> Self {
+            Self { buf, off: 0 }
+        }
 
-    /// Pyth Solana Receiver program ID (same for mainnet and devnet)
-    /// rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ
-    pub const PYTH_RECEIVER_PROGRAM_ID: Pubkey = Pubkey::new_from_array([
-        0x0c, 0xb7, 0xfa, 0xbb, 0x52, 0xf7, 0xa6, 0x48, 0xbb, 0x5b, 0x31, 0x7d, 0x9a, 0x01, 0x8b,
-        0x90, 0x57, 0xcb, 0x02, 0x47, 0x74, 0xfa, 0xfe, 0x01, 0xe6, 0xc4, 0xdf, 0x98, 0xcc, 0x38,
-        0x58, 0x81,
-    ]);
+        pub(crate) fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+            let bytes = self.buf.get(self.off..self.off + N)?;
+            self.off += N;
+            bytes.try_into().ok()
+        }
 
-    /// Chainlink OCR2 Store program ID (same for mainnet and devnet)
-    /// HEvSKofvBgfaexv23kMabbYqxasxU3mQ4ibBMEmJWHny
-    pub const CHAINLINK_OCR2_PROGRAM_ID: Pubkey = Pubkey::new_from_array([
-        0xf1, 0x4b, 0xf6, 0x5a, 0xd5, 0x6b, 0xd2, 0xba, 0x71, 0x5e, 0x45, 0x74, 0x2c, 0x23, 0x1f,
-        0x27, 0xd6, 0x36, 0x21, 0xcf, 0x5b, 0x77, 0x8f, 0x37, 0xc1, 0xa2, 0x48, 0x95, 0x1d, 0x17,
-        0x56, 0x02,
-    ]);
+        pub(crate) fn u8(&mut self) -> Option<u8> {
+            self.take::<1>().map(|b| b[0])
+        }
 
-    // PriceUpdateV2 account layout offsets (134 bytes minimum)
-    // See: https://github.com/pyth-network/pyth-crosschain/blob/main/target_chains/solana/pyth_solana_receiver_sdk/src/price_update.rs
-    const PRICE_UPDATE_V2_MIN_LEN: usize = 134;
-    const OFF_FEED_ID: usize = 42; // 32 bytes
-    const OFF_PRICE: usize = 74; // i64
-    const OFF_CONF: usize = 82; // u64
-    const OFF_EXPO: usize = 90; // i32
-    const OFF_PUBLISH_TIME: usize = 94; // i64
+        pub(crate) fn u16(&mut self) -> Option<u16> {
+            self.take::<2>().map(u16::from_le_bytes)
+        }
 
-    // Chainlink OCR2 State/Aggregator account layout offsets (devnet format)
-    // This is the simpler account format used on Solana devnet
-    // Note: Different from the Transmissions ring buffer format in older docs
-    const CL_MIN_LEN: usize = 232; // Minimum required length (covers CL_OFF_ANSWER + 16)
-    const CL_OFF_DECIMALS: usize = 138; // u8 - number of decimals
-                                        // Skip unused: latest_round_id (143), live_length (148), live_cursor (152)
-                                        // The actual price data is stored directly at tail:
-    const CL_OFF_SLOT: usize = 200; // u64 - slot when updated
-    const CL_OFF_TIMESTAMP: usize = 208; // u64 - unix timestamp (seconds)
-    const CL_OFF_ANSWER: usize = 216; // i128 - price answer
+        pub(crate) fn u64(&mut self) -> Option<u64> {
+            self.take::<8>().map(u64::from_le_bytes)
+        }
 
-    // Maximum supported exponent to prevent overflow (10^18 fits in u128)
-    const MAX_EXPO_ABS: i32 = 18;
+        pub(crate) fn i64(&mut self) -> Option<i64> {
+            self.take::<8>().map(i64::from_le_bytes)
+        }
 
-    /// Read price from a Pyth PriceUpdateV2 account.
-    ///
-    /// Parameters:
-    /// - price_ai: The PriceUpdateV2 account
-    /// - expected_feed_id: The expected Pyth feed ID (must match account's feed_id)
-    /// - now_unix_ts: Current unix timestamp (from clock.unix_timestamp)
-    /// - max_staleness_secs: Maximum age in seconds
-    /// - conf_bps: Maximum confidence interval in basis points
+        pub(crate) fn i128(&mut self) -> Option<i128> {
+            self.take::<16>().map(i128::from_le_bytes)
+        }
+
+        pub(crate) fn u128(&mut self) -> Option<u128> {
+            self.take::<16>().map(u128::from_le_bytes)
+        }
+    }
+
+    /// Global funding state returned by GetFundingIndex.
     ///
-    /// Returns the price in e6 format (e.g., 150_000_000 = 150.00 in base units).
-    pub fn read_pyth_price_e6(
-        price_ai: &AccountInfo,
-        expected_feed_id: &[u8; 32],
-        now_unix_ts: i64,
-        max_staleness_secs: u64,
-        conf_bps: u16,
-    ) -> Result<u64, ProgramError> {
-        // Validate oracle owner (skip in tests to allow mock oracles)
-        #[cfg(not(feature = "test"))]
-        {
-            if *price_ai.owner != PYTH_RECEIVER_PROGRAM_ID {
-                return Err(ProgramError::IllegalOwner);
-            }
+    /// Layout (32 bytes): funding_index_qpb_e6 i128 | last_funding_slot u64 | last_crank_slot u64
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct FundingIndex {
+        /// Cumulative funding per unit position (quote per base, e6)
+        pub funding_index_qpb_e6: i128,
+        /// Slot at which funding was last accrued into the index
+        pub last_funding_slot: u64,
+        /// Slot of the last keeper crank
+        pub last_crank_slot: u64,
+    }
+
+    impl FundingIndex {
+        pub const LEN: usize = 32;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.funding_index_qpb_e6.to_le_bytes());
+            w.put(&self.last_funding_slot.to_le_bytes());
+            w.put(&self.last_crank_slot.to_le_bytes());
+            out
         }
 
-        let data = price_ai.try_borrow_data()?;
-        if data.len() < PRICE_UPDATE_V2_MIN_LEN {
-            return Err(ProgramError::InvalidAccountData);
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                funding_index_qpb_e6: r.i128()?,
+                last_funding_slot: r.u64()?,
+                last_crank_slot: r.u64()?,
+            })
         }
 
-        // Validate feed_id matches expected
-        let feed_id: [u8; 32] = data[OFF_FEED_ID..OFF_FEED_ID + 32].try_into().map_err(|_| ProgramError::InvalidAccountData)?;
-        if &feed_id != expected_feed_id {
-            return Err(PercolatorError::InvalidOracleKey.into());
+        /// Funding owed by a position since it last settled at `account_index`.
+        /// Positive = account pays. Mirrors engine rounding: payments round up,
+        /// receipts truncate toward zero.
+        pub fn accrued_payment(&self, position_size: i128, account_index: i128) -> i128 {
+            crate::verify::funding_payment(position_size, self.funding_index_qpb_e6, account_index)
         }
+    }
 
-        // Read price fields
-        let price = i64::from_le_bytes(data[OFF_PRICE..OFF_PRICE + 8].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let conf = u64::from_le_bytes(data[OFF_CONF..OFF_CONF + 8].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let expo = i32::from_le_bytes(data[OFF_EXPO..OFF_EXPO + 4].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
-        let publish_time = i64::from_le_bytes(
-            data[OFF_PUBLISH_TIME..OFF_PUBLISH_TIME + 8]
-                .try_into()
-                .map_err(|_| ProgramError::InvalidAccountData)?,
-        );
+    /// Oracle mode reported in CrankOutcome.
+    pub const ORACLE_MODE_EXTERNAL: u8 = 0; // Pyth / Chainlink / authority push
+    pub const ORACLE_MODE_HYPERP: u8 = 1; // internal mark/index
 
-        if price <= 0 {
-            return Err(PercolatorError::OracleInvalid.into());
+    /// Result of a KeeperCrank, identical for every oracle kind so one keeper
+    /// can drive any market with the same instruction shape.
+    ///
+    /// Layout (53 bytes): slot u64 | price_e6 u64 | funding_rate_bps_per_slot i64 |
+    /// crank_cursor u16 | sweep_completed u8 | oracle_mode u8 | resolved u8 |
+    /// liquidations u64 | insurance_delta i128
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct CrankOutcome {
+        /// Slot the crank ran at
+        pub slot: u64,
+        /// Price the engine was cranked at (settlement price when resolved)
+        pub price_e6: u64,
+        /// Funding rate applied for this crank (bps per slot)
+        pub funding_rate_bps_per_slot: i64,
+        /// Engine crank cursor after this call
+        pub crank_cursor: u16,
+        /// 1 if this call completed a full sweep of the account table
+        pub sweep_completed: u8,
+        /// ORACLE_MODE_EXTERNAL or ORACLE_MODE_HYPERP
+        pub oracle_mode: u8,
+        /// 1 if the market is resolved (crank force-closes positions)
+        pub resolved: u8,
+        /// Liquidations performed by this call
+        pub liquidations: u64,
+        /// Change in the insurance fund balance over this call (engine units),
+        /// including liquidation fees and the dust sweep
+        pub insurance_delta: i128,
+    }
+
+    impl CrankOutcome {
+        pub const LEN: usize = 53;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.slot.to_le_bytes());
+            w.put(&self.price_e6.to_le_bytes());
+            w.put(&self.funding_rate_bps_per_slot.to_le_bytes());
+            w.put(&self.crank_cursor.to_le_bytes());
+            w.put(&[self.sweep_completed, self.oracle_mode, self.resolved]);
+            w.put(&self.liquidations.to_le_bytes());
+            w.put(&self.insurance_delta.to_le_bytes());
+            out
         }
 
-        // SECURITY (C3): Bound exponent to prevent overflow in pow()
-        if expo.abs() > MAX_EXPO_ABS {
-            return Err(PercolatorError::OracleInvalid.into());
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                slot: r.u64()?,
+                price_e6: r.u64()?,
+                funding_rate_bps_per_slot: r.i64()?,
+                crank_cursor: r.u16()?,
+                sweep_completed: r.u8()?,
+                oracle_mode: r.u8()?,
+                resolved: r.u8()?,
+                liquidations: r.u64()?,
+                insurance_delta: r.i128()?,
+            })
         }
+    }
 
-        // Staleness check (skip on devnet)
-        #[cfg(not(feature = "devnet"))]
-        {
-            let age = now_unix_ts.saturating_sub(publish_time);
-            if age < 0 || age as u64 > max_staleness_secs {
-                return Err(PercolatorError::OracleStale.into());
-            }
+    /// Result of a KeeperCrankBatch.
+    ///
+    /// Layout (10 bytes): processed u16 | last_slot u64
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct CrankBatch {
+        /// Slots actually cranked by this call
+        pub processed: u16,
+        /// Last slot cranked (the prior `last_crank_slot` if none was due)
+        pub last_slot: u64,
+    }
+
+    impl CrankBatch {
+        pub const LEN: usize = 10;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.processed.to_le_bytes());
+            w.put(&self.last_slot.to_le_bytes());
+            out
         }
-        #[cfg(feature = "devnet")]
-        let _ = (publish_time, max_staleness_secs, now_unix_ts);
 
-        // Confidence check (skip on devnet)
-        let price_u = price as u128;
-        #[cfg(not(feature = "devnet"))]
-        {
-            let lhs = (conf as u128) * 10_000;
-            let rhs = price_u * (conf_bps as u128);
-            if lhs > rhs {
-                return Err(PercolatorError::OracleConfTooWide.into());
-            }
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                processed: r.u16()?,
+                last_slot: r.u64()?,
+            })
         }
-        #[cfg(feature = "devnet")]
-        let _ = (conf, conf_bps);
+    }
 
-        // Convert to e6 format
-        let scale = expo + 6;
-        let final_price_u128 = if scale >= 0 {
-            let mul = 10u128.pow(scale as u32);
-            price_u
-                .checked_mul(mul)
-                .ok_or(PercolatorError::EngineOverflow)?
-        } else {
-            let div = 10u128.pow((-scale) as u32);
-            price_u / div
-        };
+    /// Fill returned by TradeNoCpi, ReducePosition and TradeCpi, so callers see
+    /// the result without re-reading the slab.
+    ///
+    /// Layout (56 bytes): exec_price_e6 u64 | exec_size i128 | fee u128 |
+    /// resulting_position i128
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct TradeExecution {
+        /// Fill price (oracle price for NoCpi fills, matcher price for CPI fills)
+        pub exec_price_e6: u64,
+        /// Filled size from the user's side (matcher's exec_size for TradeCpi)
+        pub exec_size: i128,
+        /// Trading fee credited to insurance
+        pub fee: u128,
+        /// User position after the fill
+        pub resulting_position: i128,
+    }
 
-        if final_price_u128 == 0 {
-            return Err(PercolatorError::OracleInvalid.into());
+    impl TradeExecution {
+        pub const LEN: usize = 56;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.exec_price_e6.to_le_bytes());
+            w.put(&self.exec_size.to_le_bytes());
+            w.put(&self.fee.to_le_bytes());
+            w.put(&self.resulting_position.to_le_bytes());
+            out
         }
-        if final_price_u128 > u64::MAX as u128 {
-            return Err(PercolatorError::EngineOverflow.into());
+
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                exec_price_e6: r.u64()?,
+                exec_size: r.i128()?,
+                fee: r.u128()?,
+                resulting_position: r.i128()?,
+            })
         }
+    }
 
-        Ok(final_price_u128 as u64)
+    /// Vault addresses stored at InitMarket, so clients do not re-derive them.
+    ///
+    /// Layout (65 bytes): vault_authority [32] | vault_authority_bump u8 | vault_pubkey [32]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct VaultInfo {
+        /// Vault authority PDA: `[b"vault", slab]` with the stored bump
+        pub vault_authority: [u8; 32],
+        /// Bump stored in MarketConfig
+        pub vault_authority_bump: u8,
+        /// Vault token account (owned by `vault_authority`)
+        pub vault_pubkey: [u8; 32],
     }
 
-    /// Read price from a Chainlink OCR2 State/Aggregator account.
+    impl VaultInfo {
+        pub const LEN: usize = 65;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.vault_authority);
+            w.put(&[self.vault_authority_bump]);
+            w.put(&self.vault_pubkey);
+            out
+        }
+
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                vault_authority: r.take::<32>()?,
+                vault_authority_bump: r.u8()?,
+                vault_pubkey: r.take::<32>()?,
+            })
+        }
+    }
+
+    /// Dry-run liquidation breakdown returned by SimulateLiquidation.
     ///
-    /// Parameters:
-    /// - price_ai: The Chainlink aggregator account
-    /// - expected_feed_pubkey: The expected feed account pubkey (for validation)
-    /// - now_unix_ts: Current unix timestamp (from clock.unix_timestamp)
-    /// - max_staleness_secs: Maximum age in seconds
+    /// Taken from the engine's own liquidation, run on the slab and rolled back,
+    /// so a partial close (liquidation_buffer_bps / min_liquidation_abs) is
+    /// reported as the engine makes it. All amounts in engine units.
     ///
-    /// Returns the price in e6 format (e.g., 150_000_000 = 150.00 in base units).
-    /// Note: Chainlink doesn't have confidence intervals, so conf_bps is not used.
-    pub fn read_chainlink_price_e6(
-        price_ai: &AccountInfo,
-        expected_feed_pubkey: &[u8; 32],
-        now_unix_ts: i64,
-        max_staleness_secs: u64,
-    ) -> Result<u64, ProgramError> {
-        // Validate oracle owner (skip in tests to allow mock oracles)
-        #[cfg(not(feature = "test"))]
-        {
-            if *price_ai.owner != CHAINLINK_OCR2_PROGRAM_ID {
-                return Err(ProgramError::IllegalOwner);
-            }
-        }
+    /// Layout (121 bytes): price_e6 u64 | position_size i128 | equity i128 |
+    /// fee_to_liquidator u128 | fee_to_insurance u128 | socialized_loss u128 |
+    /// refund_to_account u128 | liquidatable u8 | maintenance_requirement u128
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct LiquidationPreview {
+        /// Oracle price the preview was computed at
+        pub price_e6: u64,
+        /// Part of the position the liquidation would close (0 if healthy)
+        pub position_size: i128,
+        /// capital + pnl + mark-to-oracle
+        pub equity: i128,
+        /// Paid to the caller (always 0: liquidations carry no keeper reward)
+        pub fee_to_liquidator: u128,
+        /// Liquidation fee credited to the insurance fund
+        pub fee_to_insurance: u128,
+        /// Loss beyond the account's equity (absorbed by insurance / haircut)
+        pub socialized_loss: u128,
+        /// Equity left on the account after the close and fee
+        pub refund_to_account: u128,
+        /// 1 if the engine would liquidate the account
+        pub liquidatable: u8,
+        /// `maintenance_margin_bps` of the position's notional at `price_e6`
+        pub maintenance_requirement: u128,
+    }
 
-        // Validate feed pubkey matches expected
-        if price_ai.key.to_bytes() != *expected_feed_pubkey {
-            return Err(PercolatorError::InvalidOracleKey.into());
+    impl LiquidationPreview {
+        pub const LEN: usize = 121;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.price_e6.to_le_bytes());
+            w.put(&self.position_size.to_le_bytes());
+            w.put(&self.equity.to_le_bytes());
+            w.put(&self.fee_to_liquidator.to_le_bytes());
+            w.put(&self.fee_to_insurance.to_le_bytes());
+            w.put(&self.socialized_loss.to_le_bytes());
+            w.put(&self.refund_to_account.to_le_bytes());
+            w.put(&[self.liquidatable]);
+            w.put(&self.maintenance_requirement.to_le_bytes());
+            out
         }
 
-        let data = price_ai.try_borrow_data()?;
-        if data.len() < CL_MIN_LEN {
-            return Err(ProgramError::InvalidAccountData);
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                price_e6: r.u64()?,
+                position_size: r.i128()?,
+                equity: r.i128()?,
+                fee_to_liquidator: r.u128()?,
+                fee_to_insurance: r.u128()?,
+                socialized_loss: r.u128()?,
+                refund_to_account: r.u128()?,
+                liquidatable: r.u8()?,
+                maintenance_requirement: r.u128()?,
+            })
         }
+    }
 
-        // Read header fields
-        let decimals = data[CL_OFF_DECIMALS];
+    /// Engine slot of an account, returned by InitUser, InitLP and GetAccountIndex.
+    ///
+    /// Layout (2 bytes): idx u16
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct AccountIndex {
+        pub idx: u16,
+    }
 
-        // Read price data directly from fixed offsets
-        let timestamp = u64::from_le_bytes(
-            data[CL_OFF_TIMESTAMP..CL_OFF_TIMESTAMP + 8]
-                .try_into()
-                .map_err(|_| ProgramError::InvalidAccountData)?,
-        );
-        // Read answer as i128 (16 bytes), but only bottom 8 bytes are typically used
-        let answer =
-            i128::from_le_bytes(data[CL_OFF_ANSWER..CL_OFF_ANSWER + 16].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
+    impl AccountIndex {
+        pub const LEN: usize = 2;
 
-        if answer <= 0 {
-            return Err(PercolatorError::OracleInvalid.into());
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            self.idx.to_le_bytes()
         }
 
-        // SECURITY (C3): Bound decimals to prevent overflow in pow()
-        if decimals > MAX_EXPO_ABS as u8 {
-            return Err(PercolatorError::OracleInvalid.into());
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            Some(Self {
+                idx: Reader::new(data).u16()?,
+            })
         }
+    }
 
-        // Staleness check (skip on devnet)
-        #[cfg(not(feature = "devnet"))]
-        {
-            let age = now_unix_ts.saturating_sub(timestamp as i64);
-            if age < 0 || age as u64 > max_staleness_secs {
-                return Err(PercolatorError::OracleStale.into());
-            }
+    /// Per-account snapshot returned by GetAccountState.
+    ///
+    /// Layout (98 bytes): idx u16 | capital u128 | position_size i128 |
+    /// entry_price u64 | pnl i128 | pnl_pos u128 | pnl_warmed u128 |
+    /// warmup_remaining_slots u64
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct AccountState {
+        pub idx: u16,
+        pub capital: u128,
+        pub position_size: i128,
+        pub entry_price: u64,
+        pub pnl: i128,
+        /// Positive PnL still warming (net of reserved PnL, see `verify::warmup_schedule`)
+        pub pnl_pos: u128,
+        /// Portion of `pnl_pos` that has vested and would convert to capital now
+        pub pnl_warmed: u128,
+        /// Slots until all of `pnl_pos` has vested (u64::MAX if unscheduled)
+        pub warmup_remaining_slots: u64,
+    }
+
+    impl AccountState {
+        pub const LEN: usize = 98;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.idx.to_le_bytes());
+            w.put(&self.capital.to_le_bytes());
+            w.put(&self.position_size.to_le_bytes());
+            w.put(&self.entry_price.to_le_bytes());
+            w.put(&self.pnl.to_le_bytes());
+            w.put(&self.pnl_pos.to_le_bytes());
+            w.put(&self.pnl_warmed.to_le_bytes());
+            w.put(&self.warmup_remaining_slots.to_le_bytes());
+            out
         }
-        #[cfg(feature = "devnet")]
-        let _ = (timestamp, max_staleness_secs, now_unix_ts);
 
-        // Convert to e6 format
-        // Chainlink decimals work like: price = answer / 10^decimals
-        // We want e6, so: price_e6 = answer * 10^6 / 10^decimals = answer * 10^(6-decimals)
-        let price_u = answer as u128;
-        let scale = 6i32 - decimals as i32;
-        let final_price_u128 = if scale >= 0 {
-            let mul = 10u128.pow(scale as u32);
-            price_u
-                .checked_mul(mul)
-                .ok_or(PercolatorError::EngineOverflow)?
-        } else {
-            let div = 10u128.pow((-scale) as u32);
-            price_u / div
-        };
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                idx: r.u16()?,
+                capital: r.u128()?,
+                position_size: r.i128()?,
+                entry_price: r.u64()?,
+                pnl: r.i128()?,
+                pnl_pos: r.u128()?,
+                pnl_warmed: r.u128()?,
+                warmup_remaining_slots: r.u64()?,
+            })
+        }
+    }
 
-        if final_price_u128 == 0 {
-            return Err(PercolatorError::OracleInvalid.into());
+    /// Insurance donor leaderboard returned by GetTopDonors, sorted by total
+    /// (descending); only the first `count` entries are meaningful.
+    ///
+    /// Layout (321 bytes): count u8 | MAX_DONORS x (donor [u8; 32] | total u64)
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct TopDonors {
+        pub count: u8,
+        /// (donor pubkey, base tokens donated)
+        pub entries: [([u8; 32], u64); MAX_DONORS],
+    }
+
+    impl TopDonors {
+        pub const LEN: usize = 1 + MAX_DONORS * 40;
+
+        pub fn from_config(config: &crate::state::MarketConfig) -> Self {
+            let mut view = Self::default();
+            for (key, total) in config.donor_keys.iter().zip(config.donor_totals.iter()) {
+                if *key != [0u8; 32] {
+                    view.entries[view.count as usize] = (*key, *total);
+                    view.count += 1;
+                }
+            }
+            view.entries[..view.count as usize].sort_unstable_by(|a, b| b.1.cmp(&a.1));
+            view
         }
-        if final_price_u128 > u64::MAX as u128 {
-            return Err(PercolatorError::EngineOverflow.into());
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&[self.count]);
+            for (key, total) in self.entries.iter() {
+                w.put(key);
+                w.put(&total.to_le_bytes());
+            }
+            out
         }
 
-        Ok(final_price_u128 as u64)
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            let mut view = Self {
+                count: r.u8()?,
+                ..Default::default()
+            };
+            if view.count as usize > MAX_DONORS {
+                return None;
+            }
+            for entry in view.entries.iter_mut() {
+                *entry = (r.take::<32>()?, r.u64()?);
+            }
+            Some(view)
+        }
     }
 
-    /// Read oracle price for engine use, applying inversion and unit scaling if configured.
-    ///
-    /// Automatically detects oracle type by account owner:
-    /// - PYTH_RECEIVER_PROGRAM_ID: reads Pyth PriceUpdateV2
-    /// - CHAINLINK_OCR2_PROGRAM_ID: reads Chainlink OCR2 Transmissions
-    ///
-    /// Transformations applied in order:
-    /// 1. If invert != 0: inverted price = 1e12 / raw_e6
-    /// 2. If unit_scale > 1: scaled price = price / unit_scale
-    ///
-    /// CRITICAL: The unit_scale transformation ensures oracle-derived values (entry_price,
-    /// mark_pnl, position_value) are in the same scale as capital (which is stored in units).
-    /// Without this scaling, margin checks would compare units to base tokens incorrectly.
+    /// Warmup schedule returned by GetWarmupStatus (see `verify::warmup_schedule`).
     ///
-    /// The raw oracle is validated (staleness, confidence for Pyth) BEFORE transformations.
-    pub fn read_engine_price_e6(
-        price_ai: &AccountInfo,
-        expected_feed_id: &[u8; 32],
-        now_unix_ts: i64,
-        max_staleness_secs: u64,
-        conf_bps: u16,
-        invert: u8,
-        unit_scale: u32,
-    ) -> Result<u64, ProgramError> {
-        // Detect oracle type by account owner and dispatch
-        let raw_price = if *price_ai.owner == PYTH_RECEIVER_PROGRAM_ID {
-            read_pyth_price_e6(
-                price_ai,
-                expected_feed_id,
-                now_unix_ts,
-                max_staleness_secs,
-                conf_bps,
-            )?
-        } else if *price_ai.owner == CHAINLINK_OCR2_PROGRAM_ID {
-            read_chainlink_price_e6(price_ai, expected_feed_id, now_unix_ts, max_staleness_secs)?
-        } else {
-            // In test mode, try Pyth format first (for existing tests)
-            #[cfg(feature = "test")]
-            {
-                read_pyth_price_e6(
-                    price_ai,
-                    expected_feed_id,
-                    now_unix_ts,
-                    max_staleness_secs,
-                    conf_bps,
-                )?
-            }
-            #[cfg(not(feature = "test"))]
-            {
-                return Err(ProgramError::IllegalOwner);
-            }
-        };
+    /// Layout (40 bytes): total_warming u128 | vested_now u128 | full_vest_slot u64
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct WarmupStatus {
+        /// Positive PnL not yet converted to capital (net of reserved PnL)
+        pub total_warming: u128,
+        /// Portion of `total_warming` that has vested and would convert now
+        pub vested_now: u128,
+        /// Slot at which all of `total_warming` has vested (u64::MAX if unscheduled)
+        pub full_vest_slot: u64,
+    }
 
-        // Step 1: Apply inversion if configured (uses verify::invert_price_e6)
-        let price_after_invert = crate::verify::invert_price_e6(raw_price, invert)
-            .ok_or(PercolatorError::OracleInvalid)?;
+    impl WarmupStatus {
+        pub const LEN: usize = 40;
 
-        // Step 2: Apply unit scaling if configured (uses verify::scale_price_e6)
-        // This ensures oracle-derived values match capital scale (stored in units)
-        crate::verify::scale_price_e6(price_after_invert, unit_scale)
-            .ok_or(PercolatorError::OracleInvalid.into())
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.total_warming.to_le_bytes());
+            w.put(&self.vested_now.to_le_bytes());
+            w.put(&self.full_vest_slot.to_le_bytes());
+            out
+        }
+
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                total_warming: r.u128()?,
+                vested_now: r.u128()?,
+                full_vest_slot: r.u64()?,
+            })
+        }
     }
 
-    /// Check if authority-pushed price is available and fresh.
-    /// Returns Some(price_e6) if authority is set and price is within staleness bounds.
-    /// Returns None if no authority is set or price is stale.
+    /// Progress of one LiquidateEligible page.
     ///
-    /// Note: The stored authority_price_e6 is already in the correct format (e6, scaled).
-    pub fn read_authority_price(
-        config: &super::state::MarketConfig,
-        now_unix_ts: i64,
-        max_staleness_secs: u64,
-    ) -> Option<u64> {
-        // No authority set
-        if config.oracle_authority == [0u8; 32] {
-            return None;
-        }
-        // No price pushed yet
-        if config.authority_price_e6 == 0 {
-            return None;
+    /// Layout (6 bytes): scanned u16 | liquidated u16 | next_cursor u16
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct LiquidationScan {
+        /// Account slots examined by this call
+        pub scanned: u16,
+        /// Accounts liquidated by this call
+        pub liquidated: u16,
+        /// Cursor for the next call; 0 once the scan has reached the end of the table
+        pub next_cursor: u16,
+    }
+
+    impl LiquidationScan {
+        pub const LEN: usize = 6;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.scanned.to_le_bytes());
+            w.put(&self.liquidated.to_le_bytes());
+            w.put(&self.next_cursor.to_le_bytes());
+            out
         }
-        // Check staleness
-        let age = now_unix_ts.saturating_sub(config.authority_timestamp);
-        if age < 0 || age as u64 > max_staleness_secs {
-            return None;
+
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                scanned: r.u16()?,
+                liquidated: r.u16()?,
+                next_cursor: r.u16()?,
+            })
         }
-        Some(config.authority_price_e6)
     }
 
-    /// Read oracle price, preferring authority-pushed price over Pyth/Chainlink.
-    ///
-    /// If an oracle authority is configured and has pushed a fresh price, use that.
-    /// Otherwise, fall back to reading from the provided Pyth/Chainlink account.
+    /// Every market toggle, returned by GetFeatureFlags.
     ///
-    /// The price_ai can be any account when using authority oracle - it won't be read
-    /// if the authority price is valid.
-    pub fn read_price_with_authority(
-        config: &super::state::MarketConfig,
-        price_ai: &AccountInfo,
-        now_unix_ts: i64,
-    ) -> Result<u64, ProgramError> {
-        // Try authority price first
-        if let Some(authority_price) =
-            read_authority_price(config, now_unix_ts, config.max_staleness_secs)
-        {
-            return Ok(authority_price);
+    /// Layout (10 bytes): feature_flags u64 | market_flags u8 | market_mode u8
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct FeatureFlags {
+        /// Admin feature bits (`state::FEATURE_*`)
+        pub feature_flags: u64,
+        /// Header lifecycle bits (`state::FLAG_RESOLVED`, `state::FLAG_EMERGENCY_EXIT`)
+        pub market_flags: u8,
+        /// Trading mode (`state::MARKET_MODE_*`)
+        pub market_mode: u8,
+    }
+
+    impl FeatureFlags {
+        pub const LEN: usize = 10;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.feature_flags.to_le_bytes());
+            w.put(&[self.market_flags]);
+            w.put(&[self.market_mode]);
+            out
         }
 
-        // Fall back to Pyth/Chainlink
-        read_engine_price_e6(
-            price_ai,
-            &config.index_feed_id,
-            now_unix_ts,
-            config.max_staleness_secs,
-            config.conf_filter_bps,
-            config.invert,
-            config.unit_scale,
-        )
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                feature_flags: r.u64()?,
+                market_flags: r.u8()?,
+                market_mode: r.u8()?,
+            })
+        }
     }
 
-    /// Clamp `raw_price` so it cannot move more than `max_change_e2bps` from `last_price`.
-    /// Units: 1_000_000 e2bps = 100%. 0 = disabled (no cap). last_price == 0 = first-time.
-    pub fn clamp_oracle_price(last_price: u64, raw_price: u64, max_change_e2bps: u64) -> u64 {
-        if max_change_e2bps == 0 || last_price == 0 {
-            return raw_price;
+    /// Accounting self-check returned by VerifyInvariants. `violations` is a
+    /// bitmask of the `InvariantReport::*` bits; 0 means every check held.
+    ///
+    /// Layout (49 bytes): violations u8 | capital_sum u128 | pnl_pos_sum u128 |
+    /// vault_balance u64 | dust_base u64
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct InvariantReport {
+        pub violations: u8,
+        /// Sum of `capital` over used accounts (engine units)
+        pub capital_sum: u128,
+        /// Sum of positive `pnl` over used accounts (engine units)
+        pub pnl_pos_sum: u128,
+        /// Vault token account balance (base units)
+        pub vault_balance: u64,
+        /// Pooled sub-unit remainder (base units)
+        pub dust_base: u64,
+    }
+
+    impl InvariantReport {
+        pub const LEN: usize = 49;
+
+        /// `capital_sum != engine.c_tot`
+        pub const CAPITAL_SUM: u8 = 1 << 0;
+        /// `pnl_pos_sum != engine.pnl_pos_tot`
+        pub const PNL_POS_SUM: u8 = 1 << 1;
+        /// `engine.vault < c_tot + insurance`
+        pub const ENGINE_VAULT: u8 = 1 << 2;
+        /// `vault_balance != engine.vault * unit_scale + dust_base`
+        pub const VAULT_BALANCE: u8 = 1 << 3;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&[self.violations]);
+            w.put(&self.capital_sum.to_le_bytes());
+            w.put(&self.pnl_pos_sum.to_le_bytes());
+            w.put(&self.vault_balance.to_le_bytes());
+            w.put(&self.dust_base.to_le_bytes());
+            out
+        }
+
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                violations: r.u8()?,
+                capital_sum: r.u128()?,
+                pnl_pos_sum: r.u128()?,
+                vault_balance: r.u64()?,
+                dust_base: r.u64()?,
+            })
         }
-        let max_delta = ((last_price as u128) * (max_change_e2bps as u128) / 1_000_000) as u64;
-        let lower = last_price.saturating_sub(max_delta);
-        let upper = last_price.saturating_add(max_delta);
-        raw_price.clamp(lower, upper)
     }
 
-    /// Read oracle price with circuit-breaker clamping.
-    /// Reads raw price via `read_price_with_authority`, clamps it against
-    /// `config.last_effective_price_e6`, and updates that field to the post-clamped value.
-    pub fn read_price_clamped(
-        config: &mut super::state::MarketConfig,
-        price_ai: &AccountInfo,
-        now_unix_ts: i64,
-    ) -> Result<u64, ProgramError> {
-        let raw = read_price_with_authority(config, price_ai, now_unix_ts)?;
-        let clamped = clamp_oracle_price(
-            config.last_effective_price_e6,
-            raw,
-            config.oracle_price_cap_e2bps,
-        );
-        config.last_effective_price_e6 = clamped;
-        Ok(clamped)
+    /// Market-wide totals returned by GetMarketStats (engine units).
+    ///
+    /// Layout (90 bytes): c_tot u128 | long_oi u128 | short_oi u128 |
+    /// insurance u128 | pnl_pos_tot u128 | haircut_ratio_e6 u64 |
+    /// num_used_accounts u16
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct MarketStats {
+        /// Total capital across accounts
+        pub c_tot: u128,
+        /// Sum of long position sizes
+        pub long_oi: u128,
+        /// Sum of short position sizes (absolute)
+        pub short_oi: u128,
+        /// Insurance fund balance
+        pub insurance: u128,
+        /// Sum of positive PnL across accounts
+        pub pnl_pos_tot: u128,
+        /// Backed share of `pnl_pos_tot` (1_000_000 = no haircut), see
+        /// `verify::haircut_ratio_e6`
+        pub haircut_ratio_e6: u64,
+        pub num_used_accounts: u16,
     }
 
-    // =========================================================================
-    // Hyperp mode helpers (internal mark/index, no external oracle)
-    // =========================================================================
+    impl MarketStats {
+        pub const LEN: usize = 90;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&self.c_tot.to_le_bytes());
+            w.put(&self.long_oi.to_le_bytes());
+            w.put(&self.short_oi.to_le_bytes());
+            w.put(&self.insurance.to_le_bytes());
+            w.put(&self.pnl_pos_tot.to_le_bytes());
+            w.put(&self.haircut_ratio_e6.to_le_bytes());
+            w.put(&self.num_used_accounts.to_le_bytes());
+            out
+        }
 
-    /// Check if Hyperp mode is active (internal mark/index pricing).
-    /// Hyperp mode is active when index_feed_id is all zeros.
-    #[inline]
-    pub fn is_hyperp_mode(config: &super::state::MarketConfig) -> bool {
-        config.index_feed_id == [0u8; 32]
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self {
+                c_tot: r.u128()?,
+                long_oi: r.u128()?,
+                short_oi: r.u128()?,
+                insurance: r.u128()?,
+                pnl_pos_tot: r.u128()?,
+                haircut_ratio_e6: r.u64()?,
+                num_used_accounts: r.u16()?,
+            })
+        }
     }
 
-    /// Move `index` toward `mark`, but clamp movement by cap_e2bps * dt_slots.
-    /// cap_e2bps units: 1_000_000 = 100.00%
-    /// Returns the new index value.
+    /// Parameter check returned by ValidateInitParams. `failures` is a bitmask
+    /// of the `InitParamsReport::*` bits; 0 means the payload passes.
     ///
-    /// Security: When dt_slots == 0 (same slot) or cap_e2bps == 0 (cap disabled),
-    /// returns index unchanged to prevent bypassing rate limits.
-    pub fn clamp_toward_with_dt(index: u64, mark: u64, cap_e2bps: u64, dt_slots: u64) -> u64 {
-        if index == 0 {
-            return mark;
+    /// Layout (1 byte): failures u8
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct InitParamsReport {
+        pub failures: u8,
+    }
+
+    impl InitParamsReport {
+        pub const LEN: usize = 1;
+
+        /// `unit_scale > MAX_UNIT_SCALE`
+        pub const UNIT_SCALE: u8 = 1 << 0;
+        /// Hyperp market (zero feed id) without `initial_mark_price_e6`
+        pub const HYPERP_MARK: u8 = 1 << 1;
+        /// Hyperp `initial_mark_price_e6` that cannot be inverted
+        pub const HYPERP_INVERT: u8 = 1 << 2;
+        /// Not `maintenance_margin_bps <= initial_margin_bps <= 10_000`
+        pub const MARGINS: u8 = 1 << 3;
+        /// `trading_fee_bps` or `liquidation_fee_bps` above 100%, or
+        /// maintenance + `liquidation_buffer_bps` above 100%
+        pub const FEES: u8 = 1 << 4;
+        /// `conf_filter_bps > 10_000`
+        pub const CONF_FILTER: u8 = 1 << 5;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            [self.failures]
         }
-        // Bug #9 fix: return index (no movement) when dt=0 or cap=0,
-        // rather than mark (bypass rate limiting)
-        if cap_e2bps == 0 || dt_slots == 0 {
-            return index;
+
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            Some(Self { failures: r.u8()? })
         }
+    }
+}
 
-        let max_delta_u128 = (index as u128)
-            .saturating_mul(cap_e2bps as u128)
-            .saturating_mul(dt_slots as u128)
-            / 1_000_000u128;
+// 6c. mod events - structured program-log events for indexers
+//
+// Each event is one `PERC1:<base64>` log line. The decoded payload starts with a
+// kind byte followed by the event's little-endian fields; the `1` in the prefix
+// is the schema version and changes whenever a layout does.
+pub mod events {
+    use crate::return_data::{Reader, Writer};
 
-        let max_delta = core::cmp::min(max_delta_u128, u64::MAX as u128) as u64;
-        let lo = index.saturating_sub(max_delta);
-        let hi = index.saturating_add(max_delta);
-        mark.clamp(lo, hi)
+    pub const PREFIX: &str = "PERC1:";
+
+    pub const KIND_TRADE_EXECUTED: u8 = 1;
+    pub const KIND_LIQUIDATED: u8 = 2;
+    pub const KIND_CRANKED: u8 = 3;
+
+    /// Largest payload of any event (TradeExecuted).
+    pub const MAX_EVENT_LEN: usize = TradeExecuted::LEN;
+    /// Longest log line `emit` can produce, prefix included.
+    pub const MAX_LINE_LEN: usize = PREFIX.len() + (MAX_EVENT_LEN + 2) / 3 * 4;
+
+    /// A fill against an LP (TradeNoCpi, TradeCpi, ReducePosition, each MultiTrade leg).
+    ///
+    /// Layout (45 bytes): kind u8 | user_idx u16 | lp_idx u16 | size i128 | exec_price u64 | fee u128
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct TradeExecuted {
+        pub user_idx: u16,
+        pub lp_idx: u16,
+        /// Signed fill size from the user's side
+        pub size: i128,
+        /// Execution price (e6)
+        pub exec_price: u64,
+        /// Trading fee credited to the insurance fund by this fill
+        pub fee: u128,
     }
 
-    /// Get engine oracle price (unified: external oracle vs Hyperp mode).
-    /// In Hyperp mode: updates index toward mark with rate limiting.
-    /// In external mode: reads from Pyth/Chainlink/authority with circuit breaker.
-    pub fn get_engine_oracle_price_e6(
-        engine_last_slot: u64,
-        now_slot: u64,
-        now_unix_ts: i64,
-        config: &mut super::state::MarketConfig,
-        a_oracle: &AccountInfo,
-    ) -> Result<u64, ProgramError> {
-        // Hyperp mode: index_feed_id == 0
-        if is_hyperp_mode(config) {
-            let mark = config.authority_price_e6;
-            if mark == 0 {
-                return Err(super::error::PercolatorError::OracleInvalid.into());
+    impl TradeExecuted {
+        pub const LEN: usize = 45;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&[KIND_TRADE_EXECUTED]);
+            w.put(&self.user_idx.to_le_bytes());
+            w.put(&self.lp_idx.to_le_bytes());
+            w.put(&self.size.to_le_bytes());
+            w.put(&self.exec_price.to_le_bytes());
+            w.put(&self.fee.to_le_bytes());
+            out
+        }
+
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            if r.u8()? != KIND_TRADE_EXECUTED {
+                return None;
             }
+            Some(Self {
+                user_idx: r.u16()?,
+                lp_idx: r.u16()?,
+                size: r.i128()?,
+                exec_price: r.u64()?,
+                fee: r.u128()?,
+            })
+        }
+    }
 
-            let prev_index = config.last_effective_price_e6;
-            let dt = now_slot.saturating_sub(engine_last_slot);
-            let new_index =
-                clamp_toward_with_dt(prev_index.max(1), mark, config.oracle_price_cap_e2bps, dt);
+    /// A position closed by LiquidateAtOracle, LiquidatePartial or LiquidateEligible.
+    ///
+    /// Layout (19 bytes): kind u8 | target_idx u16 | penalty u128
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Liquidated {
+        pub target_idx: u16,
+        /// Liquidation fee credited to the insurance fund
+        pub penalty: u128,
+    }
 
-            config.last_effective_price_e6 = new_index;
-            return Ok(new_index);
+    impl Liquidated {
+        pub const LEN: usize = 19;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&[KIND_LIQUIDATED]);
+            w.put(&self.target_idx.to_le_bytes());
+            w.put(&self.penalty.to_le_bytes());
+            out
         }
 
-        // Non-Hyperp: existing behavior (authority -> Pyth/Chainlink) + circuit breaker
-        read_price_clamped(config, a_oracle, now_unix_ts)
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            if r.u8()? != KIND_LIQUIDATED {
+                return None;
+            }
+            Some(Self {
+                target_idx: r.u16()?,
+                penalty: r.u128()?,
+            })
+        }
     }
 
-    /// Compute premium-based funding rate (Hyperp funding model).
-    /// Premium = (mark - index) / index, converted to bps per slot.
-    /// Returns signed bps per slot (positive = longs pay shorts).
-    pub fn compute_premium_funding_bps_per_slot(
-        mark_e6: u64,
-        index_e6: u64,
-        funding_horizon_slots: u64,
-        funding_k_bps: u64,   // 100 = 1.00x multiplier
-        max_premium_bps: i64, // e.g. 500 = 5%
-        max_bps_per_slot: i64,
-    ) -> i64 {
-        if mark_e6 == 0 || index_e6 == 0 || funding_horizon_slots == 0 {
-            return 0;
+    /// A completed KeeperCrank.
+    ///
+    /// Layout (17 bytes): kind u8 | slot u64 | funding_rate_bps_per_slot i64
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Cranked {
+        pub slot: u64,
+        /// Funding rate applied for the coming interval (0 once resolved)
+        pub funding_rate: i64,
+    }
+
+    impl Cranked {
+        pub const LEN: usize = 17;
+
+        pub fn to_bytes(&self) -> [u8; Self::LEN] {
+            let mut out = [0u8; Self::LEN];
+            let mut w = Writer::new(&mut out);
+            w.put(&[KIND_CRANKED]);
+            w.put(&self.slot.to_le_bytes());
+            w.put(&self.funding_rate.to_le_bytes());
+            out
         }
 
-        let diff = mark_e6 as i128 - index_e6 as i128;
-        let mut premium_bps = diff.saturating_mul(10_000) / (index_e6 as i128);
+        pub fn from_bytes(data: &[u8]) -> Option<Self> {
+            let mut r = Reader::new(data);
+            if r.u8()? != KIND_CRANKED {
+                return None;
+            }
+            Some(Self {
+                slot: r.u64()?,
+                funding_rate: r.i64()?,
+            })
+        }
+    }
 
-        // Clamp premium
-        premium_bps = premium_bps.clamp(-(max_premium_bps as i128), max_premium_bps as i128);
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    /// Standard padded base64 of `data` into `out`; returns the encoded length.
+    /// `out` must hold at least `(data.len() + 2) / 3 * 4` bytes.
+    pub fn encode_base64(data: &[u8], out: &mut [u8]) -> usize {
+        let mut n = 0;
+        for chunk in data.chunks(3) {
+            let b = [
+                chunk[0],
+                chunk.get(1).copied().unwrap_or(0),
+                chunk.get(2).copied().unwrap_or(0),
+            ];
+            let v = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+            for i in 0..4 {
+                out[n + i] = if i <= chunk.len() {
+                    ALPHABET[((v >> (18 - 6 * i)) & 0x3f) as usize]
+                } else {
+                    b'='
+                };
+            }
+            n += 4;
+        }
+        n
+    }
 
-        // Apply k multiplier (100 => 1.00x)
-        let scaled = premium_bps.saturating_mul(funding_k_bps as i128) / 100i128;
+    /// Inverse of `encode_base64`; returns the decoded length, or None on
+    /// malformed input or when `out` is too small.
+    pub fn decode_base64(text: &[u8], out: &mut [u8]) -> Option<usize> {
+        if text.len() % 4 != 0 {
+            return None;
+        }
+        let chunks = text.len() / 4;
+        let mut n = 0;
+        for (i, chunk) in text.chunks(4).enumerate() {
+            // Padding is only valid at the very end
+            let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+            if pad > 2 || (pad > 0 && i + 1 != chunks) {
+                return None;
+            }
+            let mut v: u32 = 0;
+            for &c in &chunk[..4 - pad] {
+                let d = ALPHABET.iter().position(|&a| a == c)? as u32;
+                v = (v << 6) | d;
+            }
+            v <<= 6 * pad as u32;
+            let bytes = [(v >> 16) as u8, (v >> 8) as u8, v as u8];
+            let take = 3 - pad;
+            out.get_mut(n..n + take)?.copy_from_slice(&bytes[..take]);
+            n += take;
+        }
+        Some(n)
+    }
 
-        // Convert to per-slot by dividing by horizon
-        let mut per_slot = (scaled / (funding_horizon_slots as i128)) as i64;
+    /// Decode the payload of a `PERC1:` line (a leading `Program log: ` is
+    /// tolerated). Returns the payload length written to `out`.
+    pub fn decode_line(line: &str, out: &mut [u8]) -> Option<usize> {
+        let at = line.find(PREFIX)?;
+        decode_base64(line[at + PREFIX.len()..].as_bytes(), out)
+    }
 
-        // Policy clamp
-        per_slot = per_slot.clamp(-max_bps_per_slot, max_bps_per_slot);
-        per_slot
+    /// Log one event payload as `PERC1:<base64>` without heap allocation.
+    pub fn emit(payload: &[u8]) {
+        let mut line = [0u8; MAX_LINE_LEN];
+        line[..PREFIX.len()].copy_from_slice(PREFIX.as_bytes());
+        let n = encode_base64(payload, &mut line[PREFIX.len()..]);
+        // Prefix and base64 alphabet are ASCII
+        if let Ok(s) = core::str::from_utf8(&line[..PREFIX.len() + n]) {
+            solana_program::log::sol_log(s);
+        }
     }
 }
 
-// 9. mod collateral
-pub mod collateral {
-    use solana_program::{account_info::AccountInfo, program_error::ProgramError};
+// 6d. mod view - host-side slab decoding for off-chain clients
+//
+// Decodes a slab account's raw bytes without hardcoded offsets: the layout comes
+// from `state::read_header` / `read_config` and `zc::engine_ref`, so it tracks
+// the program's own. Every decoder checks the slab is initialized at `VIEW_VERSION`.
+#[cfg(not(target_os = "solana"))]
+pub mod view {
+    use crate::constants::{expected_slab_len, LEGACY_SLAB_LEN, MAGIC, VERSION};
+    use crate::error::PercolatorError;
+    use crate::state::{MarketConfig, SlabHeader};
+    use crate::zc;
+    use percolator::MAX_ACCOUNTS;
+    use solana_program::program_error::ProgramError;
 
-    #[cfg(not(feature = "test"))]
-    use solana_program::program::{invoke, invoke_signed};
+    /// Slab layout version these decoders understand (`SlabHeader::version`).
+    pub const VIEW_VERSION: u32 = VERSION;
+
+    /// One engine account as seen by clients.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct AccountView {
+        pub idx: u16,
+        pub is_lp: bool,
+        pub owner: [u8; 32],
+        pub capital: u128,
+        pub pnl: i128,
+        pub position_size: i128,
+        pub entry_price: u64,
+        pub funding_index: i128,
+    }
 
-    #[cfg(feature = "test")]
-    use solana_program::program_pack::Pack;
-    #[cfg(feature = "test")]
-    use spl_token::state::Account as TokenAccount;
+    fn check_header(h: &SlabHeader) -> Result<(), ProgramError> {
+        if h.magic != MAGIC {
+            return Err(PercolatorError::NotInitialized.into());
+        }
+        if h.version != VIEW_VERSION {
+            return Err(PercolatorError::InvalidVersion.into());
+        }
+        Ok(())
+    }
 
-    pub fn deposit<'a>(
-        _token_program: &AccountInfo<'a>,
-        source: &AccountInfo<'a>,
-        dest: &AccountInfo<'a>,
-        _authority: &AccountInfo<'a>,
-        amount: u64,
-    ) -> Result<(), ProgramError> {
-        if amount == 0 {
-            return Ok(());
+    /// Decode the slab header. InvalidSlabLen unless `data` is a whole slab of
+    /// this build (`constants::expected_slab_len`, or the legacy length).
+    pub fn decode_header(data: &[u8]) -> Result<SlabHeader, ProgramError> {
+        if data.len() != expected_slab_len() && data.len() != LEGACY_SLAB_LEN {
+            return Err(PercolatorError::InvalidSlabLen.into());
         }
-        #[cfg(not(feature = "test"))]
-        {
-            let ix = spl_token::instruction::transfer(
-                _token_program.key,
-                source.key,
-                dest.key,
-                _authority.key,
-                &[],
-                amount,
-            )?;
-            invoke(
-                &ix,
-                &[
-                    source.clone(),
-                    dest.clone(),
-                    _authority.clone(),
-                    _token_program.clone(),
-                ],
+        let header = crate::state::read_header(data);
+        check_header(&header)?;
+        Ok(header)
+    }
+
+    /// Decode the market config.
+    pub fn decode_config(data: &[u8]) -> Result<MarketConfig, ProgramError> {
+        decode_header(data)?;
+        Ok(crate::state::read_config(data))
+    }
+
+    /// Decode engine account `idx`; EngineAccountNotFound if the slot is unused.
+    pub fn decode_account(data: &[u8], idx: u16) -> Result<AccountView, ProgramError> {
+        decode_header(data)?;
+        let engine = zc::engine_ref(data)?;
+        if idx as usize >= MAX_ACCOUNTS || !engine.is_used(idx as usize) {
+            return Err(PercolatorError::EngineAccountNotFound.into());
+        }
+        let acc = &engine.accounts[idx as usize];
+        Ok(AccountView {
+            idx,
+            is_lp: acc.is_lp(),
+            owner: acc.owner,
+            capital: acc.capital.get(),
+            pnl: acc.pnl.get(),
+            position_size: acc.position_size.get(),
+            entry_price: acc.entry_price,
+            funding_index: acc.funding_index.get(),
+        })
+    }
+}
+
+// 6e. mod client - host-side instruction builders
+//
+// One source of truth for instruction data and account order: each builder
+// encodes the same layout `ix::Instruction::decode` reads and lists accounts in
+// the order the processor indexes them. Optional trailing accounts (collateral
+// mint, extra index feeds) are appended by the caller.
+#[cfg(not(target_os = "solana"))]
+pub mod client {
+    use crate::accounts::derive_vault_authority;
+    use alloc::{vec, vec::Vec};
+    use percolator::RiskParams;
+    use solana_program::instruction::{AccountMeta, Instruction};
+    use solana_program::pubkey::Pubkey;
+    use solana_program::{system_program, sysvar};
+
+    /// Accounts shared by every instruction of one market.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Market {
+        pub program_id: Pubkey,
+        pub slab: Pubkey,
+        pub mint: Pubkey,
+        pub vault: Pubkey,
+        /// Index oracle account (any readonly key for Hyperp markets)
+        pub oracle: Pubkey,
+        /// SPL Token or Token-2022
+        pub token_program: Pubkey,
+    }
+
+    impl Market {
+        /// Vault authority PDA (`[b"vault", slab]`).
+        pub fn vault_authority(&self) -> Pubkey {
+            derive_vault_authority(&self.program_id, &self.slab).0
+        }
+
+        /// LP PDA that signs TradeCpi matcher calls (`[b"lp", slab, lp_idx]`).
+        pub fn lp_pda(&self, lp_idx: u16) -> Pubkey {
+            Pubkey::find_program_address(
+                &[b"lp", self.slab.as_ref(), &lp_idx.to_le_bytes()],
+                &self.program_id,
             )
+            .0
         }
-        #[cfg(feature = "test")]
-        {
-            let mut src_data = source.try_borrow_mut_data()?;
-            let mut src_state = TokenAccount::unpack(&src_data)?;
-            src_state.amount = src_state
-                .amount
-                .checked_sub(amount)
-                .ok_or(ProgramError::InsufficientFunds)?;
-            TokenAccount::pack(src_state, &mut src_data)?;
 
-            let mut dst_data = dest.try_borrow_mut_data()?;
-            let mut dst_state = TokenAccount::unpack(&dst_data)?;
-            dst_state.amount = dst_state
-                .amount
-                .checked_add(amount)
-                .ok_or(ProgramError::InvalidAccountData)?;
-            TokenAccount::pack(dst_state, &mut dst_data)?;
-            Ok(())
+        fn ix(&self, accounts: Vec<AccountMeta>, data: Vec<u8>) -> Instruction {
+            Instruction {
+                program_id: self.program_id,
+                accounts,
+                data,
+            }
         }
     }
 
-    pub fn withdraw<'a>(
-        _token_program: &AccountInfo<'a>,
-        source: &AccountInfo<'a>,
-        dest: &AccountInfo<'a>,
-        _authority: &AccountInfo<'a>,
+    /// InitMarket data; `admin` signs and `collateral_mint` must be `Market::mint`.
+    #[derive(Debug)]
+    pub struct InitMarketArgs {
+        pub admin: Pubkey,
+        pub index_feed_id: [u8; 32],
+        pub max_staleness_secs: u64,
+        pub conf_filter_bps: u16,
+        pub invert: u8,
+        pub unit_scale: u32,
+        pub initial_mark_price_e6: u64,
+        pub risk_params: RiskParams,
+    }
+
+    fn tagged(tag: u8) -> Vec<u8> {
+        vec![tag]
+    }
+
+    fn put(data: &mut Vec<u8>, bytes: &[u8]) {
+        data.extend_from_slice(bytes);
+    }
+
+    pub fn init_market_ix(m: &Market, args: &InitMarketArgs) -> Instruction {
+        m.ix(
+            vec![
+                AccountMeta::new(args.admin, true),
+                AccountMeta::new(m.slab, false),
+                AccountMeta::new_readonly(m.mint, false),
+                AccountMeta::new(m.vault, false),
+                AccountMeta::new_readonly(m.token_program, false),
+                AccountMeta::new_readonly(sysvar::clock::ID, false),
+                AccountMeta::new_readonly(sysvar::rent::ID, false),
+                // Unused slot kept for layout compatibility
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+            init_market_data(0, m, args),
+        )
+    }
+
+    /// Dry run of `init_market_ix`'s payload; no accounts.
+    pub fn validate_init_params_ix(m: &Market, args: &InitMarketArgs) -> Instruction {
+        m.ix(vec![], init_market_data(62, m, args))
+    }
+
+    fn init_market_data(tag: u8, m: &Market, args: &InitMarketArgs) -> Vec<u8> {
+        let mut data = tagged(tag);
+        put(&mut data, args.admin.as_ref());
+        put(&mut data, m.mint.as_ref());
+        put(&mut data, &args.index_feed_id);
+        put(&mut data, &args.max_staleness_secs.to_le_bytes());
+        put(&mut data, &args.conf_filter_bps.to_le_bytes());
+        put(&mut data, &[args.invert]);
+        put(&mut data, &args.unit_scale.to_le_bytes());
+        put(&mut data, &args.initial_mark_price_e6.to_le_bytes());
+        let p = &args.risk_params;
+        put(&mut data, &p.warmup_period_slots.to_le_bytes());
+        put(&mut data, &p.maintenance_margin_bps.to_le_bytes());
+        put(&mut data, &p.initial_margin_bps.to_le_bytes());
+        put(&mut data, &p.trading_fee_bps.to_le_bytes());
+        put(&mut data, &p.max_accounts.to_le_bytes());
+        put(&mut data, &p.new_account_fee.get().to_le_bytes());
+        put(&mut data, &p.risk_reduction_threshold.get().to_le_bytes());
+        put(&mut data, &p.maintenance_fee_per_slot.get().to_le_bytes());
+        put(&mut data, &p.max_crank_staleness_slots.to_le_bytes());
+        put(&mut data, &p.liquidation_fee_bps.to_le_bytes());
+        put(&mut data, &p.liquidation_fee_cap.get().to_le_bytes());
+        put(&mut data, &p.liquidation_buffer_bps.to_le_bytes());
+        put(&mut data, &p.min_liquidation_abs.get().to_le_bytes());
+        data
+    }
+
+    /// [owner, slab, owner_ata, vault, token_program]
+    fn register_accounts(m: &Market, owner: &Pubkey, owner_ata: &Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(m.slab, false),
+            AccountMeta::new(*owner_ata, false),
+            AccountMeta::new(m.vault, false),
+            AccountMeta::new_readonly(m.token_program, false),
+        ]
+    }
+
+    pub fn init_user_ix(
+        m: &Market,
+        user: &Pubkey,
+        user_ata: &Pubkey,
+        fee_payment: u64,
+    ) -> Instruction {
+        let mut data = tagged(1);
+        put(&mut data, &fee_payment.to_le_bytes());
+        m.ix(register_accounts(m, user, user_ata), data)
+    }
+
+    pub fn init_lp_ix(
+        m: &Market,
+        owner: &Pubkey,
+        owner_ata: &Pubkey,
+        matcher_program: &Pubkey,
+        matcher_context: &Pubkey,
+        fee_payment: u64,
+    ) -> Instruction {
+        let mut data = tagged(2);
+        put(&mut data, matcher_program.as_ref());
+        put(&mut data, matcher_context.as_ref());
+        put(&mut data, &fee_payment.to_le_bytes());
+        m.ix(register_accounts(m, owner, owner_ata), data)
+    }
+
+    pub fn set_lp_matcher_ix(
+        m: &Market,
+        owner: &Pubkey,
+        lp_idx: u16,
+        matcher_program: &Pubkey,
+        matcher_context: &Pubkey,
+    ) -> Instruction {
+        let mut data = tagged(52);
+        put(&mut data, &lp_idx.to_le_bytes());
+        put(&mut data, matcher_program.as_ref());
+        put(&mut data, matcher_context.as_ref());
+        m.ix(
+            vec![
+                AccountMeta::new_readonly(*owner, true),
+                AccountMeta::new(m.slab, false),
+                AccountMeta::new_readonly(*matcher_program, false),
+                AccountMeta::new_readonly(*matcher_context, false),
+            ],
+            data,
+        )
+    }
+
+    pub fn deposit_ix(
+        m: &Market,
+        user: &Pubkey,
+        user_ata: &Pubkey,
+        user_idx: u16,
         amount: u64,
-        _signer_seeds: &[&[&[u8]]],
-    ) -> Result<(), ProgramError> {
-        if amount == 0 {
-            return Ok(());
+    ) -> Instruction {
+        let mut data = tagged(3);
+        put(&mut data, &user_idx.to_le_bytes());
+        put(&mut data, &amount.to_le_bytes());
+        let mut accounts = register_accounts(m, user, user_ata);
+        accounts.push(AccountMeta::new_readonly(sysvar::clock::ID, false));
+        m.ix(accounts, data)
+    }
+
+    /// Funds `target_idx` from `funder_ata`; the funder need not own the target.
+    pub fn deposit_to_ix(
+        m: &Market,
+        funder: &Pubkey,
+        funder_ata: &Pubkey,
+        target_idx: u16,
+        amount: u64,
+    ) -> Instruction {
+        let mut data = tagged(50);
+        put(&mut data, &target_idx.to_le_bytes());
+        put(&mut data, &amount.to_le_bytes());
+        let mut accounts = register_accounts(m, funder, funder_ata);
+        accounts.push(AccountMeta::new_readonly(sysvar::clock::ID, false));
+        m.ix(accounts, data)
+    }
+
+    /// Native-SOL markets: wraps `lamports` from `user` into `user_ata` first.
+    pub fn deposit_wrapped_ix(
+        m: &Market,
+        user: &Pubkey,
+        user_ata: &Pubkey,
+        user_idx: u16,
+        lamports: u64,
+    ) -> Instruction {
+        let mut data = tagged(49);
+        put(&mut data, &user_idx.to_le_bytes());
+        put(&mut data, &lamports.to_le_bytes());
+        let mut accounts = register_accounts(m, user, user_ata);
+        accounts.push(AccountMeta::new_readonly(sysvar::clock::ID, false));
+        accounts.push(AccountMeta::new_readonly(system_program::ID, false));
+        m.ix(accounts, data)
+    }
+
+    /// [user, slab, vault, user_ata, vault_authority, token_program, clock, oracle]
+    fn payout_accounts(m: &Market, user: &Pubkey, user_ata: &Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(m.slab, false),
+            AccountMeta::new(m.vault, false),
+            AccountMeta::new(*user_ata, false),
+            AccountMeta::new_readonly(m.vault_authority(), false),
+            AccountMeta::new_readonly(m.token_program, false),
+            AccountMeta::new_readonly(sysvar::clock::ID, false),
+            AccountMeta::new_readonly(m.oracle, false),
+        ]
+    }
+
+    pub fn withdraw_ix(
+        m: &Market,
+        user: &Pubkey,
+        user_ata: &Pubkey,
+        user_idx: u16,
+        amount: u64,
+    ) -> Instruction {
+        let mut data = tagged(4);
+        put(&mut data, &user_idx.to_le_bytes());
+        put(&mut data, &amount.to_le_bytes());
+        m.ix(payout_accounts(m, user, user_ata), data)
+    }
+
+    pub fn close_account_ix(
+        m: &Market,
+        user: &Pubkey,
+        user_ata: &Pubkey,
+        user_idx: u16,
+    ) -> Instruction {
+        let mut data = tagged(8);
+        put(&mut data, &user_idx.to_le_bytes());
+        m.ix(payout_accounts(m, user, user_ata), data)
+    }
+
+    /// [caller, slab, clock, oracle]
+    fn keeper_accounts(m: &Market, caller: &Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(*caller, true),
+            AccountMeta::new(m.slab, false),
+            AccountMeta::new_readonly(sysvar::clock::ID, false),
+            AccountMeta::new_readonly(m.oracle, false),
+        ]
+    }
+
+    /// `caller_idx == u16::MAX` cranks permissionlessly (no caller account in the engine).
+    pub fn keeper_crank_ix(
+        m: &Market,
+        caller: &Pubkey,
+        caller_idx: u16,
+        allow_panic: u8,
+    ) -> Instruction {
+        let mut data = tagged(5);
+        put(&mut data, &caller_idx.to_le_bytes());
+        put(&mut data, &[allow_panic]);
+        m.ix(keeper_accounts(m, caller), data)
+    }
+
+    pub fn liquidate_at_oracle_ix(m: &Market, caller: &Pubkey, target_idx: u16) -> Instruction {
+        let mut data = tagged(7);
+        put(&mut data, &target_idx.to_le_bytes());
+        m.ix(keeper_accounts(m, caller), data)
+    }
+
+    fn trade_data(tag: u8, lp_idx: u16, user_idx: u16, size: i128) -> Vec<u8> {
+        let mut data = tagged(tag);
+        put(&mut data, &lp_idx.to_le_bytes());
+        put(&mut data, &user_idx.to_le_bytes());
+        put(&mut data, &size.to_le_bytes());
+        data
+    }
+
+    /// Both the user and the LP owner sign.
+    pub fn trade_no_cpi_ix(
+        m: &Market,
+        user: &Pubkey,
+        lp_owner: &Pubkey,
+        lp_idx: u16,
+        user_idx: u16,
+        size: i128,
+    ) -> Instruction {
+        m.ix(
+            vec![
+                AccountMeta::new_readonly(*user, true),
+                AccountMeta::new_readonly(*lp_owner, true),
+                AccountMeta::new(m.slab, false),
+                AccountMeta::new_readonly(sysvar::clock::ID, false),
+                AccountMeta::new_readonly(m.oracle, false),
+            ],
+            trade_data(6, lp_idx, user_idx, size),
+        )
+    }
+
+    /// Only the user signs; the LP PDA signs the matcher CPI.
+    #[allow(clippy::too_many_arguments)]
+    pub fn trade_cpi_ix(
+        m: &Market,
+        user: &Pubkey,
+        lp_owner: &Pubkey,
+        lp_idx: u16,
+        user_idx: u16,
+        size: i128,
+        matcher_program: &Pubkey,
+        matcher_context: &Pubkey,
+    ) -> Instruction {
+        m.ix(
+            vec![
+                AccountMeta::new_readonly(*user, true),
+                AccountMeta::new_readonly(*lp_owner, false),
+                AccountMeta::new(m.slab, false),
+                AccountMeta::new_readonly(sysvar::clock::ID, false),
+                AccountMeta::new_readonly(m.oracle, false),
+                AccountMeta::new_readonly(*matcher_program, false),
+                AccountMeta::new(*matcher_context, false),
+                AccountMeta::new_readonly(m.lp_pda(lp_idx), false),
+            ],
+            trade_data(10, lp_idx, user_idx, size),
+        )
+    }
+}
+
+// 7. mod units - base token/units conversion at instruction boundaries
+pub mod units {
+    /// Convert base token amount to units, returning (units, dust).
+    /// Base token is the collateral (e.g., lamports for SOL, satoshis for BTC).
+    /// If scale is 0, returns (base, 0) - no scaling.
+    #[inline]
+    pub fn base_to_units(base: u64, scale: u32) -> (u64, u64) {
+        if scale == 0 {
+            return (base, 0);
         }
-        #[cfg(not(feature = "test"))]
-        {
-            let ix = spl_token::instruction::transfer(
-                _token_program.key,
-                source.key,
-                dest.key,
-                _authority.key,
-                &[],
-                amount,
-            )?;
-            invoke_signed(
-                &ix,
-                &[
-                    source.clone(),
-                    dest.clone(),
-                    _authority.clone(),
-                    _token_program.clone(),
-                ],
-                _signer_seeds,
-            )
+        let s = scale as u64;
+        (base / s, base % s)
+    }
+
+    /// Convert units to base token amount.
+    /// If scale is 0, returns units unchanged - no scaling.
+    #[inline]
+    pub fn units_to_base(units: u64, scale: u32) -> u64 {
+        if scale == 0 {
+            return units;
         }
-        #[cfg(feature = "test")]
-        {
-            let mut src_data = source.try_borrow_mut_data()?;
-            let mut src_state = TokenAccount::unpack(&src_data)?;
-            src_state.amount = src_state
-                .amount
-                .checked_sub(amount)
-                .ok_or(ProgramError::InsufficientFunds)?;
-            TokenAccount::pack(src_state, &mut src_data)?;
+        units.saturating_mul(scale as u64)
+    }
 
-            let mut dst_data = dest.try_borrow_mut_data()?;
-            let mut dst_state = TokenAccount::unpack(&dst_data)?;
-            dst_state.amount = dst_state
-                .amount
-                .checked_add(amount)
-                .ok_or(ProgramError::InvalidAccountData)?;
-            TokenAccount::pack(dst_state, &mut dst_data)?;
-            Ok(())
+    /// Convert units to base token amount with overflow check.
+    /// Returns None if overflow would occur.
+    #[inline]
+    pub fn units_to_base_checked(units: u64, scale: u32) -> Option<u64> {
+        if scale == 0 {
+            return Some(units);
         }
+        units.checked_mul(scale as u64)
     }
 }
 
-// 9. mod processor
-pub mod processor {
-    use crate::{
-        accounts, collateral,
-        constants::{
-            CONFIG_LEN, DEFAULT_FUNDING_HORIZON_SLOTS, DEFAULT_FUNDING_INV_SCALE_NOTIONAL_E6,
-            DEFAULT_FUNDING_K_BPS, DEFAULT_FUNDING_MAX_BPS_PER_SLOT,
-            DEFAULT_FUNDING_MAX_PREMIUM_BPS, DEFAULT_HYPERP_PRICE_CAP_E2BPS,
-            DEFAULT_THRESH_ALPHA_BPS, DEFAULT_THRESH_FLOOR, DEFAULT_THRESH_MAX, DEFAULT_THRESH_MIN,
-            DEFAULT_THRESH_MIN_STEP, DEFAULT_THRESH_RISK_BPS, DEFAULT_THRESH_STEP_BPS,
-            DEFAULT_THRESH_UPDATE_INTERVAL_SLOTS, MAGIC, MATCHER_CALL_LEN, MATCHER_CALL_TAG,
-            MATCHER_CONTEXT_LEN, MATCHER_CONTEXT_PREFIX_LEN, SLAB_LEN, VERSION,
-        },
-        error::{map_risk_error, PercolatorError},
-        ix::Instruction,
-        oracle,
-        state::{self, MarketConfig, SlabHeader},
-        zc,
-    };
-    use percolator::{
-        MatchingEngine, NoOpMatcher, RiskEngine, RiskError, TradeExecution, MAX_ACCOUNTS,
-    };
-    use solana_program::instruction::{AccountMeta, Instruction as SolInstruction};
+// 8. mod oracle
+pub mod oracle {
+    use crate::error::PercolatorError;
     use solana_program::{
-        account_info::AccountInfo,
-        entrypoint::ProgramResult,
-        log::{sol_log_64, sol_log_compute_units},
-        msg,
-        program_error::ProgramError,
-        program_pack::Pack,
-        pubkey::Pubkey,
-        sysvar::{clock::Clock, Sysvar},
+        account_info::AccountInfo, clock::Clock, program_error::ProgramError, pubkey::Pubkey,
     };
 
-    struct CpiMatcher {
-        exec_price: u64,
-        exec_size: i128,
+    // SECURITY (H5): The "devnet" feature disables critical oracle safety checks:
+    // - Staleness validation (stale prices accepted)
+    // - Confidence interval validation (wide confidence accepted)
+    //
+    // WARNING: NEVER deploy to mainnet with the "devnet" feature enabled!
+    // Build for mainnet with: cargo build-sbf (without --features devnet)
+
+    /// Pyth Solana Receiver program ID (same for mainnet and devnet)
+    /// rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ
+    pub const PYTH_RECEIVER_PROGRAM_ID: Pubkey = Pubkey::new_from_array([
+        0x0c, 0xb7, 0xfa, 0xbb, 0x52, 0xf7, 0xa6, 0x48, 0xbb, 0x5b, 0x31, 0x7d, 0x9a, 0x01, 0x8b,
+        0x90, 0x57, 0xcb, 0x02, 0x47, 0x74, 0xfa, 0xfe, 0x01, 0xe6, 0xc4, 0xdf, 0x98, 0xcc, 0x38,
+        0x58, 0x81,
+    ]);
+
+    /// Chainlink OCR2 Store program ID (same for mainnet and devnet)
+    /// HEvSKofvBgfaexv23kMabbYqxasxU3mQ4ibBMEmJWHny
+    pub const CHAINLINK_OCR2_PROGRAM_ID: Pubkey = Pubkey::new_from_array([
+        0xf1, 0x4b, 0xf6, 0x5a, 0xd5, 0x6b, 0xd2, 0xba, 0x71, 0x5e, 0x45, 0x74, 0x2c, 0x23, 0x1f,
+        0x27, 0xd6, 0x36, 0x21, 0xcf, 0x5b, 0x77, 0x8f, 0x37, 0xc1, 0xa2, 0x48, 0x95, 0x1d, 0x17,
+        0x56, 0x02,
+    ]);
+
+    // PriceUpdateV2 account layout offsets (134 bytes minimum)
+    // See: https://github.com/pyth-network/pyth-crosschain/blob/main/target_chains/solana/pyth_solana_receiver_sdk/src/price_update.rs
+    const PRICE_UPDATE_V2_MIN_LEN: usize = 134;
+    const OFF_FEED_ID: usize = 42; // 32 bytes
+    const OFF_PRICE: usize = 74; // i64
+    const OFF_CONF: usize = 82; // u64
+    const OFF_EXPO: usize = 90; // i32
+    const OFF_PUBLISH_TIME: usize = 94; // i64
+
+    // Chainlink OCR2 State/Aggregator account layout offsets (devnet format)
+    // This is the simpler account format used on Solana devnet
+    // Note: Different from the Transmissions ring buffer format in older docs
+    const CL_MIN_LEN: usize = 232; // Minimum required length (covers CL_OFF_ANSWER + 16)
+    const CL_OFF_DECIMALS: usize = 138; // u8 - number of decimals
+                                        // Skip unused: latest_round_id (143), live_length (148), live_cursor (152)
+                                        // The actual price data is stored directly at tail:
+    const CL_OFF_SLOT: usize = 200; // u64 - slot when updated
+    const CL_OFF_TIMESTAMP: usize = 208; // u64 - unix timestamp (seconds)
+    const CL_OFF_ANSWER: usize = 216; // i128 - price answer
+
+    // Maximum supported exponent to prevent overflow (10^18 fits in u128)
+    const MAX_EXPO_ABS: i32 = 18;
+
+    /// Side of the position a conservative price is computed for.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum PriceSide {
+        /// Long positions are valued at price - conf.
+        Long,
+        /// Short positions are valued at price + conf.
+        Short,
+    }
+
+    /// Read price from a Pyth PriceUpdateV2 account.
+    ///
+    /// Parameters:
+    /// - price_ai: The PriceUpdateV2 account
+    /// - expected_feed_id: The expected Pyth feed ID (must match account's feed_id)
+    /// - now_unix_ts: Current unix timestamp (from clock.unix_timestamp)
+    /// - max_staleness_secs: Maximum age in seconds
+    /// - conf_bps: Maximum confidence interval in basis points
+    ///
+    /// Returns the price in e6 format (e.g., 150_000_000 = 150.00 in base units).
+    pub fn read_pyth_price_e6(
+        price_ai: &AccountInfo,
+        expected_feed_id: &[u8; 32],
+        now_unix_ts: i64,
+        max_staleness_secs: u64,
+        conf_bps: u16,
+    ) -> Result<u64, ProgramError> {
+        read_pyth_price_conf_e6(
+            price_ai,
+            expected_feed_id,
+            now_unix_ts,
+            max_staleness_secs,
+            conf_bps,
+        )
+        .map(|(price, _, _)| price)
+    }
+
+    /// Read a Pyth price moved against `side` by its confidence interval:
+    /// `price - conf` for longs, `price + conf` for shorts, both in e6 format.
+    ///
+    /// Same validation as `read_pyth_price_e6`. Fails with OracleInvalid if the
+    /// long bound reaches zero.
+    pub fn read_pyth_price_e6_conservative(
+        price_ai: &AccountInfo,
+        expected_feed_id: &[u8; 32],
+        now_unix_ts: i64,
+        max_staleness_secs: u64,
+        conf_bps: u16,
+        side: PriceSide,
+    ) -> Result<u64, ProgramError> {
+        let (price, conf, _) = read_pyth_price_conf_e6(
+            price_ai,
+            expected_feed_id,
+            now_unix_ts,
+            max_staleness_secs,
+            conf_bps,
+        )?;
+        crate::verify::conf_adjusted_price(price, conf, side == PriceSide::Long)
+            .ok_or(PercolatorError::OracleInvalid.into())
+    }
+
+    /// Shared Pyth reader: returns (price_e6, conf_e6, publish_time), price and
+    /// conf scaled by the feed's expo.
+    fn read_pyth_price_conf_e6(
+        price_ai: &AccountInfo,
+        expected_feed_id: &[u8; 32],
+        now_unix_ts: i64,
+        max_staleness_secs: u64,
+        conf_bps: u16,
+    ) -> Result<(u64, u64, i64), ProgramError> {
+        // Validate oracle owner (skip in tests to allow mock oracles)
+        #[cfg(not(feature = "test"))]
+        {
+            if *price_ai.owner != PYTH_RECEIVER_PROGRAM_ID {
+                return Err(ProgramError::IllegalOwner);
+            }
+        }
+
+        let data = price_ai.try_borrow_data()?;
+        if data.len() < PRICE_UPDATE_V2_MIN_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // Validate feed_id matches expected
+        let feed_id: [u8; 32] = data[OFF_FEED_ID..OFF_FEED_ID + 32].try_into().map_err(|_| ProgramError::InvalidAccountData)?;
+        if &feed_id != expected_feed_id {
+            return Err(PercolatorError::InvalidOracleKey.into());
+        }
+
+        // Read price fields
+        let price = i64::from_le_bytes(data[OFF_PRICE..OFF_PRICE + 8].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
+        let conf = u64::from_le_bytes(data[OFF_CONF..OFF_CONF + 8].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
+        let expo = i32::from_le_bytes(data[OFF_EXPO..OFF_EXPO + 4].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
+        let publish_time = i64::from_le_bytes(
+            data[OFF_PUBLISH_TIME..OFF_PUBLISH_TIME + 8]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?,
+        );
+
+        if price <= 0 {
+            return Err(PercolatorError::OracleInvalid.into());
+        }
+
+        // SECURITY (C3): Bound exponent to prevent overflow in pow()
+        // unsigned_abs: expo.abs() itself panics on i32::MIN from a malformed feed
+        if expo.unsigned_abs() > MAX_EXPO_ABS as u32 {
+            return Err(PercolatorError::OracleInvalidExpo.into());
+        }
+
+        // Staleness check (skip on devnet)
+        #[cfg(not(feature = "devnet"))]
+        {
+            let age = now_unix_ts.saturating_sub(publish_time);
+            if age < 0 || age as u64 > max_staleness_secs {
+                return Err(PercolatorError::OracleStale.into());
+            }
+        }
+        #[cfg(feature = "devnet")]
+        let _ = (publish_time, max_staleness_secs, now_unix_ts);
+
+        // Confidence check (skip on devnet)
+        let price_u = price as u128;
+        #[cfg(not(feature = "devnet"))]
+        {
+            let lhs = (conf as u128) * 10_000;
+            let rhs = price_u * (conf_bps as u128);
+            if lhs > rhs {
+                return Err(PercolatorError::OracleConfTooWide.into());
+            }
+        }
+        #[cfg(feature = "devnet")]
+        let _ = (conf, conf_bps);
+
+        // Convert to e6 format (conf shares the price's exponent)
+        let scale = expo + 6;
+        let to_e6 = |v: u128| -> Result<u128, ProgramError> {
+            if scale >= 0 {
+                let mul = 10u128.pow(scale as u32);
+                Ok(v.checked_mul(mul).ok_or(PercolatorError::EngineOverflow)?)
+            } else {
+                let div = 10u128.pow((-scale) as u32);
+                Ok(v / div)
+            }
+        };
+        let final_price_u128 = to_e6(price_u)?;
+        let final_conf_u128 = to_e6(conf as u128)?;
+
+        if final_price_u128 == 0 {
+            return Err(PercolatorError::OracleInvalid.into());
+        }
+        if final_price_u128 > u64::MAX as u128 || final_conf_u128 > u64::MAX as u128 {
+            return Err(PercolatorError::EngineOverflow.into());
+        }
+
+        Ok((
+            final_price_u128 as u64,
+            final_conf_u128 as u64,
+            publish_time,
+        ))
+    }
+
+    /// Read price from a Chainlink OCR2 State/Aggregator account.
+    ///
+    /// Parameters:
+    /// - price_ai: The Chainlink aggregator account
+    /// - expected_feed_pubkey: The expected feed account pubkey (for validation)
+    /// - now_unix_ts: Current unix timestamp (from clock.unix_timestamp)
+    /// - max_staleness_secs: Maximum age in seconds
+    ///
+    /// Returns the price in e6 format (e.g., 150_000_000 = 150.00 in base units).
+    /// Note: Chainlink doesn't have confidence intervals, so conf_bps is not used.
+    pub fn read_chainlink_price_e6(
+        price_ai: &AccountInfo,
+        expected_feed_pubkey: &[u8; 32],
+        now_unix_ts: i64,
+        max_staleness_secs: u64,
+    ) -> Result<u64, ProgramError> {
+        read_chainlink_price_ts_e6(
+            price_ai,
+            expected_feed_pubkey,
+            now_unix_ts,
+            max_staleness_secs,
+        )
+        .map(|(price, _)| price)
+    }
+
+    /// `read_chainlink_price_e6` plus the round's unix timestamp.
+    fn read_chainlink_price_ts_e6(
+        price_ai: &AccountInfo,
+        expected_feed_pubkey: &[u8; 32],
+        now_unix_ts: i64,
+        max_staleness_secs: u64,
+    ) -> Result<(u64, i64), ProgramError> {
+        // Validate oracle owner (skip in tests to allow mock oracles)
+        #[cfg(not(feature = "test"))]
+        {
+            if *price_ai.owner != CHAINLINK_OCR2_PROGRAM_ID {
+                return Err(ProgramError::IllegalOwner);
+            }
+        }
+
+        // Validate feed pubkey matches expected
+        if price_ai.key.to_bytes() != *expected_feed_pubkey {
+            return Err(PercolatorError::InvalidOracleKey.into());
+        }
+
+        let data = price_ai.try_borrow_data()?;
+        if data.len() < CL_MIN_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // Read header fields
+        let decimals = data[CL_OFF_DECIMALS];
+
+        // Read price data directly from fixed offsets
+        let timestamp = u64::from_le_bytes(
+            data[CL_OFF_TIMESTAMP..CL_OFF_TIMESTAMP + 8]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?,
+        );
+        // Read answer as i128 (16 bytes), but only bottom 8 bytes are typically used
+        let answer =
+            i128::from_le_bytes(data[CL_OFF_ANSWER..CL_OFF_ANSWER + 16].try_into().map_err(|_| ProgramError::InvalidAccountData)?);
+
+        if answer <= 0 {
+            return Err(PercolatorError::OracleInvalid.into());
+        }
+
+        // SECURITY (C3): Bound decimals to prevent overflow in pow()
+        if decimals > MAX_EXPO_ABS as u8 {
+            return Err(PercolatorError::OracleInvalidExpo.into());
+        }
+
+        // Staleness check (skip on devnet)
+        #[cfg(not(feature = "devnet"))]
+        {
+            let age = now_unix_ts.saturating_sub(timestamp as i64);
+            if age < 0 || age as u64 > max_staleness_secs {
+                return Err(PercolatorError::OracleStale.into());
+            }
+        }
+        #[cfg(feature = "devnet")]
+        let _ = (timestamp, max_staleness_secs, now_unix_ts);
+
+        // Convert to e6 format
+        // Chainlink decimals work like: price = answer / 10^decimals
+        // We want e6, so: price_e6 = answer * 10^6 / 10^decimals = answer * 10^(6-decimals)
+        let price_u = answer as u128;
+        let scale = 6i32 - decimals as i32;
+        let final_price_u128 = if scale >= 0 {
+            let mul = 10u128.pow(scale as u32);
+            price_u
+                .checked_mul(mul)
+                .ok_or(PercolatorError::EngineOverflow)?
+        } else {
+            let div = 10u128.pow((-scale) as u32);
+            price_u / div
+        };
+
+        if final_price_u128 == 0 {
+            return Err(PercolatorError::OracleInvalid.into());
+        }
+        if final_price_u128 > u64::MAX as u128 {
+            return Err(PercolatorError::EngineOverflow.into());
+        }
+
+        Ok((final_price_u128 as u64, timestamp as i64))
+    }
+
+    /// Read oracle price for engine use, applying inversion and unit scaling if configured.
+    ///
+    /// Automatically detects oracle type by account owner:
+    /// - PYTH_RECEIVER_PROGRAM_ID: reads Pyth PriceUpdateV2
+    /// - CHAINLINK_OCR2_PROGRAM_ID: reads Chainlink OCR2 Transmissions
+    ///
+    /// Transformations applied in order:
+    /// 1. If invert != 0: inverted price = 1e12 / raw_e6
+    /// 2. If unit_scale > 1: scaled price = price / unit_scale
+    ///
+    /// CRITICAL: The unit_scale transformation ensures oracle-derived values (entry_price,
+    /// mark_pnl, position_value) are in the same scale as capital (which is stored in units).
+    /// Without this scaling, margin checks would compare units to base tokens incorrectly.
+    ///
+    /// The raw oracle is validated (staleness, confidence for Pyth) BEFORE transformations.
+    pub fn read_engine_price_e6(
+        price_ai: &AccountInfo,
+        expected_feed_id: &[u8; 32],
+        now_unix_ts: i64,
+        max_staleness_secs: u64,
+        conf_bps: u16,
+        invert: u8,
+        unit_scale: u32,
+    ) -> Result<u64, ProgramError> {
+        let (raw_price, _, _) = read_raw_quote_e6(
+            price_ai,
+            expected_feed_id,
+            now_unix_ts,
+            max_staleness_secs,
+            conf_bps,
+        )?;
+        to_engine_price_e6(raw_price, invert, unit_scale)
+    }
+
+    /// Validated external feed read, dispatched on the account owner: returns
+    /// (raw price_e6, raw conf_e6, publish time). Chainlink has no conf (0).
+    fn read_raw_quote_e6(
+        price_ai: &AccountInfo,
+        expected_feed_id: &[u8; 32],
+        now_unix_ts: i64,
+        max_staleness_secs: u64,
+        conf_bps: u16,
+    ) -> Result<(u64, u64, i64), ProgramError> {
+        // Detect oracle type by account owner and dispatch
+        if *price_ai.owner == PYTH_RECEIVER_PROGRAM_ID {
+            read_pyth_price_conf_e6(
+                price_ai,
+                expected_feed_id,
+                now_unix_ts,
+                max_staleness_secs,
+                conf_bps,
+            )
+        } else if *price_ai.owner == CHAINLINK_OCR2_PROGRAM_ID {
+            let (price, ts) = read_chainlink_price_ts_e6(
+                price_ai,
+                expected_feed_id,
+                now_unix_ts,
+                max_staleness_secs,
+            )?;
+            Ok((price, 0, ts))
+        } else {
+            // In test mode, try Pyth format first (for existing tests)
+            #[cfg(feature = "test")]
+            {
+                read_pyth_price_conf_e6(
+                    price_ai,
+                    expected_feed_id,
+                    now_unix_ts,
+                    max_staleness_secs,
+                    conf_bps,
+                )
+            }
+            #[cfg(not(feature = "test"))]
+            {
+                Err(ProgramError::IllegalOwner)
+            }
+        }
+    }
+
+    /// Raw feed price to engine terms: inversion, then unit scaling.
+    fn to_engine_price_e6(
+        raw_price: u64,
+        invert: u8,
+        unit_scale: u32,
+    ) -> Result<u64, ProgramError> {
+        // Step 1: Apply inversion if configured (uses verify::invert_price_e6)
+        let price_after_invert = crate::verify::invert_price_e6(raw_price, invert)
+            .ok_or(PercolatorError::OracleInvalid)?;
+
+        // Step 2: Apply unit scaling if configured (uses verify::scale_price_e6)
+        // This ensures oracle-derived values match capital scale (stored in units)
+        crate::verify::scale_price_e6(price_after_invert, unit_scale)
+            .ok_or(PercolatorError::OracleInvalid.into())
+    }
+
+    /// Check if authority-pushed price is available and fresh.
+    /// Returns Some(price_e6) if authority is set and price is within staleness bounds.
+    /// Returns None if no authority is set or price is stale.
+    ///
+    /// Note: The stored authority_price_e6 is already in the correct format (e6, scaled).
+    pub fn read_authority_price(
+        config: &super::state::MarketConfig,
+        now_unix_ts: i64,
+        max_staleness_secs: u64,
+    ) -> Option<u64> {
+        // No authority set
+        if config.oracle_authority == [0u8; 32] {
+            return None;
+        }
+        // No price pushed yet
+        if config.authority_price_e6 == 0 {
+            return None;
+        }
+        // Check staleness
+        let age = now_unix_ts.saturating_sub(config.authority_timestamp);
+        if age < 0 || age as u64 > max_staleness_secs {
+            return None;
+        }
+        Some(config.authority_price_e6)
+    }
+
+    /// Read oracle price, preferring authority-pushed price over Pyth/Chainlink.
+    ///
+    /// If an oracle authority is configured and has pushed a fresh price, use that.
+    /// Otherwise, fall back to reading from the provided Pyth/Chainlink account.
+    ///
+    /// The price_ai can be any account when using authority oracle - it won't be read
+    /// if the authority price is valid.
+    pub fn read_price_with_authority(
+        config: &super::state::MarketConfig,
+        price_ai: &AccountInfo,
+        now_unix_ts: i64,
+    ) -> Result<u64, ProgramError> {
+        // Try authority price first
+        if let Some(authority_price) =
+            read_authority_price(config, now_unix_ts, config.max_staleness_secs)
+        {
+            return Ok(authority_price);
+        }
+
+        // Fall back to Pyth/Chainlink
+        read_engine_price_e6(
+            price_ai,
+            &config.index_feed_id,
+            now_unix_ts,
+            config.max_staleness_secs,
+            config.conf_filter_bps,
+            config.invert,
+            config.unit_scale,
+        )
+    }
+
+    /// Read the index price as the median of the primary feed and any configured
+    /// `extra_index_feed_ids`, preferring a fresh authority price like
+    /// `read_price_with_authority`.
+    ///
+    /// `extra_oracles` holds one account per configured extra feed, in config order;
+    /// missing accounts fail with NotEnoughAccountKeys. Stale feeds are discarded and
+    /// the median of the rest is returned, as long as a majority of the configured
+    /// feeds is fresh (`verify::median_quorum_ok`), else OracleStale; every other
+    /// read error is propagated. With no extra feeds configured this is exactly
+    /// `read_price_with_authority`.
+    pub fn read_index_price_median(
+        config: &super::state::MarketConfig,
+        price_ai: &AccountInfo,
+        extra_oracles: &[AccountInfo],
+        now_unix_ts: i64,
+    ) -> Result<u64, ProgramError> {
+        if let Some(authority_price) =
+            read_authority_price(config, now_unix_ts, config.max_staleness_secs)
+        {
+            return Ok(authority_price);
+        }
+
+        let extra_feeds = config
+            .extra_index_feed_ids
+            .iter()
+            .filter(|id| **id != [0u8; 32]);
+        if extra_oracles.len() < extra_feeds.clone().count() {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+
+        let configured = 1 + extra_feeds.clone().count();
+        let mut prices = [0u64; 1 + crate::constants::MAX_EXTRA_INDEX_FEEDS];
+        let mut fresh = 0usize;
+        let feeds = core::iter::once((price_ai, &config.index_feed_id))
+            .chain(extra_oracles.iter().zip(extra_feeds));
+        for (ai, feed_id) in feeds {
+            match read_engine_price_e6(
+                ai,
+                feed_id,
+                now_unix_ts,
+                config.max_staleness_secs,
+                config.conf_filter_bps,
+                config.invert,
+                config.unit_scale,
+            ) {
+                Ok(price) => {
+                    prices[fresh] = price;
+                    fresh += 1;
+                }
+                Err(e) if e == PercolatorError::OracleStale.into() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !crate::verify::median_quorum_ok(fresh, configured) {
+            return Err(PercolatorError::OracleStale.into());
+        }
+        crate::verify::median_price(&prices[..fresh]).ok_or(PercolatorError::OracleStale.into())
+    }
+
+    /// Clamp `raw_price` so it cannot move more than `max_change_e2bps` from `last_price`.
+    /// Units: 1_000_000 e2bps = 100%. 0 = disabled (no cap). last_price == 0 = first-time.
+    pub fn clamp_oracle_price(last_price: u64, raw_price: u64, max_change_e2bps: u64) -> u64 {
+        if max_change_e2bps == 0 || last_price == 0 {
+            return raw_price;
+        }
+        let max_delta = ((last_price as u128) * (max_change_e2bps as u128) / 1_000_000) as u64;
+        let lower = last_price.saturating_sub(max_delta);
+        let upper = last_price.saturating_add(max_delta);
+        raw_price.clamp(lower, upper)
+    }
+
+    /// Read oracle price with circuit-breaker clamping.
+    /// Reads raw price via `read_price_with_authority`, clamps it against
+    /// `config.last_effective_price_e6`, and updates that field to the post-clamped value.
+    pub fn read_price_clamped(
+        config: &mut super::state::MarketConfig,
+        price_ai: &AccountInfo,
+        now_unix_ts: i64,
+    ) -> Result<u64, ProgramError> {
+        let raw = read_price_with_authority(config, price_ai, now_unix_ts)?;
+        Ok(record_clamped_price(config, raw))
+    }
+
+    /// `read_price_clamped` over `read_index_price_median`: the multi-feed median
+    /// goes through the same circuit breaker.
+    pub fn read_index_price_clamped(
+        config: &mut super::state::MarketConfig,
+        price_ai: &AccountInfo,
+        extra_oracles: &[AccountInfo],
+        now_unix_ts: i64,
+    ) -> Result<u64, ProgramError> {
+        let raw = read_index_price_median(config, price_ai, extra_oracles, now_unix_ts)?;
+        Ok(record_clamped_price(config, raw))
+    }
+
+    fn record_clamped_price(config: &mut super::state::MarketConfig, raw: u64) -> u64 {
+        let clamped = clamp_oracle_price(
+            config.last_effective_price_e6,
+            raw,
+            config.oracle_price_cap_e2bps,
+        );
+        config.last_effective_price_e6 = clamped;
+        clamped
+    }
+
+    /// Confidence width of the external oracle in engine price terms (after
+    /// inversion and unit scaling), used to move liquidation prices against the
+    /// position. Inversion makes the two sides asymmetric; the wider is returned.
+    /// Authority and Chainlink prices carry no confidence and give 0.
+    pub fn read_engine_conf_width_e6(
+        config: &super::state::MarketConfig,
+        price_ai: &AccountInfo,
+        now_unix_ts: i64,
+    ) -> Result<u64, ProgramError> {
+        if read_authority_price(config, now_unix_ts, config.max_staleness_secs).is_some()
+            || *price_ai.owner == CHAINLINK_OCR2_PROGRAM_ID
+        {
+            return Ok(0);
+        }
+        let (raw, conf, _) = read_pyth_price_conf_e6(
+            price_ai,
+            &config.index_feed_id,
+            now_unix_ts,
+            config.max_staleness_secs,
+            config.conf_filter_bps,
+        )?;
+        engine_conf_width_e6(config, raw, conf)
+    }
+
+    fn engine_conf_width_e6(
+        config: &super::state::MarketConfig,
+        raw: u64,
+        conf: u64,
+    ) -> Result<u64, ProgramError> {
+        if conf == 0 {
+            return Ok(0);
+        }
+        // The low raw bound is the wider engine-side move once inverted
+        let low = crate::verify::conf_adjusted_price(raw, conf, true)
+            .ok_or(PercolatorError::OracleInvalid)?;
+        let to_engine = |p: u64| to_engine_price_e6(p, config.invert, config.unit_scale);
+        Ok(to_engine(raw)?.abs_diff(to_engine(low)?))
+    }
+
+    /// One oracle read, parsed once, for handlers that need both the mid and
+    /// the conservative liquidation prices. `mid_e6` is what `read_price_clamped`
+    /// returns (the circuit-breaker update is applied to the config);
+    /// `bid_e6`/`ask_e6` move it by the confidence width of
+    /// `read_engine_conf_width_e6` (0 for a long bound that would reach zero).
+    /// Hyperp, authority and Chainlink readings have no band.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct OracleReading {
+        pub mid_e6: u64,
+        pub bid_e6: u64,
+        pub ask_e6: u64,
+        /// Feed publish time (unix seconds); the push time for authority
+        /// prices, 0 for Hyperp.
+        pub publish: i64,
+    }
+
+    impl OracleReading {
+        fn flat(mid_e6: u64, publish: i64) -> Self {
+            Self {
+                mid_e6,
+                bid_e6: mid_e6,
+                ask_e6: mid_e6,
+                publish,
+            }
+        }
+
+        /// Price a position of signed `size` is liquidated at: the bid for
+        /// longs, the ask for shorts, the mid when flat.
+        pub fn liquidation_price(&self, size: i128) -> Result<u64, ProgramError> {
+            let price = match size {
+                0 => self.mid_e6,
+                s if s > 0 => self.bid_e6,
+                _ => self.ask_e6,
+            };
+            if price == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
+            }
+            Ok(price)
+        }
+    }
+
+    /// Read the oracle once for liquidation pricing: Hyperp uses the index,
+    /// otherwise a fresh authority price or the external feed, clamped like
+    /// `read_price_clamped`.
+    pub fn read_oracle(
+        price_ai: &AccountInfo,
+        config: &mut super::state::MarketConfig,
+        clock: &Clock,
+    ) -> Result<OracleReading, ProgramError> {
+        if is_hyperp_mode(config) {
+            let idx = config.last_effective_price_e6;
+            if idx == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
+            }
+            return Ok(OracleReading::flat(idx, 0));
+        }
+        let now = clock.unix_timestamp;
+        if let Some(price) = read_authority_price(config, now, config.max_staleness_secs) {
+            let mid = record_clamped_price(config, price);
+            return Ok(OracleReading::flat(mid, config.authority_timestamp));
+        }
+
+        let (raw, conf, publish) = read_raw_quote_e6(
+            price_ai,
+            &config.index_feed_id,
+            now,
+            config.max_staleness_secs,
+            config.conf_filter_bps,
+        )?;
+        let mid = record_clamped_price(
+            config,
+            to_engine_price_e6(raw, config.invert, config.unit_scale)?,
+        );
+        let width = engine_conf_width_e6(config, raw, conf)?;
+        Ok(OracleReading {
+            mid_e6: mid,
+            bid_e6: crate::verify::conf_adjusted_price(mid, width, true).unwrap_or(0),
+            ask_e6: mid.saturating_add(width),
+            publish,
+        })
+    }
+
+    // =========================================================================
+    // Hyperp mode helpers (internal mark/index, no external oracle)
+    // =========================================================================
+
+    /// Check if Hyperp mode is active (internal mark/index pricing).
+    /// Hyperp mode is active when index_feed_id is all zeros.
+    #[inline]
+    pub fn is_hyperp_mode(config: &super::state::MarketConfig) -> bool {
+        config.index_feed_id == [0u8; 32]
+    }
+
+    /// Move `index` toward `mark`, but clamp movement by cap_e2bps * dt_slots.
+    /// cap_e2bps units: 1_000_000 = 100.00%
+    /// Returns the new index value.
+    ///
+    /// Security: When dt_slots == 0 (same slot) or cap_e2bps == 0 (cap disabled),
+    /// returns index unchanged to prevent bypassing rate limits.
+    pub fn clamp_toward_with_dt(index: u64, mark: u64, cap_e2bps: u64, dt_slots: u64) -> u64 {
+        if index == 0 {
+            return mark;
+        }
+        // Bug #9 fix: return index (no movement) when dt=0 or cap=0,
+        // rather than mark (bypass rate limiting)
+        if cap_e2bps == 0 || dt_slots == 0 {
+            return index;
+        }
+
+        let max_delta_u128 = (index as u128)
+            .saturating_mul(cap_e2bps as u128)
+            .saturating_mul(dt_slots as u128)
+            / 1_000_000u128;
+
+        let max_delta = core::cmp::min(max_delta_u128, u64::MAX as u128) as u64;
+        let lo = index.saturating_sub(max_delta);
+        let hi = index.saturating_add(max_delta);
+        mark.clamp(lo, hi)
+    }
+
+    /// Get engine oracle price (unified: external oracle vs Hyperp mode).
+    /// In Hyperp mode: updates index toward mark with rate limiting.
+    /// In external mode: reads from Pyth/Chainlink/authority with circuit breaker.
+    pub fn get_engine_oracle_price_e6(
+        engine_last_slot: u64,
+        now_slot: u64,
+        now_unix_ts: i64,
+        config: &mut super::state::MarketConfig,
+        a_oracle: &AccountInfo,
+        extra_oracles: &[AccountInfo],
+    ) -> Result<u64, ProgramError> {
+        // Hyperp mode: index_feed_id == 0
+        if is_hyperp_mode(config) {
+            let mark = config.authority_price_e6;
+            if mark == 0 {
+                return Err(super::error::PercolatorError::OracleInvalid.into());
+            }
+
+            let prev_index = config.last_effective_price_e6;
+            let dt = now_slot.saturating_sub(engine_last_slot);
+            let new_index =
+                clamp_toward_with_dt(prev_index.max(1), mark, config.oracle_price_cap_e2bps, dt);
+
+            config.last_effective_price_e6 = new_index;
+            return Ok(new_index);
+        }
+
+        // Non-Hyperp: authority -> median of Pyth/Chainlink feeds + circuit breaker
+        read_index_price_clamped(config, a_oracle, extra_oracles, now_unix_ts)
+    }
+
+    /// Compute premium-based funding rate (Hyperp funding model).
+    /// Premium = (mark - index) / index, converted to bps per slot.
+    /// Returns signed bps per slot (positive = longs pay shorts).
+    pub fn compute_premium_funding_bps_per_slot(
+        mark_e6: u64,
+        index_e6: u64,
+        funding_horizon_slots: u64,
+        funding_k_bps: u64,   // 100 = 1.00x multiplier
+        max_premium_bps: i64, // e.g. 500 = 5%
+        max_bps_per_slot: i64,
+    ) -> i64 {
+        if mark_e6 == 0 || index_e6 == 0 || funding_horizon_slots == 0 {
+            return 0;
+        }
+
+        let diff = mark_e6 as i128 - index_e6 as i128;
+        let mut premium_bps = diff.saturating_mul(10_000) / (index_e6 as i128);
+
+        // Clamp premium
+        premium_bps = premium_bps.clamp(-(max_premium_bps as i128), max_premium_bps as i128);
+
+        // Apply k multiplier (100 => 1.00x)
+        let scaled = premium_bps.saturating_mul(funding_k_bps as i128) / 100i128;
+
+        // Convert to per-slot by dividing by horizon
+        let mut per_slot = (scaled / (funding_horizon_slots as i128)) as i64;
+
+        // Policy clamp
+        per_slot = per_slot.clamp(-max_bps_per_slot, max_bps_per_slot);
+        per_slot
+    }
+}
+
+// 9. mod collateral
+pub mod collateral {
+    use solana_program::{
+        account_info::AccountInfo, program_error::ProgramError, program_pack::Pack, pubkey::Pubkey,
+    };
+    use spl_token::state::{Account as TokenAccount, Mint};
+
+    #[cfg(not(feature = "test"))]
+    use solana_program::{
+        instruction::{AccountMeta, Instruction},
+        program::{invoke, invoke_signed},
+    };
+
+    /// Token-2022 program ID
+    /// TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb
+    pub const TOKEN_2022_PROGRAM_ID: Pubkey = Pubkey::new_from_array([
+        0x06, 0xdd, 0xf6, 0xe1, 0xee, 0x75, 0x8f, 0xde, 0x18, 0x42, 0x5d, 0xbc, 0xe4, 0x6c, 0xcd,
+        0xda, 0xb6, 0x1a, 0xfc, 0x4d, 0x83, 0xb9, 0x0d, 0x27, 0xfe, 0xbd, 0xf9, 0x28, 0xd8, 0xa1,
+        0x8b, 0xfc,
+    ]);
+
+    // Token-2022 writes an AccountType byte right after the (padded) base layout
+    // when a mint or account carries extensions
+    const ACCOUNT_TYPE_MINT: u8 = 1;
+    const ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+
+    /// `TransferChecked` instruction tag (identical in SPL Token and Token-2022)
+    #[cfg(not(feature = "test"))]
+    const TRANSFER_CHECKED_TAG: u8 = 12;
+
+    /// `InitializeAccount3` instruction tag (identical in SPL Token and Token-2022)
+    #[cfg(not(feature = "test"))]
+    const INITIALIZE_ACCOUNT3_TAG: u8 = 18;
+
+    /// Collateral may live under SPL Token or Token-2022.
+    pub fn is_token_program(key: &Pubkey) -> bool {
+        *key == spl_token::ID || *key == TOKEN_2022_PROGRAM_ID
+    }
+
+    /// Base layout of a token account or mint owned by `owner`. Both programs
+    /// accept the exact base length; Token-2022 also accepts extended accounts,
+    /// whose extensions are ignored here.
+    fn base_layout<'d>(
+        owner: &Pubkey,
+        data: &'d [u8],
+        base_len: usize,
+        account_type: u8,
+    ) -> Option<&'d [u8]> {
+        if data.len() == base_len {
+            return Some(data);
+        }
+        if *owner == TOKEN_2022_PROGRAM_ID && data.get(TokenAccount::LEN) == Some(&account_type) {
+            return Some(&data[..base_len]);
+        }
+        None
+    }
+
+    /// Token account base layout, or None if `owner` is not a token program or
+    /// the length does not fit an SPL / Token-2022 account.
+    pub fn token_account_base<'d>(owner: &Pubkey, data: &'d [u8]) -> Option<&'d [u8]> {
+        if !is_token_program(owner) {
+            return None;
+        }
+        base_layout(owner, data, TokenAccount::LEN, ACCOUNT_TYPE_ACCOUNT)
+    }
+
+    /// Mint base layout, or None if `owner` is not a token program or the length
+    /// does not fit an SPL / Token-2022 mint.
+    pub fn mint_base<'d>(owner: &Pubkey, data: &'d [u8]) -> Option<&'d [u8]> {
+        if !is_token_program(owner) {
+            return None;
+        }
+        base_layout(owner, data, Mint::LEN, ACCOUNT_TYPE_MINT)
+    }
+
+    /// Token amount held by `account` (SPL or Token-2022 base layout).
+    pub fn token_amount(account: &AccountInfo) -> Result<u64, ProgramError> {
+        let data = account.try_borrow_data()?;
+        let base =
+            token_account_base(account.owner, &data).ok_or(ProgramError::InvalidAccountData)?;
+        Ok(TokenAccount::unpack(base)?.amount)
+    }
+
+    fn mint_decimals(mint: &AccountInfo) -> Result<u8, ProgramError> {
+        let data = mint.try_borrow_data()?;
+        let base = mint_base(mint.owner, &data).ok_or(ProgramError::InvalidAccountData)?;
+        Ok(Mint::unpack(base)?.decimals)
+    }
+
+    /// Transfer instruction for `token_program`: `transfer_checked` when the
+    /// collateral mint is supplied, else the plain `transfer`, which only
+    /// classic SPL Token markets may use.
+    #[cfg(not(feature = "test"))]
+    fn transfer_ix(
+        token_program: &Pubkey,
+        source: &Pubkey,
+        mint: Option<&AccountInfo>,
+        dest: &Pubkey,
+        authority: &Pubkey,
+        amount: u64,
+    ) -> Result<Instruction, ProgramError> {
+        let Some(mint) = mint else {
+            return spl_token::instruction::transfer(
+                token_program,
+                source,
+                dest,
+                authority,
+                &[],
+                amount,
+            );
+        };
+        let mut data = alloc::vec::Vec::with_capacity(10);
+        data.push(TRANSFER_CHECKED_TAG);
+        data.extend_from_slice(&amount.to_le_bytes());
+        data.push(mint_decimals(mint)?);
+        Ok(Instruction {
+            program_id: *token_program,
+            accounts: alloc::vec![
+                AccountMeta::new(*source, false),
+                AccountMeta::new_readonly(*mint.key, false),
+                AccountMeta::new(*dest, false),
+                AccountMeta::new_readonly(*authority, true),
+            ],
+            data,
+        })
+    }
+
+    /// Token-2022 transfers must name the mint (extensions such as transfer fees
+    /// reject the plain `transfer`).
+    fn require_mint_for_program(
+        token_program: &AccountInfo,
+        mint: Option<&AccountInfo>,
+    ) -> Result<(), ProgramError> {
+        if mint.is_none() && *token_program.key != spl_token::ID {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        Ok(())
+    }
+
+    /// Test-feature stand-in for Token-2022's TransferFeeConfig extension: the
+    /// fee its newer schedule withholds from a transfer of `amount` (0 without one).
+    #[cfg(feature = "test")]
+    fn transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64, ProgramError> {
+        const EXT_TRANSFER_FEE_CONFIG: u16 = 1;
+        // Two authorities and withheld_amount, then older_transfer_fee (18 bytes)
+        const NEWER_FEE_OFF: usize = 32 + 32 + 8 + 18;
+        if *mint.owner != TOKEN_2022_PROGRAM_ID {
+            return Ok(0);
+        }
+        let data = mint.try_borrow_data()?;
+        // TLV entries start after the padded base layout and AccountType byte
+        let mut off = TokenAccount::LEN + 1;
+        while off + 4 <= data.len() {
+            let ext = u16::from_le_bytes([data[off], data[off + 1]]);
+            let len = u16::from_le_bytes([data[off + 2], data[off + 3]]) as usize;
+            let value = data
+                .get(off + 4..off + 4 + len)
+                .ok_or(ProgramError::InvalidAccountData)?;
+            if ext == EXT_TRANSFER_FEE_CONFIG {
+                // newer_transfer_fee: epoch u64, maximum_fee u64, basis points u16
+                let fee = value
+                    .get(NEWER_FEE_OFF..NEWER_FEE_OFF + 18)
+                    .ok_or(ProgramError::InvalidAccountData)?;
+                let max_fee = u64::from_le_bytes(fee[8..16].try_into().unwrap());
+                let bps = u16::from_le_bytes([fee[16], fee[17]]) as u128;
+                let raw = (amount as u128 * bps).div_ceil(10_000);
+                return Ok((raw as u64).min(max_fee));
+            }
+            off += 4 + len;
+        }
+        Ok(0)
+    }
+
+    /// Test-feature stand-in for the token CPI: moves `amount` between the two
+    /// unpacked accounts, with the mint check `transfer_checked` would make.
+    /// The destination is credited net of any simulated transfer fee.
+    #[cfg(feature = "test")]
+    fn move_tokens(
+        source: &AccountInfo,
+        mint: Option<&AccountInfo>,
+        dest: &AccountInfo,
+        amount: u64,
+    ) -> Result<(), ProgramError> {
+        let fee = match mint {
+            Some(mint) => {
+                mint_decimals(mint)?;
+                transfer_fee(mint, amount)?
+            }
+            None => 0,
+        };
+
+        let mut src_data = source.try_borrow_mut_data()?;
+        let src_base =
+            token_account_base(source.owner, &src_data).ok_or(ProgramError::InvalidAccountData)?;
+        let mut src_state = TokenAccount::unpack(src_base)?;
+        if mint.is_some_and(|m| src_state.mint != *m.key) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        src_state.amount = src_state
+            .amount
+            .checked_sub(amount)
+            .ok_or(ProgramError::InsufficientFunds)?;
+        TokenAccount::pack(src_state, &mut src_data[..TokenAccount::LEN])?;
+
+        let mut dst_data = dest.try_borrow_mut_data()?;
+        let dst_base =
+            token_account_base(dest.owner, &dst_data).ok_or(ProgramError::InvalidAccountData)?;
+        let mut dst_state = TokenAccount::unpack(dst_base)?;
+        if mint.is_some_and(|m| dst_state.mint != *m.key) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        dst_state.amount = dst_state
+            .amount
+            .checked_add(amount.saturating_sub(fee))
+            .ok_or(ProgramError::InvalidAccountData)?;
+        TokenAccount::pack(dst_state, &mut dst_data[..TokenAccount::LEN])?;
+        Ok(())
+    }
+
+    /// Transfer `amount` from `source` into the vault `dest`. Returns what `dest`
+    /// actually received, which a Token-2022 transfer fee makes smaller than
+    /// `amount`; callers credit that, never the requested amount.
+    pub fn deposit<'a>(
+        token_program: &AccountInfo<'a>,
+        source: &AccountInfo<'a>,
+        mint: Option<&AccountInfo<'a>>,
+        dest: &AccountInfo<'a>,
+        _authority: &AccountInfo<'a>,
+        amount: u64,
+    ) -> Result<u64, ProgramError> {
+        if amount == 0 {
+            return Ok(0);
+        }
+        require_mint_for_program(token_program, mint)?;
+        let before = token_amount(dest)?;
+        #[cfg(not(feature = "test"))]
+        {
+            let ix = transfer_ix(
+                token_program.key,
+                source.key,
+                mint,
+                dest.key,
+                _authority.key,
+                amount,
+            )?;
+            invoke(
+                &ix,
+                &[
+                    source.clone(),
+                    mint.unwrap_or(source).clone(),
+                    dest.clone(),
+                    _authority.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+        }
+        #[cfg(feature = "test")]
+        {
+            move_tokens(source, mint, dest, amount)?;
+        }
+        token_amount(dest)?
+            .checked_sub(before)
+            .ok_or(ProgramError::InvalidAccountData)
+    }
+
+    /// Move `lamports` from `owner` into its native-mint token account and
+    /// `sync_native` it, so the wrapped balance moves like any SPL amount.
+    pub fn wrap_native<'a>(
+        _token_program: &AccountInfo<'a>,
+        _system_program: &AccountInfo<'a>,
+        owner: &AccountInfo<'a>,
+        ata: &AccountInfo<'a>,
+        lamports: u64,
+    ) -> Result<(), ProgramError> {
+        if lamports == 0 {
+            return Ok(());
+        }
+        #[cfg(not(feature = "test"))]
+        {
+            let ix = solana_program::system_instruction::transfer(owner.key, ata.key, lamports);
+            invoke(&ix, &[owner.clone(), ata.clone(), _system_program.clone()])?;
+            let ix = spl_token::instruction::sync_native(_token_program.key, ata.key)?;
+            invoke(&ix, &[ata.clone(), _token_program.clone()])
+        }
+        #[cfg(feature = "test")]
+        {
+            let owner_left = owner
+                .lamports()
+                .checked_sub(lamports)
+                .ok_or(ProgramError::InsufficientFunds)?;
+            let ata_total = ata
+                .lamports()
+                .checked_add(lamports)
+                .ok_or(ProgramError::InvalidAccountData)?;
+            **owner.try_borrow_mut_lamports()? = owner_left;
+            **ata.try_borrow_mut_lamports()? = ata_total;
+
+            let mut data = ata.try_borrow_mut_data()?;
+            let mut state = TokenAccount::unpack(&data)?;
+            if !state.is_native() {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            state.amount = state
+                .amount
+                .checked_add(lamports)
+                .ok_or(ProgramError::InvalidAccountData)?;
+            TokenAccount::pack(state, &mut data)?;
+            Ok(())
+        }
+    }
+
+    /// Create an empty `vault` (which must sign) as a token account of `mint`
+    /// owned by `authority`: a rent-exempt system allocation funded by `payer`,
+    /// then `InitializeAccount3` under `token_program`. Sized for a base token
+    /// account, so Token-2022 mints whose extensions need account space must
+    /// still pre-create their vault.
+    #[cfg(not(feature = "test"))]
+    pub fn create_vault<'a>(
+        payer: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        authority: &Pubkey,
+        token_program: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        rent: &solana_program::rent::Rent,
+    ) -> Result<(), ProgramError> {
+        if !is_token_program(token_program.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let ix = solana_program::system_instruction::create_account(
+            payer.key,
+            vault.key,
+            rent.minimum_balance(TokenAccount::LEN),
+            TokenAccount::LEN as u64,
+            token_program.key,
+        );
+        invoke(&ix, &[payer.clone(), vault.clone(), system_program.clone()])?;
+
+        let mut data = alloc::vec::Vec::with_capacity(33);
+        data.push(INITIALIZE_ACCOUNT3_TAG);
+        data.extend_from_slice(authority.as_ref());
+        let ix = Instruction {
+            program_id: *token_program.key,
+            accounts: alloc::vec![
+                AccountMeta::new(*vault.key, false),
+                AccountMeta::new_readonly(*mint.key, false),
+            ],
+            data,
+        };
+        invoke(&ix, &[vault.clone(), mint.clone(), token_program.clone()])
+    }
+
+    pub fn withdraw<'a>(
+        token_program: &AccountInfo<'a>,
+        source: &AccountInfo<'a>,
+        mint: Option<&AccountInfo<'a>>,
+        dest: &AccountInfo<'a>,
+        _authority: &AccountInfo<'a>,
+        amount: u64,
+        _signer_seeds: &[&[&[u8]]],
+    ) -> Result<(), ProgramError> {
+        if amount == 0 {
+            return Ok(());
+        }
+        require_mint_for_program(token_program, mint)?;
+        #[cfg(not(feature = "test"))]
+        {
+            let ix = transfer_ix(
+                token_program.key,
+                source.key,
+                mint,
+                dest.key,
+                _authority.key,
+                amount,
+            )?;
+            invoke_signed(
+                &ix,
+                &[
+                    source.clone(),
+                    mint.unwrap_or(source).clone(),
+                    dest.clone(),
+                    _authority.clone(),
+                    token_program.clone(),
+                ],
+                _signer_seeds,
+            )
+        }
+        #[cfg(feature = "test")]
+        {
+            move_tokens(source, mint, dest, amount)
+        }
+    }
+}
+
+// 9. mod processor
+pub mod processor {
+    use crate::{
+        accounts, collateral,
+        constants::{
+            CONFIG_LEN, DEFAULT_FUNDING_HORIZON_SLOTS, DEFAULT_FUNDING_INV_SCALE_NOTIONAL_E6,
+            DEFAULT_FUNDING_K_BPS, DEFAULT_FUNDING_MAX_BPS_PER_SLOT,
+            DEFAULT_FUNDING_MAX_PREMIUM_BPS, DEFAULT_HYPERP_PRICE_CAP_E2BPS,
+            DEFAULT_THRESH_ALPHA_BPS, DEFAULT_THRESH_FLOOR, DEFAULT_THRESH_MAX, DEFAULT_THRESH_MIN,
+            DEFAULT_THRESH_MIN_STEP, DEFAULT_THRESH_RISK_BPS, DEFAULT_THRESH_STEP_BPS,
+            DEFAULT_THRESH_UPDATE_INTERVAL_SLOTS, ENGINE_OFF, LEGACY_SLAB_LEN, MAGIC,
+            MATCHER_CALL_LEN, MATCHER_CALL_TAG, MATCHER_CONTEXT_LEN, MATCHER_CONTEXT_PREFIX_LEN,
+            MAX_TRADE_LEGS, SLAB_LEN, VERSION,
+        },
+        error::{map_risk_error, PercolatorError},
+        ix::Instruction,
+        oracle,
+        state::{self, MarketConfig, SlabHeader},
+        zc,
+    };
+    use percolator::{
+        MatchingEngine, NoOpMatcher, RiskEngine, RiskError, RiskParams, TradeExecution,
+        MAX_ACCOUNTS,
+    };
+    use solana_program::instruction::{AccountMeta, Instruction as SolInstruction};
+    use solana_program::{
+        account_info::AccountInfo,
+        entrypoint::ProgramResult,
+        log::{sol_log_64, sol_log_compute_units},
+        msg,
+        program::set_return_data,
+        program_error::ProgramError,
+        program_pack::Pack,
+        pubkey::Pubkey,
+        sysvar::{clock::Clock, Sysvar},
+    };
+
+    struct CpiMatcher {
+        exec_price: u64,
+        exec_size: i128,
+    }
+
+    impl MatchingEngine for CpiMatcher {
+        fn execute_match(
+            &self,
+            _lp_program: &[u8; 32],
+            _lp_context: &[u8; 32],
+            _lp_account_id: u64,
+            _oracle_price: u64,
+            _size: i128,
+        ) -> Result<TradeExecution, RiskError> {
+            Ok(TradeExecution {
+                price: self.exec_price,
+                size: self.exec_size,
+            })
+        }
+    }
+
+    /// Shape checks on a matcher CPI's accounts (TradeCpi, LiquidateCpi):
+    /// executable matcher program owning a large-enough context, and the LP
+    /// PDA `[b"lp", slab, lp_idx]` system-owned, empty and unfunded. Returns
+    /// the PDA bump for `invoke_signed`.
+    fn check_matcher_accounts(
+        program_id: &Pubkey,
+        a_slab: &AccountInfo,
+        lp_idx: u16,
+        a_matcher_prog: &AccountInfo,
+        a_matcher_ctx: &AccountInfo,
+        a_lp_pda: &AccountInfo,
+    ) -> Result<u8, ProgramError> {
+        // Matcher shape validation via verify helper (Kani-provable)
+        let matcher_shape = crate::verify::MatcherAccountsShape {
+            prog_executable: a_matcher_prog.executable,
+            ctx_executable: a_matcher_ctx.executable,
+            ctx_owner_is_prog: a_matcher_ctx.owner == a_matcher_prog.key,
+            ctx_len_ok: crate::verify::ctx_len_sufficient(a_matcher_ctx.data_len()),
+        };
+        if !crate::verify::matcher_shape_ok(matcher_shape) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // Phase 1: Validate lp_pda is the correct PDA, system-owned, empty data, 0 lamports
+        let lp_bytes = lp_idx.to_le_bytes();
+        let (expected_lp_pda, bump) =
+            Pubkey::find_program_address(&[b"lp", a_slab.key.as_ref(), &lp_bytes], program_id);
+        // PDA key validation via verify helper (Kani-provable)
+        if !crate::verify::pda_key_matches(expected_lp_pda.to_bytes(), a_lp_pda.key.to_bytes()) {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        // LP PDA shape validation via verify helper (Kani-provable)
+        let lp_pda_shape = crate::verify::LpPdaShape {
+            is_system_owned: a_lp_pda.owner == &solana_program::system_program::ID,
+            data_len_zero: a_lp_pda.data_len() == 0,
+            lamports_zero: **a_lp_pda.lamports.borrow() == 0,
+        };
+        if !crate::verify::lp_pda_shape_ok(lp_pda_shape) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(bump)
+    }
+
+    /// Reject a matcher context the matcher never initialized, right before the
+    /// CPI whose return would otherwise be read out of it.
+    fn require_matcher_initialized(a_matcher_ctx: &AccountInfo) -> Result<(), ProgramError> {
+        if !crate::matcher_abi::context_initialized(&a_matcher_ctx.try_borrow_data()?) {
+            return Err(PercolatorError::MatcherNotInitialized.into());
+        }
+        Ok(())
+    }
+
+    fn slab_guard(
+        program_id: &Pubkey,
+        slab: &AccountInfo,
+        data: &[u8],
+    ) -> Result<(), ProgramError> {
+        // Slab shape validation via verify helper (Kani-provable)
+        // Accept old slabs that are 8 bytes smaller due to Account struct reordering migration.
+        // Old slabs (1111384 bytes) work for up to 4095 accounts; new slabs (1111392) for 4096.
+        let shape = crate::verify::SlabShape {
+            owned_by_program: slab.owner == program_id,
+            correct_len: data.len() == SLAB_LEN || data.len() == LEGACY_SLAB_LEN,
+        };
+        if !crate::verify::slab_shape_ok(shape) {
+            // Return specific error based on which check failed
+            if slab.owner != program_id {
+                return Err(ProgramError::IllegalOwner);
+            }
+            // Expected vs actual, so a slab sized for another MAX_ACCOUNTS build is obvious
+            msg!("InvalidSlabLen: expected, actual");
+            solana_program::log::sol_log_64(SLAB_LEN as u64, data.len() as u64, 0, 0, 0);
+            return Err(PercolatorError::InvalidSlabLen.into());
+        }
+        Ok(())
+    }
+
+    fn require_initialized(data: &[u8]) -> Result<(), ProgramError> {
+        let h = state::read_header(data);
+        if h.magic != MAGIC {
+            return Err(PercolatorError::NotInitialized.into());
+        }
+        if h.version != VERSION {
+            return Err(PercolatorError::InvalidVersion.into());
+        }
+        Ok(())
+    }
+
+    /// Require that the signer is the current admin.
+    /// If admin is burned (all zeros), admin operations are permanently disabled.
+    /// Admin authorization via verify helper (Kani-provable)
+    fn require_admin(header_admin: [u8; 32], signer: &Pubkey) -> Result<(), ProgramError> {
+        if !crate::verify::admin_ok(header_admin, signer.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+        Ok(())
+    }
+
+    /// Shared prologue of the two-account `[admin, slab]` setters: admin signer,
+    /// writable initialized slab owned by this program, market not resolved.
+    /// Runs `f` on the slab data once all of them pass.
+    fn with_admin_slab(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        f: impl FnOnce(&mut [u8]) -> ProgramResult,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 2)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
+
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let header = state::read_header(&data);
+        require_admin(header.admin, a_admin.key)?;
+
+        f(&mut data)
+    }
+
+    /// [`with_admin_slab`] for setters that only touch [`MarketConfig`]: `f`
+    /// edits a copy, which is written back only if it returns `Ok`.
+    fn with_admin_config(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        f: impl FnOnce(&mut MarketConfig) -> ProgramResult,
+    ) -> ProgramResult {
+        with_admin_slab(program_id, accounts, |data| {
+            let mut config = state::read_config(data);
+            f(&mut config)?;
+            state::write_config(data, &config);
+            Ok(())
+        })
+    }
+
+    /// Run `f` with all engine fees zeroed, restoring the configured values afterwards.
+    /// Used for single-account paths (liquidation, CloseAccount) while an
+    /// admin-declared emergency exit is active.
+    fn with_fees_waived<T>(
+        engine: &mut RiskEngine,
+        f: impl FnOnce(&mut RiskEngine) -> Result<T, RiskError>,
+    ) -> Result<T, RiskError> {
+        let trading_fee_bps = engine.params.trading_fee_bps;
+        let liquidation_fee_bps = engine.params.liquidation_fee_bps;
+        let maintenance_fee_per_slot = engine.params.maintenance_fee_per_slot;
+        engine.params.trading_fee_bps = 0;
+        engine.params.liquidation_fee_bps = 0;
+        engine.params.maintenance_fee_per_slot = percolator::U128::ZERO;
+        let res = f(engine);
+        engine.params.trading_fee_bps = trading_fee_bps;
+        engine.params.liquidation_fee_bps = liquidation_fee_bps;
+        engine.params.maintenance_fee_per_slot = maintenance_fee_per_slot;
+        res
+    }
+
+    /// Run the fill `f` (user side `size`) with fees waived only for a side whose
+    /// position shrinks. The engine charges the trading fee to the user, so it is
+    /// zeroed only for a reducing user fill; a reducing side's maintenance fee is
+    /// waived by moving its `last_fee_slot` to `slot`, so an LP that opens or
+    /// extends risk still pays. Used while an emergency exit is active.
+    fn with_fill_fees_waived<T>(
+        engine: &mut RiskEngine,
+        user_idx: u16,
+        lp_idx: u16,
+        size: i128,
+        slot: u64,
+        f: impl FnOnce(&mut RiskEngine) -> Result<T, RiskError>,
+    ) -> Result<T, RiskError> {
+        let user_pos = engine.accounts[user_idx as usize].position_size.get();
+        let user_reducing = crate::verify::is_reducing_fill(user_pos, size);
+        for (idx, delta) in [(user_idx, size), (lp_idx, size.saturating_neg())] {
+            let pos = engine.accounts[idx as usize].position_size.get();
+            if crate::verify::is_reducing_fill(pos, delta) {
+                engine.accounts[idx as usize].last_fee_slot = slot;
+            }
+        }
+        if !user_reducing {
+            return f(engine);
+        }
+        let trading_fee_bps = engine.params.trading_fee_bps;
+        engine.params.trading_fee_bps = 0;
+        let res = f(engine);
+        engine.params.trading_fee_bps = trading_fee_bps;
+        res
+    }
+
+    /// Run `f` with liquidation params overridden so that `liquidate_at_oracle`
+    /// closes the whole position at oracle and charges exactly `penalty_bps`
+    /// of notional to insurance. Restores the configured params afterwards.
+    fn with_backstop_params<T>(
+        engine: &mut RiskEngine,
+        penalty_bps: u64,
+        f: impl FnOnce(&mut RiskEngine) -> Result<T, RiskError>,
+    ) -> Result<T, RiskError> {
+        let maintenance_margin_bps = engine.params.maintenance_margin_bps;
+        let liquidation_fee_bps = engine.params.liquidation_fee_bps;
+        let liquidation_fee_cap = engine.params.liquidation_fee_cap;
+        let liquidation_buffer_bps = engine.params.liquidation_buffer_bps;
+        let min_liquidation_abs = engine.params.min_liquidation_abs;
+        // Every open position is "unsafe" and no partial remainder is kept
+        engine.params.maintenance_margin_bps = 100_000_000;
+        engine.params.liquidation_fee_bps = penalty_bps;
+        engine.params.liquidation_fee_cap = percolator::U128::new(u128::MAX);
+        engine.params.liquidation_buffer_bps = 0;
+        engine.params.min_liquidation_abs = percolator::U128::new(u128::MAX);
+        let res = f(engine);
+        engine.params.maintenance_margin_bps = maintenance_margin_bps;
+        engine.params.liquidation_fee_bps = liquidation_fee_bps;
+        engine.params.liquidation_fee_cap = liquidation_fee_cap;
+        engine.params.liquidation_buffer_bps = liquidation_buffer_bps;
+        engine.params.min_liquidation_abs = min_liquidation_abs;
+        res
+    }
+
+    /// Run `f` with liquidation sizing overridden: the engine liquidates any
+    /// position below `margin_bps` and closes it down to what `margin_bps`
+    /// supports, keeping any remainder (`full_close == false`) or none.
+    /// Fees are unchanged. Restores the configured params afterwards.
+    fn with_liquidation_target<T>(
+        engine: &mut RiskEngine,
+        margin_bps: u64,
+        full_close: bool,
+        f: impl FnOnce(&mut RiskEngine) -> Result<T, RiskError>,
+    ) -> Result<T, RiskError> {
+        let maintenance_margin_bps = engine.params.maintenance_margin_bps;
+        let liquidation_buffer_bps = engine.params.liquidation_buffer_bps;
+        let min_liquidation_abs = engine.params.min_liquidation_abs;
+        engine.params.maintenance_margin_bps = margin_bps;
+        engine.params.liquidation_buffer_bps = 0;
+        engine.params.min_liquidation_abs =
+            percolator::U128::new(if full_close { u128::MAX } else { 0 });
+        let res = f(engine);
+        engine.params.maintenance_margin_bps = maintenance_margin_bps;
+        engine.params.liquidation_buffer_bps = liquidation_buffer_bps;
+        engine.params.min_liquidation_abs = min_liquidation_abs;
+        res
+    }
+
+    /// Run `f`, a liquidation fill through `execute_trade`, with the trading fee
+    /// set to the rate that charges `fee` (the engine's own liquidation fee for
+    /// this close) on `closed_notional`, so it goes through the engine's fee
+    /// accounting. The rate rounds down and never exceeds `liquidation_fee_bps`;
+    /// the configured trading fee is restored afterwards.
+    fn with_liquidation_fee<T>(
+        engine: &mut RiskEngine,
+        fee: u128,
+        closed_notional: u128,
+        f: impl FnOnce(&mut RiskEngine) -> Result<T, RiskError>,
+    ) -> Result<T, RiskError> {
+        let trading_fee_bps = engine.params.trading_fee_bps;
+        engine.params.trading_fee_bps = if closed_notional == 0 {
+            0
+        } else {
+            (fee.saturating_mul(10_000) / closed_notional)
+                .min(engine.params.liquidation_fee_bps as u128) as u64
+        };
+        let res = f(engine);
+        engine.params.trading_fee_bps = trading_fee_bps;
+        res
+    }
+
+    /// LiquidateAtOracle's engine call: `liquidate_at_oracle` at `price`, with
+    /// fees waived while an emergency exit is active.
+    fn liquidate_at_oracle(
+        engine: &mut RiskEngine,
+        idx: u16,
+        slot: u64,
+        price: u64,
+        emergency_exit: bool,
+    ) -> Result<bool, RiskError> {
+        if emergency_exit {
+            with_fees_waived(engine, |engine| {
+                engine.liquidate_at_oracle(idx, slot, price)
+            })
+        } else {
+            engine.liquidate_at_oracle(idx, slot, price)
+        }
+    }
+
+    /// Outcome of a rolled-back [`liquidate_at_oracle`] run.
+    struct LiquidationDryRun {
+        /// false when the engine found the account healthy
+        liquidated: bool,
+        /// Position before the close
+        position_before: i128,
+        /// Position the engine's close leaves
+        position_after: i128,
+        /// Credited to insurance: the liquidation fee
+        penalty: u128,
+        /// Equity the account is left with at the liquidation price
+        equity_after: i128,
+    }
+
+    /// Run [`liquidate_at_oracle`] on `idx` and roll it back, so a caller can
+    /// size or preview a close exactly as the engine makes it. The engine call
+    /// only writes the fields before `accounts` and `idx`'s own account; those
+    /// bytes are saved first and restored after, leaving the slab unchanged.
+    fn dry_run_liquidation(
+        data: &mut [u8],
+        idx: u16,
+        slot: u64,
+        price: u64,
+        emergency_exit: bool,
+    ) -> Result<LiquidationDryRun, ProgramError> {
+        let head = ENGINE_OFF..ENGINE_OFF + zc::ACCOUNTS_OFFSET;
+        let acc_len = core::mem::size_of::<percolator::Account>();
+        let acc_off = head.end + idx as usize * acc_len;
+        let mut saved = alloc::vec::Vec::with_capacity(head.len() + acc_len);
+        saved.extend_from_slice(&data[head.clone()]);
+        saved.extend_from_slice(&data[acc_off..acc_off + acc_len]);
+
+        let engine = zc::engine_mut(data)?;
+        let position_before = engine.accounts[idx as usize].position_size.get();
+        let ins_before = engine.insurance_fund.balance.get();
+        let res = liquidate_at_oracle(engine, idx, slot, price, emergency_exit).map(|liquidated| {
+            LiquidationDryRun {
+                liquidated,
+                position_before,
+                position_after: engine.accounts[idx as usize].position_size.get(),
+                penalty: engine
+                    .insurance_fund
+                    .balance
+                    .get()
+                    .saturating_sub(ins_before),
+                equity_after: account_equity(engine, idx, price),
+            }
+        });
+
+        data[head.clone()].copy_from_slice(&saved[..head.len()]);
+        data[acc_off..acc_off + acc_len].copy_from_slice(&saved[head.len()..]);
+        res.map_err(map_risk_error)
+    }
+
+    fn check_idx(engine: &RiskEngine, idx: u16) -> Result<(), ProgramError> {
+        if (idx as usize) >= MAX_ACCOUNTS || !engine.is_used(idx as usize) {
+            return Err(PercolatorError::EngineAccountNotFound.into());
+        }
+        Ok(())
+    }
+
+    /// Equity of `idx` at `price`: capital + PnL + mark, net of any funding not
+    /// yet settled into PnL, so it matches what the engine sees after
+    /// `settle_funding` without writing anything.
+    fn account_equity(engine: &RiskEngine, idx: u16, price: u64) -> i128 {
+        let acc = &engine.accounts[idx as usize];
+        let pos = acc.position_size.get();
+        let funding = crate::verify::funding_payment(
+            pos,
+            engine.funding_index_qpb_e6.get(),
+            acc.funding_index.get(),
+        );
+        let mark = pos.saturating_mul(price as i128 - acc.entry_price as i128) / 1_000_000;
+        (acc.capital.get() as i128)
+            .saturating_add(acc.pnl.get())
+            .saturating_sub(funding)
+            .saturating_add(mark)
+    }
+
+    /// Opens and increases must leave `initial_margin_bps` of the resulting notional
+    /// covered by equity at `price`; reducing fills only answer to maintenance (engine).
+    fn check_initial_margin(
+        engine: &RiskEngine,
+        idx: u16,
+        delta: i128,
+        price: u64,
+    ) -> Result<(), ProgramError> {
+        let acc = &engine.accounts[idx as usize];
+        let pos = acc.position_size.get();
+        if crate::verify::is_reducing_fill(pos, delta) {
+            return Ok(());
+        }
+        let equity = account_equity(engine, idx, price);
+        let notional = pos
+            .saturating_add(delta)
+            .unsigned_abs()
+            .saturating_mul(price as u128)
+            / 1_000_000;
+        if !crate::verify::equity_meets_margin(equity, notional, engine.params.initial_margin_bps) {
+            return Err(PercolatorError::EngineUndercollateralized.into());
+        }
+        Ok(())
+    }
+
+    /// Per-account leverage cap on a fill of `delta` for `idx` at `price`:
+    /// notional against deposited capital, independent of the margin math.
+    fn check_leverage_cap(
+        engine: &RiskEngine,
+        config: &MarketConfig,
+        idx: u16,
+        delta: i128,
+        price: u64,
+    ) -> Result<(), ProgramError> {
+        let acc = &engine.accounts[idx as usize];
+        if !crate::verify::leverage_ok(
+            acc.position_size.get(),
+            delta,
+            price,
+            acc.capital.get(),
+            config.max_leverage_x,
+        ) {
+            return Err(PercolatorError::LeverageCap.into());
+        }
+        Ok(())
+    }
+
+    /// Realize `idx`'s mark PnL at `price` and re-enter the position there.
+    fn mark_to_price(engine: &mut RiskEngine, idx: u16, price: u64) {
+        let acc = &engine.accounts[idx as usize];
+        let pos = acc.position_size.get();
+        if pos == 0 {
+            return;
+        }
+        let mark = pos.saturating_mul(price as i128 - acc.entry_price as i128) / 1_000_000;
+        let pnl = acc.pnl.get().saturating_add(mark);
+        engine.set_pnl(idx as usize, pnl);
+        engine.accounts[idx as usize].entry_price = price;
+    }
+
+    /// Last applied trade `client_req_id` of user account `idx`. User accounts
+    /// never bind a matcher, so it is kept in the first 8 bytes of their
+    /// otherwise unused `matcher_context`.
+    fn read_client_req_id(engine: &RiskEngine, idx: u16) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&engine.accounts[idx as usize].matcher_context[..8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Record `client_req_id` as the last applied one for user account `idx`.
+    fn write_client_req_id(engine: &mut RiskEngine, idx: u16, client_req_id: u64) {
+        engine.accounts[idx as usize].matcher_context[..8]
+            .copy_from_slice(&client_req_id.to_le_bytes());
+    }
+
+    /// Reject a resubmitted trade: `client_req_id` repeats the user's last applied
+    /// one. Only user accounts carry an id (an LP's matcher_context binds its
+    /// matcher); 0 skips the check.
+    fn check_client_req_id(
+        engine: &RiskEngine,
+        user_idx: u16,
+        client_req_id: u64,
+    ) -> Result<(), ProgramError> {
+        if client_req_id == 0 {
+            return Ok(());
+        }
+        if engine.accounts[user_idx as usize].is_lp() {
+            return Err(PercolatorError::EngineAccountKindMismatch.into());
+        }
+        let last_client_req_id = read_client_req_id(engine, user_idx);
+        if !crate::verify::client_req_id_fresh(last_client_req_id, client_req_id) {
+            return Err(PercolatorError::DuplicateRequest.into());
+        }
+        Ok(())
+    }
+
+    /// Settle `idx`'s funding up to the current global index so a trade in the
+    /// slot a crank advanced the index charges the pre-trade position exactly
+    /// once, whichever of the two lands first.
+    fn settle_funding(engine: &mut RiskEngine, idx: u16) {
+        let global = engine.funding_index_qpb_e6.get();
+        let acc = &engine.accounts[idx as usize];
+        if acc.funding_index.get() == global {
+            return;
+        }
+        let payment = crate::verify::funding_payment(
+            acc.position_size.get(),
+            global,
+            acc.funding_index.get(),
+        );
+        let pnl = acc.pnl.get().saturating_sub(payment);
+        engine.set_pnl(idx as usize, pnl);
+        engine.accounts[idx as usize].funding_index = percolator::I128::new(global);
+    }
+
+    fn verify_vault(
+        a_vault: &AccountInfo,
+        expected_owner: &Pubkey,
+        expected_mint: &Pubkey,
+        expected_pubkey: &Pubkey,
+    ) -> Result<(), ProgramError> {
+        if a_vault.key != expected_pubkey {
+            return Err(PercolatorError::InvalidVaultAta.into());
+        }
+        // SPL Token or Token-2022 (extensions allowed)
+        let data = a_vault.try_borrow_data()?;
+        let base = collateral::token_account_base(a_vault.owner, &data)
+            .ok_or(PercolatorError::InvalidVaultAta)?;
+        let tok = spl_token::state::Account::unpack(base)?;
+        if tok.mint != *expected_mint {
+            return Err(PercolatorError::InvalidMint.into());
+        }
+        if tok.owner != *expected_owner {
+            return Err(PercolatorError::InvalidVaultAta.into());
+        }
+        // SECURITY (H3): Verify vault token account is initialized
+        // Uninitialized vault could brick deposits/withdrawals
+        if tok.state != spl_token::state::AccountState::Initialized {
+            return Err(PercolatorError::InvalidVaultAta.into());
+        }
+        Ok(())
+    }
+
+    /// Native-SOL vaults: refuse a payout that would leave the vault token account
+    /// below its rent-exempt reserve. SPL vaults keep only their rent in lamports,
+    /// so this is a no-op for them.
+    fn check_vault_rent_buffer(a_vault: &AccountInfo, amount: u64) -> Result<(), ProgramError> {
+        let data = a_vault.try_borrow_data()?;
+        let base = collateral::token_account_base(a_vault.owner, &data)
+            .ok_or(PercolatorError::InvalidVaultAta)?;
+        let tok = spl_token::state::Account::unpack(base)?;
+        if let solana_program::program_option::COption::Some(reserve) = tok.is_native {
+            if !crate::verify::vault_rent_buffer_ok(a_vault.lamports(), reserve, amount) {
+                return Err(PercolatorError::VaultRentExemptBreach.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify a user's token account: owner, mint, and initialized state.
+    /// Skip in tests to allow mock accounts.
+    #[allow(unused_variables)]
+    fn verify_token_account(
+        a_token_account: &AccountInfo,
+        expected_owner: &Pubkey,
+        expected_mint: &Pubkey,
+    ) -> Result<(), ProgramError> {
+        #[cfg(not(feature = "test"))]
+        {
+            let data = a_token_account.try_borrow_data()?;
+            let base = collateral::token_account_base(a_token_account.owner, &data)
+                .ok_or(PercolatorError::InvalidTokenAccount)?;
+            let tok = spl_token::state::Account::unpack(base)?;
+            if tok.mint != *expected_mint {
+                return Err(PercolatorError::InvalidMint.into());
+            }
+            if tok.owner != *expected_owner {
+                return Err(PercolatorError::InvalidTokenAccount.into());
+            }
+            if tok.state != spl_token::state::AccountState::Initialized {
+                return Err(PercolatorError::InvalidTokenAccount.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Paused markets take no new risk (`state::FEATURE_PAUSED`).
+    fn require_not_paused(config: &MarketConfig) -> ProgramResult {
+        if state::feature_enabled(config, state::FEATURE_PAUSED) {
+            return Err(PercolatorError::MarketPaused.into());
+        }
+        Ok(())
+    }
+
+    /// Oracle price and funding rate for a crank at `slot`; persists the config
+    /// updates the price read makes.
+    fn crank_price_and_rate(
+        data: &mut [u8],
+        a_oracle: &AccountInfo,
+        extra_oracles: &[AccountInfo],
+        slot: u64,
+        unix_ts: i64,
+    ) -> Result<(u64, i64), ProgramError> {
+        let mut config = state::read_config(data);
+
+        // Oracle-kind agnostic: Hyperp moves the index toward mark with rate limiting,
+        // external oracles go through authority -> median of Pyth/Chainlink feeds +
+        // circuit breaker. Keepers pass the same four accounts either way, plus one
+        // trailing account per configured extra index feed.
+        let is_hyperp = oracle::is_hyperp_mode(&config);
+        let engine_last_slot = {
+            let engine = zc::engine_ref(data)?;
+            engine.current_slot
+        };
+
+        let price = oracle::get_engine_oracle_price_e6(
+            engine_last_slot,
+            slot,
+            unix_ts,
+            &mut config,
+            a_oracle,
+            extra_oracles,
+        )?;
+
+        // Hyperp mode: compute and store funding rate BEFORE engine borrow
+        // This avoids borrow conflicts with config read/write
+        let hyperp_funding_rate = if is_hyperp {
+            // Read previous funding rate (piecewise-constant: use stored rate, then update)
+            // authority_timestamp is reinterpreted as i64 funding rate in Hyperp mode
+            // Legacy states may still contain unix timestamps in this slot; clamp to policy.
+            let prev_rate = config.authority_timestamp.clamp(
+                -config.funding_max_bps_per_slot,
+                config.funding_max_bps_per_slot,
+            );
+
+            // Compute new rate from premium
+            let mark_e6 = config.authority_price_e6;
+            let index_e6 = config.last_effective_price_e6;
+            let new_rate = oracle::compute_premium_funding_bps_per_slot(
+                mark_e6,
+                index_e6,
+                config.funding_horizon_slots,
+                config.funding_k_bps,
+                config.funding_max_premium_bps,
+                config.funding_max_bps_per_slot,
+            );
+
+            // Store new rate in config for next crank
+            config.authority_timestamp = new_rate;
+
+            Some(prev_rate) // Use PREVIOUS rate for this crank (piecewise-constant model)
+        } else {
+            None
+        };
+
+        // Funding TWAP: sample every crank so the window is ready when the flag
+        // is toggled on; only the funding price below depends on the flag.
+        let (twap_sum, twap_dt) = crate::verify::twap_accumulate(
+            config.funding_twap_price_sum,
+            config.funding_twap_dt_total,
+            config.funding_twap_last_slot,
+            price,
+            slot,
+            config.funding_horizon_slots,
+        );
+        config.funding_twap_price_sum = twap_sum;
+        config.funding_twap_dt_total = twap_dt;
+        config.funding_twap_last_slot = slot;
+        state::write_config(data, &config);
+
+        // Compute funding rate:
+        // - Hyperp mode: use pre-computed rate (avoids borrow conflict)
+        // - Normal mode: inventory-based funding from LP net position
+        let effective_funding_rate = if let Some(rate) = hyperp_funding_rate {
+            rate
+        } else {
+            // Normal mode: inventory-based funding from LP net position
+            // Engine internally gates same-slot compounding via dt = now_slot - last_funding_slot,
+            // so passing the same rate multiple times in the same slot is harmless (dt=0 => no change).
+            let net_lp_pos = crate::compute_net_lp_pos(zc::engine_ref(data)?);
+            let funding_price = if state::feature_enabled(&config, state::FEATURE_FUNDING_TWAP) {
+                crate::verify::twap_price(twap_sum, twap_dt).unwrap_or(price)
+            } else {
+                price
+            };
+            crate::compute_inventory_funding_bps_per_slot(
+                net_lp_pos,
+                funding_price,
+                config.funding_horizon_slots,
+                config.funding_k_bps,
+                config.funding_inv_scale_notional_e6,
+                config.funding_max_premium_bps,
+                config.funding_max_bps_per_slot,
+            )
+        };
+        Ok((price, effective_funding_rate))
+    }
+
+    /// Vest warmed PnL for used accounts in `start..end`. Walks the used bitmap a
+    /// word at a time so empty slots cost nothing, and skips accounts with zero
+    /// PnL (nothing to vest or settle); an account that gains PnL before the
+    /// next crank is picked up then.
+    fn settle_warmup_window(engine: &mut RiskEngine, start: usize, end: usize) -> ProgramResult {
+        let mut idx = start;
+        while idx < end {
+            let word = engine.used[idx / 64] >> (idx % 64);
+            if word == 0 {
+                idx = (idx / 64 + 1) * 64;
+                continue;
+            }
+            idx += word.trailing_zeros() as usize;
+            if idx >= end {
+                break;
+            }
+            if engine.accounts[idx].pnl.get() != 0 {
+                engine
+                    .settle_warmup_to_capital(idx as u16)
+                    .map_err(map_risk_error)?;
+            }
+            idx += 1;
+        }
+        Ok(())
+    }
+
+    /// One normal-mode keeper crank at `slot`: oracle price, funding, engine
+    /// crank, warmup vesting over the swept window, dust sweep and threshold
+    /// update. Callers authorize first (`authorize_crank`). Emits the Cranked
+    /// event and returns the outcome for `set_return_data`.
+    fn crank_at_slot(
+        data: &mut [u8],
+        a_oracle: &AccountInfo,
+        extra_oracles: &[AccountInfo],
+        effective_caller_idx: u16,
+        slot: u64,
+        unix_ts: i64,
+        allow_panic: u8,
+    ) -> Result<crate::return_data::CrankOutcome, ProgramError> {
+        // Read last threshold update slot BEFORE mutable engine borrow
+        let last_thr_slot = state::read_last_thr_update_slot(data)?;
+
+        // Read dust before borrowing engine (for dust sweep later)
+        let dust_before = state::read_dust_base(data)?;
+
+        let (price, effective_funding_rate) =
+            crank_price_and_rate(data, a_oracle, extra_oracles, slot, unix_ts)?;
+        let mut config = state::read_config(data);
+        let unit_scale = config.unit_scale;
+        let is_hyperp = oracle::is_hyperp_mode(&config);
+
+        let engine = zc::engine_mut(data)?;
+        #[cfg(feature = "cu-audit")]
+        {
+            msg!("CU_CHECKPOINT: keeper_crank_start");
+            sol_log_compute_units();
+        }
+        let cursor_before = engine.crank_cursor;
+        let liqs_before = engine.lifetime_liquidations;
+        let ins_before = engine.insurance_fund.balance.get();
+        let _outcome = engine
+            .keeper_crank(
+                effective_caller_idx,
+                slot,
+                price,
+                effective_funding_rate,
+                allow_panic != 0,
+            )
+            .map_err(map_risk_error)?;
+        #[cfg(feature = "cu-audit")]
+        {
+            msg!("CU_CHECKPOINT: keeper_crank_end");
+            sol_log_compute_units();
+        }
+        // Cursor wrapped past the end (or a one-call cycle from 0 back to 0): full
+        // sweep completed. A cursor left where it was mid-table swept nothing new.
+        let crank_cursor = engine.crank_cursor;
+        let sweep_completed =
+            crank_cursor < cursor_before || (cursor_before == 0 && crank_cursor == 0);
+
+        // Vest warmed PnL for every used account in the window this call swept,
+        // so idle accounts convert without user activity. Settling is idempotent
+        // (only newly vested PnL moves), so overlapping partial sweeps are harmless.
+        let window_end = if sweep_completed {
+            MAX_ACCOUNTS as u16
+        } else {
+            crank_cursor
+        };
+        let wrapped = if sweep_completed { crank_cursor } else { 0 };
+        settle_warmup_window(engine, cursor_before as usize, window_end as usize)?;
+        settle_warmup_window(engine, 0, wrapped as usize)?;
+
+        // Dust sweep: if accumulated dust >= unit_scale, sweep to insurance fund
+        // Done before copying stats so insurance balance reflects the sweep
+        let remaining_dust = if unit_scale > 0 {
+            let scale = unit_scale as u64;
+            if dust_before >= scale {
+                let units_to_sweep = dust_before / scale;
+                engine
+                    .top_up_insurance_fund(units_to_sweep as u128)
+                    .map_err(map_risk_error)?;
+                Some(dust_before % scale)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        // Keeper reward, only for work this call did and only to a self-cranking
+        // caller's account. A sweep pays once per slot so repeated same-slot
+        // cranks of a small table cannot drain insurance.
+        if effective_caller_idx != crate::constants::CRANK_NO_CALLER {
+            let insurance = engine.insurance_fund.balance.get();
+            let reward = crate::verify::crank_reward(
+                engine.lifetime_liquidations - liqs_before,
+                sweep_completed && slot > config.last_full_sweep_slot,
+                config.crank_reward_per_liquidation,
+                config.crank_reward_per_sweep,
+                insurance,
+                config.insurance_floor,
+            );
+            if reward > 0 {
+                let idx = effective_caller_idx as usize;
+                engine.insurance_fund.balance = percolator::U128::new(insurance - reward);
+                let capital = engine.accounts[idx].capital.get();
+                engine.set_capital(idx, capital.saturating_add(reward));
+            }
+        }
+
+        // Copy stats before threshold update (avoid borrow conflict)
+        let liqs = engine.lifetime_liquidations;
+        let force = engine.lifetime_force_realize_closes;
+        let ins_low = engine.insurance_fund.balance.get() as u64;
+        let insurance_delta = engine.insurance_fund.balance.get() as i128 - ins_before as i128;
+
+        // --- Threshold auto-update (rate-limited + EWMA smoothed + step-clamped)
+        if slot >= last_thr_slot.saturating_add(config.thresh_update_interval_slots) {
+            let risk_units = crate::compute_system_risk_units(engine);
+            // Convert risk_units (contracts) to notional using price
+            let risk_notional = risk_units.saturating_mul(price as u128) / 1_000_000;
+            // raw target: floor + risk_notional * thresh_risk_bps / 10000
+            let raw_target = config.thresh_floor.saturating_add(
+                risk_notional.saturating_mul(config.thresh_risk_bps as u128) / 10_000,
+            );
+            let clamped_target = raw_target.clamp(config.thresh_min, config.thresh_max);
+            let current = engine.risk_reduction_threshold();
+            // EWMA: new = alpha * target + (1 - alpha) * current
+            let alpha = config.thresh_alpha_bps as u128;
+            let smoothed = (alpha * clamped_target + (10_000 - alpha) * current) / 10_000;
+            // Step clamp: max step = thresh_step_bps / 10000 of current (but at least thresh_min_step)
+            // Bug #6 fix: When current == 0, allow stepping to clamped_target directly
+            // Otherwise threshold would only increase by thresh_min_step (=1) per update
+            let max_step = if current == 0 {
+                clamped_target // Allow full jump when starting from zero
+            } else {
+                (current * config.thresh_step_bps as u128 / 10_000).max(config.thresh_min_step)
+            };
+            let final_thresh = if smoothed > current {
+                current.saturating_add(max_step.min(smoothed - current))
+            } else {
+                current.saturating_sub(max_step.min(current - smoothed))
+            };
+            engine.set_risk_reduction_threshold(
+                final_thresh.clamp(config.thresh_min, config.thresh_max),
+            );
+            drop(engine);
+            state::write_last_thr_update_slot(data, slot);
+        }
+
+        // Write remaining dust if sweep occurred
+        if let Some(dust) = remaining_dust {
+            state::write_dust_base(data, dust);
+        }
+
+        if sweep_completed {
+            config.last_full_sweep_slot = slot;
+            state::write_config(data, &config);
+        }
+
+        let outcome = crate::return_data::CrankOutcome {
+            slot,
+            price_e6: price,
+            funding_rate_bps_per_slot: effective_funding_rate,
+            crank_cursor,
+            sweep_completed: sweep_completed as u8,
+            oracle_mode: if is_hyperp {
+                crate::return_data::ORACLE_MODE_HYPERP
+            } else {
+                crate::return_data::ORACLE_MODE_EXTERNAL
+            },
+            resolved: 0,
+            liquidations: liqs - liqs_before,
+            insurance_delta,
+        };
+        let event = crate::events::Cranked {
+            slot,
+            funding_rate: effective_funding_rate,
+        };
+        crate::events::emit(&event.to_bytes());
+
+        // Debug: log lifetime counters (sol_log_64: tag, liqs, force, max_accounts, insurance)
+        msg!("CRANK_STATS");
+        sol_log_64(0xC8A4C, liqs, force, MAX_ACCOUNTS as u64, ins_low);
+
+        Ok(outcome)
+    }
+
+    /// KeeperCrankWindow body. The window at 0 runs the global step once per
+    /// sweep (price, funding rate, funding accrual); every window then settles
+    /// funding, vests warmup and liquidates underwater accounts in
+    /// `[start, start + count)`, advancing `crank_cursor` so successive windows
+    /// cover the whole account set.
+    fn crank_window(
+        data: &mut [u8],
+        a_oracle: &AccountInfo,
+        extra_oracles: &[AccountInfo],
+        start: u16,
+        count: u16,
+        slot: u64,
+        unix_ts: i64,
+    ) -> Result<crate::return_data::CrankOutcome, ProgramError> {
+        // Windows continue the sweep where the last one stopped; 0 restarts it
+        if start != 0 && start != zc::engine_ref(data)?.crank_cursor {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let end = core::cmp::min(start.saturating_add(count), MAX_ACCOUNTS as u16);
+
+        let (price, funding_rate) = if start == 0 {
+            crank_price_and_rate(data, a_oracle, extra_oracles, slot, unix_ts)?
+        } else {
+            let mut config = state::read_config(data);
+            let engine_last_slot = zc::engine_ref(data)?.current_slot;
+            let price = oracle::get_engine_oracle_price_e6(
+                engine_last_slot,
+                slot,
+                unix_ts,
+                &mut config,
+                a_oracle,
+                extra_oracles,
+            )?;
+            state::write_config(data, &config);
+            (price, 0)
+        };
+        let mut config = state::read_config(data);
+
+        let engine = zc::engine_mut(data)?;
+        if start == 0 {
+            engine
+                .accrue_funding(slot, price, funding_rate)
+                .map_err(map_risk_error)?;
+            engine.last_crank_slot = slot;
+        }
+        engine.current_slot = core::cmp::max(engine.current_slot, slot);
+        let liqs_before = engine.lifetime_liquidations;
+        let ins_before = engine.insurance_fund.balance.get();
+
+        for idx in start..end {
+            if !engine.is_used(idx as usize) {
+                continue;
+            }
+            settle_funding(engine, idx);
+            engine
+                .settle_warmup_to_capital(idx)
+                .map_err(map_risk_error)?;
+            if engine.accounts[idx as usize].position_size.get() != 0 {
+                engine
+                    .liquidate_at_oracle(idx, slot, price)
+                    .map_err(map_risk_error)?;
+            }
+        }
+
+        let sweep_completed = end >= MAX_ACCOUNTS as u16;
+        engine.crank_cursor = if sweep_completed { 0 } else { end };
+        let crank_cursor = engine.crank_cursor;
+        let liquidations = engine.lifetime_liquidations - liqs_before;
+        let insurance_delta = engine.insurance_fund.balance.get() as i128 - ins_before as i128;
+        if sweep_completed {
+            config.last_full_sweep_slot = slot;
+            state::write_config(data, &config);
+        }
+
+        if start == 0 {
+            let event = crate::events::Cranked { slot, funding_rate };
+            crate::events::emit(&event.to_bytes());
+        }
+        Ok(crate::return_data::CrankOutcome {
+            slot,
+            price_e6: price,
+            funding_rate_bps_per_slot: funding_rate,
+            crank_cursor,
+            sweep_completed: sweep_completed as u8,
+            oracle_mode: if oracle::is_hyperp_mode(&config) {
+                crate::return_data::ORACLE_MODE_HYPERP
+            } else {
+                crate::return_data::ORACLE_MODE_EXTERNAL
+            },
+            resolved: 0,
+            liquidations,
+            insurance_delta,
+        })
+    }
+
+    /// KeeperCrank authorization. `allow_panic` needs the admin signer; a
+    /// self-crank needs the signer to own `caller_idx`. Returns the index to pass
+    /// to the engine (`CRANK_NO_CALLER` when permissionless: no caller settle).
+    fn authorize_crank(
+        data: &[u8],
+        a_caller: &AccountInfo,
+        caller_idx: u16,
+        allow_panic: u8,
+    ) -> Result<u16, ProgramError> {
+        use crate::constants::CRANK_NO_CALLER;
+
+        // SECURITY (C4): allow_panic triggers global settlement - admin only
+        // This prevents griefing attacks where anyone triggers panic at worst moment
+        if allow_panic != 0 {
+            accounts::expect_signer(a_caller)?;
+            let header = state::read_header(data);
+            if !crate::verify::admin_ok(header.admin, a_caller.key.to_bytes()) {
+                return Err(PercolatorError::EngineUnauthorized.into());
+            }
+        }
+
+        // - Permissionless mode (caller_idx == u16::MAX): anyone can crank
+        // - Self-crank mode: caller_idx must be a valid, existing account owned by signer
+        if caller_idx == CRANK_NO_CALLER {
+            return Ok(CRANK_NO_CALLER);
+        }
+        let engine = zc::engine_ref(data)?;
+        check_idx(engine, caller_idx)?;
+        let stored_owner = engine.accounts[caller_idx as usize].owner;
+        if !crate::verify::owner_ok(stored_owner, a_caller.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+        Ok(caller_idx)
+    }
+
+    /// A halted market (`state::MARKET_MODE_HALTED`) only closes accounts and cranks.
+    fn require_not_halted(data: &[u8]) -> ProgramResult {
+        if state::read_market_mode(data) == state::MARKET_MODE_HALTED {
+            return Err(PercolatorError::MarketHalted.into());
+        }
+        Ok(())
+    }
+
+    /// Reduce-only mode (`state::MARKET_MODE_REDUCE_ONLY`) rejects fills that
+    /// open or grow the user's position.
+    fn require_mode_allows_fill(mode: u8, old_pos: i128, size: i128) -> ProgramResult {
+        if mode == state::MARKET_MODE_REDUCE_ONLY && !crate::verify::is_reducing_fill(old_pos, size)
+        {
+            return Err(PercolatorError::EngineRiskReductionOnlyMode.into());
+        }
+        Ok(())
+    }
+
+    /// TradeNoCpi-style fills are off while `state::FEATURE_CPI_ONLY` is set.
+    fn require_nocpi_trading(config: &MarketConfig) -> ProgramResult {
+        if state::feature_enabled(config, state::FEATURE_CPI_ONLY) {
+            return Err(PercolatorError::NoCpiTradingDisabled.into());
+        }
+        Ok(())
+    }
+
+    /// Optional trailing collateral mint account at `accounts[at]`. Token-2022
+    /// markets must pass it (transfers go through `transfer_checked`); classic
+    /// SPL Token markets may omit it and keep the plain `transfer`.
+    fn collateral_mint_account<'a, 'b>(
+        accounts: &'b [AccountInfo<'a>],
+        at: usize,
+        config: &MarketConfig,
+    ) -> Result<Option<&'b AccountInfo<'a>>, ProgramError> {
+        let Some(a_mint) = accounts.get(at) else {
+            return Ok(None);
+        };
+        if a_mint.key.to_bytes() != config.collateral_mint {
+            return Err(PercolatorError::InvalidMint.into());
+        }
+        Ok(Some(a_mint))
+    }
+
+    /// Verify the token program account is valid.
+    /// Skip in tests to allow mock accounts.
+    #[allow(unused_variables)]
+    fn verify_token_program(a_token: &AccountInfo) -> Result<(), ProgramError> {
+        #[cfg(not(feature = "test"))]
+        {
+            if !collateral::is_token_program(a_token.key) {
+                return Err(PercolatorError::InvalidTokenProgram.into());
+            }
+            if !a_token.executable {
+                return Err(PercolatorError::InvalidTokenProgram.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Which deposit instruction `deposit_collateral` is running.
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum DepositKind {
+        /// DepositCollateral: the signer funds its own account.
+        Own,
+        /// DepositCollateralWrapped: as `Own`, wrapping native SOL first.
+        OwnWrapped,
+        /// DepositTo: the signer funds any account, owner unchecked.
+        Sponsor,
+    }
+
+    /// Shared deposit body; accounts `[signer, slab, signer_ata, vault, token, clock]`.
+    /// `OwnWrapped` adds the system program at `accounts[6]` and first wraps
+    /// `amount` lamports into `signer_ata` (native-mint markets only).
+    fn deposit_collateral(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+        amount: u64,
+        kind: DepositKind,
+    ) -> ProgramResult {
+        let wrap = kind == DepositKind::OwnWrapped;
+        let mint_at = if wrap { 7 } else { 6 };
+        accounts::expect_len(accounts, mint_at)?;
+        let a_user = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_user_ata = &accounts[2];
+        let a_vault = &accounts[3];
+        let a_token = &accounts[4];
+        let a_clock = &accounts[5];
+
+        accounts::expect_signer(a_user)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        // A zero deposit is a no-op that would still pay for a token CPI
+        if amount == 0 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        // Block deposits when market is resolved
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        require_not_halted(&data)?;
+        let config = state::read_config(&data);
+        require_not_paused(&config)?;
+        let mint = Pubkey::new_from_array(config.collateral_mint);
+
+        let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        verify_vault(
+            a_vault,
+            &auth,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        verify_token_account(a_user_ata, a_user.key, &mint)?;
+
+        if wrap {
+            if mint != spl_token::native_mint::id() {
+                return Err(PercolatorError::InvalidMint.into());
+            }
+            let a_system = &accounts[6];
+            if *a_system.key != solana_program::system_program::id() {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            collateral::wrap_native(a_token, a_system, a_user, a_user_ata, amount)?;
+        }
+
+        let clock = Clock::from_account_info(a_clock)?;
+
+        // Transfer base tokens to vault; credit what arrived
+        let a_mint = collateral_mint_account(accounts, mint_at, &config)?;
+        let received = collateral::deposit(a_token, a_user_ata, a_mint, a_vault, a_user, amount)?;
+
+        // Convert base tokens to units for engine
+        let (units, dust) = crate::units::base_to_units(received, config.unit_scale);
+
+        // Accumulate dust
+        let old_dust = state::read_dust_base(&data)?;
+        state::write_dust_base(&mut data, old_dust.saturating_add(dust));
+
+        let engine = zc::engine_mut(&mut data)?;
+
+        check_idx(engine, user_idx)?;
+
+        // Owner authorization via verify helper (Kani-provable); a sponsor only
+        // adds funds, so any account may receive them
+        let owner = engine.accounts[user_idx as usize].owner;
+        if kind != DepositKind::Sponsor && !crate::verify::owner_ok(owner, a_user.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+
+        engine
+            .deposit(user_idx, units as u128, clock.slot)
+            .map_err(map_risk_error)?;
+        Ok(())
+    }
+
+    /// TradeNoCpi fill of `size` for `user_idx` against `lp_idx`; accounts
+    /// `[user, lp, slab, clock, oracle]`. Shared by TradeNoCpi and ReducePosition;
+    /// `limit_price_e6` of 0 disables the slippage check and `client_req_id` of 0
+    /// the idempotency check.
+    fn trade_no_cpi(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        lp_idx: u16,
+        user_idx: u16,
+        size: i128,
+        limit_price_e6: u64,
+        client_req_id: u64,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 5)?;
+        let a_user = &accounts[0];
+        let a_lp = &accounts[1];
+        let a_slab = &accounts[2];
+
+        // Signer policy via verify helper (Kani-provable): both counterparties sign
+        if !crate::verify::trade_signers_ok(false, a_user.is_signer, a_lp.is_signer) {
+            return Err(PercolatorError::ExpectedSigner.into());
+        }
+        accounts::expect_writable(a_slab)?;
+
+        // A zero-size fill is a no-op that would still burn CU
+        if size == 0 {
+            return Err(PercolatorError::ZeroSizeTrade.into());
+        }
+        if !crate::verify::trade_size_in_range(size) {
+            return Err(PercolatorError::EngineOverflow.into());
+        }
+        if lp_idx == user_idx {
+            return Err(PercolatorError::SelfTrade.into());
+        }
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        // Block trading when market is resolved
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        require_not_halted(&data)?;
+
+        let mut config = state::read_config(&data);
+        let emergency_exit = state::is_emergency_exit(&data);
+        let market_mode = state::read_market_mode(&data);
+
+        let clock = Clock::from_account_info(&accounts[3])?;
+        let a_oracle = &accounts[4];
+
+        // Hyperp mode: reject TradeNoCpi to prevent mark price manipulation
+        // All trades must go through TradeCpi with a pinned matcher
+        if oracle::is_hyperp_mode(&config) {
+            return Err(PercolatorError::HyperpTradeNoCpiDisabled.into());
+        }
+        require_not_paused(&config)?;
+        require_nocpi_trading(&config)?;
+
+        // Read oracle price (median of configured index feeds) with circuit-breaker clamping
+        let price = oracle::read_index_price_clamped(
+            &mut config,
+            a_oracle,
+            &accounts[5..],
+            clock.unix_timestamp,
+        )?;
+        // NoCpi fills at the oracle price, so that is the execution price to bound
+        if !crate::verify::limit_price_ok(size, price, limit_price_e6) {
+            return Err(PercolatorError::SlippageExceeded.into());
+        }
+        state::write_config(&mut data, &config);
+
+        let engine = zc::engine_mut(&mut data)?;
+
+        check_idx(engine, lp_idx)?;
+        check_idx(engine, user_idx)?;
+
+        let u_owner = engine.accounts[user_idx as usize].owner;
+
+        // Owner authorization via verify helper (Kani-provable)
+        if !crate::verify::owner_ok(u_owner, a_user.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+        let l_owner = engine.accounts[lp_idx as usize].owner;
+        if !crate::verify::owner_ok(l_owner, a_lp.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+        // A resubmitted trade must not fill twice
+        check_client_req_id(engine, user_idx, client_req_id)?;
+
+        let env = NoCpiFillEnv {
+            config: &config,
+            slot: clock.slot,
+            price,
+            emergency_exit,
+            market_mode,
+        };
+        let fill = fill_no_cpi(engine, &env, lp_idx, user_idx, size, true)?;
+        if client_req_id != 0 {
+            write_client_req_id(engine, user_idx, client_req_id);
+        }
+        set_return_data(&fill.to_bytes());
+        Ok(())
+    }
+
+    /// Market state shared by every fill of one TradeNoCpi-style instruction.
+    struct NoCpiFillEnv<'c> {
+        config: &'c MarketConfig,
+        slot: u64,
+        price: u64,
+        emergency_exit: bool,
+        market_mode: u8,
+    }
+
+    /// Execute one authorized NoOpMatcher fill with all per-fill wrapper checks.
+    /// `user_margin == false` defers the user's initial-margin check to the
+    /// caller (MultiTrade checks the combined result once). Returns the fill.
+    fn fill_no_cpi(
+        engine: &mut RiskEngine,
+        env: &NoCpiFillEnv,
+        lp_idx: u16,
+        user_idx: u16,
+        size: i128,
+        user_margin: bool,
+    ) -> Result<crate::return_data::TradeExecution, ProgramError> {
+        let config = env.config;
+        let price = env.price;
+        // Single-fill notional cap: large orders must be split
+        if !crate::verify::trade_notional_ok(size, price, config.max_trade_notional_e6) {
+            return Err(PercolatorError::TradeTooLarge.into());
+        }
+
+        // Sweep metering: keepers lagging => only reducing trades
+        let old_user_pos = engine.accounts[user_idx as usize].position_size.get();
+        if !crate::verify::open_interest_ok(
+            engine.total_open_interest.get(),
+            old_user_pos,
+            engine.accounts[lp_idx as usize].position_size.get(),
+            size,
+            config.max_open_interest_abs,
+        ) {
+            return Err(PercolatorError::OpenInterestCap.into());
+        }
+        if crate::verify::sweep_backlog_exceeded(
+            env.slot,
+            config.last_full_sweep_slot,
+            config.max_sweep_lag_slots,
+        ) && !crate::verify::is_reducing_fill(old_user_pos, size)
+        {
+            return Err(PercolatorError::KeeperSweepStale.into());
+        }
+        require_mode_allows_fill(env.market_mode, old_user_pos, size)?;
+
+        // Gate: if insurance_fund <= threshold, only allow risk-reducing trades
+        // LP delta is -size (LP takes opposite side of user's trade)
+        // O(1) check after single O(n) scan
+        // Gate activation via verify helper (Kani-provable)
+        let bal = engine.insurance_fund.balance.get();
+        let thr = engine.risk_reduction_threshold();
+        if crate::verify::gate_active(thr, bal) {
+            #[cfg(feature = "cu-audit")]
+            {
+                msg!("CU_CHECKPOINT: trade_nocpi_compute_start");
+                sol_log_compute_units();
+            }
+            let risk_state = crate::LpRiskState::compute(engine);
+            #[cfg(feature = "cu-audit")]
+            {
+                msg!("CU_CHECKPOINT: trade_nocpi_compute_end");
+                sol_log_compute_units();
+            }
+            let old_lp_pos = engine.accounts[lp_idx as usize].position_size.get();
+            if risk_state.would_increase_risk(old_lp_pos, -size) {
+                return Err(PercolatorError::EngineRiskReductionOnlyMode.into());
+            }
+        }
+
+        // Funding race: settle both sides to the current index before the fill
+        settle_funding(engine, user_idx);
+        settle_funding(engine, lp_idx);
+
+        // Finding L: risk-increasing fills need initial (not maintenance) margin
+        if user_margin {
+            check_initial_margin(engine, user_idx, size, price)?;
+            check_leverage_cap(engine, config, user_idx, size, price)?;
+        }
+        check_initial_margin(engine, lp_idx, -size, price)?;
+        check_leverage_cap(engine, config, lp_idx, -size, price)?;
+
+        #[cfg(feature = "cu-audit")]
+        {
+            msg!("CU_CHECKPOINT: trade_nocpi_execute_start");
+            sol_log_compute_units();
+        }
+        // Emergency exit: the reducing side of a fill is fee-free, the other
+        // side follows normal rules
+        let ins_before = engine.insurance_fund.balance.get();
+        let trade = |engine: &mut RiskEngine| {
+            engine.execute_trade(&NoOpMatcher, lp_idx, user_idx, env.slot, price, size)
+        };
+        if env.emergency_exit {
+            with_fill_fees_waived(engine, user_idx, lp_idx, size, env.slot, trade)
+        } else {
+            trade(engine)
+        }
+        .map_err(map_risk_error)?;
+        #[cfg(feature = "cu-audit")]
+        {
+            msg!("CU_CHECKPOINT: trade_nocpi_execute_end");
+            sol_log_compute_units();
+        }
+        let event = crate::events::TradeExecuted {
+            user_idx,
+            lp_idx,
+            size,
+            exec_price: price,
+            fee: engine
+                .insurance_fund
+                .balance
+                .get()
+                .saturating_sub(ins_before),
+        };
+        crate::events::emit(&event.to_bytes());
+        Ok(crate::return_data::TradeExecution {
+            exec_price_e6: price,
+            exec_size: size,
+            fee: event.fee,
+            resulting_position: engine.accounts[user_idx as usize].position_size.get(),
+        })
+    }
+
+    /// Decode one instruction and dispatch it to its `handle_*` function.
+    pub fn process_instruction<'a, 'b>(
+        program_id: &Pubkey,
+        accounts: &'b [AccountInfo<'a>],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let instruction = Instruction::decode(instruction_data)?;
+
+        match instruction {
+            Instruction::InitMarket {
+                admin,
+                collateral_mint,
+                index_feed_id,
+                max_staleness_secs,
+                conf_filter_bps,
+                invert,
+                unit_scale,
+                initial_mark_price_e6,
+                risk_params,
+            } => handle_init_market(
+                program_id,
+                accounts,
+                admin,
+                collateral_mint,
+                index_feed_id,
+                max_staleness_secs,
+                conf_filter_bps,
+                invert,
+                unit_scale,
+                initial_mark_price_e6,
+                risk_params,
+            ),
+            Instruction::InitUser { fee_payment } => {
+                handle_init_user(program_id, accounts, fee_payment)
+            }
+            Instruction::InitLP {
+                matcher_program,
+                matcher_context,
+                fee_payment,
+            } => handle_init_lp(
+                program_id,
+                accounts,
+                matcher_program,
+                matcher_context,
+                fee_payment,
+            ),
+            Instruction::DepositCollateral { user_idx, amount } => {
+                handle_deposit(program_id, accounts, user_idx, amount)
+            }
+            Instruction::DepositCollateralWrapped { user_idx, lamports } => {
+                handle_deposit_wrapped(program_id, accounts, user_idx, lamports)
+            }
+            Instruction::DepositTo { target_idx, amount } => {
+                handle_deposit_to(program_id, accounts, target_idx, amount)
+            }
+            Instruction::WithdrawCollateral { user_idx, amount } => {
+                handle_withdraw(program_id, accounts, user_idx, amount)
+            }
+            Instruction::KeeperCrank {
+                caller_idx,
+                allow_panic,
+            } => handle_keeper_crank(program_id, accounts, caller_idx, allow_panic),
+            Instruction::KeeperCrankBatch {
+                caller_idx,
+                up_to_slot,
+                max_iters,
+                allow_panic,
+            } => handle_keeper_crank_batch(
+                program_id,
+                accounts,
+                caller_idx,
+                up_to_slot,
+                max_iters,
+                allow_panic,
+            ),
+            Instruction::KeeperCrankWindow {
+                caller_idx,
+                start,
+                count,
+            } => handle_keeper_crank_window(program_id, accounts, caller_idx, start, count),
+            Instruction::TransferPosition {
+                from_idx,
+                to_idx,
+                size,
+            } => handle_transfer_position(program_id, accounts, from_idx, to_idx, size),
+            Instruction::TradeNoCpi {
+                lp_idx,
+                user_idx,
+                size,
+                limit_price_e6,
+                client_req_id,
+            } => handle_trade_no_cpi(
+                program_id,
+                accounts,
+                lp_idx,
+                user_idx,
+                size,
+                limit_price_e6,
+                client_req_id,
+            ),
+            Instruction::ReducePosition {
+                lp_idx,
+                user_idx,
+                target_abs,
+            } => handle_reduce_position(program_id, accounts, lp_idx, user_idx, target_abs),
+            Instruction::MultiTrade {
+                user_idx,
+                leg_count,
+                legs,
+            } => handle_multi_trade(program_id, accounts, user_idx, leg_count, legs),
+            Instruction::TradeCpi {
+                lp_idx,
+                user_idx,
+                size,
+                limit_price_e6,
+                client_req_id,
+            } => handle_trade_cpi(
+                program_id,
+                accounts,
+                lp_idx,
+                user_idx,
+                size,
+                limit_price_e6,
+                client_req_id,
+            ),
+            Instruction::LiquidateAtOracle { target_idx } => {
+                handle_liquidate_at_oracle(program_id, accounts, target_idx)
+            }
+            Instruction::LiquidatePartial {
+                target_idx,
+                close_bps,
+            } => handle_liquidate_partial(program_id, accounts, target_idx, close_bps),
+            Instruction::CloseAccount { user_idx } => {
+                handle_close_account(program_id, accounts, user_idx)
+            }
+            Instruction::TopUpInsurance { amount, donor } => {
+                handle_top_up_insurance(program_id, accounts, amount, donor)
+            }
+            Instruction::SetRiskThreshold { new_threshold } => {
+                handle_set_risk_threshold(program_id, accounts, new_threshold)
+            }
+            Instruction::UpdateAdmin { new_admin } => {
+                handle_update_admin(program_id, accounts, new_admin)
+            }
+            Instruction::CloseSlab => handle_close_slab(program_id, accounts),
+            Instruction::UpdateConfig {
+                funding_horizon_slots,
+                funding_k_bps,
+                funding_inv_scale_notional_e6,
+                funding_max_premium_bps,
+                funding_max_bps_per_slot,
+                thresh_floor,
+                thresh_risk_bps,
+                thresh_update_interval_slots,
+                thresh_step_bps,
+                thresh_alpha_bps,
+                thresh_min,
+                thresh_max,
+                thresh_min_step,
+            } => handle_update_config(
+                program_id,
+                accounts,
+                funding_horizon_slots,
+                funding_k_bps,
+                funding_inv_scale_notional_e6,
+                funding_max_premium_bps,
+                funding_max_bps_per_slot,
+                thresh_floor,
+                thresh_risk_bps,
+                thresh_update_interval_slots,
+                thresh_step_bps,
+                thresh_alpha_bps,
+                thresh_min,
+                thresh_max,
+                thresh_min_step,
+            ),
+            Instruction::SetMaintenanceFee { new_fee } => {
+                handle_set_maintenance_fee(program_id, accounts, new_fee)
+            }
+            Instruction::SetOracleAuthority { new_authority } => {
+                handle_set_oracle_authority(program_id, accounts, new_authority)
+            }
+            Instruction::PushOraclePrice {
+                price_e6,
+                timestamp,
+            } => handle_push_oracle_price(program_id, accounts, price_e6, timestamp),
+            Instruction::SetOraclePriceCap { max_change_e2bps } => {
+                handle_set_oracle_price_cap(program_id, accounts, max_change_e2bps)
+            }
+            Instruction::ResolveMarket => handle_resolve_market(program_id, accounts),
+            Instruction::WithdrawInsurance { amount } => {
+                handle_withdraw_insurance(program_id, accounts, amount)
+            }
+            Instruction::SweepDust => handle_sweep_dust(program_id, accounts),
+            Instruction::AdminForceCloseAccount { user_idx } => {
+                handle_admin_force_close_account(program_id, accounts, user_idx)
+            }
+            Instruction::SetEmergencyExit { enabled } => {
+                handle_set_emergency_exit(program_id, accounts, enabled)
+            }
+            Instruction::SetMaxTradeNotional {
+                max_trade_notional_e6,
+            } => handle_set_max_trade_notional(program_id, accounts, max_trade_notional_e6),
+            Instruction::SetOracleParams {
+                conf_filter_bps,
+                max_staleness_secs,
+            } => {
+                handle_set_oracle_params(program_id, accounts, conf_filter_bps, max_staleness_secs)
+            }
+            Instruction::SetIndexFeeds {
+                secondary_feed_id,
+                tertiary_feed_id,
+            } => handle_set_index_feeds(program_id, accounts, secondary_feed_id, tertiary_feed_id),
+            Instruction::SetMaxSweepLag {
+                max_sweep_lag_slots,
+            } => handle_set_max_sweep_lag(program_id, accounts, max_sweep_lag_slots),
+            Instruction::SetInsuranceFloor { insurance_floor } => {
+                handle_set_insurance_floor(program_id, accounts, insurance_floor)
+            }
+            Instruction::SetMaxMarkIndexDivergence {
+                max_mark_index_divergence_bps,
+            } => handle_set_max_mark_index_divergence(
+                program_id,
+                accounts,
+                max_mark_index_divergence_bps,
+            ),
+            Instruction::SetRiskParams {
+                new_params,
+                allow_force,
+            } => handle_set_risk_params(program_id, accounts, new_params, allow_force),
+            Instruction::SetBackstopParams {
+                penalty_bps,
+                max_notional_per_slot_e6,
+            } => handle_set_backstop_params(
+                program_id,
+                accounts,
+                penalty_bps,
+                max_notional_per_slot_e6,
+            ),
+            Instruction::CloseWithBackstop { user_idx } => {
+                handle_close_with_backstop(program_id, accounts, user_idx)
+            }
+            Instruction::GetFundingIndex => handle_get_funding_index(program_id, accounts),
+            Instruction::SimulateLiquidation { target_idx } => {
+                handle_simulate_liquidation(program_id, accounts, target_idx)
+            }
+            Instruction::GetAccountState { user_idx } => {
+                handle_get_account_state(program_id, accounts, user_idx)
+            }
+            Instruction::GetWarmupStatus { user_idx } => {
+                handle_get_warmup_status(program_id, accounts, user_idx)
+            }
+            Instruction::SetFeatureFlags { set, clear } => {
+                handle_set_feature_flags(program_id, accounts, set, clear)
+            }
+            Instruction::GetFeatureFlags => handle_get_feature_flags(program_id, accounts),
+            Instruction::SetMarketMode { mode } => {
+                handle_set_market_mode(program_id, accounts, mode)
+            }
+            Instruction::GetTopDonors => handle_get_top_donors(program_id, accounts),
+            Instruction::LiquidateEligible { cursor, max_scan } => {
+                handle_liquidate_eligible(program_id, accounts, cursor, max_scan)
+            }
+            Instruction::GetVaultInfo => handle_get_vault_info(program_id, accounts),
+            Instruction::VerifyInvariants => handle_verify_invariants(program_id, accounts),
+            Instruction::SetLpMatcher {
+                lp_idx,
+                matcher_program,
+                matcher_context,
+            } => handle_set_lp_matcher(
+                program_id,
+                accounts,
+                lp_idx,
+                matcher_program,
+                matcher_context,
+            ),
+            Instruction::GetAccountIndex { owner } => {
+                handle_get_account_index(program_id, accounts, owner)
+            }
+            Instruction::SetFundingParams {
+                max_premium_bps,
+                max_bps_per_slot,
+                horizon_slots,
+            } => handle_set_funding_params(
+                program_id,
+                accounts,
+                max_premium_bps,
+                max_bps_per_slot,
+                horizon_slots,
+            ),
+            Instruction::SetCrankRewards {
+                per_liquidation,
+                per_sweep,
+            } => handle_set_crank_rewards(program_id, accounts, per_liquidation, per_sweep),
+            Instruction::GetMarketStats => handle_get_market_stats(program_id, accounts),
+            Instruction::CloseLp { lp_idx } => handle_close_lp(program_id, accounts, lp_idx),
+            Instruction::SetOpenInterestCap {
+                max_open_interest_abs,
+            } => handle_set_open_interest_cap(program_id, accounts, max_open_interest_abs),
+            Instruction::LiquidateCpi { target_idx, lp_idx } => {
+                handle_liquidate_cpi(program_id, accounts, target_idx, lp_idx)
+            }
+            Instruction::MigrateSlab { from_version } => {
+                handle_migrate_slab(program_id, accounts, from_version)
+            }
+            Instruction::SetLiquidationParams {
+                liquidation_fee_bps,
+                liquidation_fee_cap,
+                liquidation_buffer_bps,
+                min_liquidation_abs,
+            } => handle_set_liquidation_params(
+                program_id,
+                accounts,
+                liquidation_fee_bps,
+                liquidation_fee_cap,
+                liquidation_buffer_bps,
+                min_liquidation_abs,
+            ),
+            Instruction::ValidateInitParams {
+                index_feed_id,
+                max_staleness_secs: _,
+                conf_filter_bps,
+                invert,
+                unit_scale,
+                initial_mark_price_e6,
+                risk_params,
+            } => handle_validate_init_params(
+                index_feed_id,
+                conf_filter_bps,
+                invert,
+                unit_scale,
+                initial_mark_price_e6,
+                &risk_params,
+            ),
+            Instruction::SetMaxLeverage { max_leverage_x } => {
+                handle_set_max_leverage(program_id, accounts, max_leverage_x)
+            }
+        }
     }
 
-    impl MatchingEngine for CpiMatcher {
-        fn execute_match(
-            &self,
-            _lp_program: &[u8; 32],
-            _lp_context: &[u8; 32],
-            _lp_account_id: u64,
-            _oracle_price: u64,
-            _size: i128,
-        ) -> Result<TradeExecution, RiskError> {
-            Ok(TradeExecution {
-                price: self.exec_price,
-                size: self.exec_size,
-            })
+    /// Handler for [`Instruction::InitMarket`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_init_market(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        admin: Pubkey,
+        collateral_mint: Pubkey,
+        index_feed_id: [u8; 32],
+        max_staleness_secs: u64,
+        conf_filter_bps: u16,
+        invert: u8,
+        unit_scale: u32,
+        initial_mark_price_e6: u64,
+        risk_params: RiskParams,
+    ) -> ProgramResult {
+        // Reduced from 11 to 9: removed pyth_index and pyth_collateral accounts
+        // (feed_id is now passed in instruction data, not as account)
+        accounts::expect_len(accounts, 9)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_mint = &accounts[2];
+        let a_vault = &accounts[3];
+
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
+
+        // Ensure instruction data matches the signer
+        if admin != *a_admin.key {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        // SECURITY (H1): Enforce collateral_mint matches the account
+        // This prevents signers from being confused by mismatched instruction data
+        if collateral_mint != *a_mint.key {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        // SECURITY (H2): Validate mint is a real SPL Token or Token-2022 mint
+        // Check owner is a token program and data is a (possibly extended) Mint
+        #[cfg(not(feature = "test"))]
+        {
+            use spl_token::state::Mint;
+            if !collateral::is_token_program(a_mint.owner) {
+                return Err(ProgramError::IllegalOwner);
+            }
+            // Verify mint is initialized by unpacking
+            let mint_data = a_mint.try_borrow_data()?;
+            let base = collateral::mint_base(a_mint.owner, &mint_data)
+                .ok_or(ProgramError::InvalidAccountData)?;
+            let _ = Mint::unpack(base)?;
+        }
+
+        // Validate unit_scale: reject huge values that make most deposits credit 0 units
+        if !crate::verify::init_market_scale_ok(unit_scale) {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        // Hyperp mode validation: if index_feed_id is all zeros, require initial_mark_price_e6
+        let is_hyperp = index_feed_id == [0u8; 32];
+        if is_hyperp && initial_mark_price_e6 == 0 {
+            // Hyperp mode requires a non-zero initial mark price
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        if !crate::verify::margins_ok(
+            risk_params.maintenance_margin_bps,
+            risk_params.initial_margin_bps,
+        ) {
+            return Err(PercolatorError::InvalidRiskParams.into());
+        }
+
+        // Optional 10th account: index oracle. When supplied, it must return a sane
+        // price now, so a wrong oracle fails at creation instead of on the first trade.
+        // Hyperp markets have no external oracle (initial_mark_price_e6 > 0 above).
+        if !is_hyperp && accounts.len() > 9 {
+            let clock = Clock::from_account_info(&accounts[5])?;
+            let price = oracle::read_engine_price_e6(
+                &accounts[9],
+                &index_feed_id,
+                clock.unix_timestamp,
+                max_staleness_secs,
+                conf_filter_bps,
+                invert,
+                unit_scale,
+            )?;
+            if price == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
+            }
+        }
+
+        // For Hyperp mode with inverted markets, apply inversion to initial price
+        // This ensures the stored mark/index are in "market price" form
+        let initial_mark_price_e6 = if is_hyperp && invert != 0 {
+            crate::verify::invert_price_e6(initial_mark_price_e6, invert)
+                .ok_or(PercolatorError::OracleInvalid)?
+        } else {
+            initial_mark_price_e6
+        };
+
+        #[cfg(debug_assertions)]
+        {
+            if core::mem::size_of::<MarketConfig>() != CONFIG_LEN {
+                return Err(ProgramError::InvalidAccountData);
+            }
+        }
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+
+        let _ = zc::engine_mut(&mut data)?;
+
+        let header = state::read_header(&data);
+        if header.magic == MAGIC {
+            return Err(PercolatorError::AlreadyInitialized.into());
+        }
+
+        let (auth, bump) = accounts::derive_vault_authority(program_id, a_slab.key);
+        // Create-if-empty: a fresh vault keypair (signing) is allocated and
+        // initialized under the vault authority in this instruction. Native
+        // `test` builds cannot CPI, so they keep requiring a pre-created vault.
+        #[cfg(not(feature = "test"))]
+        if a_vault.data_is_empty() {
+            accounts::expect_signer(a_vault)?;
+            let rent = solana_program::rent::Rent::from_account_info(&accounts[6])?;
+            collateral::create_vault(
+                a_admin,
+                a_vault,
+                a_mint,
+                &auth,
+                &accounts[4],
+                &accounts[8],
+                &rent,
+            )?;
+        }
+        verify_vault(a_vault, &auth, a_mint.key, a_vault.key)?;
+
+        for b in data.iter_mut() {
+            *b = 0;
+        }
+
+        // Initialize engine in-place (zero-copy) to avoid stack overflow.
+        // The data is already zeroed above, so init_in_place only sets non-zero fields.
+        let engine = zc::engine_mut(&mut data)?;
+        engine.init_in_place(risk_params);
+
+        // Initialize slot fields to current slot to prevent overflow on first crank
+        // (accrue_funding checks dt < 31_536_000, which fails if last_funding_slot=0)
+        let a_clock = &accounts[5];
+        let clock = Clock::from_account_info(a_clock)?;
+        engine.current_slot = clock.slot;
+        engine.last_funding_slot = clock.slot;
+        engine.last_crank_slot = clock.slot;
+
+        let config = MarketConfig {
+            collateral_mint: a_mint.key.to_bytes(),
+            vault_pubkey: a_vault.key.to_bytes(),
+            index_feed_id,
+            max_staleness_secs,
+            conf_filter_bps,
+            vault_authority_bump: bump,
+            invert,
+            unit_scale,
+            // Funding parameters (defaults)
+            funding_horizon_slots: DEFAULT_FUNDING_HORIZON_SLOTS,
+            funding_k_bps: DEFAULT_FUNDING_K_BPS,
+            funding_inv_scale_notional_e6: DEFAULT_FUNDING_INV_SCALE_NOTIONAL_E6,
+            funding_max_premium_bps: DEFAULT_FUNDING_MAX_PREMIUM_BPS,
+            funding_max_bps_per_slot: DEFAULT_FUNDING_MAX_BPS_PER_SLOT,
+            // Threshold parameters (defaults)
+            thresh_floor: DEFAULT_THRESH_FLOOR,
+            thresh_risk_bps: DEFAULT_THRESH_RISK_BPS,
+            thresh_update_interval_slots: DEFAULT_THRESH_UPDATE_INTERVAL_SLOTS,
+            thresh_step_bps: DEFAULT_THRESH_STEP_BPS,
+            thresh_alpha_bps: DEFAULT_THRESH_ALPHA_BPS,
+            thresh_min: DEFAULT_THRESH_MIN,
+            thresh_max: DEFAULT_THRESH_MAX,
+            thresh_min_step: DEFAULT_THRESH_MIN_STEP,
+            // Oracle authority (disabled by default - use Pyth/Chainlink)
+            // In Hyperp mode: authority_price_e6 = mark, last_effective_price_e6 = index
+            oracle_authority: [0u8; 32],
+            authority_price_e6: if is_hyperp { initial_mark_price_e6 } else { 0 },
+            authority_timestamp: 0, // In Hyperp mode: stores funding rate (bps per slot)
+            // Oracle price circuit breaker
+            // In Hyperp mode: used for rate-limited index smoothing AND mark price clamping
+            // Default: disabled for non-Hyperp, 1% per slot for Hyperp
+            oracle_price_cap_e2bps: if is_hyperp {
+                DEFAULT_HYPERP_PRICE_CAP_E2BPS
+            } else {
+                0
+            },
+            last_effective_price_e6: if is_hyperp { initial_mark_price_e6 } else { 0 },
+            // Trade limits (disabled by default)
+            max_trade_notional_e6: 0,
+            // Sweep metering (disabled by default)
+            max_sweep_lag_slots: 0,
+            last_full_sweep_slot: clock.slot,
+            // Insurance backstop (disabled by default)
+            backstop_penalty_bps: 0,
+            backstop_max_notional_per_slot_e6: 0,
+            backstop_slot: 0,
+            backstop_used_notional_e6: 0,
+            // Hyperp mark guard (disabled by default)
+            max_mark_index_divergence_bps: 0,
+            // Donor leaderboard (empty)
+            donor_keys: [[0u8; 32]; crate::constants::MAX_DONORS],
+            donor_totals: [0; crate::constants::MAX_DONORS],
+            // Feature flags (all off)
+            feature_flags: 0,
+            _feature_flags_pad: 0,
+            // Additional index oracles (primary feed only)
+            extra_index_feed_ids: [[0; 32]; crate::constants::MAX_EXTRA_INDEX_FEEDS],
+            // Funding TWAP (empty window)
+            funding_twap_price_sum: 0,
+            funding_twap_dt_total: 0,
+            funding_twap_last_slot: 0,
+            insurance_floor: 0,
+            // Keeper rewards (disabled by default)
+            crank_reward_per_liquidation: 0,
+            crank_reward_per_sweep: 0,
+            // Open interest cap (disabled by default)
+            max_open_interest_abs: 0,
+            // Leverage cap (disabled by default)
+            max_leverage_x: 0,
+            _max_leverage_pad: 0,
+            _reserved: [0; state::CONFIG_RESERVED_LEN],
+        };
+        state::write_config(&mut data, &config);
+
+        let new_header = SlabHeader {
+            magic: MAGIC,
+            version: VERSION,
+            bump,
+            _padding: [0; 3],
+            admin: a_admin.key.to_bytes(),
+            _reserved: [0; 24],
+        };
+        state::write_header(&mut data, &new_header);
+        // Step 4: Explicitly initialize nonce to 0 for determinism
+        state::write_req_nonce(&mut data, 0);
+        // Initialize threshold update slot to 0
+        state::write_last_thr_update_slot(&mut data, 0);
+        Ok(())
+    }
+
+    /// Handler for [`Instruction::InitUser`].
+    pub fn handle_init_user(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        fee_payment: u64,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 5)?;
+        let a_user = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_user_ata = &accounts[2];
+        let a_vault = &accounts[3];
+        let a_token = &accounts[4];
+
+        accounts::expect_signer(a_user)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        // Block new users when market is resolved
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        require_not_halted(&data)?;
+        let config = state::read_config(&data);
+        require_not_paused(&config)?;
+        let mint = Pubkey::new_from_array(config.collateral_mint);
+
+        let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        verify_vault(
+            a_vault,
+            &auth,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        verify_token_account(a_user_ata, a_user.key, &mint)?;
+
+        // Transfer base tokens to vault; credit what arrived
+        let a_mint = collateral_mint_account(accounts, 5, &config)?;
+        let received =
+            collateral::deposit(a_token, a_user_ata, a_mint, a_vault, a_user, fee_payment)?;
+
+        // Convert base tokens to units for engine
+        let (units, dust) = crate::units::base_to_units(received, config.unit_scale);
+
+        // Accumulate dust
+        let old_dust = state::read_dust_base(&data)?;
+        state::write_dust_base(&mut data, old_dust.saturating_add(dust));
+
+        let engine = zc::engine_mut(&mut data)?;
+        // Bug #4 fix: overpayment becomes capital instead of untracked vault tokens
+        let (fee_units, excess) =
+            crate::verify::split_account_fee(units as u128, engine.params.new_account_fee.get());
+        let idx = engine.add_user(fee_units).map_err(map_risk_error)?;
+        engine
+            .set_owner(idx, a_user.key.to_bytes())
+            .map_err(map_risk_error)?;
+        if excess > 0 {
+            let slot = engine.current_slot;
+            engine.deposit(idx, excess, slot).map_err(map_risk_error)?;
+        }
+        // A reused slot must not inherit the previous owner's last client_req_id
+        write_client_req_id(engine, idx, 0);
+        set_return_data(&crate::return_data::AccountIndex { idx }.to_bytes());
+        Ok(())
+    }
+
+    /// Handler for [`Instruction::InitLP`].
+    pub fn handle_init_lp(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        matcher_program: Pubkey,
+        matcher_context: Pubkey,
+        fee_payment: u64,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 5)?;
+        let a_user = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_user_ata = &accounts[2];
+        let a_vault = &accounts[3];
+        let a_token = &accounts[4];
+
+        accounts::expect_signer(a_user)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        // Block new LPs when market is resolved
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        require_not_halted(&data)?;
+        let config = state::read_config(&data);
+        require_not_paused(&config)?;
+        let mint = Pubkey::new_from_array(config.collateral_mint);
+
+        let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        verify_vault(
+            a_vault,
+            &auth,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        verify_token_account(a_user_ata, a_user.key, &mint)?;
+
+        // Transfer base tokens to vault; credit what arrived
+        let a_mint = collateral_mint_account(accounts, 5, &config)?;
+        let received =
+            collateral::deposit(a_token, a_user_ata, a_mint, a_vault, a_user, fee_payment)?;
+
+        // Convert base tokens to units for engine
+        let (units, dust) = crate::units::base_to_units(received, config.unit_scale);
+
+        // Accumulate dust
+        let old_dust = state::read_dust_base(&data)?;
+        state::write_dust_base(&mut data, old_dust.saturating_add(dust));
+
+        let engine = zc::engine_mut(&mut data)?;
+        // Bug #4 fix: overpayment becomes capital instead of untracked vault tokens
+        let (fee_units, excess) =
+            crate::verify::split_account_fee(units as u128, engine.params.new_account_fee.get());
+        let idx = engine
+            .add_lp(
+                matcher_program.to_bytes(),
+                matcher_context.to_bytes(),
+                fee_units,
+            )
+            .map_err(map_risk_error)?;
+        engine
+            .set_owner(idx, a_user.key.to_bytes())
+            .map_err(map_risk_error)?;
+        if excess > 0 {
+            let slot = engine.current_slot;
+            engine.deposit(idx, excess, slot).map_err(map_risk_error)?;
+        }
+        set_return_data(&crate::return_data::AccountIndex { idx }.to_bytes());
+        Ok(())
+    }
+
+    /// Handler for [`Instruction::DepositCollateral`].
+    pub fn handle_deposit(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+        amount: u64,
+    ) -> ProgramResult {
+        deposit_collateral(program_id, accounts, user_idx, amount, DepositKind::Own)
+    }
+
+    /// Handler for [`Instruction::DepositCollateralWrapped`].
+    pub fn handle_deposit_wrapped(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+        lamports: u64,
+    ) -> ProgramResult {
+        let kind = DepositKind::OwnWrapped;
+        deposit_collateral(program_id, accounts, user_idx, lamports, kind)
+    }
+
+    /// Handler for [`Instruction::DepositTo`].
+    pub fn handle_deposit_to(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        target_idx: u16,
+        amount: u64,
+    ) -> ProgramResult {
+        let kind = DepositKind::Sponsor;
+        deposit_collateral(program_id, accounts, target_idx, amount, kind)
+    }
+
+    /// Handler for [`Instruction::WithdrawCollateral`].
+    pub fn handle_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+        amount: u64,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 8)?;
+        let a_user = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_vault = &accounts[2];
+        let a_user_ata = &accounts[3];
+        let a_vault_pda = &accounts[4];
+        let a_token = &accounts[5];
+        let a_clock = &accounts[6];
+        let a_oracle_idx = &accounts[7];
+
+        accounts::expect_signer(a_user)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        // A zero withdrawal is a no-op that would still pay for a token CPI
+        if amount == 0 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        require_not_halted(&data)?;
+        let mut config = state::read_config(&data);
+        let mint = Pubkey::new_from_array(config.collateral_mint);
+
+        let (derived_pda, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        accounts::expect_key(a_vault_pda, &derived_pda)?;
+
+        verify_vault(
+            a_vault,
+            &derived_pda,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        verify_token_account(a_user_ata, a_user.key, &mint)?;
+        check_vault_rent_buffer(a_vault, amount)?;
+
+        // The optional collateral mint holds accounts[8]; extra index feeds follow it
+        let a_mint = collateral_mint_account(accounts, 8, &config)?;
+
+        let clock = Clock::from_account_info(a_clock)?;
+        // Read oracle price: Hyperp mode uses index directly, otherwise circuit-breaker clamping
+        let is_hyperp = oracle::is_hyperp_mode(&config);
+        let price = if is_hyperp {
+            let idx = config.last_effective_price_e6;
+            if idx == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
+            }
+            idx
+        } else {
+            oracle::read_index_price_clamped(
+                &mut config,
+                a_oracle_idx,
+                accounts.get(9..).unwrap_or(&[]),
+                clock.unix_timestamp,
+            )?
+        };
+        state::write_config(&mut data, &config);
+
+        let engine = zc::engine_mut(&mut data)?;
+
+        check_idx(engine, user_idx)?;
+
+        // Owner authorization via verify helper (Kani-provable)
+        let owner = engine.accounts[user_idx as usize].owner;
+        if !crate::verify::owner_ok(owner, a_user.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+
+        // Reject misaligned withdrawal amounts (cleaner UX than silent floor)
+        if config.unit_scale != 0 && amount % config.unit_scale as u64 != 0 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        // Convert requested base tokens to units
+        let (units_requested, _) = crate::units::base_to_units(amount, config.unit_scale);
+
+        // Vest warmed PnL up to this slot before sizing the withdrawal, so the
+        // vested portion is withdrawable mid-period without waiting for a crank.
+        // Unvested PnL stays in pnl and remains locked.
+        engine.current_slot = engine.current_slot.max(clock.slot);
+        engine
+            .settle_warmup_to_capital(user_idx)
+            .map_err(map_risk_error)?;
+
+        engine
+            .withdraw(user_idx, units_requested as u128, clock.slot, price)
+            .map_err(map_risk_error)?;
+
+        // Convert units back to base tokens for payout (checked to prevent silent overflow)
+        let base_to_pay = crate::units::units_to_base_checked(units_requested, config.unit_scale)
+            .ok_or(PercolatorError::EngineOverflow)?;
+
+        let seed1: &[u8] = b"vault";
+        let seed2: &[u8] = a_slab.key.as_ref();
+        let bump_arr: [u8; 1] = [config.vault_authority_bump];
+        let seed3: &[u8] = &bump_arr;
+        let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
+        let signer_seeds: [&[&[u8]]; 1] = [&seeds];
+
+        collateral::withdraw(
+            a_token,
+            a_vault,
+            a_mint,
+            a_user_ata,
+            a_vault_pda,
+            base_to_pay,
+            &signer_seeds,
+        )?;
+        Ok(())
+    }
+
+    /// Handler for [`Instruction::KeeperCrank`].
+    pub fn handle_keeper_crank(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        caller_idx: u16,
+        allow_panic: u8,
+    ) -> ProgramResult {
+        use crate::constants::CRANK_NO_CALLER;
+
+        accounts::expect_len(accounts, 4)?;
+        let a_caller = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_clock = &accounts[2];
+        let a_oracle = &accounts[3];
+
+        // Permissionless mode: caller_idx == u16::MAX means anyone can crank
+        let permissionless = caller_idx == CRANK_NO_CALLER;
+
+        if !permissionless {
+            // Self-crank mode: require signer + owner authorization
+            accounts::expect_signer(a_caller)?;
         }
-    }
+        accounts::expect_writable(a_slab)?;
 
-    fn slab_guard(
-        program_id: &Pubkey,
-        slab: &AccountInfo,
-        data: &[u8],
-    ) -> Result<(), ProgramError> {
-        // Slab shape validation via verify helper (Kani-provable)
-        // Accept old slabs that are 8 bytes smaller due to Account struct reordering migration.
-        // Old slabs (1111384 bytes) work for up to 4095 accounts; new slabs (1111392) for 4096.
-        const OLD_SLAB_LEN: usize = SLAB_LEN - 8;
-        let shape = crate::verify::SlabShape {
-            owned_by_program: slab.owner == program_id,
-            correct_len: data.len() == SLAB_LEN || data.len() == OLD_SLAB_LEN,
-        };
-        if !crate::verify::slab_shape_ok(shape) {
-            // Return specific error based on which check failed
-            if slab.owner != program_id {
-                return Err(ProgramError::IllegalOwner);
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        // Check if market is resolved - if so, force-close positions instead of normal crank
+        if state::is_resolved(&data) {
+            let config = state::read_config(&data);
+            let settlement_price = config.authority_price_e6;
+            if settlement_price == 0 {
+                return Err(ProgramError::InvalidAccountData);
             }
-            solana_program::log::sol_log_64(SLAB_LEN as u64, data.len() as u64, 0, 0, 0);
-            return Err(PercolatorError::InvalidSlabLen.into());
+
+            let clock = Clock::from_account_info(a_clock)?;
+            let engine = zc::engine_mut(&mut data)?;
+
+            // Force-close positions in a paginated manner using crank_cursor
+            // Process up to 64 accounts per crank call (bounded compute)
+            const BATCH_SIZE: u16 = 64;
+            let start = engine.crank_cursor;
+            let end = core::cmp::min(start + BATCH_SIZE, percolator::MAX_ACCOUNTS as u16);
+
+            for idx in start..end {
+                if engine.is_used(idx as usize) {
+                    let acc = &engine.accounts[idx as usize];
+                    let pos = acc.position_size.get();
+                    if pos != 0 {
+                        // Settle position at settlement price
+                        // PnL = position * (settlement_price - entry_price) / 1e6
+                        let entry = acc.entry_price as i128;
+                        let settle = settlement_price as i128;
+                        let pnl_delta =
+                            pos.saturating_mul(settle.saturating_sub(entry)) / 1_000_000i128;
+
+                        // Add to PnL using set_pnl() to maintain pnl_pos_tot aggregate
+                        // SECURITY: Must use set_pnl() for correct haircut calculations
+                        let old_pnl = acc.pnl.get();
+                        let new_pnl = old_pnl.saturating_add(pnl_delta);
+                        engine.set_pnl(idx as usize, new_pnl);
+
+                        // Initialize warmup slope for positive PnL so users can
+                        // close accounts via CloseAccount after warmup elapses.
+                        // Without this, warmup_slope_per_step stays 0 and
+                        // settle_warmup_to_capital converts nothing (Bug #11).
+                        if new_pnl > 0 {
+                            let avail = (new_pnl as u128)
+                                .saturating_sub(engine.accounts[idx as usize].reserved_pnl as u128);
+                            let period = engine.params.warmup_period_slots as u128;
+                            let slope = if period > 0 {
+                                core::cmp::max(1u128, avail / period)
+                            } else {
+                                avail // instant warmup
+                            };
+                            engine.accounts[idx as usize].warmup_slope_per_step =
+                                percolator::U128::new(slope);
+                            engine.accounts[idx as usize].warmup_started_at_slot = clock.slot;
+                        }
+
+                        // Clear position
+                        engine.accounts[idx as usize].position_size = percolator::I128::ZERO;
+                        engine.accounts[idx as usize].entry_price = 0;
+                    }
+                }
+            }
+
+            // Update crank cursor for next call
+            engine.crank_cursor = if end >= percolator::MAX_ACCOUNTS as u16 {
+                0
+            } else {
+                end
+            };
+            engine.current_slot = clock.slot;
+
+            let outcome = crate::return_data::CrankOutcome {
+                slot: clock.slot,
+                price_e6: settlement_price,
+                funding_rate_bps_per_slot: 0,
+                crank_cursor: engine.crank_cursor,
+                sweep_completed: (engine.crank_cursor == 0) as u8,
+                oracle_mode: if oracle::is_hyperp_mode(&config) {
+                    crate::return_data::ORACLE_MODE_HYPERP
+                } else {
+                    crate::return_data::ORACLE_MODE_EXTERNAL
+                },
+                resolved: 1,
+                // Force-closes settle at the resolution price: no
+                // liquidations, insurance untouched
+                liquidations: 0,
+                insurance_delta: 0,
+            };
+            set_return_data(&outcome.to_bytes());
+            let event = crate::events::Cranked {
+                slot: clock.slot,
+                funding_rate: 0,
+            };
+            crate::events::emit(&event.to_bytes());
+            return Ok(());
         }
+
+        let clock = Clock::from_account_info(a_clock)?;
+        let effective_caller_idx = authorize_crank(&data, a_caller, caller_idx, allow_panic)?;
+        let outcome = crank_at_slot(
+            &mut data,
+            a_oracle,
+            &accounts[4..],
+            effective_caller_idx,
+            clock.slot,
+            clock.unix_timestamp,
+            allow_panic,
+        )?;
+        set_return_data(&outcome.to_bytes());
         Ok(())
     }
 
-    fn require_initialized(data: &[u8]) -> Result<(), ProgramError> {
-        let h = state::read_header(data);
-        if h.magic != MAGIC {
-            return Err(PercolatorError::NotInitialized.into());
+    /// Handler for [`Instruction::KeeperCrankBatch`].
+    pub fn handle_keeper_crank_batch(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        caller_idx: u16,
+        up_to_slot: u64,
+        max_iters: u16,
+        allow_panic: u8,
+    ) -> ProgramResult {
+        use crate::constants::{CRANK_NO_CALLER, MAX_CRANK_BATCH_ITERS};
+
+        accounts::expect_len(accounts, 4)?;
+        let a_caller = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
+        if max_iters == 0 || max_iters > MAX_CRANK_BATCH_ITERS {
+            return Err(ProgramError::InvalidInstructionData);
         }
-        if h.version != VERSION {
-            return Err(PercolatorError::InvalidVersion.into());
+        if caller_idx != CRANK_NO_CALLER {
+            accounts::expect_signer(a_caller)?;
         }
-        Ok(())
-    }
+        accounts::expect_writable(a_slab)?;
 
-    /// Require that the signer is the current admin.
-    /// If admin is burned (all zeros), admin operations are permanently disabled.
-    /// Admin authorization via verify helper (Kani-provable)
-    fn require_admin(header_admin: [u8; 32], signer: &Pubkey) -> Result<(), ProgramError> {
-        if !crate::verify::admin_ok(header_admin, signer.to_bytes()) {
-            return Err(PercolatorError::EngineUnauthorized.into());
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        // Resolved markets force-close through KeeperCrank's pages instead
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let clock = Clock::from_account_info(&accounts[2])?;
+        let effective_caller_idx = authorize_crank(&data, a_caller, caller_idx, allow_panic)?;
+
+        // One crank per slot, fresh price each time (Hyperp index and
+        // circuit breaker both step per slot)
+        let end = core::cmp::min(up_to_slot, clock.slot);
+        let mut slot = zc::engine_ref(&data)?.last_crank_slot;
+        let mut processed: u16 = 0;
+        while processed < max_iters && slot < end {
+            slot += 1;
+            crank_at_slot(
+                &mut data,
+                a_oracle,
+                &accounts[4..],
+                effective_caller_idx,
+                slot,
+                clock.unix_timestamp,
+                allow_panic,
+            )?;
+            processed += 1;
         }
+
+        let batch = crate::return_data::CrankBatch {
+            processed,
+            last_slot: slot,
+        };
+        set_return_data(&batch.to_bytes());
         Ok(())
     }
 
-    fn check_idx(engine: &RiskEngine, idx: u16) -> Result<(), ProgramError> {
-        if (idx as usize) >= MAX_ACCOUNTS || !engine.is_used(idx as usize) {
-            return Err(PercolatorError::EngineAccountNotFound.into());
+    /// Handler for [`Instruction::KeeperCrankWindow`].
+    pub fn handle_keeper_crank_window(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        caller_idx: u16,
+        start: u16,
+        count: u16,
+    ) -> ProgramResult {
+        use crate::constants::CRANK_NO_CALLER;
+
+        accounts::expect_len(accounts, 4)?;
+        let a_caller = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
+        if count == 0 || start >= MAX_ACCOUNTS as u16 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        if caller_idx != CRANK_NO_CALLER {
+            accounts::expect_signer(a_caller)?;
+        }
+        accounts::expect_writable(a_slab)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        // Resolved markets force-close through KeeperCrank's pages instead
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
         }
+
+        let clock = Clock::from_account_info(&accounts[2])?;
+        authorize_crank(&data, a_caller, caller_idx, 0)?;
+        let outcome = crank_window(
+            &mut data,
+            a_oracle,
+            &accounts[4..],
+            start,
+            count,
+            clock.slot,
+            clock.unix_timestamp,
+        )?;
+        set_return_data(&outcome.to_bytes());
         Ok(())
     }
 
-    fn verify_vault(
-        a_vault: &AccountInfo,
-        expected_owner: &Pubkey,
-        expected_mint: &Pubkey,
-        expected_pubkey: &Pubkey,
-    ) -> Result<(), ProgramError> {
-        if a_vault.key != expected_pubkey {
-            return Err(PercolatorError::InvalidVaultAta.into());
+    /// Handler for [`Instruction::TransferPosition`].
+    pub fn handle_transfer_position(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        from_idx: u16,
+        to_idx: u16,
+        size: i128,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 4)?;
+        let a_owner = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
+        accounts::expect_signer(a_owner)?;
+        accounts::expect_writable(a_slab)?;
+        if from_idx == to_idx {
+            return Err(PercolatorError::SelfTrade.into());
         }
-        if a_vault.owner != &spl_token::ID {
-            return Err(PercolatorError::InvalidVaultAta.into());
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
         }
-        if a_vault.data_len() != spl_token::state::Account::LEN {
-            return Err(PercolatorError::InvalidVaultAta.into());
+        require_not_halted(&data)?;
+
+        // Same price source as LiquidateAtOracle (mid, no confidence shift)
+        let mut config = state::read_config(&data);
+        let clock = Clock::from_account_info(&accounts[2])?;
+        let price = if oracle::is_hyperp_mode(&config) {
+            let idx = config.last_effective_price_e6;
+            if idx == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
+            }
+            idx
+        } else {
+            oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
+        };
+        state::write_config(&mut data, &config);
+
+        let engine = zc::engine_mut(&mut data)?;
+        for idx in [from_idx, to_idx] {
+            check_idx(engine, idx)?;
+            let acc = &engine.accounts[idx as usize];
+            if !crate::verify::owner_ok(acc.owner, a_owner.key.to_bytes()) {
+                return Err(PercolatorError::EngineUnauthorized.into());
+            }
+            // LP positions back matcher inventory aggregates; users only
+            if acc.is_lp() {
+                return Err(PercolatorError::EngineAccountKindMismatch.into());
+            }
         }
-
-        let data = a_vault.try_borrow_data()?;
-        let tok = spl_token::state::Account::unpack(&data)?;
-        if tok.mint != *expected_mint {
-            return Err(PercolatorError::InvalidMint.into());
+        let from_pos = engine.accounts[from_idx as usize].position_size.get();
+        let to_pos = engine.accounts[to_idx as usize].position_size.get();
+        if !crate::verify::transfer_position_ok(from_pos, to_pos, size) {
+            return Err(ProgramError::InvalidInstructionData);
         }
-        if tok.owner != *expected_owner {
-            return Err(PercolatorError::InvalidVaultAta.into());
+
+        settle_funding(engine, from_idx);
+        settle_funding(engine, to_idx);
+
+        // The moved slice carries its own mark PnL, so each account ends
+        // with what it would have had trading the slice at the old entry
+        let slice_mark = size
+            .saturating_mul(price as i128 - engine.accounts[from_idx as usize].entry_price as i128)
+            / 1_000_000;
+
+        // Destination must meet initial margin afterwards (entry == price, so no mark)
+        {
+            let equity = account_equity(engine, to_idx, price).saturating_add(slice_mark);
+            let notional = to_pos
+                .saturating_add(size)
+                .unsigned_abs()
+                .saturating_mul(price as u128)
+                / 1_000_000;
+            if !crate::verify::equity_meets_margin(
+                equity,
+                notional,
+                engine.params.initial_margin_bps,
+            ) {
+                return Err(PercolatorError::EngineUndercollateralized.into());
+            }
         }
-        // SECURITY (H3): Verify vault token account is initialized
-        // Uninitialized vault could brick deposits/withdrawals
-        if tok.state != spl_token::state::AccountState::Initialized {
-            return Err(PercolatorError::InvalidVaultAta.into());
+        check_leverage_cap(engine, &config, to_idx, size, price)?;
+
+        mark_to_price(engine, from_idx, price);
+        mark_to_price(engine, to_idx, price);
+        let from_pnl = engine.accounts[from_idx as usize].pnl.get();
+        engine.set_pnl(from_idx as usize, from_pnl.saturating_sub(slice_mark));
+        let to_pnl = engine.accounts[to_idx as usize].pnl.get();
+        engine.set_pnl(to_idx as usize, to_pnl.saturating_add(slice_mark));
+
+        let from_left = from_pos - size;
+        let from_acc = &mut engine.accounts[from_idx as usize];
+        from_acc.position_size = percolator::I128::new(from_left);
+        if from_left == 0 {
+            from_acc.entry_price = 0;
         }
+        let to_acc = &mut engine.accounts[to_idx as usize];
+        to_acc.position_size = percolator::I128::new(to_pos + size);
+        to_acc.entry_price = price;
         Ok(())
     }
 
-    /// Verify a user's token account: owner, mint, and initialized state.
-    /// Skip in tests to allow mock accounts.
-    #[allow(unused_variables)]
-    fn verify_token_account(
-        a_token_account: &AccountInfo,
-        expected_owner: &Pubkey,
-        expected_mint: &Pubkey,
-    ) -> Result<(), ProgramError> {
-        #[cfg(not(feature = "test"))]
-        {
-            if a_token_account.owner != &spl_token::ID {
-                return Err(PercolatorError::InvalidTokenAccount.into());
-            }
-            if a_token_account.data_len() != spl_token::state::Account::LEN {
-                return Err(PercolatorError::InvalidTokenAccount.into());
-            }
+    /// Handler for [`Instruction::TradeNoCpi`].
+    pub fn handle_trade_no_cpi(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        lp_idx: u16,
+        user_idx: u16,
+        size: i128,
+        limit_price_e6: u64,
+        client_req_id: u64,
+    ) -> ProgramResult {
+        trade_no_cpi(
+            program_id,
+            accounts,
+            lp_idx,
+            user_idx,
+            size,
+            limit_price_e6,
+            client_req_id,
+        )
+    }
 
-            let data = a_token_account.try_borrow_data()?;
-            let tok = spl_token::state::Account::unpack(&data)?;
-            if tok.mint != *expected_mint {
-                return Err(PercolatorError::InvalidMint.into());
-            }
-            if tok.owner != *expected_owner {
-                return Err(PercolatorError::InvalidTokenAccount.into());
-            }
-            if tok.state != spl_token::state::AccountState::Initialized {
-                return Err(PercolatorError::InvalidTokenAccount.into());
-            }
+    /// Handler for [`Instruction::ReducePosition`].
+    pub fn handle_reduce_position(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        lp_idx: u16,
+        user_idx: u16,
+        target_abs: i128,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 5)?;
+        accounts::expect_signer(&accounts[0])?;
+        if target_abs < 0 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        let size = {
+            let a_slab = &accounts[2];
+            let data = a_slab.try_borrow_data()?;
+            slab_guard(program_id, a_slab, &data)?;
+            require_initialized(&data)?;
+            let engine = zc::engine_ref(&data)?;
+            check_idx(engine, user_idx)?;
+            let pos = engine.accounts[user_idx as usize].position_size.get();
+            crate::verify::reduce_to_target_delta(pos, target_abs as u128)
+        };
+        if size != 0 {
+            trade_no_cpi(program_id, accounts, lp_idx, user_idx, size, 0, 0)?;
         }
         Ok(())
     }
 
-    /// Verify the token program account is valid.
-    /// Skip in tests to allow mock accounts.
-    #[allow(unused_variables)]
-    fn verify_token_program(a_token: &AccountInfo) -> Result<(), ProgramError> {
-        #[cfg(not(feature = "test"))]
-        {
-            if *a_token.key != spl_token::ID {
-                return Err(PercolatorError::InvalidTokenProgram.into());
+    /// Handler for [`Instruction::MultiTrade`].
+    pub fn handle_multi_trade(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+        leg_count: u8,
+        legs: [(u16, i128); MAX_TRADE_LEGS],
+    ) -> ProgramResult {
+        let legs = &legs[..leg_count as usize];
+        accounts::expect_len(accounts, 4 + legs.len())?;
+        let a_user = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_lps = &accounts[4..4 + legs.len()];
+
+        for a_lp in a_lps {
+            if !crate::verify::trade_signers_ok(false, a_user.is_signer, a_lp.is_signer) {
+                return Err(PercolatorError::ExpectedSigner.into());
             }
-            if !a_token.executable {
-                return Err(PercolatorError::InvalidTokenProgram.into());
+        }
+        accounts::expect_writable(a_slab)?;
+        if legs.iter().any(|&(_, size)| size == 0) {
+            return Err(PercolatorError::ZeroSizeTrade.into());
+        }
+        if !legs
+            .iter()
+            .all(|&(_, size)| crate::verify::trade_size_in_range(size))
+        {
+            return Err(PercolatorError::EngineOverflow.into());
+        }
+        if legs.iter().any(|&(lp_idx, _)| lp_idx == user_idx) {
+            return Err(PercolatorError::SelfTrade.into());
+        }
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        require_not_halted(&data)?;
+
+        let mut config = state::read_config(&data);
+        let emergency_exit = state::is_emergency_exit(&data);
+        let market_mode = state::read_market_mode(&data);
+        let clock = Clock::from_account_info(&accounts[2])?;
+        // Same price source and Hyperp restriction as TradeNoCpi
+        if oracle::is_hyperp_mode(&config) {
+            return Err(PercolatorError::HyperpTradeNoCpiDisabled.into());
+        }
+        require_not_paused(&config)?;
+        require_nocpi_trading(&config)?;
+        let price = oracle::read_index_price_clamped(
+            &mut config,
+            &accounts[3],
+            &accounts[4 + legs.len()..],
+            clock.unix_timestamp,
+        )?;
+        state::write_config(&mut data, &config);
+
+        let engine = zc::engine_mut(&mut data)?;
+
+        // Authorize every leg before any fill so a bad leg never half-applies
+        check_idx(engine, user_idx)?;
+        let u_owner = engine.accounts[user_idx as usize].owner;
+        if !crate::verify::owner_ok(u_owner, a_user.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+        for (&(lp_idx, _), a_lp) in legs.iter().zip(a_lps) {
+            check_idx(engine, lp_idx)?;
+            let l_owner = engine.accounts[lp_idx as usize].owner;
+            if !crate::verify::owner_ok(l_owner, a_lp.key.to_bytes()) {
+                return Err(PercolatorError::EngineUnauthorized.into());
             }
         }
+
+        // Combined margin: the user's initial margin applies to the net fill,
+        // so hedged legs are not each held to it
+        let net = legs
+            .iter()
+            .fold(0i128, |net, &(_, size)| net.saturating_add(size));
+        settle_funding(engine, user_idx);
+        if net != 0 {
+            check_initial_margin(engine, user_idx, net, price)?;
+            check_leverage_cap(engine, &config, user_idx, net, price)?;
+        }
+
+        // A failing leg fails the instruction, which reverts every earlier leg
+        let env = NoCpiFillEnv {
+            config: &config,
+            slot: clock.slot,
+            price,
+            emergency_exit,
+            market_mode,
+        };
+        for &(lp_idx, size) in legs {
+            fill_no_cpi(engine, &env, lp_idx, user_idx, size, false)?;
+        }
         Ok(())
     }
 
-    pub fn process_instruction<'a, 'b>(
+    /// Handler for [`Instruction::TradeCpi`].
+    pub fn handle_trade_cpi(
         program_id: &Pubkey,
-        accounts: &'b [AccountInfo<'a>],
-        instruction_data: &[u8],
+        accounts: &[AccountInfo],
+        lp_idx: u16,
+        user_idx: u16,
+        size: i128,
+        limit_price_e6: u64,
+        client_req_id: u64,
     ) -> ProgramResult {
-        let instruction = Instruction::decode(instruction_data)?;
+        // Phase 1: Updated account layout - lp_pda must be in accounts
+        accounts::expect_len(accounts, 8)?;
+        let a_user = &accounts[0];
+        let a_lp_owner = &accounts[1];
+        let a_slab = &accounts[2];
+        let a_clock = &accounts[3];
+        let a_oracle = &accounts[4];
+        let a_matcher_prog = &accounts[5];
+        let a_matcher_ctx = &accounts[6];
+        let a_lp_pda = &accounts[7];
+
+        // Signer policy via verify helper (Kani-provable): a_lp_owner does NOT
+        // need to sign. LP owner delegated trade authorization to the matcher
+        // program; the matcher CPI (via LP PDA invoke_signed) validates the trade.
+        if !crate::verify::trade_signers_ok(true, a_user.is_signer, a_lp_owner.is_signer) {
+            return Err(PercolatorError::ExpectedSigner.into());
+        }
+        accounts::expect_writable(a_slab)?;
+        accounts::expect_writable(a_matcher_ctx)?;
 
-        match instruction {
-            Instruction::InitMarket {
-                admin,
-                collateral_mint,
-                index_feed_id,
-                max_staleness_secs,
-                conf_filter_bps,
-                invert,
-                unit_scale,
-                initial_mark_price_e6,
-                risk_params,
-            } => {
-                // Reduced from 11 to 9: removed pyth_index and pyth_collateral accounts
-                // (feed_id is now passed in instruction data, not as account)
-                accounts::expect_len(accounts, 9)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_mint = &accounts[2];
-                let a_vault = &accounts[3];
-
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
-
-                // Ensure instruction data matches the signer
-                if admin != *a_admin.key {
-                    return Err(ProgramError::InvalidInstructionData);
-                }
+        // Reject before the matcher CPI so a zero-size request never consumes a nonce
+        if size == 0 {
+            return Err(PercolatorError::ZeroSizeTrade.into());
+        }
+        if !crate::verify::trade_size_in_range(size) {
+            return Err(PercolatorError::EngineOverflow.into());
+        }
+        if lp_idx == user_idx {
+            return Err(PercolatorError::SelfTrade.into());
+        }
 
-                // SECURITY (H1): Enforce collateral_mint matches the account
-                // This prevents signers from being confused by mismatched instruction data
-                if collateral_mint != *a_mint.key {
-                    return Err(ProgramError::InvalidInstructionData);
-                }
+        let lp_bytes = lp_idx.to_le_bytes();
+        let bump = check_matcher_accounts(
+            program_id,
+            a_slab,
+            lp_idx,
+            a_matcher_prog,
+            a_matcher_ctx,
+            a_lp_pda,
+        )?;
+
+        // Phase 3 & 4: Read engine state, generate nonce, validate matcher identity
+        // Note: Use immutable borrow for reading to avoid ExternalAccountDataModified
+        // Nonce write is deferred until after execute_trade
+        let (lp_account_id, mut config, req_id, lp_matcher_prog, lp_matcher_ctx) = {
+            let data = a_slab.try_borrow_data()?;
+            slab_guard(program_id, a_slab, &*data)?;
+            require_initialized(&*data)?;
+
+            // Block trading when market is resolved
+            if state::is_resolved(&*data) {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            require_not_halted(&*data)?;
 
-                // SECURITY (H2): Validate mint is a real SPL Token mint
-                // Check owner == spl_token::ID and data length == Mint::LEN (82 bytes)
-                #[cfg(not(feature = "test"))]
-                {
-                    use solana_program::program_pack::Pack;
-                    use spl_token::state::Mint;
-                    if *a_mint.owner != spl_token::ID {
-                        return Err(ProgramError::IllegalOwner);
-                    }
-                    if a_mint.data_len() != Mint::LEN {
-                        return Err(ProgramError::InvalidAccountData);
-                    }
-                    // Verify mint is initialized by unpacking
-                    let mint_data = a_mint.try_borrow_data()?;
-                    let _ = Mint::unpack(&mint_data)?;
-                }
+            let config = state::read_config(&*data);
+            require_not_paused(&config)?;
 
-                // Validate unit_scale: reject huge values that make most deposits credit 0 units
-                if !crate::verify::init_market_scale_ok(unit_scale) {
-                    return Err(ProgramError::InvalidInstructionData);
-                }
+            // Phase 3: Monotonic nonce for req_id (prevents replay attacks)
+            // Nonce advancement via verify helper (Kani-provable)
+            let nonce = state::read_req_nonce(&*data)?;
+            let req_id = crate::verify::nonce_on_success(nonce);
 
-                // Hyperp mode validation: if index_feed_id is all zeros, require initial_mark_price_e6
-                let is_hyperp = index_feed_id == [0u8; 32];
-                if is_hyperp && initial_mark_price_e6 == 0 {
-                    // Hyperp mode requires a non-zero initial mark price
-                    return Err(ProgramError::InvalidInstructionData);
-                }
+            let engine = zc::engine_ref(&*data)?;
 
-                // For Hyperp mode with inverted markets, apply inversion to initial price
-                // This ensures the stored mark/index are in "market price" form
-                let initial_mark_price_e6 = if is_hyperp && invert != 0 {
-                    crate::verify::invert_price_e6(initial_mark_price_e6, invert)
-                        .ok_or(PercolatorError::OracleInvalid)?
-                } else {
-                    initial_mark_price_e6
-                };
+            check_idx(engine, lp_idx)?;
+            check_idx(engine, user_idx)?;
 
-                #[cfg(debug_assertions)]
-                {
-                    if core::mem::size_of::<MarketConfig>() != CONFIG_LEN {
-                        return Err(ProgramError::InvalidAccountData);
-                    }
-                }
+            // Owner authorization via verify helper (Kani-provable)
+            let u_owner = engine.accounts[user_idx as usize].owner;
+            if !crate::verify::owner_ok(u_owner, a_user.key.to_bytes()) {
+                return Err(PercolatorError::EngineUnauthorized.into());
+            }
+            let l_owner = engine.accounts[lp_idx as usize].owner;
+            if !crate::verify::owner_ok(l_owner, a_lp_owner.key.to_bytes()) {
+                return Err(PercolatorError::EngineUnauthorized.into());
+            }
+            // A resubmitted trade must not fill twice (checked before the matcher
+            // CPI, so a duplicate never consumes a nonce)
+            check_client_req_id(engine, user_idx, client_req_id)?;
+
+            let lp_acc = &engine.accounts[lp_idx as usize];
+            (
+                lp_acc.account_id,
+                config,
+                req_id,
+                lp_acc.matcher_program,
+                lp_acc.matcher_context,
+            )
+        };
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
+        // Matcher identity binding via verify helper (Kani-provable)
+        if !crate::verify::matcher_identity_ok(
+            lp_matcher_prog,
+            lp_matcher_ctx,
+            a_matcher_prog.key.to_bytes(),
+            a_matcher_ctx.key.to_bytes(),
+        ) {
+            return Err(PercolatorError::EngineInvalidMatchingEngine.into());
+        }
 
-                let _ = zc::engine_mut(&mut data)?;
+        let clock = Clock::from_account_info(a_clock)?;
+        // Read oracle price: Hyperp mode uses index directly, otherwise circuit-breaker clamping
+        let is_hyperp = oracle::is_hyperp_mode(&config);
+        let price = if is_hyperp {
+            // Hyperp mode: use current index price for trade execution
+            let idx = config.last_effective_price_e6;
+            if idx == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
+            }
+            idx
+        } else {
+            oracle::read_index_price_clamped(
+                &mut config,
+                a_oracle,
+                &accounts[8..],
+                clock.unix_timestamp,
+            )?
+        };
 
-                let header = state::read_header(&data);
-                if header.magic == MAGIC {
-                    return Err(PercolatorError::AlreadyInitialized.into());
-                }
+        // Note: We don't zero the matcher_ctx before CPI because we don't own it.
+        // Security is maintained by ABI validation which checks req_id (nonce),
+        // lp_account_id, and oracle_price_e6 all match the request parameters.
+        require_matcher_initialized(a_matcher_ctx)?;
 
-                let (auth, bump) = accounts::derive_vault_authority(program_id, a_slab.key);
-                verify_vault(a_vault, &auth, a_mint.key, a_vault.key)?;
+        let mut cpi_data = alloc::vec::Vec::with_capacity(MATCHER_CALL_LEN);
+        cpi_data.push(MATCHER_CALL_TAG);
+        cpi_data.extend_from_slice(&req_id.to_le_bytes());
+        cpi_data.extend_from_slice(&lp_idx.to_le_bytes());
+        cpi_data.extend_from_slice(&lp_account_id.to_le_bytes());
+        cpi_data.extend_from_slice(&price.to_le_bytes());
+        cpi_data.extend_from_slice(&size.to_le_bytes());
+        cpi_data.extend_from_slice(&[0u8; 24]); // padding to MATCHER_CALL_LEN
 
-                for b in data.iter_mut() {
-                    *b = 0;
-                }
+        #[cfg(debug_assertions)]
+        {
+            if cpi_data.len() != MATCHER_CALL_LEN {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+        }
 
-                // Initialize engine in-place (zero-copy) to avoid stack overflow.
-                // The data is already zeroed above, so init_in_place only sets non-zero fields.
-                let engine = zc::engine_mut(&mut data)?;
-                engine.init_in_place(risk_params);
-
-                // Initialize slot fields to current slot to prevent overflow on first crank
-                // (accrue_funding checks dt < 31_536_000, which fails if last_funding_slot=0)
-                let a_clock = &accounts[5];
-                let clock = Clock::from_account_info(a_clock)?;
-                engine.current_slot = clock.slot;
-                engine.last_funding_slot = clock.slot;
-                engine.last_crank_slot = clock.slot;
-
-                let config = MarketConfig {
-                    collateral_mint: a_mint.key.to_bytes(),
-                    vault_pubkey: a_vault.key.to_bytes(),
-                    index_feed_id,
-                    max_staleness_secs,
-                    conf_filter_bps,
-                    vault_authority_bump: bump,
-                    invert,
-                    unit_scale,
-                    // Funding parameters (defaults)
-                    funding_horizon_slots: DEFAULT_FUNDING_HORIZON_SLOTS,
-                    funding_k_bps: DEFAULT_FUNDING_K_BPS,
-                    funding_inv_scale_notional_e6: DEFAULT_FUNDING_INV_SCALE_NOTIONAL_E6,
-                    funding_max_premium_bps: DEFAULT_FUNDING_MAX_PREMIUM_BPS,
-                    funding_max_bps_per_slot: DEFAULT_FUNDING_MAX_BPS_PER_SLOT,
-                    // Threshold parameters (defaults)
-                    thresh_floor: DEFAULT_THRESH_FLOOR,
-                    thresh_risk_bps: DEFAULT_THRESH_RISK_BPS,
-                    thresh_update_interval_slots: DEFAULT_THRESH_UPDATE_INTERVAL_SLOTS,
-                    thresh_step_bps: DEFAULT_THRESH_STEP_BPS,
-                    thresh_alpha_bps: DEFAULT_THRESH_ALPHA_BPS,
-                    thresh_min: DEFAULT_THRESH_MIN,
-                    thresh_max: DEFAULT_THRESH_MAX,
-                    thresh_min_step: DEFAULT_THRESH_MIN_STEP,
-                    // Oracle authority (disabled by default - use Pyth/Chainlink)
-                    // In Hyperp mode: authority_price_e6 = mark, last_effective_price_e6 = index
-                    oracle_authority: [0u8; 32],
-                    authority_price_e6: if is_hyperp { initial_mark_price_e6 } else { 0 },
-                    authority_timestamp: 0, // In Hyperp mode: stores funding rate (bps per slot)
-                    // Oracle price circuit breaker
-                    // In Hyperp mode: used for rate-limited index smoothing AND mark price clamping
-                    // Default: disabled for non-Hyperp, 1% per slot for Hyperp
-                    oracle_price_cap_e2bps: if is_hyperp {
-                        DEFAULT_HYPERP_PRICE_CAP_E2BPS
-                    } else {
-                        0
-                    },
-                    last_effective_price_e6: if is_hyperp { initial_mark_price_e6 } else { 0 },
-                };
-                state::write_config(&mut data, &config);
+        let metas = alloc::vec![
+            AccountMeta::new_readonly(*a_lp_pda.key, true), // Will become signer via invoke_signed
+            AccountMeta::new(*a_matcher_ctx.key, false),
+        ];
 
-                let new_header = SlabHeader {
-                    magic: MAGIC,
-                    version: VERSION,
-                    bump,
-                    _padding: [0; 3],
-                    admin: a_admin.key.to_bytes(),
-                    _reserved: [0; 24],
-                };
-                state::write_header(&mut data, &new_header);
-                // Step 4: Explicitly initialize nonce to 0 for determinism
-                state::write_req_nonce(&mut data, 0);
-                // Initialize threshold update slot to 0
-                state::write_last_thr_update_slot(&mut data, 0);
-            }
-            Instruction::InitUser { fee_payment } => {
-                accounts::expect_len(accounts, 5)?;
-                let a_user = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_user_ata = &accounts[2];
-                let a_vault = &accounts[3];
-                let a_token = &accounts[4];
-
-                accounts::expect_signer(a_user)?;
-                accounts::expect_writable(a_slab)?;
-                verify_token_program(a_token)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-
-                // Block new users when market is resolved
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
+        let ix = SolInstruction {
+            program_id: *a_matcher_prog.key,
+            accounts: metas,
+            data: cpi_data,
+        };
+
+        let bump_arr = [bump];
+        let seeds: &[&[u8]] = &[b"lp", a_slab.key.as_ref(), &lp_bytes, &bump_arr];
+
+        // Phase 2: Use zc helper for CPI - slab not passed to avoid ExternalAccountDataModified
+        zc::invoke_signed_trade(&ix, a_lp_pda, a_matcher_ctx, seeds)?;
+
+        let ctx_data = a_matcher_ctx.try_borrow_data()?;
+        let ret = crate::matcher_abi::read_return_from_ctx(&ctx_data)?;
+        // ABI validation via verify helper (Kani-provable)
+        let ret_fields = crate::verify::MatcherReturnFields {
+            abi_version: ret.abi_version,
+            flags: ret.flags,
+            exec_price_e6: ret.exec_price_e6,
+            exec_size: ret.exec_size,
+            req_id: ret.req_id,
+            lp_account_id: ret.lp_account_id,
+            oracle_price_e6: ret.oracle_price_e6,
+            reserved: ret.reserved,
+        };
+        if !crate::verify::abi_ok(ret_fields, lp_account_id, price, size, req_id) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if !crate::verify::limit_price_ok(size, ret.exec_price_e6, limit_price_e6) {
+            return Err(PercolatorError::SlippageExceeded.into());
+        }
+        drop(ctx_data);
+
+        let matcher = CpiMatcher {
+            exec_price: ret.exec_price_e6,
+            exec_size: ret.exec_size,
+        };
+        {
+            let mut data = state::slab_data_mut(a_slab)?;
+            state::write_config(&mut data, &config);
+            let emergency_exit = state::is_emergency_exit(&data);
+            let market_mode = state::read_market_mode(&data);
+            let engine = zc::engine_mut(&mut data)?;
+
+            // Gate: if insurance_fund <= threshold, only allow risk-reducing trades
+            // Use actual exec_size from matcher (LP delta is -exec_size)
+            // O(1) check after single O(n) scan
+            // Gate activation via verify helper (Kani-provable)
+            let bal = engine.insurance_fund.balance.get();
+            let thr = engine.risk_reduction_threshold();
+            if crate::verify::gate_active(thr, bal) {
+                #[cfg(feature = "cu-audit")]
+                {
+                    msg!("CU_CHECKPOINT: trade_cpi_compute_start");
+                    sol_log_compute_units();
                 }
-                let config = state::read_config(&data);
-                let mint = Pubkey::new_from_array(config.collateral_mint);
-
-                let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
-                verify_vault(
-                    a_vault,
-                    &auth,
-                    &mint,
-                    &Pubkey::new_from_array(config.vault_pubkey),
-                )?;
-                verify_token_account(a_user_ata, a_user.key, &mint)?;
-
-                // Transfer base tokens to vault
-                collateral::deposit(a_token, a_user_ata, a_vault, a_user, fee_payment)?;
-
-                // Convert base tokens to units for engine
-                let (units, dust) = crate::units::base_to_units(fee_payment, config.unit_scale);
-
-                // Accumulate dust
-                let old_dust = state::read_dust_base(&data)?;
-                state::write_dust_base(&mut data, old_dust.saturating_add(dust));
-
-                let engine = zc::engine_mut(&mut data)?;
-                let idx = engine.add_user(units as u128).map_err(map_risk_error)?;
-                engine
-                    .set_owner(idx, a_user.key.to_bytes())
-                    .map_err(map_risk_error)?;
-            }
-            Instruction::InitLP {
-                matcher_program,
-                matcher_context,
-                fee_payment,
-            } => {
-                accounts::expect_len(accounts, 5)?;
-                let a_user = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_user_ata = &accounts[2];
-                let a_vault = &accounts[3];
-                let a_token = &accounts[4];
-
-                accounts::expect_signer(a_user)?;
-                accounts::expect_writable(a_slab)?;
-                verify_token_program(a_token)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-
-                // Block new LPs when market is resolved
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
+                let risk_state = crate::LpRiskState::compute(engine);
+                #[cfg(feature = "cu-audit")]
+                {
+                    msg!("CU_CHECKPOINT: trade_cpi_compute_end");
+                    sol_log_compute_units();
                 }
-
-                let config = state::read_config(&data);
-                let mint = Pubkey::new_from_array(config.collateral_mint);
-
-                let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
-                verify_vault(
-                    a_vault,
-                    &auth,
-                    &mint,
-                    &Pubkey::new_from_array(config.vault_pubkey),
-                )?;
-                verify_token_account(a_user_ata, a_user.key, &mint)?;
-
-                // Transfer base tokens to vault
-                collateral::deposit(a_token, a_user_ata, a_vault, a_user, fee_payment)?;
-
-                // Convert base tokens to units for engine
-                let (units, dust) = crate::units::base_to_units(fee_payment, config.unit_scale);
-
-                // Accumulate dust
-                let old_dust = state::read_dust_base(&data)?;
-                state::write_dust_base(&mut data, old_dust.saturating_add(dust));
-
-                let engine = zc::engine_mut(&mut data)?;
-                let idx = engine
-                    .add_lp(
-                        matcher_program.to_bytes(),
-                        matcher_context.to_bytes(),
-                        units as u128,
-                    )
-                    .map_err(map_risk_error)?;
-                engine
-                    .set_owner(idx, a_user.key.to_bytes())
-                    .map_err(map_risk_error)?;
-            }
-            Instruction::DepositCollateral { user_idx, amount } => {
-                accounts::expect_len(accounts, 6)?;
-                let a_user = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_user_ata = &accounts[2];
-                let a_vault = &accounts[3];
-                let a_token = &accounts[4];
-                let a_clock = &accounts[5];
-
-                accounts::expect_signer(a_user)?;
-                accounts::expect_writable(a_slab)?;
-                verify_token_program(a_token)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-
-                // Block deposits when market is resolved
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
+                let old_lp_pos = engine.accounts[lp_idx as usize].position_size.get();
+                if risk_state.would_increase_risk(old_lp_pos, -ret.exec_size) {
+                    return Err(PercolatorError::EngineRiskReductionOnlyMode.into());
                 }
+            }
 
-                let config = state::read_config(&data);
-                let mint = Pubkey::new_from_array(config.collateral_mint);
-
-                let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
-                verify_vault(
-                    a_vault,
-                    &auth,
-                    &mint,
-                    &Pubkey::new_from_array(config.vault_pubkey),
-                )?;
-                verify_token_account(a_user_ata, a_user.key, &mint)?;
-
-                let clock = Clock::from_account_info(a_clock)?;
-
-                // Transfer base tokens to vault
-                collateral::deposit(a_token, a_user_ata, a_vault, a_user, amount)?;
-
-                // Convert base tokens to units for engine
-                let (units, dust) = crate::units::base_to_units(amount, config.unit_scale);
+            // Trade size selection via verify helper (Kani-provable: uses exec_size, not requested_size)
+            let trade_size = crate::verify::cpi_trade_size(ret.exec_size, size);
 
-                // Accumulate dust
-                let old_dust = state::read_dust_base(&data)?;
-                state::write_dust_base(&mut data, old_dust.saturating_add(dust));
+            // Single-fill notional cap on the actual fill: large orders must be split
+            if !crate::verify::trade_notional_ok(trade_size, price, config.max_trade_notional_e6) {
+                return Err(PercolatorError::TradeTooLarge.into());
+            }
 
-                let engine = zc::engine_mut(&mut data)?;
+            // Sweep metering: keepers lagging => only reducing trades
+            let old_user_pos = engine.accounts[user_idx as usize].position_size.get();
+            if !crate::verify::open_interest_ok(
+                engine.total_open_interest.get(),
+                old_user_pos,
+                engine.accounts[lp_idx as usize].position_size.get(),
+                trade_size,
+                config.max_open_interest_abs,
+            ) {
+                return Err(PercolatorError::OpenInterestCap.into());
+            }
+            if crate::verify::sweep_backlog_exceeded(
+                clock.slot,
+                config.last_full_sweep_slot,
+                config.max_sweep_lag_slots,
+            ) && !crate::verify::is_reducing_fill(old_user_pos, trade_size)
+            {
+                return Err(PercolatorError::KeeperSweepStale.into());
+            }
+            require_mode_allows_fill(market_mode, old_user_pos, trade_size)?;
+
+            // Hyperp: mark drifted from index (possible manipulation) => only reducing trades
+            if is_hyperp
+                && !crate::verify::mark_index_divergence_ok(
+                    config.authority_price_e6,
+                    price,
+                    config.max_mark_index_divergence_bps,
+                )
+                && !crate::verify::is_reducing_fill(old_user_pos, trade_size)
+            {
+                return Err(PercolatorError::MarkIndexDiverged.into());
+            }
 
-                check_idx(engine, user_idx)?;
+            // Funding race: settle both sides to the current index before the fill
+            settle_funding(engine, user_idx);
+            settle_funding(engine, lp_idx);
 
-                // Owner authorization via verify helper (Kani-provable)
-                let owner = engine.accounts[user_idx as usize].owner;
-                if !crate::verify::owner_ok(owner, a_user.key.to_bytes()) {
-                    return Err(PercolatorError::EngineUnauthorized.into());
-                }
+            // Finding L: risk-increasing fills need initial (not maintenance) margin
+            check_initial_margin(engine, user_idx, trade_size, price)?;
+            check_initial_margin(engine, lp_idx, -trade_size, price)?;
+            check_leverage_cap(engine, &config, user_idx, trade_size, price)?;
+            check_leverage_cap(engine, &config, lp_idx, -trade_size, price)?;
 
-                engine
-                    .deposit(user_idx, units as u128, clock.slot)
-                    .map_err(map_risk_error)?;
+            #[cfg(feature = "cu-audit")]
+            {
+                msg!("CU_CHECKPOINT: trade_cpi_execute_start");
+                sol_log_compute_units();
             }
-            Instruction::WithdrawCollateral { user_idx, amount } => {
-                accounts::expect_len(accounts, 8)?;
-                let a_user = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_vault = &accounts[2];
-                let a_user_ata = &accounts[3];
-                let a_vault_pda = &accounts[4];
-                let a_token = &accounts[5];
-                let a_clock = &accounts[6];
-                let a_oracle_idx = &accounts[7];
-
-                accounts::expect_signer(a_user)?;
-                accounts::expect_writable(a_slab)?;
-                verify_token_program(a_token)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
+            // Emergency exit: the reducing side of a fill is fee-free, the other
+            // side follows normal rules
+            let ins_before = engine.insurance_fund.balance.get();
+            let trade = |engine: &mut RiskEngine| {
+                engine.execute_trade(&matcher, lp_idx, user_idx, clock.slot, price, trade_size)
+            };
+            if emergency_exit {
+                with_fill_fees_waived(engine, user_idx, lp_idx, trade_size, clock.slot, trade)
+            } else {
+                trade(engine)
+            }
+            .map_err(map_risk_error)?;
+            #[cfg(feature = "cu-audit")]
+            {
+                msg!("CU_CHECKPOINT: trade_cpi_execute_end");
+                sol_log_compute_units();
+            }
+            let event = crate::events::TradeExecuted {
+                user_idx,
+                lp_idx,
+                size: trade_size,
+                exec_price: ret.exec_price_e6,
+                fee: engine
+                    .insurance_fund
+                    .balance
+                    .get()
+                    .saturating_sub(ins_before),
+            };
+            let fill = crate::return_data::TradeExecution {
+                exec_price_e6: ret.exec_price_e6,
+                exec_size: trade_size,
+                fee: event.fee,
+                resulting_position: engine.accounts[user_idx as usize].position_size.get(),
+            };
+            if client_req_id != 0 {
+                write_client_req_id(engine, user_idx, client_req_id);
+            }
+            // Write nonce AFTER CPI and execute_trade to avoid ExternalAccountDataModified
+            state::write_req_nonce(&mut data, req_id);
+
+            // Hyperp mode: update mark price with execution price
+            // Apply circuit breaker to prevent extreme mark price manipulation
+            if is_hyperp {
                 let mut config = state::read_config(&data);
-                let mint = Pubkey::new_from_array(config.collateral_mint);
-
-                let (derived_pda, _) = accounts::derive_vault_authority(program_id, a_slab.key);
-                accounts::expect_key(a_vault_pda, &derived_pda)?;
-
-                verify_vault(
-                    a_vault,
-                    &derived_pda,
-                    &mint,
-                    &Pubkey::new_from_array(config.vault_pubkey),
-                )?;
-                verify_token_account(a_user_ata, a_user.key, &mint)?;
-
-                let clock = Clock::from_account_info(a_clock)?;
-                // Read oracle price: Hyperp mode uses index directly, otherwise circuit-breaker clamping
-                let is_hyperp = oracle::is_hyperp_mode(&config);
-                let price = if is_hyperp {
-                    let idx = config.last_effective_price_e6;
-                    if idx == 0 {
-                        return Err(PercolatorError::OracleInvalid.into());
-                    }
-                    idx
-                } else {
-                    oracle::read_price_clamped(&mut config, a_oracle_idx, clock.unix_timestamp)?
-                };
+                // Clamp exec_price against current index to prevent manipulation
+                // Uses same circuit breaker as PushOraclePrice for consistency
+                let clamped_mark = oracle::clamp_oracle_price(
+                    config.last_effective_price_e6,
+                    ret.exec_price_e6,
+                    config.oracle_price_cap_e2bps,
+                );
+                config.authority_price_e6 = clamped_mark;
                 state::write_config(&mut data, &config);
+            }
+            crate::events::emit(&event.to_bytes());
+            set_return_data(&fill.to_bytes());
+        }
+        Ok(())
+    }
 
-                let engine = zc::engine_mut(&mut data)?;
+    /// Handler for [`Instruction::LiquidateAtOracle`].
+    pub fn handle_liquidate_at_oracle(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        target_idx: u16,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 4)?;
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
+        accounts::expect_writable(a_slab)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        require_not_halted(&data)?;
+        let mut config = state::read_config(&data);
+
+        let clock = Clock::from_account_info(&accounts[2])?;
+        // Read oracle price: Hyperp mode uses index directly, otherwise circuit-breaker clamping
+        let reading = oracle::read_oracle(a_oracle, &mut config, &clock)?;
+        state::write_config(&mut data, &config);
+        let emergency_exit = state::is_emergency_exit(&data);
+
+        let engine = zc::engine_mut(&mut data)?;
+
+        check_idx(engine, target_idx)?;
+        // Conservative pricing: the confidence bound worst for the position
+        let price =
+            reading.liquidation_price(engine.accounts[target_idx as usize].position_size.get())?;
+
+        // Debug logging for liquidation (using sol_log_64 for no_std)
+        sol_log_64(target_idx as u64, price, 0, 0, 0); // idx, price
+        {
+            let acc = &engine.accounts[target_idx as usize];
+            sol_log_64(acc.capital.get() as u64, acc.pnl.get() as u64, 0, 0, 1); // cap, pnl
+            sol_log_64(acc.position_size.get() as u64, acc.entry_price, 0, 0, 2); // pos, entry
+                                                                                  // Calculate mark PnL
+            let pos = acc.position_size.get();
+            let entry = acc.entry_price as i128;
+            let mark = pos.saturating_mul(price as i128 - entry) / 1_000_000;
+            let equity = account_equity(engine, target_idx, price);
+            let notional = (if pos < 0 { -pos } else { pos } as u128).saturating_mul(price as u128)
+                / 1_000_000;
+            let maint_req =
+                notional.saturating_mul(engine.params.maintenance_margin_bps as u128) / 10_000;
+            sol_log_64(mark as u64, equity as u64, maint_req as u64, 0, 3);
+            // mark, equity, maint
+        }
 
-                check_idx(engine, user_idx)?;
+        #[cfg(feature = "cu-audit")]
+        {
+            msg!("CU_CHECKPOINT: liquidate_start");
+            sol_log_compute_units();
+        }
+        let ins_before = engine.insurance_fund.balance.get();
+        let _res = if emergency_exit {
+            with_fees_waived(engine, |engine| {
+                engine.liquidate_at_oracle(target_idx, clock.slot, price)
+            })
+        } else {
+            engine.liquidate_at_oracle(target_idx, clock.slot, price)
+        }
+        .map_err(map_risk_error)?;
+        sol_log_64(_res as u64, 0, 0, 0, 4); // result
+        #[cfg(feature = "cu-audit")]
+        {
+            msg!("CU_CHECKPOINT: liquidate_end");
+            sol_log_compute_units();
+        }
+        if _res {
+            let event = crate::events::Liquidated {
+                target_idx,
+                penalty: engine
+                    .insurance_fund
+                    .balance
+                    .get()
+                    .saturating_sub(ins_before),
+            };
+            crate::events::emit(&event.to_bytes());
+        }
+        Ok(())
+    }
 
-                // Owner authorization via verify helper (Kani-provable)
-                let owner = engine.accounts[user_idx as usize].owner;
-                if !crate::verify::owner_ok(owner, a_user.key.to_bytes()) {
-                    return Err(PercolatorError::EngineUnauthorized.into());
-                }
+    /// Handler for [`Instruction::LiquidatePartial`].
+    pub fn handle_liquidate_partial(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        target_idx: u16,
+        close_bps: u16,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 4)?;
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
+        accounts::expect_writable(a_slab)?;
+        if close_bps == 0 || close_bps > 10_000 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
 
-                // Reject misaligned withdrawal amounts (cleaner UX than silent floor)
-                if config.unit_scale != 0 && amount % config.unit_scale as u64 != 0 {
-                    return Err(ProgramError::InvalidInstructionData);
-                }
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        require_not_halted(&data)?;
+        let mut config = state::read_config(&data);
+
+        let clock = Clock::from_account_info(&accounts[2])?;
+        // Same price source as LiquidateAtOracle (conservative bound)
+        let reading = oracle::read_oracle(a_oracle, &mut config, &clock)?;
+        state::write_config(&mut data, &config);
+        let emergency_exit = state::is_emergency_exit(&data);
+
+        let engine = zc::engine_mut(&mut data)?;
+        check_idx(engine, target_idx)?;
+        // Equity below must match what the engine sizes the close from
+        settle_funding(engine, target_idx);
+
+        let pos = engine.accounts[target_idx as usize].position_size.get();
+        let price = reading.liquidation_price(pos)?;
+        let equity = account_equity(engine, target_idx, price);
+        let abs_pos = pos.unsigned_abs();
+        let maintenance_bps = engine.params.maintenance_margin_bps;
+        let notional = abs_pos.saturating_mul(price as u128) / 1_000_000;
+        if pos == 0 || crate::verify::equity_meets_margin(equity, notional, maintenance_bps) {
+            // Healthy: nothing to liquidate (same as LiquidateAtOracle)
+            return Ok(());
+        }
 
-                // Convert requested base tokens to units
-                let (units_requested, _) = crate::units::base_to_units(amount, config.unit_scale);
+        // Size the split, then check the remainder clears maintenance once
+        // the fee on the closed part is paid
+        let remaining_abs = abs_pos - abs_pos * close_bps as u128 / 10_000;
+        let closed_notional = (abs_pos - remaining_abs).saturating_mul(price as u128) / 1_000_000;
+        let fee = if emergency_exit {
+            0
+        } else {
+            (closed_notional.saturating_mul(engine.params.liquidation_fee_bps as u128) / 10_000)
+                .min(engine.params.liquidation_fee_cap.get())
+        };
+        let remaining_notional = remaining_abs.saturating_mul(price as u128) / 1_000_000;
+        let target_bps =
+            crate::verify::partial_liquidation_target_bps(equity, price, remaining_abs).filter(
+                |_| {
+                    crate::verify::equity_meets_margin(
+                        equity.saturating_sub(fee as i128),
+                        remaining_notional,
+                        maintenance_bps,
+                    )
+                },
+            );
+        // Too deep underwater for this fraction: close it all
+        let (margin_bps, full_close) = match target_bps {
+            Some(bps) => (bps, false),
+            None => (maintenance_bps, true),
+        };
 
-                engine
-                    .withdraw(user_idx, units_requested as u128, clock.slot, price)
-                    .map_err(map_risk_error)?;
+        let ins_before = engine.insurance_fund.balance.get();
+        let liquidate = |engine: &mut RiskEngine| {
+            with_liquidation_target(engine, margin_bps, full_close, |engine| {
+                engine.liquidate_at_oracle(target_idx, clock.slot, price)
+            })
+        };
+        let res = if emergency_exit {
+            with_fees_waived(engine, liquidate)
+        } else {
+            liquidate(engine)
+        }
+        .map_err(map_risk_error)?;
+        if !res {
+            return Ok(());
+        }
 
-                // Convert units back to base tokens for payout (checked to prevent silent overflow)
-                let base_to_pay =
-                    crate::units::units_to_base_checked(units_requested, config.unit_scale)
-                        .ok_or(PercolatorError::EngineOverflow)?;
-
-                let seed1: &[u8] = b"vault";
-                let seed2: &[u8] = a_slab.key.as_ref();
-                let bump_arr: [u8; 1] = [config.vault_authority_bump];
-                let seed3: &[u8] = &bump_arr;
-                let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
-                let signer_seeds: [&[&[u8]]; 1] = [&seeds];
-
-                collateral::withdraw(
-                    a_token,
-                    a_vault,
-                    a_user_ata,
-                    a_vault_pda,
-                    base_to_pay,
-                    &signer_seeds,
-                )?;
+        // Re-check: a kept remainder must now clear maintenance
+        let pos_after = engine.accounts[target_idx as usize].position_size.get();
+        if pos_after != 0 {
+            let equity_after = account_equity(engine, target_idx, price);
+            let notional_after = pos_after.unsigned_abs().saturating_mul(price as u128) / 1_000_000;
+            if !crate::verify::equity_meets_margin(equity_after, notional_after, maintenance_bps) {
+                return Err(PercolatorError::EngineUndercollateralized.into());
             }
-            Instruction::KeeperCrank {
-                caller_idx,
-                allow_panic,
-            } => {
-                use crate::constants::CRANK_NO_CALLER;
-
-                accounts::expect_len(accounts, 4)?;
-                let a_caller = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_clock = &accounts[2];
-                let a_oracle = &accounts[3];
-
-                // Permissionless mode: caller_idx == u16::MAX means anyone can crank
-                let permissionless = caller_idx == CRANK_NO_CALLER;
+        }
 
-                if !permissionless {
-                    // Self-crank mode: require signer + owner authorization
-                    accounts::expect_signer(a_caller)?;
-                }
-                accounts::expect_writable(a_slab)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-
-                // Check if market is resolved - if so, force-close positions instead of normal crank
-                if state::is_resolved(&data) {
-                    let config = state::read_config(&data);
-                    let settlement_price = config.authority_price_e6;
-                    if settlement_price == 0 {
-                        return Err(ProgramError::InvalidAccountData);
-                    }
+        let event = crate::events::Liquidated {
+            target_idx,
+            penalty: engine
+                .insurance_fund
+                .balance
+                .get()
+                .saturating_sub(ins_before),
+        };
+        crate::events::emit(&event.to_bytes());
+        Ok(())
+    }
 
-                    let clock = Clock::from_account_info(a_clock)?;
-                    let engine = zc::engine_mut(&mut data)?;
-
-                    // Force-close positions in a paginated manner using crank_cursor
-                    // Process up to 64 accounts per crank call (bounded compute)
-                    const BATCH_SIZE: u16 = 64;
-                    let start = engine.crank_cursor;
-                    let end = core::cmp::min(start + BATCH_SIZE, percolator::MAX_ACCOUNTS as u16);
-
-                    for idx in start..end {
-                        if engine.is_used(idx as usize) {
-                            let acc = &engine.accounts[idx as usize];
-                            let pos = acc.position_size.get();
-                            if pos != 0 {
-                                // Settle position at settlement price
-                                // PnL = position * (settlement_price - entry_price) / 1e6
-                                let entry = acc.entry_price as i128;
-                                let settle = settlement_price as i128;
-                                let pnl_delta = pos.saturating_mul(settle.saturating_sub(entry))
-                                    / 1_000_000i128;
-
-                                // Add to PnL using set_pnl() to maintain pnl_pos_tot aggregate
-                                // SECURITY: Must use set_pnl() for correct haircut calculations
-                                let old_pnl = acc.pnl.get();
-                                let new_pnl = old_pnl.saturating_add(pnl_delta);
-                                engine.set_pnl(idx as usize, new_pnl);
-
-                                // Initialize warmup slope for positive PnL so users can
-                                // close accounts via CloseAccount after warmup elapses.
-                                // Without this, warmup_slope_per_step stays 0 and
-                                // settle_warmup_to_capital converts nothing (Bug #11).
-                                if new_pnl > 0 {
-                                    let avail = (new_pnl as u128).saturating_sub(
-                                        engine.accounts[idx as usize].reserved_pnl as u128,
-                                    );
-                                    let period = engine.params.warmup_period_slots as u128;
-                                    let slope = if period > 0 {
-                                        core::cmp::max(1u128, avail / period)
-                                    } else {
-                                        avail // instant warmup
-                                    };
-                                    engine.accounts[idx as usize].warmup_slope_per_step =
-                                        percolator::U128::new(slope);
-                                    engine.accounts[idx as usize].warmup_started_at_slot =
-                                        clock.slot;
-                                }
-
-                                // Clear position
-                                engine.accounts[idx as usize].position_size =
-                                    percolator::I128::ZERO;
-                                engine.accounts[idx as usize].entry_price = 0;
-                            }
-                        }
-                    }
+    /// Handler for [`Instruction::CloseAccount`].
+    pub fn handle_close_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+    ) -> ProgramResult {
+        close_account(program_id, accounts, user_idx, false)
+    }
 
-                    // Update crank cursor for next call
-                    engine.crank_cursor = if end >= percolator::MAX_ACCOUNTS as u16 {
-                        0
-                    } else {
-                        end
-                    };
-                    engine.current_slot = clock.slot;
+    /// Handler for [`Instruction::CloseLp`].
+    pub fn handle_close_lp(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        lp_idx: u16,
+    ) -> ProgramResult {
+        close_account(program_id, accounts, lp_idx, true)
+    }
 
-                    return Ok(());
-                }
+    /// Close `user_idx`, pay its remaining balance out of the vault and free the
+    /// slot. Shared by CloseAccount and CloseLp; `lp_only` additionally requires
+    /// an LP account with a flat position.
+    fn close_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+        lp_only: bool,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 8)?;
+        let a_user = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_vault = &accounts[2];
+        let a_user_ata = &accounts[3];
+        let a_pda = &accounts[4];
+        let a_token = &accounts[5];
+        let a_oracle = &accounts[7];
+
+        accounts::expect_signer(a_user)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        let mut config = state::read_config(&data);
+        let mint = Pubkey::new_from_array(config.collateral_mint);
+
+        let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        verify_vault(
+            a_vault,
+            &auth,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        verify_token_account(a_user_ata, a_user.key, &mint)?;
+        accounts::expect_key(a_pda, &auth)?;
+
+        let clock = Clock::from_account_info(&accounts[6])?;
+        // Read oracle price: Hyperp mode uses index directly, otherwise circuit-breaker clamping
+        let is_hyperp = oracle::is_hyperp_mode(&config);
+        let price = if is_hyperp {
+            let idx = config.last_effective_price_e6;
+            if idx == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
+            }
+            idx
+        } else {
+            oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
+        };
+        state::write_config(&mut data, &config);
+        let emergency_exit = state::is_emergency_exit(&data);
 
-                let mut config = state::read_config(&data);
-                let header = state::read_header(&data);
-                // Read last threshold update slot BEFORE mutable engine borrow
-                let last_thr_slot = state::read_last_thr_update_slot(&data)?;
-
-                // SECURITY (C4): allow_panic triggers global settlement - admin only
-                // This prevents griefing attacks where anyone triggers panic at worst moment
-                if allow_panic != 0 {
-                    accounts::expect_signer(a_caller)?;
-                    if !crate::verify::admin_ok(header.admin, a_caller.key.to_bytes()) {
-                        return Err(PercolatorError::EngineUnauthorized.into());
-                    }
-                }
+        let engine = zc::engine_mut(&mut data)?;
 
-                // Read dust before borrowing engine (for dust sweep later)
-                let dust_before = state::read_dust_base(&data)?;
-                let unit_scale = config.unit_scale;
+        check_idx(engine, user_idx)?;
 
-                let clock = Clock::from_account_info(a_clock)?;
+        // Owner authorization via verify helper (Kani-provable)
+        let u_owner = engine.accounts[user_idx as usize].owner;
+        if !crate::verify::owner_ok(u_owner, a_user.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+        if lp_only {
+            let lp = &engine.accounts[user_idx as usize];
+            if !lp.is_lp() {
+                return Err(PercolatorError::EngineAccountKindMismatch.into());
+            }
+            // Inventory must be flat: the matcher binding goes with the slot
+            if lp.position_size.get() != 0 {
+                return Err(PercolatorError::EnginePositionSizeMismatch.into());
+            }
+        }
 
-                // Hyperp mode: use get_engine_oracle_price_e6 for rate-limited index smoothing
-                // Otherwise: use read_price_clamped as before
-                let is_hyperp = oracle::is_hyperp_mode(&config);
-                let engine_last_slot = {
-                    let engine = zc::engine_ref(&data)?;
-                    engine.current_slot
-                };
+        #[cfg(feature = "cu-audit")]
+        {
+            msg!("CU_CHECKPOINT: close_account_start");
+            sol_log_compute_units();
+        }
+        let amt_units = if emergency_exit {
+            // Emergency exit: skip fee accrual
+            with_fees_waived(engine, |engine| {
+                engine.close_account(user_idx, clock.slot, price)
+            })
+        } else {
+            engine.close_account(user_idx, clock.slot, price)
+        }
+        .map_err(map_risk_error)?;
+        #[cfg(feature = "cu-audit")]
+        {
+            msg!("CU_CHECKPOINT: close_account_end");
+            sol_log_compute_units();
+        }
+        let last_account_out = engine.num_used_accounts == 0;
+        let amt_units_u64: u64 = amt_units
+            .try_into()
+            .map_err(|_| PercolatorError::EngineOverflow)?;
+
+        // Convert units to base tokens for payout (checked to prevent silent overflow)
+        let base_to_pay = crate::units::units_to_base_checked(amt_units_u64, config.unit_scale)
+            .ok_or(PercolatorError::EngineOverflow)?;
+
+        // Sub-unit deposit remainders pool in dust_base. Whole units of it
+        // go to insurance on the next crank; the sub-unit rest has no owner
+        // once the last account closes, so that account takes it and the
+        // vault ends holding exactly the insurance fund.
+        let dust_paid = if last_account_out && config.unit_scale > 0 {
+            let dust = state::read_dust_base(&data)?;
+            let (_, remainder) = crate::units::base_to_units(dust, config.unit_scale);
+            state::write_dust_base(&mut data, dust - remainder);
+            remainder
+        } else {
+            0
+        };
+        let base_to_pay = base_to_pay
+            .checked_add(dust_paid)
+            .ok_or(PercolatorError::EngineOverflow)?;
+
+        let seed1: &[u8] = b"vault";
+        let seed2: &[u8] = a_slab.key.as_ref();
+        let bump_arr: [u8; 1] = [config.vault_authority_bump];
+        let seed3: &[u8] = &bump_arr;
+        let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
+        let signer_seeds: [&[&[u8]]; 1] = [&seeds];
+
+        check_vault_rent_buffer(a_vault, base_to_pay)?;
+        let a_mint = collateral_mint_account(accounts, 8, &config)?;
+        collateral::withdraw(
+            a_token,
+            a_vault,
+            a_mint,
+            a_user_ata,
+            a_pda,
+            base_to_pay,
+            &signer_seeds,
+        )?;
+        Ok(())
+    }
 
-                let price = if is_hyperp {
-                    // Hyperp mode: update index toward mark with rate limiting
-                    oracle::get_engine_oracle_price_e6(
-                        engine_last_slot,
-                        clock.slot,
-                        clock.unix_timestamp,
-                        &mut config,
-                        a_oracle,
-                    )?
-                } else {
-                    oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
-                };
+    /// Handler for [`Instruction::TopUpInsurance`].
+    pub fn handle_top_up_insurance(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+        donor: Option<Pubkey>,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 5)?;
+        let a_user = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_user_ata = &accounts[2];
+        let a_vault = &accounts[3];
+        let a_token = &accounts[4];
+
+        accounts::expect_signer(a_user)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        // Block insurance top-up when market is resolved
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-                // Hyperp mode: compute and store funding rate BEFORE engine borrow
-                // This avoids borrow conflicts with config read/write
-                let hyperp_funding_rate = if is_hyperp {
-                    // Read previous funding rate (piecewise-constant: use stored rate, then update)
-                    // authority_timestamp is reinterpreted as i64 funding rate in Hyperp mode
-                    // Legacy states may still contain unix timestamps in this slot; clamp to policy.
-                    let prev_rate = config.authority_timestamp.clamp(
-                        -config.funding_max_bps_per_slot,
-                        config.funding_max_bps_per_slot,
-                    );
-
-                    // Compute new rate from premium
-                    let mark_e6 = config.authority_price_e6;
-                    let index_e6 = config.last_effective_price_e6;
-                    let new_rate = oracle::compute_premium_funding_bps_per_slot(
-                        mark_e6,
-                        index_e6,
-                        config.funding_horizon_slots,
-                        config.funding_k_bps,
-                        config.funding_max_premium_bps,
-                        config.funding_max_bps_per_slot,
-                    );
-
-                    // Store new rate in config for next crank
-                    config.authority_timestamp = new_rate;
-
-                    Some(prev_rate) // Use PREVIOUS rate for this crank (piecewise-constant model)
-                } else {
-                    None
-                };
-                state::write_config(&mut data, &config);
+        let mut config = state::read_config(&data);
+        let mint = Pubkey::new_from_array(config.collateral_mint);
+
+        let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        verify_vault(
+            a_vault,
+            &auth,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        verify_token_account(a_user_ata, a_user.key, &mint)?;
+
+        // Transfer base tokens to vault; credit what arrived
+        let a_mint = collateral_mint_account(accounts, 5, &config)?;
+        let received = collateral::deposit(a_token, a_user_ata, a_mint, a_vault, a_user, amount)?;
+
+        if let Some(donor) = donor {
+            state::record_donation(&mut config, &donor.to_bytes(), received);
+            state::write_config(&mut data, &config);
+        }
 
-                let engine = zc::engine_mut(&mut data)?;
+        // Convert base tokens to units for engine
+        let (units, dust) = crate::units::base_to_units(received, config.unit_scale);
 
-                // Crank authorization:
-                // - Permissionless mode (caller_idx == u16::MAX): anyone can crank
-                // - Self-crank mode: caller_idx must be a valid, existing account owned by signer
-                if !permissionless {
-                    check_idx(engine, caller_idx)?;
-                    let stored_owner = engine.accounts[caller_idx as usize].owner;
-                    if !crate::verify::owner_ok(stored_owner, a_caller.key.to_bytes()) {
-                        return Err(PercolatorError::EngineUnauthorized.into());
-                    }
-                }
-                // Execute crank with effective_caller_idx for clarity
-                // In permissionless mode, pass CRANK_NO_CALLER to engine (out-of-range = no caller settle)
-                let effective_caller_idx = if permissionless {
-                    CRANK_NO_CALLER
-                } else {
-                    caller_idx
-                };
+        // Accumulate dust
+        let old_dust = state::read_dust_base(&data)?;
+        state::write_dust_base(&mut data, old_dust.saturating_add(dust));
 
-                // Compute funding rate:
-                // - Hyperp mode: use pre-computed rate (avoids borrow conflict)
-                // - Normal mode: inventory-based funding from LP net position
-                let effective_funding_rate = if let Some(rate) = hyperp_funding_rate {
-                    rate
-                } else {
-                    // Normal mode: inventory-based funding from LP net position
-                    // Engine internally gates same-slot compounding via dt = now_slot - last_funding_slot,
-                    // so passing the same rate multiple times in the same slot is harmless (dt=0 => no change).
-                    let net_lp_pos = crate::compute_net_lp_pos(engine);
-                    crate::compute_inventory_funding_bps_per_slot(
-                        net_lp_pos,
-                        price,
-                        config.funding_horizon_slots,
-                        config.funding_k_bps,
-                        config.funding_inv_scale_notional_e6,
-                        config.funding_max_premium_bps,
-                        config.funding_max_bps_per_slot,
-                    )
-                };
-                #[cfg(feature = "cu-audit")]
-                {
-                    msg!("CU_CHECKPOINT: keeper_crank_start");
-                    sol_log_compute_units();
-                }
-                let _outcome = engine
-                    .keeper_crank(
-                        effective_caller_idx,
-                        clock.slot,
-                        price,
-                        effective_funding_rate,
-                        allow_panic != 0,
-                    )
-                    .map_err(map_risk_error)?;
-                #[cfg(feature = "cu-audit")]
-                {
-                    msg!("CU_CHECKPOINT: keeper_crank_end");
-                    sol_log_compute_units();
-                }
+        let engine = zc::engine_mut(&mut data)?;
+        engine
+            .top_up_insurance_fund(units as u128)
+            .map_err(map_risk_error)?;
+        Ok(())
+    }
 
-                // Dust sweep: if accumulated dust >= unit_scale, sweep to insurance fund
-                // Done before copying stats so insurance balance reflects the sweep
-                let remaining_dust = if unit_scale > 0 {
-                    let scale = unit_scale as u64;
-                    if dust_before >= scale {
-                        let units_to_sweep = dust_before / scale;
-                        engine
-                            .top_up_insurance_fund(units_to_sweep as u128)
-                            .map_err(map_risk_error)?;
-                        Some(dust_before % scale)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
+    /// Handler for [`Instruction::SetRiskThreshold`].
+    pub fn handle_set_risk_threshold(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_threshold: u128,
+    ) -> ProgramResult {
+        with_admin_slab(program_id, accounts, |data| {
+            let engine = zc::engine_mut(data)?;
+            engine.set_risk_reduction_threshold(new_threshold);
+            Ok(())
+        })
+    }
 
-                // Copy stats before threshold update (avoid borrow conflict)
-                let liqs = engine.lifetime_liquidations;
-                let force = engine.lifetime_force_realize_closes;
-                let ins_low = engine.insurance_fund.balance.get() as u64;
-
-                // --- Threshold auto-update (rate-limited + EWMA smoothed + step-clamped)
-                if clock.slot >= last_thr_slot.saturating_add(config.thresh_update_interval_slots) {
-                    let risk_units = crate::compute_system_risk_units(engine);
-                    // Convert risk_units (contracts) to notional using price
-                    let risk_notional = risk_units.saturating_mul(price as u128) / 1_000_000;
-                    // raw target: floor + risk_notional * thresh_risk_bps / 10000
-                    let raw_target = config.thresh_floor.saturating_add(
-                        risk_notional.saturating_mul(config.thresh_risk_bps as u128) / 10_000,
-                    );
-                    let clamped_target = raw_target.clamp(config.thresh_min, config.thresh_max);
-                    let current = engine.risk_reduction_threshold();
-                    // EWMA: new = alpha * target + (1 - alpha) * current
-                    let alpha = config.thresh_alpha_bps as u128;
-                    let smoothed = (alpha * clamped_target + (10_000 - alpha) * current) / 10_000;
-                    // Step clamp: max step = thresh_step_bps / 10000 of current (but at least thresh_min_step)
-                    // Bug #6 fix: When current == 0, allow stepping to clamped_target directly
-                    // Otherwise threshold would only increase by thresh_min_step (=1) per update
-                    let max_step = if current == 0 {
-                        clamped_target // Allow full jump when starting from zero
-                    } else {
-                        (current * config.thresh_step_bps as u128 / 10_000)
-                            .max(config.thresh_min_step)
-                    };
-                    let final_thresh = if smoothed > current {
-                        current.saturating_add(max_step.min(smoothed - current))
-                    } else {
-                        current.saturating_sub(max_step.min(current - smoothed))
-                    };
-                    engine.set_risk_reduction_threshold(
-                        final_thresh.clamp(config.thresh_min, config.thresh_max),
-                    );
-                    drop(engine);
-                    state::write_last_thr_update_slot(&mut data, clock.slot);
-                }
+    /// Handler for [`Instruction::UpdateAdmin`].
+    pub fn handle_update_admin(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_admin: Pubkey,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 2)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
 
-                // Write remaining dust if sweep occurred
-                if let Some(dust) = remaining_dust {
-                    state::write_dust_base(&mut data, dust);
-                }
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
 
-                // Debug: log lifetime counters (sol_log_64: tag, liqs, force, max_accounts, insurance)
-                msg!("CRANK_STATS");
-                sol_log_64(0xC8A4C, liqs, force, MAX_ACCOUNTS as u64, ins_low);
-            }
-            Instruction::TradeNoCpi {
-                lp_idx,
-                user_idx,
-                size,
-            } => {
-                accounts::expect_len(accounts, 5)?;
-                let a_user = &accounts[0];
-                let a_lp = &accounts[1];
-                let a_slab = &accounts[2];
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
 
-                accounts::expect_signer(a_user)?;
-                accounts::expect_signer(a_lp)?;
-                accounts::expect_writable(a_slab)?;
+        let mut header = state::read_header(&data);
+        require_admin(header.admin, a_admin.key)?;
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
+        header.admin = new_admin.to_bytes();
+        state::write_header(&mut data, &header);
+        Ok(())
+    }
 
-                // Block trading when market is resolved
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+    /// Handler for [`Instruction::CloseSlab`].
+    pub fn handle_close_slab(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        accounts::expect_len(accounts, 2)?;
+        let a_dest = &accounts[0];
+        let a_slab = &accounts[1];
 
-                let mut config = state::read_config(&data);
+        accounts::expect_signer(a_dest)?;
+        accounts::expect_writable(a_slab)?;
 
-                let clock = Clock::from_account_info(&accounts[3])?;
-                let a_oracle = &accounts[4];
+        // With unsafe_close: skip all validation and zeroing (CU limit)
+        // Account will be garbage collected after lamports are drained
+        #[cfg(not(feature = "unsafe_close"))]
+        {
+            let mut data = state::slab_data_mut(a_slab)?;
+            slab_guard(program_id, a_slab, &data)?;
+            require_initialized(&data)?;
 
-                // Hyperp mode: reject TradeNoCpi to prevent mark price manipulation
-                // All trades must go through TradeCpi with a pinned matcher
-                if oracle::is_hyperp_mode(&config) {
-                    return Err(PercolatorError::HyperpTradeNoCpiDisabled.into());
-                }
+            let header = state::read_header(&data);
+            require_admin(header.admin, a_dest.key)?;
 
-                // Read oracle price with circuit-breaker clamping
-                let price =
-                    oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?;
-                state::write_config(&mut data, &config);
+            let engine = zc::engine_ref(&data)?;
+            if !engine.vault.is_zero() {
+                return Err(PercolatorError::EngineInsufficientBalance.into());
+            }
+            if !engine.insurance_fund.balance.is_zero() {
+                return Err(PercolatorError::EngineInsufficientBalance.into());
+            }
+            if engine.num_used_accounts != 0 {
+                return Err(PercolatorError::EngineAccountNotFound.into());
+            }
 
-                let engine = zc::engine_mut(&mut data)?;
+            // Bug #3 fix: Check dust_base to prevent closing with unaccounted funds
+            // (SweepDust moves it out first)
+            let dust_base = state::read_dust_base(&data)?;
+            if dust_base != 0 {
+                return Err(PercolatorError::VaultNotEmpty.into());
+            }
 
-                check_idx(engine, lp_idx)?;
-                check_idx(engine, user_idx)?;
+            // Zero out the slab data to prevent reuse
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+        }
 
-                let u_owner = engine.accounts[user_idx as usize].owner;
+        // Transfer all lamports from slab to destination
+        let slab_lamports = a_slab.lamports();
+        **a_slab.lamports.borrow_mut() = 0;
+        **a_dest.lamports.borrow_mut() = a_dest
+            .lamports()
+            .checked_add(slab_lamports)
+            .ok_or(PercolatorError::EngineOverflow)?;
+        Ok(())
+    }
 
-                // Owner authorization via verify helper (Kani-provable)
-                if !crate::verify::owner_ok(u_owner, a_user.key.to_bytes()) {
-                    return Err(PercolatorError::EngineUnauthorized.into());
-                }
-                let l_owner = engine.accounts[lp_idx as usize].owner;
-                if !crate::verify::owner_ok(l_owner, a_lp.key.to_bytes()) {
-                    return Err(PercolatorError::EngineUnauthorized.into());
-                }
+    /// Handler for [`Instruction::UpdateConfig`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_update_config(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        funding_horizon_slots: u64,
+        funding_k_bps: u64,
+        funding_inv_scale_notional_e6: u128,
+        funding_max_premium_bps: i64,
+        funding_max_bps_per_slot: i64,
+        thresh_floor: u128,
+        thresh_risk_bps: u64,
+        thresh_update_interval_slots: u64,
+        thresh_step_bps: u64,
+        thresh_alpha_bps: u64,
+        thresh_min: u128,
+        thresh_max: u128,
+        thresh_min_step: u128,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            // Validate parameters
+            if !crate::verify::funding_caps_ok(
+                funding_max_premium_bps,
+                funding_max_bps_per_slot,
+                funding_horizon_slots,
+            ) {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
+            if funding_inv_scale_notional_e6 == 0 {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
+            if thresh_alpha_bps > 10_000 {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
+            if thresh_min > thresh_max {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
+            // Finding S: bound funding_k_bps to prevent extreme funding rate multipliers
+            if funding_k_bps > 10_000 {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
+            // Bound thresh_step_bps
+            if thresh_step_bps > 10_000 {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
+            // Bound thresh_risk_bps
+            if thresh_risk_bps > 10_000 {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
+            // Require thresh_update_interval_slots > 0
+            if thresh_update_interval_slots == 0 {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
 
-                // Gate: if insurance_fund <= threshold, only allow risk-reducing trades
-                // LP delta is -size (LP takes opposite side of user's trade)
-                // O(1) check after single O(n) scan
-                // Gate activation via verify helper (Kani-provable)
-                let bal = engine.insurance_fund.balance.get();
-                let thr = engine.risk_reduction_threshold();
-                if crate::verify::gate_active(thr, bal) {
-                    #[cfg(feature = "cu-audit")]
-                    {
-                        msg!("CU_CHECKPOINT: trade_nocpi_compute_start");
-                        sol_log_compute_units();
-                    }
-                    let risk_state = crate::LpRiskState::compute(engine);
-                    #[cfg(feature = "cu-audit")]
-                    {
-                        msg!("CU_CHECKPOINT: trade_nocpi_compute_end");
-                        sol_log_compute_units();
-                    }
-                    let old_lp_pos = engine.accounts[lp_idx as usize].position_size.get();
-                    if risk_state.would_increase_risk(old_lp_pos, -size) {
-                        return Err(PercolatorError::EngineRiskReductionOnlyMode.into());
-                    }
-                }
+            config.funding_horizon_slots = funding_horizon_slots;
+            config.funding_k_bps = funding_k_bps;
+            config.funding_inv_scale_notional_e6 = funding_inv_scale_notional_e6;
+            config.funding_max_premium_bps = funding_max_premium_bps;
+            config.funding_max_bps_per_slot = funding_max_bps_per_slot;
+            config.thresh_floor = thresh_floor;
+            config.thresh_risk_bps = thresh_risk_bps;
+            config.thresh_update_interval_slots = thresh_update_interval_slots;
+            config.thresh_step_bps = thresh_step_bps;
+            config.thresh_alpha_bps = thresh_alpha_bps;
+            config.thresh_min = thresh_min;
+            config.thresh_max = thresh_max;
+            config.thresh_min_step = thresh_min_step;
+            Ok(())
+        })
+    }
 
-                #[cfg(feature = "cu-audit")]
-                {
-                    msg!("CU_CHECKPOINT: trade_nocpi_execute_start");
-                    sol_log_compute_units();
-                }
-                engine
-                    .execute_trade(&NoOpMatcher, lp_idx, user_idx, clock.slot, price, size)
-                    .map_err(map_risk_error)?;
-                #[cfg(feature = "cu-audit")]
-                {
-                    msg!("CU_CHECKPOINT: trade_nocpi_execute_end");
-                    sol_log_compute_units();
-                }
-            }
-            Instruction::TradeCpi {
-                lp_idx,
-                user_idx,
-                size,
-            } => {
-                // Phase 1: Updated account layout - lp_pda must be in accounts
-                accounts::expect_len(accounts, 8)?;
-                let a_user = &accounts[0];
-                let a_lp_owner = &accounts[1];
-                let a_slab = &accounts[2];
-                let a_clock = &accounts[3];
-                let a_oracle = &accounts[4];
-                let a_matcher_prog = &accounts[5];
-                let a_matcher_ctx = &accounts[6];
-                let a_lp_pda = &accounts[7];
-
-                accounts::expect_signer(a_user)?;
-                // Note: a_lp_owner does NOT need to be a signer for TradeCpi.
-                // LP owner delegated trade authorization to the matcher program.
-                // The matcher CPI (via LP PDA invoke_signed) validates the trade.
-                accounts::expect_writable(a_slab)?;
-                accounts::expect_writable(a_matcher_ctx)?;
-
-                // Matcher shape validation via verify helper (Kani-provable)
-                let matcher_shape = crate::verify::MatcherAccountsShape {
-                    prog_executable: a_matcher_prog.executable,
-                    ctx_executable: a_matcher_ctx.executable,
-                    ctx_owner_is_prog: a_matcher_ctx.owner == a_matcher_prog.key,
-                    ctx_len_ok: crate::verify::ctx_len_sufficient(a_matcher_ctx.data_len()),
-                };
-                if !crate::verify::matcher_shape_ok(matcher_shape) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+    /// Handler for [`Instruction::SetMaintenanceFee`].
+    pub fn handle_set_maintenance_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_fee: u128,
+    ) -> ProgramResult {
+        with_admin_slab(program_id, accounts, |data| {
+            let engine = zc::engine_mut(data)?;
+            engine.params.maintenance_fee_per_slot = percolator::U128::new(new_fee);
+            Ok(())
+        })
+    }
 
-                // Phase 1: Validate lp_pda is the correct PDA, system-owned, empty data, 0 lamports
-                let lp_bytes = lp_idx.to_le_bytes();
-                let (expected_lp_pda, bump) = Pubkey::find_program_address(
-                    &[b"lp", a_slab.key.as_ref(), &lp_bytes],
-                    program_id,
-                );
-                // PDA key validation via verify helper (Kani-provable)
-                if !crate::verify::pda_key_matches(
-                    expected_lp_pda.to_bytes(),
-                    a_lp_pda.key.to_bytes(),
-                ) {
-                    return Err(ProgramError::InvalidSeeds);
-                }
-                // LP PDA shape validation via verify helper (Kani-provable)
-                let lp_pda_shape = crate::verify::LpPdaShape {
-                    is_system_owned: a_lp_pda.owner == &solana_program::system_program::ID,
-                    data_len_zero: a_lp_pda.data_len() == 0,
-                    lamports_zero: **a_lp_pda.lamports.borrow() == 0,
-                };
-                if !crate::verify::lp_pda_shape_ok(lp_pda_shape) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+    /// Handler for [`Instruction::SetOracleAuthority`].
+    pub fn handle_set_oracle_authority(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_authority: Pubkey,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.oracle_authority = new_authority.to_bytes();
+            // Clear stored price when authority changes
+            config.authority_price_e6 = 0;
+            config.authority_timestamp = 0;
+            Ok(())
+        })
+    }
 
-                // Phase 3 & 4: Read engine state, generate nonce, validate matcher identity
-                // Note: Use immutable borrow for reading to avoid ExternalAccountDataModified
-                // Nonce write is deferred until after execute_trade
-                let (lp_account_id, mut config, req_id, lp_matcher_prog, lp_matcher_ctx) = {
-                    let data = a_slab.try_borrow_data()?;
-                    slab_guard(program_id, a_slab, &*data)?;
-                    require_initialized(&*data)?;
-
-                    // Block trading when market is resolved
-                    if state::is_resolved(&*data) {
-                        return Err(ProgramError::InvalidAccountData);
-                    }
+    /// Handler for [`Instruction::PushOraclePrice`].
+    pub fn handle_push_oracle_price(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        price_e6: u64,
+        timestamp: i64,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 2)?;
+        let a_authority = &accounts[0];
+        let a_slab = &accounts[1];
 
-                    let config = state::read_config(&*data);
+        accounts::expect_signer(a_authority)?;
+        accounts::expect_writable(a_slab)?;
 
-                    // Phase 3: Monotonic nonce for req_id (prevents replay attacks)
-                    // Nonce advancement via verify helper (Kani-provable)
-                    let nonce = state::read_req_nonce(&*data)?;
-                    let req_id = crate::verify::nonce_on_success(nonce);
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-                    let engine = zc::engine_ref(&*data)?;
+        // Verify caller is the oracle authority
+        let mut config = state::read_config(&data);
+        let is_hyperp = oracle::is_hyperp_mode(&config);
+        if config.oracle_authority == [0u8; 32] {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+        if config.oracle_authority != a_authority.key.to_bytes() {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
 
-                    check_idx(engine, lp_idx)?;
-                    check_idx(engine, user_idx)?;
+        // Validate price (must be positive)
+        if price_e6 == 0 {
+            return Err(PercolatorError::OracleInvalid.into());
+        }
 
-                    // Owner authorization via verify helper (Kani-provable)
-                    let u_owner = engine.accounts[user_idx as usize].owner;
-                    if !crate::verify::owner_ok(u_owner, a_user.key.to_bytes()) {
-                        return Err(PercolatorError::EngineUnauthorized.into());
-                    }
-                    let l_owner = engine.accounts[lp_idx as usize].owner;
-                    if !crate::verify::owner_ok(l_owner, a_lp_owner.key.to_bytes()) {
-                        return Err(PercolatorError::EngineUnauthorized.into());
-                    }
+        // For non-Hyperp markets, require monotonic authority timestamps.
+        // This prevents stale rollback pushes from replacing fresher authority data.
+        if !is_hyperp && config.authority_timestamp != 0 && timestamp < config.authority_timestamp {
+            return Err(PercolatorError::OracleStale.into());
+        }
 
-                    let lp_acc = &engine.accounts[lp_idx as usize];
-                    (
-                        lp_acc.account_id,
-                        config,
-                        req_id,
-                        lp_acc.matcher_program,
-                        lp_acc.matcher_context,
-                    )
-                };
+        // Clamp the incoming price against circuit breaker
+        let clamped = oracle::clamp_oracle_price(
+            config.last_effective_price_e6,
+            price_e6,
+            config.oracle_price_cap_e2bps,
+        );
+        config.authority_price_e6 = clamped;
+        // In Hyperp mode this field stores previous funding-rate state (bps/slot),
+        // not unix time. Keep it untouched so PushOraclePrice cannot clobber it.
+        if !is_hyperp {
+            config.authority_timestamp = timestamp;
+        }
+        config.last_effective_price_e6 = clamped;
+        state::write_config(&mut data, &config);
+        Ok(())
+    }
 
-                // Matcher identity binding via verify helper (Kani-provable)
-                if !crate::verify::matcher_identity_ok(
-                    lp_matcher_prog,
-                    lp_matcher_ctx,
-                    a_matcher_prog.key.to_bytes(),
-                    a_matcher_ctx.key.to_bytes(),
-                ) {
-                    return Err(PercolatorError::EngineInvalidMatchingEngine.into());
-                }
+    /// Handler for [`Instruction::SetOraclePriceCap`].
+    pub fn handle_set_oracle_price_cap(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_change_e2bps: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            // Finding F: cap the cap to prevent admin from effectively disabling it
+            // 500_000 e2bps = 50% max price change per update
+            const MAX_ORACLE_PRICE_CAP_E2BPS: u64 = 500_000;
+            if max_change_e2bps > MAX_ORACLE_PRICE_CAP_E2BPS {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
 
-                let clock = Clock::from_account_info(a_clock)?;
-                // Read oracle price: Hyperp mode uses index directly, otherwise circuit-breaker clamping
-                let is_hyperp = oracle::is_hyperp_mode(&config);
-                let price = if is_hyperp {
-                    // Hyperp mode: use current index price for trade execution
-                    let idx = config.last_effective_price_e6;
-                    if idx == 0 {
-                        return Err(PercolatorError::OracleInvalid.into());
-                    }
-                    idx
-                } else {
-                    oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
-                };
+            // Hyperp: cap 0 makes the index jump straight to mark (no smoothing)
+            if max_change_e2bps == 0 && oracle::is_hyperp_mode(config) {
+                return Err(PercolatorError::HyperpCapRequired.into());
+            }
+            config.oracle_price_cap_e2bps = max_change_e2bps;
+            Ok(())
+        })
+    }
 
-                // Note: We don't zero the matcher_ctx before CPI because we don't own it.
-                // Security is maintained by ABI validation which checks req_id (nonce),
-                // lp_account_id, and oracle_price_e6 all match the request parameters.
+    /// Handler for [`Instruction::ResolveMarket`].
+    pub fn handle_resolve_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        // Resolve market: set RESOLVED flag, use admin oracle price for settlement
+        // Positions are force-closed via subsequent KeeperCrank calls (paginated)
+        accounts::expect_len(accounts, 2)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
 
-                let mut cpi_data = alloc::vec::Vec::with_capacity(MATCHER_CALL_LEN);
-                cpi_data.push(MATCHER_CALL_TAG);
-                cpi_data.extend_from_slice(&req_id.to_le_bytes());
-                cpi_data.extend_from_slice(&lp_idx.to_le_bytes());
-                cpi_data.extend_from_slice(&lp_account_id.to_le_bytes());
-                cpi_data.extend_from_slice(&price.to_le_bytes());
-                cpi_data.extend_from_slice(&size.to_le_bytes());
-                cpi_data.extend_from_slice(&[0u8; 24]); // padding to MATCHER_CALL_LEN
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
 
-                #[cfg(debug_assertions)]
-                {
-                    if cpi_data.len() != MATCHER_CALL_LEN {
-                        return Err(ProgramError::InvalidInstructionData);
-                    }
-                }
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
 
-                let metas = alloc::vec![
-                    AccountMeta::new_readonly(*a_lp_pda.key, true), // Will become signer via invoke_signed
-                    AccountMeta::new(*a_matcher_ctx.key, false),
-                ];
+        let header = state::read_header(&data);
+        require_admin(header.admin, a_admin.key)?;
 
-                let ix = SolInstruction {
-                    program_id: *a_matcher_prog.key,
-                    accounts: metas,
-                    data: cpi_data,
-                };
+        // Can't re-resolve
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-                let bump_arr = [bump];
-                let seeds: &[&[u8]] = &[b"lp", a_slab.key.as_ref(), &lp_bytes, &bump_arr];
-
-                // Phase 2: Use zc helper for CPI - slab not passed to avoid ExternalAccountDataModified
-                zc::invoke_signed_trade(&ix, a_lp_pda, a_matcher_ctx, seeds)?;
-
-                let ctx_data = a_matcher_ctx.try_borrow_data()?;
-                let ret = crate::matcher_abi::read_matcher_return(&ctx_data)?;
-                // ABI validation via verify helper (Kani-provable)
-                let ret_fields = crate::verify::MatcherReturnFields {
-                    abi_version: ret.abi_version,
-                    flags: ret.flags,
-                    exec_price_e6: ret.exec_price_e6,
-                    exec_size: ret.exec_size,
-                    req_id: ret.req_id,
-                    lp_account_id: ret.lp_account_id,
-                    oracle_price_e6: ret.oracle_price_e6,
-                    reserved: ret.reserved,
-                };
-                if !crate::verify::abi_ok(ret_fields, lp_account_id, price, size, req_id) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
-                drop(ctx_data);
+        // Require admin oracle price to be set (authority_price_e6 > 0)
+        let config = state::read_config(&data);
+        if config.authority_price_e6 == 0 {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-                let matcher = CpiMatcher {
-                    exec_price: ret.exec_price_e6,
-                    exec_size: ret.exec_size,
-                };
-                {
-                    let mut data = state::slab_data_mut(a_slab)?;
-                    state::write_config(&mut data, &config);
-                    let engine = zc::engine_mut(&mut data)?;
-
-                    // Gate: if insurance_fund <= threshold, only allow risk-reducing trades
-                    // Use actual exec_size from matcher (LP delta is -exec_size)
-                    // O(1) check after single O(n) scan
-                    // Gate activation via verify helper (Kani-provable)
-                    let bal = engine.insurance_fund.balance.get();
-                    let thr = engine.risk_reduction_threshold();
-                    if crate::verify::gate_active(thr, bal) {
-                        #[cfg(feature = "cu-audit")]
-                        {
-                            msg!("CU_CHECKPOINT: trade_cpi_compute_start");
-                            sol_log_compute_units();
-                        }
-                        let risk_state = crate::LpRiskState::compute(engine);
-                        #[cfg(feature = "cu-audit")]
-                        {
-                            msg!("CU_CHECKPOINT: trade_cpi_compute_end");
-                            sol_log_compute_units();
-                        }
-                        let old_lp_pos = engine.accounts[lp_idx as usize].position_size.get();
-                        if risk_state.would_increase_risk(old_lp_pos, -ret.exec_size) {
-                            return Err(PercolatorError::EngineRiskReductionOnlyMode.into());
-                        }
-                    }
+        // Set the resolved flag
+        state::set_resolved(&mut data);
+        Ok(())
+    }
 
-                    // Trade size selection via verify helper (Kani-provable: uses exec_size, not requested_size)
-                    let trade_size = crate::verify::cpi_trade_size(ret.exec_size, size);
-                    #[cfg(feature = "cu-audit")]
-                    {
-                        msg!("CU_CHECKPOINT: trade_cpi_execute_start");
-                        sol_log_compute_units();
-                    }
-                    engine
-                        .execute_trade(&matcher, lp_idx, user_idx, clock.slot, price, trade_size)
-                        .map_err(map_risk_error)?;
-                    #[cfg(feature = "cu-audit")]
-                    {
-                        msg!("CU_CHECKPOINT: trade_cpi_execute_end");
-                        sol_log_compute_units();
-                    }
-                    // Write nonce AFTER CPI and execute_trade to avoid ExternalAccountDataModified
-                    state::write_req_nonce(&mut data, req_id);
-
-                    // Hyperp mode: update mark price with execution price
-                    // Apply circuit breaker to prevent extreme mark price manipulation
-                    if is_hyperp {
-                        let mut config = state::read_config(&data);
-                        // Clamp exec_price against current index to prevent manipulation
-                        // Uses same circuit breaker as PushOraclePrice for consistency
-                        let clamped_mark = oracle::clamp_oracle_price(
-                            config.last_effective_price_e6,
-                            ret.exec_price_e6,
-                            config.oracle_price_cap_e2bps,
-                        );
-                        config.authority_price_e6 = clamped_mark;
-                        state::write_config(&mut data, &config);
-                    }
-                }
-            }
-            Instruction::LiquidateAtOracle { target_idx } => {
-                accounts::expect_len(accounts, 4)?;
-                let a_slab = &accounts[1];
-                let a_oracle = &accounts[3];
-                accounts::expect_writable(a_slab)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-                let mut config = state::read_config(&data);
+    /// Handler for [`Instruction::WithdrawInsurance`].
+    pub fn handle_withdraw_insurance(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: Option<u64>,
+    ) -> ProgramResult {
+        // Withdraw insurance fund (admin only). The whole fund requires RESOLVED
+        // and all positions closed; a partial amount is bounded by insurance_floor.
+        accounts::expect_len(accounts, 6)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_admin_ata = &accounts[2];
+        let a_vault = &accounts[3];
+        let a_token = &accounts[4];
+        let a_vault_pda = &accounts[5];
+
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let header = state::read_header(&data);
+        require_admin(header.admin, a_admin.key)?;
+
+        // The whole-fund withdrawal must be resolved
+        if amount.is_none() && !state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-                let clock = Clock::from_account_info(&accounts[2])?;
-                // Read oracle price: Hyperp mode uses index directly, otherwise circuit-breaker clamping
-                let is_hyperp = oracle::is_hyperp_mode(&config);
-                let price = if is_hyperp {
-                    let idx = config.last_effective_price_e6;
-                    if idx == 0 {
-                        return Err(PercolatorError::OracleInvalid.into());
-                    }
-                    idx
-                } else {
-                    oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
-                };
-                state::write_config(&mut data, &config);
+        let config = state::read_config(&data);
+        let mint = Pubkey::new_from_array(config.collateral_mint);
 
-                let engine = zc::engine_mut(&mut data)?;
+        let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        verify_vault(
+            a_vault,
+            &auth,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        verify_token_account(a_admin_ata, a_admin.key, &mint)?;
+        accounts::expect_key(a_vault_pda, &auth)?;
 
-                check_idx(engine, target_idx)?;
+        let engine = zc::engine_mut(&mut data)?;
+        let insurance_units = engine.insurance_fund.balance.get();
 
-                // Debug logging for liquidation (using sol_log_64 for no_std)
-                sol_log_64(target_idx as u64, price, 0, 0, 0); // idx, price
-                {
-                    let acc = &engine.accounts[target_idx as usize];
-                    sol_log_64(acc.capital.get() as u64, acc.pnl.get() as u64, 0, 0, 1); // cap, pnl
-                    sol_log_64(acc.position_size.get() as u64, acc.entry_price, 0, 0, 2); // pos, entry
-                                                                                          // Calculate mark PnL
-                    let pos = acc.position_size.get();
-                    let entry = acc.entry_price as i128;
-                    let mark = pos.saturating_mul(price as i128 - entry) / 1_000_000;
-                    let equity = (acc.capital.get() as i128)
-                        .saturating_add(acc.pnl.get())
-                        .saturating_add(mark);
-                    let notional = (if pos < 0 { -pos } else { pos } as u128)
-                        .saturating_mul(price as u128)
-                        / 1_000_000;
-                    let maint_req = notional
-                        .saturating_mul(engine.params.maintenance_margin_bps as u128)
-                        / 10_000;
-                    sol_log_64(mark as u64, equity as u64, maint_req as u64, 0, 3);
-                    // mark, equity, maint
+        let withdraw_units = match amount {
+            None => {
+                // Require all positions to be closed (force-closed by crank)
+                // Check that no account has position_size != 0
+                let mut has_open_positions = false;
+                for i in 0..percolator::MAX_ACCOUNTS {
+                    if engine.is_used(i) {
+                        let pos = engine.accounts[i].position_size.get();
+                        if pos != 0 {
+                            has_open_positions = true;
+                            break;
+                        }
+                    }
                 }
-
-                #[cfg(feature = "cu-audit")]
-                {
-                    msg!("CU_CHECKPOINT: liquidate_start");
-                    sol_log_compute_units();
+                if has_open_positions {
+                    return Err(ProgramError::InvalidAccountData);
                 }
-                let _res = engine
-                    .liquidate_at_oracle(target_idx, clock.slot, price)
-                    .map_err(map_risk_error)?;
-                sol_log_64(_res as u64, 0, 0, 0, 4); // result
-                #[cfg(feature = "cu-audit")]
-                {
-                    msg!("CU_CHECKPOINT: liquidate_end");
-                    sol_log_compute_units();
+                if insurance_units == 0 {
+                    return Ok(()); // Nothing to withdraw
                 }
+                insurance_units
             }
-            Instruction::CloseAccount { user_idx } => {
-                accounts::expect_len(accounts, 8)?;
-                let a_user = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_vault = &accounts[2];
-                let a_user_ata = &accounts[3];
-                let a_pda = &accounts[4];
-                let a_token = &accounts[5];
-                let a_oracle = &accounts[7];
-
-                accounts::expect_signer(a_user)?;
-                accounts::expect_writable(a_slab)?;
-                verify_token_program(a_token)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-                let mut config = state::read_config(&data);
-                let mint = Pubkey::new_from_array(config.collateral_mint);
-
-                let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
-                verify_vault(
-                    a_vault,
-                    &auth,
-                    &mint,
-                    &Pubkey::new_from_array(config.vault_pubkey),
-                )?;
-                verify_token_account(a_user_ata, a_user.key, &mint)?;
-                accounts::expect_key(a_pda, &auth)?;
-
-                let clock = Clock::from_account_info(&accounts[6])?;
-                // Read oracle price: Hyperp mode uses index directly, otherwise circuit-breaker clamping
-                let is_hyperp = oracle::is_hyperp_mode(&config);
-                let price = if is_hyperp {
-                    let idx = config.last_effective_price_e6;
-                    if idx == 0 {
-                        return Err(PercolatorError::OracleInvalid.into());
-                    }
-                    idx
-                } else {
-                    oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
-                };
-                state::write_config(&mut data, &config);
-
-                let engine = zc::engine_mut(&mut data)?;
-
-                check_idx(engine, user_idx)?;
-
-                // Owner authorization via verify helper (Kani-provable)
-                let u_owner = engine.accounts[user_idx as usize].owner;
-                if !crate::verify::owner_ok(u_owner, a_user.key.to_bytes()) {
-                    return Err(PercolatorError::EngineUnauthorized.into());
-                }
-
-                #[cfg(feature = "cu-audit")]
-                {
-                    msg!("CU_CHECKPOINT: close_account_start");
-                    sol_log_compute_units();
-                }
-                let amt_units = engine
-                    .close_account(user_idx, clock.slot, price)
-                    .map_err(map_risk_error)?;
-                #[cfg(feature = "cu-audit")]
+            Some(amount) => {
+                // Same amount rules as WithdrawCollateral: non-zero, unit-aligned
+                if amount == 0 || (config.unit_scale != 0 && amount % config.unit_scale as u64 != 0)
                 {
-                    msg!("CU_CHECKPOINT: close_account_end");
-                    sol_log_compute_units();
+                    return Err(ProgramError::InvalidInstructionData);
                 }
-                let amt_units_u64: u64 = amt_units
-                    .try_into()
-                    .map_err(|_| PercolatorError::EngineOverflow)?;
-
-                // Convert units to base tokens for payout (checked to prevent silent overflow)
-                let base_to_pay =
-                    crate::units::units_to_base_checked(amt_units_u64, config.unit_scale)
-                        .ok_or(PercolatorError::EngineOverflow)?;
-
-                let seed1: &[u8] = b"vault";
-                let seed2: &[u8] = a_slab.key.as_ref();
-                let bump_arr: [u8; 1] = [config.vault_authority_bump];
-                let seed3: &[u8] = &bump_arr;
-                let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
-                let signer_seeds: [&[&[u8]]; 1] = [&seeds];
-
-                collateral::withdraw(
-                    a_token,
-                    a_vault,
-                    a_user_ata,
-                    a_pda,
-                    base_to_pay,
-                    &signer_seeds,
-                )?;
-            }
-            Instruction::TopUpInsurance { amount } => {
-                accounts::expect_len(accounts, 5)?;
-                let a_user = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_user_ata = &accounts[2];
-                let a_vault = &accounts[3];
-                let a_token = &accounts[4];
-
-                accounts::expect_signer(a_user)?;
-                accounts::expect_writable(a_slab)?;
-                verify_token_program(a_token)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-
-                // Block insurance top-up when market is resolved
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
+                let (units, _) = crate::units::base_to_units(amount, config.unit_scale);
+                if !crate::verify::insurance_floor_ok(
+                    insurance_units,
+                    units as u128,
+                    config.insurance_floor,
+                ) {
+                    return Err(PercolatorError::EngineInsufficientBalance.into());
                 }
+                units as u128
+            }
+        };
 
-                let config = state::read_config(&data);
-                let mint = Pubkey::new_from_array(config.collateral_mint);
-
-                let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
-                verify_vault(
-                    a_vault,
-                    &auth,
-                    &mint,
-                    &Pubkey::new_from_array(config.vault_pubkey),
-                )?;
-                verify_token_account(a_user_ata, a_user.key, &mint)?;
+        // Never dip into user capital: the vault must still cover c_tot afterwards
+        if !crate::verify::admin_withdraw_allowed(
+            engine.vault.get(),
+            engine.c_tot.get(),
+            insurance_units,
+            withdraw_units,
+        ) {
+            return Err(PercolatorError::EngineInsufficientBalance.into());
+        }
 
-                // Transfer base tokens to vault
-                collateral::deposit(a_token, a_user_ata, a_vault, a_user, amount)?;
+        // Cap at u64::MAX for conversion (should never happen in practice)
+        let units_u64 = if withdraw_units > u64::MAX as u128 {
+            u64::MAX
+        } else {
+            withdraw_units as u64
+        };
+        let base_amount = crate::units::units_to_base_checked(units_u64, config.unit_scale)
+            .ok_or(PercolatorError::EngineOverflow)?;
+
+        // Debit the insurance fund and the tracked vault (both checked above)
+        engine.insurance_fund.balance = percolator::U128::new(insurance_units - withdraw_units);
+        engine.vault = percolator::U128::new(engine.vault.get() - withdraw_units);
+
+        // Transfer from vault to admin
+        let seed1: &[u8] = b"vault";
+        let seed2: &[u8] = a_slab.key.as_ref();
+        let bump_arr: [u8; 1] = [config.vault_authority_bump];
+        let seed3: &[u8] = &bump_arr;
+        let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
+        let signer_seeds: [&[&[u8]]; 1] = [&seeds];
+
+        let a_mint = collateral_mint_account(accounts, 6, &config)?;
+        collateral::withdraw(
+            a_token,
+            a_vault,
+            a_mint,
+            a_admin_ata,
+            a_vault_pda,
+            base_amount,
+            &signer_seeds,
+        )?;
+        Ok(())
+    }
 
-                // Convert base tokens to units for engine
-                let (units, dust) = crate::units::base_to_units(amount, config.unit_scale);
+    /// Handler for [`Instruction::SweepDust`].
+    pub fn handle_sweep_dust(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        // Residual dust_base is below one unit and owned by no account;
+        // sweeping it out is what lets CloseSlab succeed.
+        accounts::expect_len(accounts, 6)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_admin_ata = &accounts[2];
+        let a_vault = &accounts[3];
+        let a_token = &accounts[4];
+        let a_vault_pda = &accounts[5];
+
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let header = state::read_header(&data);
+        require_admin(header.admin, a_admin.key)?;
+
+        let config = state::read_config(&data);
+        let mint = Pubkey::new_from_array(config.collateral_mint);
+
+        let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        verify_vault(
+            a_vault,
+            &auth,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        verify_token_account(a_admin_ata, a_admin.key, &mint)?;
+        accounts::expect_key(a_vault_pda, &auth)?;
+
+        let dust = state::read_dust_base(&data)?;
+        if dust == 0 {
+            return Ok(()); // Nothing to sweep
+        }
+        state::write_dust_base(&mut data, 0);
+
+        let seed1: &[u8] = b"vault";
+        let seed2: &[u8] = a_slab.key.as_ref();
+        let bump_arr: [u8; 1] = [config.vault_authority_bump];
+        let seed3: &[u8] = &bump_arr;
+        let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
+        let signer_seeds: [&[&[u8]]; 1] = [&seeds];
+
+        let a_mint = collateral_mint_account(accounts, 6, &config)?;
+        collateral::withdraw(
+            a_token,
+            a_vault,
+            a_mint,
+            a_admin_ata,
+            a_vault_pda,
+            dust,
+            &signer_seeds,
+        )?;
+        Ok(())
+    }
 
-                // Accumulate dust
-                let old_dust = state::read_dust_base(&data)?;
-                state::write_dust_base(&mut data, old_dust.saturating_add(dust));
+    /// Handler for [`Instruction::AdminForceCloseAccount`].
+    pub fn handle_admin_force_close_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+    ) -> ProgramResult {
+        // Admin force-close an abandoned account after market resolution.
+        // Settles PnL (with haircut for positive), forgives fee debt,
+        // then delegates to engine.close_account() for the rest.
+        accounts::expect_len(accounts, 8)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_vault = &accounts[2];
+        let a_owner_ata = &accounts[3];
+        let a_pda = &accounts[4];
+        let a_token = &accounts[5];
+        let a_oracle = &accounts[7];
+
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
+        verify_token_program(a_token)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let header = state::read_header(&data);
+        require_admin(header.admin, a_admin.key)?;
+
+        // Must be resolved
+        if !state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-                let engine = zc::engine_mut(&mut data)?;
-                engine
-                    .top_up_insurance_fund(units as u128)
-                    .map_err(map_risk_error)?;
+        let mut config = state::read_config(&data);
+        let mint = Pubkey::new_from_array(config.collateral_mint);
+
+        let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
+        verify_vault(
+            a_vault,
+            &auth,
+            &mint,
+            &Pubkey::new_from_array(config.vault_pubkey),
+        )?;
+        accounts::expect_key(a_pda, &auth)?;
+
+        let clock = Clock::from_account_info(&accounts[6])?;
+
+        // Read oracle price (hyperp uses last_effective_price_e6)
+        let is_hyperp = oracle::is_hyperp_mode(&config);
+        let price = if is_hyperp {
+            let idx = config.last_effective_price_e6;
+            if idx == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
             }
-            Instruction::SetRiskThreshold { new_threshold } => {
-                accounts::expect_len(accounts, 2)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
+            idx
+        } else {
+            oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
+        };
+        state::write_config(&mut data, &config);
 
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
+        let engine = zc::engine_mut(&mut data)?;
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        check_idx(engine, user_idx)?;
 
-                let header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
+        // Position must be zero (force-closed by prior crank)
+        if !engine.accounts[user_idx as usize].position_size.is_zero() {
+            return Err(PercolatorError::EngineUndercollateralized.into());
+        }
 
-                let engine = zc::engine_mut(&mut data)?;
-                engine.set_risk_reduction_threshold(new_threshold);
-            }
+        // Read account owner pubkey and verify owner ATA
+        let owner_pubkey = Pubkey::new_from_array(engine.accounts[user_idx as usize].owner);
+        verify_token_account(a_owner_ata, &owner_pubkey, &mint)?;
+
+        // Force-settle PnL so close_account's pnl==0 check passes
+        let pnl = engine.accounts[user_idx as usize].pnl.get();
+        let capital = engine.accounts[user_idx as usize].capital.get();
+        if pnl > 0 {
+            let haircutted = engine.effective_pos_pnl(pnl);
+            engine.set_capital(user_idx as usize, capital.saturating_add(haircutted));
+            engine.set_pnl(user_idx as usize, 0);
+        } else if pnl < 0 {
+            let loss = (-pnl) as u128;
+            engine.set_capital(user_idx as usize, capital.saturating_sub(loss));
+            engine.set_pnl(user_idx as usize, 0);
+        }
 
-            Instruction::UpdateAdmin { new_admin } => {
-                accounts::expect_len(accounts, 2)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
+        // Forgive fee debt so close_account doesn't fail
+        engine.accounts[user_idx as usize].fee_credits = percolator::I128::ZERO;
+
+        // close_account: touch_account_full, free_slot, vault decrement
+        let amt_units = engine
+            .close_account(user_idx, clock.slot, price)
+            .map_err(map_risk_error)?;
+        let amt_units_u64: u64 = amt_units
+            .try_into()
+            .map_err(|_| PercolatorError::EngineOverflow)?;
+
+        let base_to_pay = crate::units::units_to_base_checked(amt_units_u64, config.unit_scale)
+            .ok_or(PercolatorError::EngineOverflow)?;
+
+        let seed1: &[u8] = b"vault";
+        let seed2: &[u8] = a_slab.key.as_ref();
+        let bump_arr: [u8; 1] = [config.vault_authority_bump];
+        let seed3: &[u8] = &bump_arr;
+        let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
+        let signer_seeds: [&[&[u8]]; 1] = [&seeds];
+
+        let a_mint = collateral_mint_account(accounts, 8, &config)?;
+        collateral::withdraw(
+            a_token,
+            a_vault,
+            a_mint,
+            a_owner_ata,
+            a_pda,
+            base_to_pay,
+            &signer_seeds,
+        )?;
+        Ok(())
+    }
 
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
+    /// Handler for [`Instruction::SetEmergencyExit`].
+    pub fn handle_set_emergency_exit(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        enabled: u8,
+    ) -> ProgramResult {
+        with_admin_slab(program_id, accounts, |data| {
+            state::set_emergency_exit(data, enabled != 0);
+            Ok(())
+        })
+    }
+
+    /// Handler for [`Instruction::SetMaxTradeNotional`].
+    pub fn handle_set_max_trade_notional(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_trade_notional_e6: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.max_trade_notional_e6 = max_trade_notional_e6;
+            Ok(())
+        })
+    }
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
+    /// Handler for [`Instruction::SetOracleParams`].
+    pub fn handle_set_oracle_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        conf_filter_bps: u16,
+        max_staleness_secs: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            if conf_filter_bps > 10_000 {
+                return Err(ProgramError::InvalidInstructionData);
+            }
 
-                let mut header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
+            config.conf_filter_bps = conf_filter_bps;
+            config.max_staleness_secs = max_staleness_secs;
+            Ok(())
+        })
+    }
 
-                header.admin = new_admin.to_bytes();
-                state::write_header(&mut data, &header);
+    /// Handler for [`Instruction::SetIndexFeeds`].
+    pub fn handle_set_index_feeds(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        secondary_feed_id: [u8; 32],
+        tertiary_feed_id: [u8; 32],
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            // Hyperp markets have no external index to take a median of
+            if oracle::is_hyperp_mode(config) {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let extra = [secondary_feed_id, tertiary_feed_id];
+            if !crate::verify::extra_index_feeds_ok(&config.index_feed_id, &extra) {
+                return Err(ProgramError::InvalidInstructionData);
             }
+            config.extra_index_feed_ids = extra;
+            Ok(())
+        })
+    }
 
-            Instruction::CloseSlab => {
-                accounts::expect_len(accounts, 2)?;
-                let a_dest = &accounts[0];
-                let a_slab = &accounts[1];
+    /// Handler for [`Instruction::SetMaxSweepLag`].
+    pub fn handle_set_max_sweep_lag(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_sweep_lag_slots: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.max_sweep_lag_slots = max_sweep_lag_slots;
+            Ok(())
+        })
+    }
 
-                accounts::expect_signer(a_dest)?;
-                accounts::expect_writable(a_slab)?;
+    /// Handler for [`Instruction::SetInsuranceFloor`].
+    pub fn handle_set_insurance_floor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        insurance_floor: u128,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.insurance_floor = insurance_floor;
+            Ok(())
+        })
+    }
 
-                // With unsafe_close: skip all validation and zeroing (CU limit)
-                // Account will be garbage collected after lamports are drained
-                #[cfg(not(feature = "unsafe_close"))]
-                {
-                    let mut data = state::slab_data_mut(a_slab)?;
-                    slab_guard(program_id, a_slab, &data)?;
-                    require_initialized(&data)?;
+    /// Handler for [`Instruction::SetMaxMarkIndexDivergence`].
+    pub fn handle_set_max_mark_index_divergence(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_mark_index_divergence_bps: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.max_mark_index_divergence_bps = max_mark_index_divergence_bps;
+            Ok(())
+        })
+    }
 
-                    let header = state::read_header(&data);
-                    require_admin(header.admin, a_dest.key)?;
+    /// Handler for [`Instruction::SetRiskParams`].
+    pub fn handle_set_risk_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_params: RiskParams,
+        allow_force: u8,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 4)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
+
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-                    let engine = zc::engine_ref(&data)?;
-                    if !engine.vault.is_zero() {
-                        return Err(PercolatorError::EngineInsufficientBalance.into());
-                    }
-                    if !engine.insurance_fund.balance.is_zero() {
-                        return Err(PercolatorError::EngineInsufficientBalance.into());
-                    }
-                    if engine.num_used_accounts != 0 {
-                        return Err(PercolatorError::EngineAccountNotFound.into());
-                    }
+        let header = state::read_header(&data);
+        require_admin(header.admin, a_admin.key)?;
 
-                    // Bug #3 fix: Check dust_base to prevent closing with unaccounted funds
-                    let dust_base = state::read_dust_base(&data)?;
-                    if dust_base != 0 {
-                        return Err(PercolatorError::EngineInsufficientBalance.into());
-                    }
+        if !crate::verify::margins_ok(
+            new_params.maintenance_margin_bps,
+            new_params.initial_margin_bps,
+        ) {
+            return Err(PercolatorError::InvalidRiskParams.into());
+        }
+        if !crate::verify::risk_params_update_ok(
+            new_params.maintenance_margin_bps,
+            new_params.initial_margin_bps,
+            new_params.trading_fee_bps,
+            new_params.liquidation_fee_bps,
+        ) {
+            return Err(PercolatorError::InvalidConfigParam.into());
+        }
 
-                    // Zero out the slab data to prevent reuse
-                    for b in data.iter_mut() {
-                        *b = 0;
-                    }
+        let old_mm = zc::engine_ref(&data)?.params.maintenance_margin_bps;
+        let tightening = new_params.maintenance_margin_bps > old_mm;
+
+        // Tightening maintenance must not retroactively put open positions
+        // under water, unless the admin explicitly forces it.
+        if tightening && allow_force == 0 {
+            let mut config = state::read_config(&data);
+            let clock = Clock::from_account_info(&accounts[2])?;
+            // Same price source as LiquidateAtOracle
+            let price = if oracle::is_hyperp_mode(&config) {
+                let idx = config.last_effective_price_e6;
+                if idx == 0 {
+                    return Err(PercolatorError::OracleInvalid.into());
+                }
+                idx
+            } else {
+                oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
+            };
+            state::write_config(&mut data, &config);
+
+            let engine = zc::engine_ref(&data)?;
+            for i in 0..percolator::MAX_ACCOUNTS {
+                if !engine.is_used(i) {
+                    continue;
+                }
+                let pos = engine.accounts[i].position_size.get();
+                if pos == 0 {
+                    continue;
+                }
+                let equity = account_equity(engine, i as u16, price);
+                let notional = pos.unsigned_abs().saturating_mul(price as u128) / 1_000_000;
+                if !crate::verify::equity_meets_margin(
+                    equity,
+                    notional,
+                    new_params.maintenance_margin_bps,
+                ) {
+                    return Err(PercolatorError::EngineUndercollateralized.into());
                 }
+            }
+        }
+
+        let engine = zc::engine_mut(&mut data)?;
+        let p = &engine.params;
+        msg!(
+            "SetRiskParams: maintenance_margin_bps {} -> {}",
+            p.maintenance_margin_bps,
+            new_params.maintenance_margin_bps
+        );
+        msg!(
+            "SetRiskParams: initial_margin_bps {} -> {}",
+            p.initial_margin_bps,
+            new_params.initial_margin_bps
+        );
+        msg!(
+            "SetRiskParams: trading_fee_bps {} -> {}",
+            p.trading_fee_bps,
+            new_params.trading_fee_bps
+        );
+        msg!(
+            "SetRiskParams: liquidation_fee_bps {} -> {}",
+            p.liquidation_fee_bps,
+            new_params.liquidation_fee_bps
+        );
+        if tightening && allow_force != 0 {
+            msg!("SetRiskParams: forced");
+        }
+        engine.params.maintenance_margin_bps = new_params.maintenance_margin_bps;
+        engine.params.initial_margin_bps = new_params.initial_margin_bps;
+        engine.params.trading_fee_bps = new_params.trading_fee_bps;
+        engine.params.liquidation_fee_bps = new_params.liquidation_fee_bps;
+        Ok(())
+    }
 
-                // Transfer all lamports from slab to destination
-                let slab_lamports = a_slab.lamports();
-                **a_slab.lamports.borrow_mut() = 0;
-                **a_dest.lamports.borrow_mut() = a_dest
-                    .lamports()
-                    .checked_add(slab_lamports)
-                    .ok_or(PercolatorError::EngineOverflow)?;
+    /// Handler for [`Instruction::SetBackstopParams`].
+    pub fn handle_set_backstop_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        penalty_bps: u64,
+        max_notional_per_slot_e6: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            if penalty_bps > 10_000 {
+                return Err(PercolatorError::InvalidConfigParam.into());
             }
 
-            Instruction::UpdateConfig {
-                funding_horizon_slots,
-                funding_k_bps,
-                funding_inv_scale_notional_e6,
-                funding_max_premium_bps,
-                funding_max_bps_per_slot,
-                thresh_floor,
-                thresh_risk_bps,
-                thresh_update_interval_slots,
-                thresh_step_bps,
-                thresh_alpha_bps,
-                thresh_min,
-                thresh_max,
-                thresh_min_step,
-            } => {
-                accounts::expect_len(accounts, 2)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
+            config.backstop_penalty_bps = penalty_bps;
+            config.backstop_max_notional_per_slot_e6 = max_notional_per_slot_e6;
+            Ok(())
+        })
+    }
 
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
+    /// Handler for [`Instruction::CloseWithBackstop`].
+    pub fn handle_close_with_backstop(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 4)?;
+        let a_user = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        accounts::expect_signer(a_user)?;
+        accounts::expect_writable(a_slab)?;
 
-                let header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
 
-                // Validate parameters
-                if funding_horizon_slots == 0 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                if funding_inv_scale_notional_e6 == 0 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                if thresh_alpha_bps > 10_000 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                if thresh_min > thresh_max {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                // Finding S: bound funding_k_bps to prevent extreme funding rate multipliers
-                if funding_k_bps > 10_000 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                // Bound funding_max_premium_bps (cap at 100% = 10000 bps)
-                if funding_max_premium_bps > 10_000 || funding_max_premium_bps < 0 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                // Bound funding_max_bps_per_slot (cap at 100 bps per slot)
-                if funding_max_bps_per_slot > 100 || funding_max_bps_per_slot < 0 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                // Bound thresh_step_bps
-                if thresh_step_bps > 10_000 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                // Bound thresh_risk_bps
-                if thresh_risk_bps > 10_000 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
-                // Require thresh_update_interval_slots > 0
-                if thresh_update_interval_slots == 0 {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
+        // Resolved markets settle through the resolution crank
+        if state::is_resolved(&data) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-                // Read existing config and update
-                let mut config = state::read_config(&data);
-                config.funding_horizon_slots = funding_horizon_slots;
-                config.funding_k_bps = funding_k_bps;
-                config.funding_inv_scale_notional_e6 = funding_inv_scale_notional_e6;
-                config.funding_max_premium_bps = funding_max_premium_bps;
-                config.funding_max_bps_per_slot = funding_max_bps_per_slot;
-                config.thresh_floor = thresh_floor;
-                config.thresh_risk_bps = thresh_risk_bps;
-                config.thresh_update_interval_slots = thresh_update_interval_slots;
-                config.thresh_step_bps = thresh_step_bps;
-                config.thresh_alpha_bps = thresh_alpha_bps;
-                config.thresh_min = thresh_min;
-                config.thresh_max = thresh_max;
-                config.thresh_min_step = thresh_min_step;
-                state::write_config(&mut data, &config);
-            }
+        let mut config = state::read_config(&data);
+        if config.backstop_max_notional_per_slot_e6 == 0 {
+            return Err(PercolatorError::BackstopUnavailable.into());
+        }
 
-            Instruction::SetMaintenanceFee { new_fee } => {
-                accounts::expect_len(accounts, 2)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
+        let clock = Clock::from_account_info(&accounts[2])?;
+        // Same price source as LiquidateAtOracle
+        let price = if oracle::is_hyperp_mode(&config) {
+            let idx = config.last_effective_price_e6;
+            if idx == 0 {
+                return Err(PercolatorError::OracleInvalid.into());
+            }
+            idx
+        } else {
+            oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
+        };
 
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
+        let engine = zc::engine_mut(&mut data)?;
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        check_idx(engine, user_idx)?;
 
-                let header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
+        let owner = engine.accounts[user_idx as usize].owner;
+        if !crate::verify::owner_ok(owner, a_user.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
 
-                let engine = zc::engine_mut(&mut data)?;
-                engine.params.maintenance_fee_per_slot = percolator::U128::new(new_fee);
-            }
+        let pos = engine.accounts[user_idx as usize].position_size.get();
+        if pos == 0 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
 
-            Instruction::SetOracleAuthority { new_authority } => {
-                accounts::expect_len(accounts, 2)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
+        // Last resort only: while any LP can carry the other side (initial margin
+        // and leverage cap at oracle), the close must go through that LP instead
+        let lp_available = (0..MAX_ACCOUNTS).any(|i| {
+            i != user_idx as usize
+                && engine.is_used(i)
+                && engine.accounts[i].is_lp()
+                && check_initial_margin(engine, i as u16, pos, price).is_ok()
+                && check_leverage_cap(engine, &config, i as u16, pos, price).is_ok()
+        });
+        if lp_available {
+            return Err(PercolatorError::BackstopLpAvailable.into());
+        }
 
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
+        // Per-slot exposure bound
+        if config.backstop_slot != clock.slot {
+            config.backstop_slot = clock.slot;
+            config.backstop_used_notional_e6 = 0;
+        }
+        let notional = pos.unsigned_abs().saturating_mul(price as u128) / 1_000_000;
+        if !crate::verify::backstop_capacity_ok(
+            config.backstop_used_notional_e6,
+            notional,
+            config.backstop_max_notional_per_slot_e6,
+        ) {
+            return Err(PercolatorError::BackstopCapacityExceeded.into());
+        }
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        // Insurance takes the other side at oracle; the penalty goes to insurance
+        with_backstop_params(engine, config.backstop_penalty_bps, |engine| {
+            engine.liquidate_at_oracle(user_idx, clock.slot, price)
+        })
+        .map_err(map_risk_error)?;
+        // Engine declined to fully close (e.g. stale crank): fail rather than half-exit
+        if engine.accounts[user_idx as usize].position_size.get() != 0 {
+            return Err(PercolatorError::BackstopUnavailable.into());
+        }
 
-                let header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
+        // notional <= max_per_slot (u64), so this fits
+        config.backstop_used_notional_e6 += notional as u64;
+        state::write_config(&mut data, &config);
+        Ok(())
+    }
 
-                // Update oracle authority in config
-                let mut config = state::read_config(&data);
-                config.oracle_authority = new_authority.to_bytes();
-                // Clear stored price when authority changes
-                config.authority_price_e6 = 0;
-                config.authority_timestamp = 0;
-                state::write_config(&mut data, &config);
-            }
+    /// Handler for [`Instruction::GetFundingIndex`].
+    pub fn handle_get_funding_index(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 1)?;
+        let a_slab = &accounts[0];
+
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let engine = zc::engine_ref(&data)?;
+        let view = crate::return_data::FundingIndex {
+            funding_index_qpb_e6: engine.funding_index_qpb_e6.get(),
+            last_funding_slot: engine.last_funding_slot,
+            last_crank_slot: engine.last_crank_slot,
+        };
+        set_return_data(&view.to_bytes());
+        Ok(())
+    }
 
-            Instruction::PushOraclePrice {
-                price_e6,
-                timestamp,
-            } => {
-                accounts::expect_len(accounts, 2)?;
-                let a_authority = &accounts[0];
-                let a_slab = &accounts[1];
+    /// Handler for [`Instruction::SimulateLiquidation`].
+    pub fn handle_simulate_liquidation(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        target_idx: u16,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 3)?;
+        let a_slab = &accounts[0];
+        let a_oracle = &accounts[2];
+
+        // The engine liquidation below runs on the slab and is rolled back, so
+        // the account data is left byte-for-byte unchanged
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        // Local config copy: the circuit-breaker update is discarded (read-only)
+        let mut config = state::read_config(&data);
+        let clock = Clock::from_account_info(&accounts[1])?;
+        let reading = oracle::read_oracle(a_oracle, &mut config, &clock)?;
+        let emergency_exit = state::is_emergency_exit(&data);
+
+        let engine = zc::engine_ref(&data)?;
+        check_idx(engine, target_idx)?;
+
+        let pos = engine.accounts[target_idx as usize].position_size.get();
+        let price = reading.liquidation_price(pos)?;
+        let equity = account_equity(engine, target_idx, price);
+        let notional = pos.unsigned_abs().saturating_mul(price as u128) / 1_000_000;
+        let maintenance_requirement =
+            notional.saturating_mul(engine.params.maintenance_margin_bps as u128) / 10_000;
+
+        // Same engine call LiquidateAtOracle makes, so the split is exact
+        // (partial closes included)
+        let run = dry_run_liquidation(&mut data, target_idx, clock.slot, price, emergency_exit)?;
+        let mut view = crate::return_data::LiquidationPreview {
+            price_e6: price,
+            equity,
+            liquidatable: run.liquidated as u8,
+            maintenance_requirement,
+            ..Default::default()
+        };
+        if run.liquidated {
+            view.position_size = run.position_before - run.position_after;
+            view.fee_to_insurance = run.penalty;
+            view.refund_to_account = run.equity_after.max(0) as u128;
+            view.socialized_loss = equity.min(0).unsigned_abs();
+        }
+        set_return_data(&view.to_bytes());
+        Ok(())
+    }
 
-                accounts::expect_signer(a_authority)?;
-                accounts::expect_writable(a_slab)?;
+    /// Handler for [`Instruction::GetAccountState`].
+    pub fn handle_get_account_state(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 1)?;
+        let a_slab = &accounts[0];
+
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let engine = zc::engine_ref(&data)?;
+        check_idx(engine, user_idx)?;
+        let now_slot = match accounts.get(1) {
+            Some(a_clock) => Clock::from_account_info(a_clock)?.slot,
+            None => engine.current_slot,
+        };
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        let acc = &engine.accounts[user_idx as usize];
+        let (pnl_pos, pnl_warmed, full_vest_slot) = crate::verify::warmup_schedule(
+            acc.pnl.get(),
+            acc.reserved_pnl as u128,
+            acc.warmup_slope_per_step.get(),
+            acc.warmup_started_at_slot,
+            engine.params.warmup_period_slots,
+            now_slot,
+        );
+        let warmup_remaining_slots = if full_vest_slot == u64::MAX {
+            u64::MAX
+        } else {
+            full_vest_slot.saturating_sub(now_slot)
+        };
+        let view = crate::return_data::AccountState {
+            idx: user_idx,
+            capital: acc.capital.get(),
+            position_size: acc.position_size.get(),
+            entry_price: acc.entry_price,
+            pnl: acc.pnl.get(),
+            pnl_pos,
+            pnl_warmed,
+            warmup_remaining_slots,
+        };
+        set_return_data(&view.to_bytes());
+        Ok(())
+    }
 
-                // Verify caller is the oracle authority
-                let mut config = state::read_config(&data);
-                let is_hyperp = oracle::is_hyperp_mode(&config);
-                if config.oracle_authority == [0u8; 32] {
-                    return Err(PercolatorError::EngineUnauthorized.into());
-                }
-                if config.oracle_authority != a_authority.key.to_bytes() {
-                    return Err(PercolatorError::EngineUnauthorized.into());
-                }
+    /// Handler for [`Instruction::GetWarmupStatus`].
+    pub fn handle_get_warmup_status(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        user_idx: u16,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 2)?;
+        let a_slab = &accounts[0];
+
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        let clock = Clock::from_account_info(&accounts[1])?;
+
+        let engine = zc::engine_ref(&data)?;
+        check_idx(engine, user_idx)?;
+
+        let acc = &engine.accounts[user_idx as usize];
+        let (total_warming, vested_now, full_vest_slot) = crate::verify::warmup_schedule(
+            acc.pnl.get(),
+            acc.reserved_pnl as u128,
+            acc.warmup_slope_per_step.get(),
+            acc.warmup_started_at_slot,
+            engine.params.warmup_period_slots,
+            clock.slot,
+        );
+        let view = crate::return_data::WarmupStatus {
+            total_warming,
+            vested_now,
+            full_vest_slot,
+        };
+        set_return_data(&view.to_bytes());
+        Ok(())
+    }
 
-                // Validate price (must be positive)
-                if price_e6 == 0 {
-                    return Err(PercolatorError::OracleInvalid.into());
-                }
+    /// Handler for [`Instruction::SetFeatureFlags`].
+    pub fn handle_set_feature_flags(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        set: u64,
+        clear: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.feature_flags = crate::verify::feature_flags_update(
+                config.feature_flags,
+                set,
+                clear,
+                state::FEATURE_KNOWN_MASK,
+            )
+            .ok_or(ProgramError::InvalidInstructionData)?;
+            Ok(())
+        })
+    }
 
-                // For non-Hyperp markets, require monotonic authority timestamps.
-                // This prevents stale rollback pushes from replacing fresher authority data.
-                if !is_hyperp
-                    && config.authority_timestamp != 0
-                    && timestamp < config.authority_timestamp
-                {
-                    return Err(PercolatorError::OracleStale.into());
-                }
+    /// Handler for [`Instruction::GetFeatureFlags`].
+    pub fn handle_get_feature_flags(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 1)?;
+        let a_slab = &accounts[0];
+
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let config = state::read_config(&data);
+        let view = crate::return_data::FeatureFlags {
+            feature_flags: config.feature_flags,
+            market_flags: state::read_flags(&data),
+            market_mode: state::read_market_mode(&data),
+        };
+        set_return_data(&view.to_bytes());
+        Ok(())
+    }
 
-                // Clamp the incoming price against circuit breaker
-                let clamped = oracle::clamp_oracle_price(
-                    config.last_effective_price_e6,
-                    price_e6,
-                    config.oracle_price_cap_e2bps,
-                );
-                config.authority_price_e6 = clamped;
-                // In Hyperp mode this field stores previous funding-rate state (bps/slot),
-                // not unix time. Keep it untouched so PushOraclePrice cannot clobber it.
-                if !is_hyperp {
-                    config.authority_timestamp = timestamp;
-                }
-                config.last_effective_price_e6 = clamped;
-                state::write_config(&mut data, &config);
+    /// Handler for [`Instruction::SetMarketMode`].
+    pub fn handle_set_market_mode(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        mode: u8,
+    ) -> ProgramResult {
+        with_admin_slab(program_id, accounts, |data| {
+            if mode > state::MARKET_MODE_HALTED {
+                return Err(ProgramError::InvalidInstructionData);
             }
 
-            Instruction::SetOraclePriceCap { max_change_e2bps } => {
-                accounts::expect_len(accounts, 2)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
+            state::write_market_mode(data, mode);
+            Ok(())
+        })
+    }
 
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
+    /// Handler for [`Instruction::GetTopDonors`].
+    pub fn handle_get_top_donors(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        accounts::expect_len(accounts, 1)?;
+        let a_slab = &accounts[0];
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
 
-                let header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
+        let config = state::read_config(&data);
+        let view = crate::return_data::TopDonors::from_config(&config);
+        set_return_data(&view.to_bytes());
+        Ok(())
+    }
 
-                // Finding F: cap the cap to prevent admin from effectively disabling it
-                // 500_000 e2bps = 50% max price change per update
-                const MAX_ORACLE_PRICE_CAP_E2BPS: u64 = 500_000;
-                if max_change_e2bps > MAX_ORACLE_PRICE_CAP_E2BPS {
-                    return Err(PercolatorError::InvalidConfigParam.into());
-                }
+    /// Handler for [`Instruction::LiquidateEligible`].
+    pub fn handle_liquidate_eligible(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        cursor: u16,
+        max_scan: u16,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 4)?;
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
+        accounts::expect_writable(a_slab)?;
+        if max_scan == 0 || cursor as usize >= MAX_ACCOUNTS {
+            return Err(ProgramError::InvalidInstructionData);
+        }
 
-                let mut config = state::read_config(&data);
-                config.oracle_price_cap_e2bps = max_change_e2bps;
-                state::write_config(&mut data, &config);
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+        require_not_halted(&data)?;
+        let mut config = state::read_config(&data);
+
+        // Same price source as LiquidateAtOracle
+        let clock = Clock::from_account_info(&accounts[2])?;
+        let reading = oracle::read_oracle(a_oracle, &mut config, &clock)?;
+        state::write_config(&mut data, &config);
+        let emergency_exit = state::is_emergency_exit(&data);
+
+        let engine = zc::engine_mut(&mut data)?;
+
+        // Bounded page so a keeper can split a full-book scan across transactions
+        let end = core::cmp::min(cursor as usize + max_scan as usize, MAX_ACCOUNTS);
+        let mut liquidated: u16 = 0;
+        for idx in cursor as usize..end {
+            let pos = engine.accounts[idx].position_size.get();
+            if !engine.is_used(idx) || pos == 0 {
+                continue;
             }
+            let price = reading.liquidation_price(pos)?;
+            let ins_before = engine.insurance_fund.balance.get();
+            let closed = if emergency_exit {
+                with_fees_waived(engine, |engine| {
+                    engine.liquidate_at_oracle(idx as u16, clock.slot, price)
+                })
+            } else {
+                engine.liquidate_at_oracle(idx as u16, clock.slot, price)
+            }
+            .map_err(map_risk_error)?;
+            if closed {
+                liquidated += 1;
+                let event = crate::events::Liquidated {
+                    target_idx: idx as u16,
+                    penalty: engine
+                        .insurance_fund
+                        .balance
+                        .get()
+                        .saturating_sub(ins_before),
+                };
+                crate::events::emit(&event.to_bytes());
+            }
+        }
 
-            Instruction::ResolveMarket => {
-                // Resolve market: set RESOLVED flag, use admin oracle price for settlement
-                // Positions are force-closed via subsequent KeeperCrank calls (paginated)
-                accounts::expect_len(accounts, 2)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
-
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
+        let scan = crate::return_data::LiquidationScan {
+            scanned: (end - cursor as usize) as u16,
+            liquidated,
+            next_cursor: if end >= MAX_ACCOUNTS { 0 } else { end as u16 },
+        };
+        set_return_data(&scan.to_bytes());
+        Ok(())
+    }
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
+    /// Handler for [`Instruction::GetVaultInfo`].
+    pub fn handle_get_vault_info(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        accounts::expect_len(accounts, 1)?;
+        let a_slab = &accounts[0];
+
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        // Stored bump makes this a single create_program_address, not a search
+        let config = state::read_config(&data);
+        let bump = [config.vault_authority_bump];
+        let auth =
+            Pubkey::create_program_address(&[b"vault", a_slab.key.as_ref(), &bump], program_id)
+                .map_err(|_| ProgramError::InvalidAccountData)?;
+
+        let view = crate::return_data::VaultInfo {
+            vault_authority: auth.to_bytes(),
+            vault_authority_bump: config.vault_authority_bump,
+            vault_pubkey: config.vault_pubkey,
+        };
+        set_return_data(&view.to_bytes());
+        Ok(())
+    }
 
-                let header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
+    /// Handler for [`Instruction::VerifyInvariants`].
+    pub fn handle_verify_invariants(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 2)?;
+        let a_slab = &accounts[0];
+        let a_vault = &accounts[1];
+
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let config = state::read_config(&data);
+        accounts::expect_key(a_vault, &Pubkey::new_from_array(config.vault_pubkey))?;
+        let vault_balance = collateral::token_amount(a_vault)?;
+        let dust_base = state::read_dust_base(&data)?;
+        let engine = zc::engine_ref(&data)?;
+
+        let mut capital_sum = 0u128;
+        let mut pnl_pos_sum = 0u128;
+        for idx in 0..MAX_ACCOUNTS {
+            if !engine.is_used(idx) {
+                continue;
+            }
+            let acc = &engine.accounts[idx];
+            capital_sum = capital_sum.saturating_add(acc.capital.get());
+            pnl_pos_sum = pnl_pos_sum.saturating_add(acc.pnl.get().max(0) as u128);
+        }
 
-                // Can't re-resolve
-                if state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        use crate::return_data::InvariantReport;
+        let mut violations = 0u8;
+        if capital_sum != engine.c_tot.get() {
+            violations |= InvariantReport::CAPITAL_SUM;
+        }
+        if pnl_pos_sum != engine.pnl_pos_tot.get() {
+            violations |= InvariantReport::PNL_POS_SUM;
+        }
+        if !crate::verify::engine_vault_covers(
+            engine.vault.get(),
+            engine.c_tot.get(),
+            engine.insurance_fund.balance.get(),
+        ) {
+            violations |= InvariantReport::ENGINE_VAULT;
+        }
+        if !crate::verify::vault_balance_matches(
+            vault_balance,
+            engine.vault.get(),
+            config.unit_scale,
+            dust_base,
+        ) {
+            violations |= InvariantReport::VAULT_BALANCE;
+        }
 
-                // Require admin oracle price to be set (authority_price_e6 > 0)
-                let config = state::read_config(&data);
-                if config.authority_price_e6 == 0 {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        let report = InvariantReport {
+            violations,
+            capital_sum,
+            pnl_pos_sum,
+            vault_balance,
+            dust_base,
+        };
+        set_return_data(&report.to_bytes());
+        Ok(())
+    }
 
-                // Set the resolved flag
-                state::set_resolved(&mut data);
-            }
+    /// Handler for [`Instruction::SetLpMatcher`].
+    pub fn handle_set_lp_matcher(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        lp_idx: u16,
+        matcher_program: Pubkey,
+        matcher_context: Pubkey,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 4)?;
+        let a_owner = &accounts[0];
+        let a_slab = &accounts[1];
+        let a_matcher_prog = &accounts[2];
+        let a_matcher_ctx = &accounts[3];
+
+        accounts::expect_signer(a_owner)?;
+        accounts::expect_writable(a_slab)?;
+        accounts::expect_key(a_matcher_prog, &matcher_program)?;
+        accounts::expect_key(a_matcher_ctx, &matcher_context)?;
+
+        // Reject a matcher TradeCpi could never call, before it replaces a working one
+        let matcher_shape = crate::verify::MatcherAccountsShape {
+            prog_executable: a_matcher_prog.executable,
+            ctx_executable: a_matcher_ctx.executable,
+            ctx_owner_is_prog: a_matcher_ctx.owner == a_matcher_prog.key,
+            ctx_len_ok: crate::verify::ctx_len_sufficient(a_matcher_ctx.data_len()),
+        };
+        if !crate::verify::matcher_shape_ok(matcher_shape) {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
-            Instruction::WithdrawInsurance => {
-                // Withdraw insurance fund (admin only, requires RESOLVED and all positions closed)
-                accounts::expect_len(accounts, 6)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_admin_ata = &accounts[2];
-                let a_vault = &accounts[3];
-                let a_token = &accounts[4];
-                let a_vault_pda = &accounts[5];
+        let mut data = state::slab_data_mut(a_slab)?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
 
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
-                verify_token_program(a_token)?;
+        let engine = zc::engine_mut(&mut data)?;
+        check_idx(engine, lp_idx)?;
+        let lp = &mut engine.accounts[lp_idx as usize];
+        if !crate::verify::owner_ok(lp.owner, a_owner.key.to_bytes()) {
+            return Err(PercolatorError::EngineUnauthorized.into());
+        }
+        if !lp.is_lp() {
+            return Err(PercolatorError::EngineAccountKindMismatch.into());
+        }
+        lp.matcher_program = matcher_program.to_bytes();
+        lp.matcher_context = matcher_context.to_bytes();
+        Ok(())
+    }
 
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
+    /// Handler for [`Instruction::GetAccountIndex`].
+    pub fn handle_get_account_index(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        owner: Pubkey,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 1)?;
+        let a_slab = &accounts[0];
+
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let engine = zc::engine_ref(&data)?;
+        let owner = owner.to_bytes();
+        let idx = (0..MAX_ACCOUNTS)
+            .find(|&idx| engine.is_used(idx) && engine.accounts[idx].owner == owner)
+            .ok_or(PercolatorError::EngineAccountNotFound)?;
+        set_return_data(&crate::return_data::AccountIndex { idx: idx as u16 }.to_bytes());
+        Ok(())
+    }
 
-                let header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
+    /// Handler for [`Instruction::SetFundingParams`].
+    pub fn handle_set_funding_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_premium_bps: i64,
+        max_bps_per_slot: i64,
+        horizon_slots: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            if !crate::verify::funding_caps_ok(max_premium_bps, max_bps_per_slot, horizon_slots) {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
 
-                // Must be resolved
-                if !state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+            config.funding_max_premium_bps = max_premium_bps;
+            config.funding_max_bps_per_slot = max_bps_per_slot;
+            config.funding_horizon_slots = horizon_slots;
+            Ok(())
+        })
+    }
 
-                let config = state::read_config(&data);
-                let mint = Pubkey::new_from_array(config.collateral_mint);
+    /// Handler for [`Instruction::SetCrankRewards`].
+    pub fn handle_set_crank_rewards(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        per_liquidation: u128,
+        per_sweep: u128,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.crank_reward_per_liquidation = per_liquidation;
+            config.crank_reward_per_sweep = per_sweep;
+            Ok(())
+        })
+    }
 
-                let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
-                verify_vault(
-                    a_vault,
-                    &auth,
-                    &mint,
-                    &Pubkey::new_from_array(config.vault_pubkey),
-                )?;
-                verify_token_account(a_admin_ata, a_admin.key, &mint)?;
-                accounts::expect_key(a_vault_pda, &auth)?;
+    /// Handler for [`Instruction::GetMarketStats`].
+    pub fn handle_get_market_stats(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        accounts::expect_len(accounts, 1)?;
+        let a_slab = &accounts[0];
+
+        let data = a_slab.try_borrow_data()?;
+        slab_guard(program_id, a_slab, &data)?;
+        require_initialized(&data)?;
+
+        let engine = zc::engine_ref(&data)?;
+        let mut long_oi = 0u128;
+        let mut short_oi = 0u128;
+        for idx in 0..MAX_ACCOUNTS {
+            if !engine.is_used(idx) {
+                continue;
+            }
+            let pos = engine.accounts[idx].position_size.get();
+            if pos > 0 {
+                long_oi = long_oi.saturating_add(pos.unsigned_abs());
+            } else {
+                short_oi = short_oi.saturating_add(pos.unsigned_abs());
+            }
+        }
 
-                let engine = zc::engine_mut(&mut data)?;
+        let c_tot = engine.c_tot.get();
+        let insurance = engine.insurance_fund.balance.get();
+        let pnl_pos_tot = engine.pnl_pos_tot.get();
+        let view = crate::return_data::MarketStats {
+            c_tot,
+            long_oi,
+            short_oi,
+            insurance,
+            pnl_pos_tot,
+            haircut_ratio_e6: crate::verify::haircut_ratio_e6(
+                engine.vault.get(),
+                c_tot,
+                insurance,
+                pnl_pos_tot,
+            ),
+            num_used_accounts: engine.num_used_accounts,
+        };
+        set_return_data(&view.to_bytes());
+        Ok(())
+    }
 
-                // Require all positions to be closed (force-closed by crank)
-                // Check that no account has position_size != 0
-                let mut has_open_positions = false;
-                for i in 0..percolator::MAX_ACCOUNTS {
-                    if engine.is_used(i) {
-                        let pos = engine.accounts[i].position_size.get();
-                        if pos != 0 {
-                            has_open_positions = true;
-                            break;
-                        }
-                    }
-                }
-                if has_open_positions {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+    /// Handler for [`Instruction::SetOpenInterestCap`].
+    pub fn handle_set_open_interest_cap(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_open_interest_abs: u128,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.max_open_interest_abs = max_open_interest_abs;
+            Ok(())
+        })
+    }
 
-                // Get insurance balance and convert to base tokens
-                let insurance_units = engine.insurance_fund.balance.get();
-                if insurance_units == 0 {
-                    return Ok(()); // Nothing to withdraw
-                }
+    /// Handler for [`Instruction::LiquidateCpi`].
+    pub fn handle_liquidate_cpi(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        target_idx: u16,
+        lp_idx: u16,
+    ) -> ProgramResult {
+        accounts::expect_len(accounts, 7)?;
+        let a_slab = &accounts[1];
+        let a_oracle = &accounts[3];
+        let a_matcher_prog = &accounts[4];
+        let a_matcher_ctx = &accounts[5];
+        let a_lp_pda = &accounts[6];
+        accounts::expect_writable(a_slab)?;
+        accounts::expect_writable(a_matcher_ctx)?;
+        if lp_idx == target_idx {
+            return Err(PercolatorError::SelfTrade.into());
+        }
 
-                // Cap at u64::MAX for conversion (should never happen in practice)
-                let units_u64 = if insurance_units > u64::MAX as u128 {
-                    u64::MAX
-                } else {
-                    insurance_units as u64
-                };
-                let base_amount = crate::units::units_to_base_checked(units_u64, config.unit_scale)
-                    .ok_or(PercolatorError::EngineOverflow)?;
-
-                // Zero out insurance fund
-                engine.insurance_fund.balance = percolator::U128::ZERO;
-
-                // Transfer from vault to admin
-                let seed1: &[u8] = b"vault";
-                let seed2: &[u8] = a_slab.key.as_ref();
-                let bump_arr: [u8; 1] = [config.vault_authority_bump];
-                let seed3: &[u8] = &bump_arr;
-                let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
-                let signer_seeds: [&[&[u8]]; 1] = [&seeds];
-
-                collateral::withdraw(
-                    a_token,
-                    a_vault,
-                    a_admin_ata,
-                    a_vault_pda,
-                    base_amount,
-                    &signer_seeds,
-                )?;
+        let lp_bytes = lp_idx.to_le_bytes();
+        let bump = check_matcher_accounts(
+            program_id,
+            a_slab,
+            lp_idx,
+            a_matcher_prog,
+            a_matcher_ctx,
+            a_lp_pda,
+        )?;
+
+        // Read-only phase, as in TradeCpi: the slab is not written before the CPI
+        let (lp_account_id, mut config, req_id, lp_matcher_prog, lp_matcher_ctx) = {
+            let data = a_slab.try_borrow_data()?;
+            slab_guard(program_id, a_slab, &*data)?;
+            require_initialized(&*data)?;
+            if state::is_resolved(&*data) {
+                return Err(ProgramError::InvalidAccountData);
             }
+            require_not_halted(&*data)?;
+
+            let config = state::read_config(&*data);
+            let nonce = state::read_req_nonce(&*data)?;
+            let req_id = crate::verify::nonce_on_success(nonce);
+
+            let engine = zc::engine_ref(&*data)?;
+            check_idx(engine, lp_idx)?;
+            check_idx(engine, target_idx)?;
+            let lp_acc = &engine.accounts[lp_idx as usize];
+            if !lp_acc.is_lp() {
+                return Err(PercolatorError::EngineAccountKindMismatch.into());
+            }
+            (
+                lp_acc.account_id,
+                config,
+                req_id,
+                lp_acc.matcher_program,
+                lp_acc.matcher_context,
+            )
+        };
 
-            Instruction::AdminForceCloseAccount { user_idx } => {
-                // Admin force-close an abandoned account after market resolution.
-                // Settles PnL (with haircut for positive), forgives fee debt,
-                // then delegates to engine.close_account() for the rest.
-                accounts::expect_len(accounts, 8)?;
-                let a_admin = &accounts[0];
-                let a_slab = &accounts[1];
-                let a_vault = &accounts[2];
-                let a_owner_ata = &accounts[3];
-                let a_pda = &accounts[4];
-                let a_token = &accounts[5];
-                let a_oracle = &accounts[7];
-
-                accounts::expect_signer(a_admin)?;
-                accounts::expect_writable(a_slab)?;
-                verify_token_program(a_token)?;
-
-                let mut data = state::slab_data_mut(a_slab)?;
-                slab_guard(program_id, a_slab, &data)?;
-                require_initialized(&data)?;
-
-                let header = state::read_header(&data);
-                require_admin(header.admin, a_admin.key)?;
-
-                // Must be resolved
-                if !state::is_resolved(&data) {
-                    return Err(ProgramError::InvalidAccountData);
-                }
+        if !crate::verify::matcher_identity_ok(
+            lp_matcher_prog,
+            lp_matcher_ctx,
+            a_matcher_prog.key.to_bytes(),
+            a_matcher_ctx.key.to_bytes(),
+        ) {
+            return Err(PercolatorError::EngineInvalidMatchingEngine.into());
+        }
 
-                let mut config = state::read_config(&data);
-                let mint = Pubkey::new_from_array(config.collateral_mint);
-
-                let (auth, _) = accounts::derive_vault_authority(program_id, a_slab.key);
-                verify_vault(
-                    a_vault,
-                    &auth,
-                    &mint,
-                    &Pubkey::new_from_array(config.vault_pubkey),
-                )?;
-                accounts::expect_key(a_pda, &auth)?;
-
-                let clock = Clock::from_account_info(&accounts[6])?;
-
-                // Read oracle price (hyperp uses last_effective_price_e6)
-                let is_hyperp = oracle::is_hyperp_mode(&config);
-                let price = if is_hyperp {
-                    let idx = config.last_effective_price_e6;
-                    if idx == 0 {
-                        return Err(PercolatorError::OracleInvalid.into());
-                    }
-                    idx
-                } else {
-                    oracle::read_price_clamped(&mut config, a_oracle, clock.unix_timestamp)?
-                };
-                state::write_config(&mut data, &config);
+        let clock = Clock::from_account_info(&accounts[2])?;
+        // Same price source as LiquidateAtOracle (conservative bound)
+        let reading = oracle::read_oracle(a_oracle, &mut config, &clock)?;
+        let mid = reading.mid_e6;
+
+        // The matcher is asked for exactly what LiquidateAtOracle would close,
+        // taken from a rolled-back run of the engine's liquidation (the slab is
+        // back as it was before the CPI). Healthy targets are a no-op before any
+        // matcher call.
+        let (run, price, slippage_bps) = {
+            let mut data = state::slab_data_mut(a_slab)?;
+            let emergency_exit = state::is_emergency_exit(&data);
+            let pos = zc::engine_ref(&data)?.accounts[target_idx as usize]
+                .position_size
+                .get();
+            let price = reading.liquidation_price(pos)?;
+            let run =
+                dry_run_liquidation(&mut data, target_idx, clock.slot, price, emergency_exit)?;
+            if !run.liquidated {
+                return Ok(());
+            }
+            let slippage_bps = zc::engine_ref(&data)?.params.liquidation_fee_bps;
+            (run, price, slippage_bps)
+        };
+        let close_size = run.position_after.saturating_sub(run.position_before);
+        if !crate::verify::trade_size_in_range(close_size) {
+            return Err(PercolatorError::EngineOverflow.into());
+        }
+        require_matcher_initialized(a_matcher_ctx)?;
+
+        let mut cpi_data = alloc::vec::Vec::with_capacity(MATCHER_CALL_LEN);
+        cpi_data.push(MATCHER_CALL_TAG);
+        cpi_data.extend_from_slice(&req_id.to_le_bytes());
+        cpi_data.extend_from_slice(&lp_idx.to_le_bytes());
+        cpi_data.extend_from_slice(&lp_account_id.to_le_bytes());
+        cpi_data.extend_from_slice(&mid.to_le_bytes());
+        cpi_data.extend_from_slice(&close_size.to_le_bytes());
+        cpi_data.extend_from_slice(&[0u8; 24]); // padding to MATCHER_CALL_LEN
+
+        let ix = SolInstruction {
+            program_id: *a_matcher_prog.key,
+            accounts: alloc::vec![
+                AccountMeta::new_readonly(*a_lp_pda.key, true),
+                AccountMeta::new(*a_matcher_ctx.key, false),
+            ],
+            data: cpi_data,
+        };
+        let bump_arr = [bump];
+        let seeds: &[&[u8]] = &[b"lp", a_slab.key.as_ref(), &lp_bytes, &bump_arr];
+        // A matcher that fails outright aborts the transaction; only a
+        // returned rejection can be fallen back from
+        zc::invoke_signed_trade(&ix, a_lp_pda, a_matcher_ctx, seeds)?;
+
+        let ctx_data = a_matcher_ctx.try_borrow_data()?;
+        let ret = crate::matcher_abi::read_return_from_ctx(&ctx_data)?;
+        let matched = if ret.flags & crate::matcher_abi::FLAG_REJECTED != 0 {
+            false
+        } else {
+            let ret_fields = crate::verify::MatcherReturnFields {
+                abi_version: ret.abi_version,
+                flags: ret.flags,
+                exec_price_e6: ret.exec_price_e6,
+                exec_size: ret.exec_size,
+                req_id: ret.req_id,
+                lp_account_id: ret.lp_account_id,
+                oracle_price_e6: ret.oracle_price_e6,
+                reserved: ret.reserved,
+            };
+            if !crate::verify::abi_ok(ret_fields, lp_account_id, mid, close_size, req_id) {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            crate::verify::liquidation_fill_ok(
+                close_size,
+                ret.exec_size,
+                ret.exec_price_e6,
+                price,
+                slippage_bps,
+            )
+        };
+        drop(ctx_data);
+
+        let mut data = state::slab_data_mut(a_slab)?;
+        state::write_config(&mut data, &config);
+        let emergency_exit = state::is_emergency_exit(&data);
+        let engine = zc::engine_mut(&mut data)?;
+        settle_funding(engine, target_idx);
+        settle_funding(engine, lp_idx);
+
+        // Bad debt (negative equity at the fill) and an LP that cannot carry
+        // the position (margin or leverage cap) both go through oracle seizure
+        // instead
+        let equity_at_exec = account_equity(engine, target_idx, ret.exec_price_e6);
+        let matched = matched
+            && equity_at_exec >= 0
+            && check_initial_margin(engine, lp_idx, -close_size, mid).is_ok()
+            && check_leverage_cap(engine, &config, lp_idx, -close_size, mid).is_ok();
+
+        let ins_before = engine.insurance_fund.balance.get();
+        let liquidated = if matched {
+            let matcher = CpiMatcher {
+                exec_price: ret.exec_price_e6,
+                exec_size: close_size,
+            };
+            let trade = |engine: &mut RiskEngine| {
+                engine.execute_trade(&matcher, lp_idx, target_idx, clock.slot, mid, close_size)
+            };
+            // The fee the engine's own close charged replaces the trading fee
+            let closed_notional =
+                close_size.unsigned_abs().saturating_mul(price as u128) / 1_000_000;
+            if emergency_exit {
+                with_fill_fees_waived(engine, target_idx, lp_idx, close_size, clock.slot, trade)
+            } else {
+                with_liquidation_fee(engine, run.penalty, closed_notional, trade)
+            }
+            .map_err(map_risk_error)?;
+            engine.lifetime_liquidations = engine.lifetime_liquidations.saturating_add(1);
+            true
+        } else {
+            liquidate_at_oracle(engine, target_idx, clock.slot, price, emergency_exit)
+                .map_err(map_risk_error)?
+        };
 
-                let engine = zc::engine_mut(&mut data)?;
+        let event = crate::events::Liquidated {
+            target_idx,
+            penalty: engine
+                .insurance_fund
+                .balance
+                .get()
+                .saturating_sub(ins_before),
+        };
+        // The matcher saw this req_id either way
+        state::write_req_nonce(&mut data, req_id);
+        if liquidated {
+            crate::events::emit(&event.to_bytes());
+        }
+        Ok(())
+    }
 
-                check_idx(engine, user_idx)?;
+    /// Handler for [`Instruction::MigrateSlab`].
+    pub fn handle_migrate_slab(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        from_version: u32,
+    ) -> ProgramResult {
+        use crate::constants::SLAB_LEN_V1;
 
-                // Position must be zero (force-closed by prior crank)
-                if !engine.accounts[user_idx as usize].position_size.is_zero() {
-                    return Err(PercolatorError::EngineUndercollateralized.into());
-                }
+        accounts::expect_len(accounts, 2)?;
+        let a_admin = &accounts[0];
+        let a_slab = &accounts[1];
 
-                // Read account owner pubkey and verify owner ATA
-                let owner_pubkey = Pubkey::new_from_array(engine.accounts[user_idx as usize].owner);
-                verify_token_account(a_owner_ata, &owner_pubkey, &mint)?;
-
-                // Force-settle PnL so close_account's pnl==0 check passes
-                let pnl = engine.accounts[user_idx as usize].pnl.get();
-                let capital = engine.accounts[user_idx as usize].capital.get();
-                if pnl > 0 {
-                    let haircutted = engine.effective_pos_pnl(pnl);
-                    engine.set_capital(user_idx as usize, capital.saturating_add(haircutted));
-                    engine.set_pnl(user_idx as usize, 0);
-                } else if pnl < 0 {
-                    let loss = (-pnl) as u128;
-                    engine.set_capital(user_idx as usize, capital.saturating_sub(loss));
-                    engine.set_pnl(user_idx as usize, 0);
-                }
+        accounts::expect_signer(a_admin)?;
+        accounts::expect_writable(a_slab)?;
+        if a_slab.owner != program_id {
+            return Err(ProgramError::IllegalOwner);
+        }
+        // Version 1 is the only older layout
+        if from_version != 1 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        // At least a legacy version-1 slab (8 bytes shorter, see slab_guard)
+        let len = a_slab.data_len();
+        if len < SLAB_LEN_V1 - 8 {
+            return Err(PercolatorError::InvalidSlabLen.into());
+        }
+        if !crate::verify::slab_migration_ok(from_version, VERSION, len, SLAB_LEN) {
+            return Err(PercolatorError::InvalidSlabLen.into());
+        }
+        {
+            let data = a_slab.try_borrow_data()?;
+            let header = state::read_header(&data);
+            if header.magic != MAGIC {
+                return Err(PercolatorError::NotInitialized.into());
+            }
+            if header.version != from_version {
+                return Err(PercolatorError::InvalidVersion.into());
+            }
+            require_admin(header.admin, a_admin.key)?;
+        }
+
+        // The header, config head and engine stay where they are: the slab only
+        // grows by the config tail (plus a legacy engine's missing 8 bytes), all
+        // zero, so every config field added since version 1 starts disabled
+        a_slab.realloc(SLAB_LEN, true)?;
+        let mut data = state::slab_data_mut(a_slab)?;
+        let mut header = state::read_header(&data);
+        header.version = VERSION;
+        state::write_header(&mut data, &header);
+        Ok(())
+    }
 
-                // Forgive fee debt so close_account doesn't fail
-                engine.accounts[user_idx as usize].fee_credits = percolator::I128::ZERO;
+    /// Handler for [`Instruction::SetLiquidationParams`].
+    pub fn handle_set_liquidation_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        liquidation_fee_bps: u64,
+        liquidation_fee_cap: u128,
+        liquidation_buffer_bps: u64,
+        min_liquidation_abs: u128,
+    ) -> ProgramResult {
+        with_admin_slab(program_id, accounts, |data| {
+            let engine = zc::engine_mut(data)?;
+            if !crate::verify::liquidation_params_ok(
+                engine.params.maintenance_margin_bps,
+                liquidation_fee_bps,
+                liquidation_buffer_bps,
+            ) {
+                return Err(PercolatorError::InvalidConfigParam.into());
+            }
+            // Eligibility is equity vs maintenance_margin_bps only: these change how
+            // much a liquidation closes and charges, never who can be liquidated
+            engine.params.liquidation_fee_bps = liquidation_fee_bps;
+            engine.params.liquidation_fee_cap = percolator::U128::new(liquidation_fee_cap);
+            engine.params.liquidation_buffer_bps = liquidation_buffer_bps;
+            engine.params.min_liquidation_abs = percolator::U128::new(min_liquidation_abs);
+            Ok(())
+        })
+    }
 
-                // close_account: touch_account_full, free_slot, vault decrement
-                let amt_units = engine
-                    .close_account(user_idx, clock.slot, price)
-                    .map_err(map_risk_error)?;
-                let amt_units_u64: u64 = amt_units
-                    .try_into()
-                    .map_err(|_| PercolatorError::EngineOverflow)?;
-
-                let base_to_pay =
-                    crate::units::units_to_base_checked(amt_units_u64, config.unit_scale)
-                        .ok_or(PercolatorError::EngineOverflow)?;
-
-                let seed1: &[u8] = b"vault";
-                let seed2: &[u8] = a_slab.key.as_ref();
-                let bump_arr: [u8; 1] = [config.vault_authority_bump];
-                let seed3: &[u8] = &bump_arr;
-                let seeds: [&[u8]; 3] = [seed1, seed2, seed3];
-                let signer_seeds: [&[&[u8]]; 1] = [&seeds];
-
-                collateral::withdraw(
-                    a_token,
-                    a_vault,
-                    a_owner_ata,
-                    a_pda,
-                    base_to_pay,
-                    &signer_seeds,
-                )?;
+    /// Handler for [`Instruction::ValidateInitParams`].
+    pub fn handle_validate_init_params(
+        index_feed_id: [u8; 32],
+        conf_filter_bps: u16,
+        invert: u8,
+        unit_scale: u32,
+        initial_mark_price_e6: u64,
+        risk_params: &RiskParams,
+    ) -> ProgramResult {
+        use crate::return_data::InitParamsReport;
+        let mut failures = 0u8;
+        // Same checks as handle_init_market
+        if !crate::verify::init_market_scale_ok(unit_scale) {
+            failures |= InitParamsReport::UNIT_SCALE;
+        }
+        if index_feed_id == [0u8; 32] {
+            if initial_mark_price_e6 == 0 {
+                failures |= InitParamsReport::HYPERP_MARK;
+            } else if invert != 0
+                && crate::verify::invert_price_e6(initial_mark_price_e6, invert).is_none()
+            {
+                failures |= InitParamsReport::HYPERP_INVERT;
             }
         }
+        if !crate::verify::margins_ok(
+            risk_params.maintenance_margin_bps,
+            risk_params.initial_margin_bps,
+        ) {
+            failures |= InitParamsReport::MARGINS;
+        }
+        // Bounds the post-init setters (SetRiskParams, SetLiquidationParams,
+        // SetOracleParams) hold the market to
+        if risk_params.trading_fee_bps > 10_000
+            || !crate::verify::liquidation_params_ok(
+                risk_params.maintenance_margin_bps,
+                risk_params.liquidation_fee_bps,
+                risk_params.liquidation_buffer_bps,
+            )
+        {
+            failures |= InitParamsReport::FEES;
+        }
+        if conf_filter_bps > 10_000 {
+            failures |= InitParamsReport::CONF_FILTER;
+        }
+        set_return_data(&InitParamsReport { failures }.to_bytes());
         Ok(())
     }
+
+    /// Handler for [`Instruction::SetMaxLeverage`].
+    pub fn handle_set_max_leverage(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_leverage_x: u64,
+    ) -> ProgramResult {
+        with_admin_config(program_id, accounts, |config| {
+            config.max_leverage_x = max_leverage_x;
+            Ok(())
+        })
+    }
 }
 
 // 10. mod entrypoint
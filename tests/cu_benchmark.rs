@@ -29,10 +29,10 @@ use std::path::PathBuf;
 
 // SLAB_LEN for SBF - differs between test and production
 #[cfg(feature = "test")]
-const SLAB_LEN: usize = 16312; // MAX_ACCOUNTS=64 - haircut-ratio engine + oracle circuit breaker (no padding)
+const SLAB_LEN: usize = 17016; // MAX_ACCOUNTS=64 - haircut-ratio engine + MarketConfig tail (no padding)
 
 #[cfg(not(feature = "test"))]
-const SLAB_LEN: usize = 992560; // MAX_ACCOUNTS=4096 - haircut-ratio engine + oracle circuit breaker (no padding)
+const SLAB_LEN: usize = 993264; // MAX_ACCOUNTS=4096 - haircut-ratio engine + MarketConfig tail (no padding)
 
 #[cfg(feature = "test")]
 const MAX_ACCOUNTS: usize = 64;
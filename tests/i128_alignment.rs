@@ -24,7 +24,7 @@ use spl_token::state::{Account as TokenAccount, AccountState};
 use std::path::PathBuf;
 
 // SLAB_LEN for production BPF (MAX_ACCOUNTS=4096) - haircut-ratio engine (no padding)
-const SLAB_LEN: usize = 992560;
+const SLAB_LEN: usize = 993264;
 const MAX_ACCOUNTS: usize = 4096;
 
 // Pyth Receiver program ID
@@ -28,7 +28,7 @@ use std::path::PathBuf;
 // Note: We use production BPF (not test feature) because test feature
 // bypasses CPI for token transfers, which fails in LiteSVM.
 // Haircut-ratio engine (ADL/socialization scratch arrays removed)
-const SLAB_LEN: usize = 992560; // MAX_ACCOUNTS=4096 + oracle circuit breaker (no padding)
+const SLAB_LEN: usize = 993264; // MAX_ACCOUNTS=4096 + oracle circuit breaker + MarketConfig tail (no padding)
 const MAX_ACCOUNTS: usize = 4096;
 
 // Pyth Receiver program ID
@@ -151,6 +151,14 @@ fn encode_init_lp(matcher: &Pubkey, ctx: &Pubkey, fee: u64) -> Vec<u8> {
     data
 }
 
+fn encode_set_lp_matcher(lp_idx: u16, matcher: &Pubkey, ctx: &Pubkey) -> Vec<u8> {
+    let mut data = vec![52u8];
+    data.extend_from_slice(&lp_idx.to_le_bytes());
+    data.extend_from_slice(matcher.as_ref());
+    data.extend_from_slice(ctx.as_ref());
+    data
+}
+
 fn encode_init_user(fee: u64) -> Vec<u8> {
     let mut data = vec![1u8];
     data.extend_from_slice(&fee.to_le_bytes());
@@ -164,6 +172,13 @@ fn encode_deposit(user_idx: u16, amount: u64) -> Vec<u8> {
     data
 }
 
+fn encode_deposit_wrapped(user_idx: u16, lamports: u64) -> Vec<u8> {
+    let mut data = vec![49u8];
+    data.extend_from_slice(&user_idx.to_le_bytes());
+    data.extend_from_slice(&lamports.to_le_bytes());
+    data
+}
+
 fn encode_trade(lp: u16, user: u16, size: i128) -> Vec<u8> {
     let mut data = vec![6u8];
     data.extend_from_slice(&lp.to_le_bytes());
@@ -1308,8 +1323,13 @@ fn test_bug3_close_slab_with_dust_should_fail() {
         vault_after
     );
 
-    // FIXED: CloseSlab now returns error when dust_base > 0
-    assert!(result.is_err(), "CloseSlab should fail when dust_base > 0");
+    // FIXED: CloseSlab now returns VaultNotEmpty when dust_base > 0
+    let err = result.expect_err("CloseSlab should fail when dust_base > 0");
+    assert!(
+        err.contains("Custom(35)"),
+        "Expected VaultNotEmpty (35), got: {}",
+        err
+    );
 }
 
 // ============================================================================
@@ -1375,25 +1395,22 @@ fn test_bug4_fee_overpayment_should_be_handled() {
 
     // Get vault balance before
     let vault_before = env.vault_balance();
+    let engine_vault_before = env.read_engine_vault();
 
     let user = Keypair::new();
     // Pay 5000 when only 1000 is required
-    let _user_idx = env.init_user_with_fee(&user, 5000);
-
-    // Get vault balance after
-    let vault_after = env.vault_balance();
+    let user_idx = env.init_user_with_fee(&user, 5000);
 
     // Vault received 5000 tokens
-    let deposited = vault_after - vault_before;
+    let deposited = env.vault_balance() - vault_before;
     assert_eq!(deposited, 5000, "Vault should receive full payment");
 
-    // BUG: The excess 4000 is trapped - not credited to user capital,
-    // not tracked in engine.vault (only 1000 is tracked)
-    // After fix: excess should either be rejected or credited to user
-    println!(
-        "Bug #4 test: Deposited {} (required: 1000, excess: {})",
-        deposited,
-        deposited - 1000
+    // FIXED: the 4000 excess is credited to the new account's capital
+    assert_eq!(env.read_account_capital(user_idx), 4000);
+    assert_eq!(
+        env.read_engine_vault() - engine_vault_before,
+        5000,
+        "engine.vault must track the whole payment"
     );
 }
 
@@ -1479,10 +1496,10 @@ fn test_bug6_threshold_slow_ramp_from_zero() {
     println!("Bug #6: Threshold EWMA slow ramp from zero");
     println!("  - When current=0, max_step = min_step (1)");
     println!("  - Even with large target, only increases by 1 per update");
-    println!("  - Fix: Special-case current=0 to allow larger initial step");
+    println!("  - Fixed: current=0 allows the full EWMA step toward target");
 
-    // Note: Full test would require reading threshold from slab state
-    // and verifying it doesn't ramp quickly enough
+    // Covered by unit test test_crank_updates_threshold_from_risk_metric, which
+    // cranks a real LP position and checks the first and subsequent steps
 }
 
 // ============================================================================
@@ -1530,14 +1547,12 @@ fn test_bug7_pending_epoch_wraparound() {
 // Finding L: Margin check uses maintenance instead of initial margin
 // ============================================================================
 
-/// Test that execute_trade() incorrectly uses maintenance_margin_bps instead of
-/// initial_margin_bps, allowing users to open positions at 2x intended leverage.
-///
-/// Finding L from security audit:
+/// Finding L from security audit: opening a trade must check initial_margin_bps,
+/// not maintenance_margin_bps.
 /// - maintenance_margin_bps = 500 (5%)
 /// - initial_margin_bps = 1000 (10%)
-/// - Bug: Trade opening checks 5% margin instead of 10%
-/// - Result: Users can open at ~20x leverage instead of max 10x
+/// - Bug: Trade opening checked 5% margin instead of 10% (~20x leverage instead of 10x)
+/// - Fix: risk-increasing fills are checked against initial margin before execute_trade
 #[test]
 fn test_bug_finding_l_margin_check_uses_maintenance_instead_of_initial() {
     let path = program_path();
@@ -1546,56 +1561,118 @@ fn test_bug_finding_l_margin_check_uses_maintenance_instead_of_initial() {
         return;
     }
 
-    // Finding L: execute_trade() uses maintenance_margin_bps (5%) instead of
-    // initial_margin_bps (10%), allowing 2x intended leverage.
-    //
-    // RiskParams in encode_init_market:
-    //   maintenance_margin_bps = 500 (5%)
-    //   initial_margin_bps = 1000 (10%)
-    //
-    // Test: deposit enough to pass maintenance but fail initial margin check.
-    // BUG: trade succeeds when it should be rejected.
-
+    // invert=1: the $138 oracle price becomes 1e12 / 138_000_000 = 7246
     let mut env = TestEnv::new();
     env.init_market_with_invert(1);
 
-    // Create LP with sufficient capital
     let lp = Keypair::new();
     let lp_idx = env.init_lp(&lp);
     env.deposit(&lp, lp_idx, 100_000_000_000); // 100 SOL
 
-    // Create user with capital between maintenance and initial margin requirements
+    // For 10 SOL notional at the inverted price:
+    //   Maintenance margin (5%) = 0.5 SOL
+    //   Initial margin (10%) = 1.0 SOL
+    // Deposit 0.6 SOL (above maint, below initial)
     let user = Keypair::new();
     let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 600_000_000); // 0.6 SOL
+
+    // size = notional * 1_000_000 / price = 10_000_000_000 * 1_000_000 / 7_246
+    let size: i128 = 1_380_071_763_731; // ~10 SOL notional at 7246
+
+    let result = env.try_trade(&user, &lp, lp_idx, user_idx, size);
+    let err = result.expect_err("Trade at ~16.7x leverage must be rejected (initial margin 10%)");
+    assert!(
+        err.contains("Custom(14)"),
+        "Expected EngineUndercollateralized (14), got: {}",
+        err
+    );
+    assert_eq!(env.read_account_position(user_idx), 0);
+}
 
-    // For 10 SOL notional at $138 price:
+/// Finding L on a non-inverted market: the same ~10 SOL open at $138 directly.
+#[test]
+fn test_bug_finding_l_margin_check_uses_maintenance_instead_of_initial_invert_zero() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    // invert=0 so the $138 oracle price is used directly and the notional is ~10 SOL
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+
+    let lp = Keypair::new();
+    let lp_idx = env.init_lp(&lp);
+    env.deposit(&lp, lp_idx, 100_000_000_000); // 100 SOL
+
+    // For 10 SOL notional at $138:
     //   Maintenance margin (5%) = 0.5 SOL
     //   Initial margin (10%) = 1.0 SOL
     // Deposit 0.6 SOL (above maint, below initial)
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
     env.deposit(&user, user_idx, 600_000_000); // 0.6 SOL
 
-    // Calculate position size for ~10 SOL notional
-    // size * price / 1_000_000 = notional
     // size = notional * 1_000_000 / price = 10_000_000_000 * 1_000_000 / 138_000_000
     let size: i128 = 72_463_768; // ~10 SOL notional at $138
 
-    // BUG: This trade should be REJECTED (equity 0.6 < initial margin 1.0)
-    // But it is ACCEPTED (equity 0.6 > maintenance margin 0.5)
     let result = env.try_trade(&user, &lp, lp_idx, user_idx, size);
+    let err = result.expect_err("Trade at ~16.7x leverage must be rejected (initial margin 10%)");
+    assert!(
+        err.contains("Custom(14)"),
+        "Expected EngineUndercollateralized (14), got: {}",
+        err
+    );
+    assert_eq!(env.read_account_position(user_idx), 0);
+}
+
+/// Companion to Finding L: an open at exactly 10% initial margin is accepted, and once
+/// the position sits between maintenance and initial margin it can still be reduced
+/// (but not increased).
+#[test]
+fn test_finding_l_open_at_initial_margin_and_reduce_at_maintenance() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+
+    let lp = Keypair::new();
+    let lp_idx = env.init_lp(&lp);
+    env.deposit(&lp, lp_idx, 100_000_000_000); // 100 SOL
+
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 1_000_000_000); // 1 SOL = 10% of 10 SOL notional
+
+    let size: i128 = 72_463_768; // ~10 SOL notional at $138
+    env.try_trade(&user, &lp, lp_idx, user_idx, size)
+        .expect("open at exactly 10% initial margin must succeed");
+    assert_eq!(env.read_account_position(user_idx), size);
 
+    // $138 -> $132: loss ~0.43 SOL, equity ~0.57 SOL on ~9.57 SOL notional (~5.9%)
+    env.set_slot_and_price(100, 132_000_000);
+    env.crank();
+
+    // Increasing is an open: needs 10%
+    let err = env
+        .try_trade(&user, &lp, lp_idx, user_idx, 1_000_000)
+        .expect_err("increase below initial margin must be rejected");
     assert!(
-        result.is_ok(),
-        "FINDING L REPRODUCED: Trade at ~16.7x leverage accepted. \
-         Should require 10% initial margin but only checks 5% maintenance. \
-         Expected: Ok (bug), Got: {:?}",
-        result
+        err.contains("Custom(14)"),
+        "Expected EngineUndercollateralized (14), got: {}",
+        err
     );
 
-    println!("FINDING L CONFIRMED: execute_trade() checks maintenance_margin_bps (5%)");
-    println!("instead of initial_margin_bps (10%). User opened position at ~16.7x leverage.");
-    println!("Position notional: ~10 SOL, Equity: 0.6 SOL");
-    println!("Maintenance margin required: 0.5 SOL (passes)");
-    println!("Initial margin required: 1.0 SOL (should fail but doesn't)");
+    // Reducing only answers to maintenance (5%)
+    env.try_trade(&user, &lp, lp_idx, user_idx, -size / 2)
+        .expect("reduce above maintenance margin must succeed");
+    assert_eq!(env.read_account_position(user_idx), size - size / 2);
 }
 
 /// Corrected version of Finding L test - uses invert=0 for accurate notional calculation.
@@ -1979,13 +2056,10 @@ fn test_hyperp_issue_trade_nocpi_sets_mark_equals_index() {
 /// In clamp_toward_with_dt():
 ///   if cap_e2bps == 0 || dt_slots == 0 { return mark; }
 ///
-/// When oracle_price_cap_e2bps == 0 (the InitMarket default), the index
-/// immediately jumps to mark without any rate limiting.
-///
-/// This means the "smooth index chase" feature is disabled by default!
-/// Admin must call SetOraclePriceCap after InitMarket to enable smoothing.
-///
-/// This is a KNOWN CONFIGURATION ISSUE.
+/// FIXED: Hyperp markets start at DEFAULT_HYPERP_PRICE_CAP_E2BPS (1% per slot),
+/// and SetOraclePriceCap rejects 0 on a Hyperp market with HyperpCapRequired
+/// (Custom(36)), so smoothing cannot be disabled after init. Standard oracle
+/// markets may still set cap = 0.
 #[test]
 fn test_hyperp_issue_default_cap_zero_bypasses_smoothing() {
     let path = program_path();
@@ -1994,17 +2068,11 @@ fn test_hyperp_issue_default_cap_zero_bypasses_smoothing() {
         return;
     }
 
-    println!("HYPERP CONFIGURATION ISSUE: Default oracle_price_cap_e2bps = 0");
-    println!("In InitMarket, oracle_price_cap_e2bps defaults to 0.");
-    println!("When cap == 0, clamp_toward_with_dt() returns mark immediately.");
-    println!("This means index smoothing is DISABLED by default!");
+    println!("HYPERP CONFIGURATION FIX: oracle_price_cap_e2bps can never be 0");
+    println!("InitMarket defaults Hyperp markets to a 1% per slot cap.");
+    println!("SetOraclePriceCap(0) on a Hyperp market fails with HyperpCapRequired.");
     println!("");
-    println!("Fix: Admin must call SetOraclePriceCap to set a non-zero value");
-    println!("     after InitMarket to enable rate-limited index smoothing.");
-    println!("");
-    println!("Example: SetOraclePriceCap with max_change_e2bps = 1000 (0.1% per slot)");
-
-    // This test documents the configuration requirement
+    println!("Covered by unit test test_hyperp_requires_nonzero_price_cap");
 }
 
 // ============================================================================
@@ -4334,6 +4402,13 @@ fn encode_trade_cpi(lp_idx: u16, user_idx: u16, size: i128) -> Vec<u8> {
     data
 }
 
+fn encode_liquidate_cpi(target_idx: u16, lp_idx: u16) -> Vec<u8> {
+    let mut data = vec![59u8]; // LiquidateCpi instruction tag
+    data.extend_from_slice(&target_idx.to_le_bytes());
+    data.extend_from_slice(&lp_idx.to_le_bytes());
+    data
+}
+
 /// Test environment extended for TradeCpi tests
 struct TradeCpiTestEnv {
     svm: LiteSVM,
@@ -5312,6 +5387,73 @@ impl TradeCpiTestEnv {
             .map(|_| ())
             .map_err(|e| format!("{:?}", e))
     }
+
+    /// Create and initialize a fresh matcher context bound to `lp_idx`'s PDA
+    fn create_matcher_ctx_for_lp(&mut self, payer: &Keypair, lp_idx: u16) -> Pubkey {
+        let lp_bytes = lp_idx.to_le_bytes();
+        let (lp_pda, _) =
+            Pubkey::find_program_address(&[b"lp", self.slab.as_ref(), &lp_bytes], &self.program_id);
+        let ctx = Pubkey::new_unique();
+        self.svm
+            .set_account(
+                ctx,
+                Account {
+                    lamports: 10_000_000,
+                    data: vec![0u8; MATCHER_CONTEXT_LEN],
+                    owner: self.matcher_program_id,
+                    executable: false,
+                    rent_epoch: 0,
+                },
+            )
+            .unwrap();
+        let init_ix = Instruction {
+            program_id: self.matcher_program_id,
+            accounts: vec![
+                AccountMeta::new_readonly(lp_pda, false),
+                AccountMeta::new(ctx, false),
+            ],
+            data: encode_init_vamm(MatcherMode::Passive, 5, 10, 200, 0, 0, 1_000_000_000_000, 0),
+        };
+        let tx = Transaction::new_signed_with_payer(
+            &[init_ix],
+            Some(&payer.pubkey()),
+            &[payer],
+            self.svm.latest_blockhash(),
+        );
+        self.svm
+            .send_transaction(tx)
+            .expect("init matcher context failed");
+        ctx
+    }
+
+    fn try_set_lp_matcher(
+        &mut self,
+        owner: &Keypair,
+        lp_idx: u16,
+        matcher_prog: &Pubkey,
+        matcher_ctx: &Pubkey,
+    ) -> Result<(), String> {
+        let ix = Instruction {
+            program_id: self.program_id,
+            accounts: vec![
+                AccountMeta::new(owner.pubkey(), true),
+                AccountMeta::new(self.slab, false),
+                AccountMeta::new_readonly(*matcher_prog, false),
+                AccountMeta::new_readonly(*matcher_ctx, false),
+            ],
+            data: encode_set_lp_matcher(lp_idx, matcher_prog, matcher_ctx),
+        };
+        let tx = Transaction::new_signed_with_payer(
+            &[ix],
+            Some(&owner.pubkey()),
+            &[owner],
+            self.svm.latest_blockhash(),
+        );
+        self.svm
+            .send_transaction(tx)
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e))
+    }
 }
 
 // ============================================================================
@@ -5782,6 +5924,61 @@ fn test_attack_tradecpi_alias_slab_as_matcher_context_rejected() {
     );
 }
 
+/// A correctly-owned but never-initialized matcher context passes the shape
+/// checks; TradeCpi must refuse it with MatcherNotInitialized rather than read
+/// a return out of zeroed bytes.
+#[test]
+fn test_tradecpi_rejects_uninitialized_matcher_context() {
+    let Some(mut env) = TradeCpiTestEnv::new() else {
+        println!("SKIP: Programs not found. Run: cargo build-sbf && cd ../percolator-match && cargo build-sbf");
+        return;
+    };
+
+    env.init_market();
+    let matcher_prog = env.matcher_program_id;
+
+    // Owned by the real matcher and long enough, but InitVamm never ran
+    let zeroed_ctx = Pubkey::new_unique();
+    env.svm
+        .set_account(
+            zeroed_ctx,
+            Account {
+                lamports: 10_000_000,
+                data: vec![0u8; MATCHER_CONTEXT_LEN],
+                owner: matcher_prog,
+                executable: false,
+                rent_epoch: 0,
+            },
+        )
+        .unwrap();
+
+    let lp = Keypair::new();
+    let lp_idx = env.init_lp_with_raw_matcher(&lp, &matcher_prog, &zeroed_ctx);
+    env.deposit(&lp, lp_idx, 100_000_000_000);
+
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 10_000_000_000);
+
+    let result = env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        1_000_000,
+        &matcher_prog,
+        &zeroed_ctx,
+    );
+    let err = result.expect_err("TradeCpi must reject an uninitialized matcher context");
+    assert!(
+        err.contains("Custom(45)"),
+        "Expected MatcherNotInitialized (Custom(45)), got: {}",
+        err
+    );
+    assert_eq!(env.read_account_position(user_idx), 0);
+    assert_eq!(env.read_account_position(lp_idx), 0);
+}
+
 // ============================================================================
 // Test: Multiple LPs have independent matcher bindings
 // ============================================================================
@@ -15986,8 +16183,8 @@ fn test_attack_multiple_liquidations_insurance_drain() {
     );
 }
 
-/// ATTACK: Deposit zero amount should be a no-op.
-/// Verify depositing 0 tokens doesn't affect state.
+/// ATTACK: Deposit zero amount must not affect state.
+/// Zero deposits are rejected (InvalidInstructionData), leaving state unchanged.
 #[test]
 fn test_attack_deposit_zero_amount_noop() {
     let path = program_path();
@@ -16010,8 +16207,8 @@ fn test_attack_deposit_zero_amount_noop() {
 
     let cap_before = env.read_account_capital(user_idx);
 
-    // Deposit 0 should succeed as no-op
-    env.deposit(&user, user_idx, 0);
+    // Deposit 0 is rejected
+    assert!(env.try_deposit(&user, user_idx, 0).is_err());
 
     let cap_after = env.read_account_capital(user_idx);
     assert_eq!(
@@ -22909,8 +23106,8 @@ fn test_attack_invert_price_zero_result() {
 }
 
 /// ATTACK: Inverted market with raw price = 1 (smallest non-zero).
-/// invert_price_e6(1, 1) = 10^12 / 1 = 10^12 → within u64 range.
-/// Verify the market handles extreme inverted prices.
+/// invert_price_e6(1, 1) would be 10^12, but raw is below
+/// MIN_INVERTIBLE_PRICE_E6, so the oracle read fails with OracleInvalid.
 #[test]
 fn test_attack_invert_price_extreme_small_raw() {
     let path = program_path();
@@ -22933,10 +23130,13 @@ fn test_attack_invert_price_extreme_small_raw() {
     env.crank();
     env.trade(&user, &lp, lp_idx, user_idx, 1_000_000);
 
-    // Set raw price = 1 → inverted = 10^12
-    // Circuit breaker will cap the movement, but the inverted price is valid
+    // Set raw price = 1: below the inversion floor, so the crank is rejected
     env.set_slot_and_price(200, 1);
     let crank_result = env.try_crank();
+    assert!(
+        crank_result.is_err(),
+        "Crank must reject a raw price below the inversion floor"
+    );
 
     // Conservation must hold regardless
     let vault = env.vault_balance();
@@ -28866,3 +29066,1103 @@ fn test_honest_participants_standard_market_full_lifecycle() {
 
     println!("HONEST PARTICIPANTS STANDARD MARKET FULL LIFECYCLE: PASSED");
 }
+
+// ============================================================================
+// Hyperp mark/index divergence guard
+// ============================================================================
+
+fn encode_set_max_mark_index_divergence(max_bps: u64) -> Vec<u8> {
+    let mut data = vec![29u8]; // Tag 29: SetMaxMarkIndexDivergence
+    data.extend_from_slice(&max_bps.to_le_bytes());
+    data
+}
+
+impl TradeCpiTestEnv {
+    fn try_set_max_mark_index_divergence(
+        &mut self,
+        admin: &Keypair,
+        max_bps: u64,
+    ) -> Result<(), String> {
+        let ix = Instruction {
+            program_id: self.program_id,
+            accounts: vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new(self.slab, false),
+            ],
+            data: encode_set_max_mark_index_divergence(max_bps),
+        };
+
+        let tx = Transaction::new_signed_with_payer(
+            &[ix],
+            Some(&admin.pubkey()),
+            &[admin],
+            self.svm.latest_blockhash(),
+        );
+        self.svm
+            .send_transaction(tx)
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    /// Overwrite the Hyperp mark (authority_price_e6) directly, leaving the index alone.
+    fn force_hyperp_mark(&mut self, mark_e6: u64) {
+        const AUTH_PRICE_OFF: usize = 360;
+        let mut slab = self.svm.get_account(&self.slab).unwrap();
+        slab.data[AUTH_PRICE_OFF..AUTH_PRICE_OFF + 8].copy_from_slice(&mark_e6.to_le_bytes());
+        self.svm.set_account(self.slab, slab).unwrap();
+    }
+}
+
+/// Hyperp: while mark and index diverge beyond max_mark_index_divergence_bps,
+/// opening trades are rejected but closes still go through.
+#[test]
+fn test_hyperp_mark_index_divergence_blocks_opens_allows_closes() {
+    let Some(mut env) = TradeCpiTestEnv::new() else {
+        println!("SKIP: Programs not found");
+        return;
+    };
+
+    env.init_market_hyperp(1_000_000); // mark = index = 1.0
+    let admin = Keypair::from_bytes(&env.payer.to_bytes()).unwrap();
+    let mp = env.matcher_program_id;
+    env.try_set_oracle_authority(&admin, &admin.pubkey())
+        .expect("oracle authority setup must succeed");
+
+    let payer = Keypair::from_bytes(&env.payer.to_bytes()).unwrap();
+    env.top_up_insurance(&payer, 1);
+
+    let lp = Keypair::new();
+    let (lp_idx, matcher_ctx) = env.init_lp_with_matcher(&lp, &mp);
+    env.deposit(&lp, lp_idx, 100_000_000_000);
+
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 10_000_000_000);
+
+    env.try_push_oracle_price(&admin, 1_000_000, 1000)
+        .expect("oracle price push must succeed");
+    env.set_slot(100);
+    env.crank();
+
+    env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        100_000,
+        &mp,
+        &matcher_ctx,
+    )
+    .expect("open before guard must succeed");
+
+    // 5% guard, then push mark 10% away from index
+    env.try_set_max_mark_index_divergence(&admin, 500)
+        .expect("admin must be able to set divergence guard");
+    env.force_hyperp_mark(1_100_000);
+
+    let result = env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        10_000,
+        &mp,
+        &matcher_ctx,
+    );
+    assert!(result.is_err(), "open must be rejected while diverged");
+    assert_eq!(env.read_account_position(user_idx), 100_000);
+
+    env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        -50_000,
+        &mp,
+        &matcher_ctx,
+    )
+    .expect("close must proceed while diverged");
+    assert_eq!(env.read_account_position(user_idx), 50_000);
+
+    // Still diverged: opens stay blocked
+    env.force_hyperp_mark(1_100_000);
+    let result = env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        20_000,
+        &mp,
+        &matcher_ctx,
+    );
+    assert!(result.is_err(), "open must be rejected while diverged");
+
+    // Reconverge (push sets mark = index), opens allowed again
+    env.try_push_oracle_price(&admin, 1_000_000, 2000)
+        .expect("oracle price push must succeed");
+    env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        30_000,
+        &mp,
+        &matcher_ctx,
+    )
+    .expect("open must succeed after convergence");
+    assert_eq!(env.read_account_position(user_idx), 80_000);
+}
+
+// ============================================================================
+// SweepDust: clear residual dust so CloseSlab can succeed
+// ============================================================================
+
+fn encode_sweep_dust() -> Vec<u8> {
+    vec![33u8]
+}
+
+impl TestEnv {
+    fn try_sweep_dust(&mut self, admin: &Keypair, admin_ata: Pubkey) -> Result<(), String> {
+        let (vault_pda, _) =
+            Pubkey::find_program_address(&[b"vault", self.slab.as_ref()], &self.program_id);
+        let ix = Instruction {
+            program_id: self.program_id,
+            accounts: vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new(self.slab, false),
+                AccountMeta::new(admin_ata, false),
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new_readonly(spl_token::ID, false),
+                AccountMeta::new_readonly(vault_pda, false),
+            ],
+            data: encode_sweep_dust(),
+        };
+        let tx = Transaction::new_signed_with_payer(
+            &[ix],
+            Some(&admin.pubkey()),
+            &[admin],
+            self.svm.latest_blockhash(),
+        );
+        self.svm
+            .send_transaction(tx)
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e))
+    }
+}
+
+#[test]
+fn test_sweep_dust_then_close_slab() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_full(0, 1000, 0);
+
+    // 10_000 units + 500 base of dust
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 10_000_500);
+    env.set_slot(200);
+    env.crank();
+    env.close_account(&user, user_idx);
+    assert_eq!(env.vault_balance(), 500);
+
+    let admin = Keypair::from_bytes(&env.payer.to_bytes()).unwrap();
+
+    // Non-admin cannot sweep
+    let attacker = Keypair::new();
+    env.svm.airdrop(&attacker.pubkey(), 1_000_000_000).unwrap();
+    let attacker_ata = env.create_ata(&attacker.pubkey(), 0);
+    assert!(env.try_sweep_dust(&attacker, attacker_ata).is_err());
+
+    let admin_ata = env.create_ata(&admin.pubkey(), 0);
+    env.try_sweep_dust(&admin, admin_ata)
+        .expect("admin sweep must succeed");
+    assert_eq!(env.vault_balance(), 0);
+    let ata = env.svm.get_account(&admin_ata).unwrap();
+    assert_eq!(TokenAccount::unpack(&ata.data).unwrap().amount, 500);
+
+    env.try_close_slab()
+        .expect("CloseSlab must succeed once dust is swept");
+}
+
+// ============================================================================
+// MultiTrade: all legs fill or none do
+// ============================================================================
+
+fn encode_multi_trade(user_idx: u16, legs: &[(u16, i128)]) -> Vec<u8> {
+    let mut data = vec![38u8];
+    data.extend_from_slice(&user_idx.to_le_bytes());
+    data.push(legs.len() as u8);
+    for &(lp_idx, size) in legs {
+        data.extend_from_slice(&lp_idx.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+    }
+    data
+}
+
+impl TestEnv {
+    fn try_multi_trade(
+        &mut self,
+        user: &Keypair,
+        user_idx: u16,
+        legs: &[(&Keypair, u16, i128)],
+    ) -> Result<(), String> {
+        let mut accounts = vec![
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(self.slab, false),
+            AccountMeta::new_readonly(sysvar::clock::ID, false),
+            AccountMeta::new_readonly(self.pyth_index, false),
+        ];
+        let mut signers = vec![user];
+        for (lp, _, _) in legs {
+            accounts.push(AccountMeta::new_readonly(lp.pubkey(), true));
+            signers.push(*lp);
+        }
+        let data: Vec<(u16, i128)> = legs.iter().map(|&(_, idx, size)| (idx, size)).collect();
+        let ix = Instruction {
+            program_id: self.program_id,
+            accounts,
+            data: encode_multi_trade(user_idx, &data),
+        };
+        let tx = Transaction::new_signed_with_payer(
+            &[ix],
+            Some(&user.pubkey()),
+            &signers,
+            self.svm.latest_blockhash(),
+        );
+        self.svm
+            .send_transaction(tx)
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e))
+    }
+}
+
+#[test]
+fn test_multi_trade_failed_second_leg_rolls_back_first() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+
+    let lp_a = Keypair::new();
+    let lp_a_idx = env.init_lp(&lp_a);
+    env.deposit(&lp_a, lp_a_idx, 100_000_000_000); // 100 SOL
+    let lp_b = Keypair::new();
+    let lp_b_idx = env.init_lp(&lp_b);
+    env.deposit(&lp_b, lp_b_idx, 200_000_000); // 0.2 SOL
+
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 10_000_000_000); // 10 SOL
+
+    // ~1 SOL + ~1 SOL notional at $138: both LPs covered
+    env.try_multi_trade(
+        &user,
+        user_idx,
+        &[(&lp_a, lp_a_idx, 7_246_376), (&lp_b, lp_b_idx, 7_246_376)],
+    )
+    .expect("two-leg trade must succeed");
+    assert_eq!(env.read_account_position(user_idx), 14_492_752);
+    assert_eq!(env.read_account_position(lp_a_idx), -7_246_376);
+    assert_eq!(env.read_account_position(lp_b_idx), -7_246_376);
+
+    // Second leg (LP B to ~11 SOL notional) is beyond its 0.2 SOL initial margin:
+    // the first leg must not survive
+    env.set_slot(100);
+    let err = env
+        .try_multi_trade(
+            &user,
+            user_idx,
+            &[(&lp_a, lp_a_idx, 7_246_376), (&lp_b, lp_b_idx, 72_463_768)],
+        )
+        .expect_err("undercollateralized second leg must fail the whole trade");
+    assert!(
+        err.contains("Custom(14)"),
+        "Expected EngineUndercollateralized (14), got: {}",
+        err
+    );
+    assert_eq!(env.read_account_position(user_idx), 14_492_752);
+    assert_eq!(env.read_account_position(lp_a_idx), -7_246_376);
+    assert_eq!(env.read_account_position(lp_b_idx), -7_246_376);
+}
+
+// ============================================================================
+// Structured log events (PERC1)
+// ============================================================================
+
+/// Decode every `PERC1:` event payload found in a transaction's logs.
+fn perc_events(logs: &[String]) -> Vec<Vec<u8>> {
+    use percolator_prog::events::{decode_line, MAX_EVENT_LEN, PREFIX};
+
+    logs.iter()
+        .filter(|l| l.starts_with("Program log: ") && l.contains(PREFIX))
+        .map(|l| {
+            let mut out = [0u8; MAX_EVENT_LEN];
+            let n = decode_line(l, &mut out).expect("PERC1 payload must be valid base64");
+            out[..n].to_vec()
+        })
+        .collect()
+}
+
+#[test]
+fn test_trade_and_crank_emit_decodable_events() {
+    use percolator_prog::events::{Cranked, TradeExecuted};
+
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+
+    let lp = Keypair::new();
+    let lp_idx = env.init_lp(&lp);
+    env.deposit(&lp, lp_idx, 10_000_000_000);
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 1_000_000_000);
+
+    let ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(lp.pubkey(), true),
+            AccountMeta::new(env.slab, false),
+            AccountMeta::new_readonly(sysvar::clock::ID, false),
+            AccountMeta::new_readonly(env.pyth_index, false),
+        ],
+        data: encode_trade(lp_idx, user_idx, 1_000_000),
+    };
+    let tx = Transaction::new_signed_with_payer(
+        &[ix],
+        Some(&user.pubkey()),
+        &[&user, &lp],
+        env.svm.latest_blockhash(),
+    );
+    let meta = env.svm.send_transaction(tx).expect("trade failed");
+    let events = perc_events(&meta.logs);
+    assert_eq!(events.len(), 1, "logs: {:#?}", meta.logs);
+    let trade = TradeExecuted::from_bytes(&events[0]).expect("TradeExecuted payload");
+    assert_eq!(trade.user_idx, user_idx);
+    assert_eq!(trade.lp_idx, lp_idx);
+    assert_eq!(trade.size, 1_000_000);
+    assert_eq!(trade.exec_price, 138_000_000);
+
+    env.set_slot(10);
+    let caller = Keypair::new();
+    env.svm.airdrop(&caller.pubkey(), 1_000_000_000).unwrap();
+    let ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![
+            AccountMeta::new(caller.pubkey(), true),
+            AccountMeta::new(env.slab, false),
+            AccountMeta::new_readonly(sysvar::clock::ID, false),
+            AccountMeta::new_readonly(env.pyth_index, false),
+        ],
+        data: encode_crank_permissionless(),
+    };
+    let tx = Transaction::new_signed_with_payer(
+        &[ix],
+        Some(&caller.pubkey()),
+        &[&caller],
+        env.svm.latest_blockhash(),
+    );
+    let meta = env.svm.send_transaction(tx).expect("crank failed");
+    let events = perc_events(&meta.logs);
+    assert_eq!(events.len(), 1, "logs: {:#?}", meta.logs);
+    let crank = Cranked::from_bytes(&events[0]).expect("Cranked payload");
+    assert_eq!(crank.slot, 10);
+}
+
+#[test]
+fn test_trade_returns_execution_result() {
+    use percolator_prog::events::TradeExecuted;
+    use percolator_prog::return_data::TradeExecution;
+
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+
+    let lp = Keypair::new();
+    let lp_idx = env.init_lp(&lp);
+    env.deposit(&lp, lp_idx, 10_000_000_000);
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 1_000_000_000);
+
+    let trade = |env: &mut TestEnv, size: i128| {
+        let ix = Instruction {
+            program_id: env.program_id,
+            accounts: vec![
+                AccountMeta::new(user.pubkey(), true),
+                AccountMeta::new(lp.pubkey(), true),
+                AccountMeta::new(env.slab, false),
+                AccountMeta::new_readonly(sysvar::clock::ID, false),
+                AccountMeta::new_readonly(env.pyth_index, false),
+            ],
+            data: encode_trade(lp_idx, user_idx, size),
+        };
+        let tx = Transaction::new_signed_with_payer(
+            &[ix],
+            Some(&user.pubkey()),
+            &[&user, &lp],
+            env.svm.latest_blockhash(),
+        );
+        let meta = env.svm.send_transaction(tx).expect("trade failed");
+        assert_eq!(meta.return_data.program_id, env.program_id);
+        let fill = TradeExecution::from_bytes(&meta.return_data.data).expect("TradeExecution");
+        let events = perc_events(&meta.logs);
+        let event = TradeExecuted::from_bytes(&events[0]).expect("TradeExecuted payload");
+        assert_eq!(fill.fee, event.fee);
+        fill
+    };
+
+    let fill = trade(&mut env, 1_000_000);
+    assert_eq!(fill.exec_price_e6, 138_000_000);
+    assert_eq!(fill.exec_size, 1_000_000);
+    assert_eq!(fill.resulting_position, 1_000_000);
+
+    env.svm.expire_blockhash();
+    let fill = trade(&mut env, -400_000);
+    assert_eq!(fill.exec_size, -400_000);
+    assert_eq!(fill.resulting_position, 600_000);
+}
+
+// ============================================================================
+// DepositCollateralWrapped: native SOL without a separate wrap step
+// ============================================================================
+
+#[test]
+fn test_deposit_collateral_wrapped_native_sol() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+
+    // Switch the collateral to the native mint, with a native vault
+    let native = spl_token::native_mint::id();
+    let (vault_pda, _) =
+        Pubkey::find_program_address(&[b"vault", env.slab.as_ref()], &env.program_id);
+    let rent = env
+        .svm
+        .minimum_balance_for_rent_exemption(TokenAccount::LEN);
+    let native_token_account = |owner: &Pubkey| {
+        let mut data = make_token_account_data(&native, owner, 0);
+        let mut tok = TokenAccount::unpack(&data).unwrap();
+        tok.is_native = solana_sdk::program_option::COption::Some(rent);
+        TokenAccount::pack(tok, &mut data).unwrap();
+        Account {
+            lamports: rent,
+            data,
+            owner: spl_token::ID,
+            executable: false,
+            rent_epoch: 0,
+        }
+    };
+    let mut mint_data = make_mint_data();
+    let mut mint = spl_token::state::Mint::unpack(&mint_data).unwrap();
+    mint.decimals = 9;
+    spl_token::state::Mint::pack(mint, &mut mint_data).unwrap();
+    env.svm
+        .set_account(
+            native,
+            Account {
+                lamports: 1_000_000,
+                data: mint_data,
+                owner: spl_token::ID,
+                executable: false,
+                rent_epoch: 0,
+            },
+        )
+        .unwrap();
+    env.svm
+        .set_account(env.vault, native_token_account(&vault_pda))
+        .unwrap();
+    env.mint = native;
+    env.init_market_with_invert(0);
+
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    let user_wsol = Pubkey::new_unique();
+    env.svm
+        .set_account(user_wsol, native_token_account(&user.pubkey()))
+        .unwrap();
+    let lamports_before = env.svm.get_balance(&user.pubkey()).unwrap();
+
+    let ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(env.slab, false),
+            AccountMeta::new(user_wsol, false),
+            AccountMeta::new(env.vault, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+            AccountMeta::new_readonly(sysvar::clock::ID, false),
+            AccountMeta::new_readonly(solana_sdk::system_program::ID, false),
+        ],
+        data: encode_deposit_wrapped(user_idx, 500_000_000),
+    };
+    let tx = Transaction::new_signed_with_payer(
+        &[ix],
+        Some(&user.pubkey()),
+        &[&user],
+        env.svm.latest_blockhash(),
+    );
+    env.svm
+        .send_transaction(tx)
+        .expect("wrapped deposit failed");
+
+    // The wallet paid the lamports (plus the tx fee); the wrapped account is
+    // back to its rent reserve and the vault holds the deposit
+    let lamports_after = env.svm.get_balance(&user.pubkey()).unwrap();
+    assert!(lamports_before - lamports_after >= 500_000_000);
+    let wsol = env.svm.get_account(&user_wsol).unwrap();
+    assert_eq!(wsol.lamports, rent);
+    assert_eq!(TokenAccount::unpack(&wsol.data).unwrap().amount, 0);
+    assert_eq!(env.vault_balance(), 500_000_000);
+    assert_eq!(env.read_account_capital(user_idx), 500_000_000);
+}
+
+// ============================================================================
+// KeeperCrank return data
+// ============================================================================
+
+#[test]
+fn test_crank_returns_funding_rate_for_imbalanced_book() {
+    use percolator_prog::return_data::CrankOutcome;
+
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+
+    let lp = Keypair::new();
+    let lp_idx = env.init_lp(&lp);
+    env.deposit(&lp, lp_idx, 2_000_000_000_000);
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 2_000_000_000_000);
+
+    // User long 4e10 @ $138 leaves the LP net short 5.52e12 notional, past
+    // the max funding premium
+    env.trade(&user, &lp, lp_idx, user_idx, 40_000_000_000);
+    env.set_slot(200);
+
+    let caller = Keypair::new();
+    env.svm.airdrop(&caller.pubkey(), 1_000_000_000).unwrap();
+    let ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![
+            AccountMeta::new(caller.pubkey(), true),
+            AccountMeta::new(env.slab, false),
+            AccountMeta::new_readonly(sysvar::clock::ID, false),
+            AccountMeta::new_readonly(env.pyth_index, false),
+        ],
+        data: encode_crank_permissionless(),
+    };
+    let tx = Transaction::new_signed_with_payer(
+        &[ix],
+        Some(&caller.pubkey()),
+        &[&caller],
+        env.svm.latest_blockhash(),
+    );
+    let meta = env.svm.send_transaction(tx).expect("crank failed");
+    assert_eq!(meta.return_data.program_id, env.program_id);
+    let outcome = CrankOutcome::from_bytes(&meta.return_data.data).expect("CrankOutcome");
+
+    assert_eq!(outcome.slot, 200);
+    assert_eq!(outcome.price_e6, 138_000_000);
+    // An LP net short gives a negative inventory rate
+    assert!(outcome.funding_rate_bps_per_slot < 0);
+    assert_eq!(outcome.liquidations, 0);
+}
+
+/// SetLpMatcher rebinds an LP to a new matcher context without closing it:
+/// TradeCpi then validates against the new identity and rejects the old one.
+#[test]
+fn test_set_lp_matcher_rebinds_trade_cpi_identity() {
+    let Some(mut env) = TradeCpiTestEnv::new() else {
+        println!("SKIP: Programs not found. Run: cargo build-sbf && cd ../percolator-match && cargo build-sbf");
+        return;
+    };
+
+    env.init_market();
+    let matcher_prog = env.matcher_program_id;
+
+    let lp = Keypair::new();
+    let (lp_idx, old_ctx) = env.init_lp_with_matcher(&lp, &matcher_prog);
+    env.deposit(&lp, lp_idx, 100_000_000_000);
+
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 10_000_000_000);
+
+    let new_ctx = env.create_matcher_ctx_for_lp(&lp, lp_idx);
+
+    // Only the LP owner may rebind
+    let stranger = Keypair::new();
+    env.svm.airdrop(&stranger.pubkey(), 1_000_000_000).unwrap();
+    assert!(env
+        .try_set_lp_matcher(&stranger, lp_idx, &matcher_prog, &new_ctx)
+        .is_err());
+
+    // The context must pass TradeCpi's shape checks (owned by the matcher program)
+    let unowned_ctx = Pubkey::new_unique();
+    env.svm
+        .set_account(
+            unowned_ctx,
+            Account {
+                lamports: 10_000_000,
+                data: vec![0u8; MATCHER_CONTEXT_LEN],
+                owner: Pubkey::new_unique(),
+                executable: false,
+                rent_epoch: 0,
+            },
+        )
+        .unwrap();
+    assert!(env
+        .try_set_lp_matcher(&lp, lp_idx, &matcher_prog, &unowned_ctx)
+        .is_err());
+
+    env.try_set_lp_matcher(&lp, lp_idx, &matcher_prog, &new_ctx)
+        .expect("LP owner must be able to replace its matcher");
+
+    let old = env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        1_000_000,
+        &matcher_prog,
+        &old_ctx,
+    );
+    assert!(old.is_err(), "TradeCpi must reject the replaced context");
+
+    let new = env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        1_000_000,
+        &matcher_prog,
+        &new_ctx,
+    );
+    assert!(
+        new.is_ok(),
+        "TradeCpi via the new context failed: {:?}",
+        new
+    );
+    assert!(env.read_account_position(user_idx) > 0);
+}
+
+/// InitMarket with an empty vault: the vault keypair co-signs and the program
+/// allocates and initializes it under the vault authority in the same instruction.
+#[test]
+fn test_init_market_creates_empty_vault() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    let vault = Keypair::new();
+    env.vault = vault.pubkey();
+    assert!(env.svm.get_account(&env.vault).is_none());
+
+    let admin = env.payer.pubkey();
+    let ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![
+            AccountMeta::new(admin, true),
+            AccountMeta::new(env.slab, false),
+            AccountMeta::new_readonly(env.mint, false),
+            AccountMeta::new(env.vault, true),
+            AccountMeta::new_readonly(spl_token::ID, false),
+            AccountMeta::new_readonly(sysvar::clock::ID, false),
+            AccountMeta::new_readonly(sysvar::rent::ID, false),
+            AccountMeta::new_readonly(solana_sdk::system_program::ID, false),
+            AccountMeta::new_readonly(solana_sdk::system_program::ID, false),
+        ],
+        data: encode_init_market_with_invert(&admin, &env.mint, &TEST_FEED_ID, 0),
+    };
+    let tx = Transaction::new_signed_with_payer(
+        &[ix],
+        Some(&admin),
+        &[&env.payer, &vault],
+        env.svm.latest_blockhash(),
+    );
+    env.svm
+        .send_transaction(tx)
+        .expect("InitMarket must create the empty vault");
+
+    let (vault_pda, _) =
+        Pubkey::find_program_address(&[b"vault", env.slab.as_ref()], &env.program_id);
+    let account = env.svm.get_account(&env.vault).unwrap();
+    assert_eq!(account.owner, spl_token::ID);
+    let token = TokenAccount::unpack(&account.data).unwrap();
+    assert_eq!(token.mint, env.mint);
+    assert_eq!(token.owner, vault_pda);
+    assert_eq!(token.state, AccountState::Initialized);
+    assert_eq!(token.amount, 0);
+
+    // The created vault is the market's vault: deposits land in it
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 1_000_000);
+    let account = env.svm.get_account(&env.vault).unwrap();
+    assert_eq!(
+        TokenAccount::unpack(&account.data).unwrap().amount,
+        1_000_000
+    );
+}
+
+/// LiquidateCpi closes what the engine's liquidation would against the LP
+/// through the real matcher: the LP absorbs the inventory (unlike oracle
+/// seizure, which leaves the LP's side open) and the liquidation fee goes to
+/// insurance.
+#[test]
+fn test_liquidate_cpi_matcher_absorbs_position() {
+    let Some(mut env) = TradeCpiTestEnv::new() else {
+        println!("SKIP: Programs not found. Run: cargo build-sbf && cd ../percolator-match && cargo build-sbf");
+        return;
+    };
+
+    env.init_market();
+    let matcher_prog = env.matcher_program_id;
+
+    let lp = Keypair::new();
+    let (lp_idx, matcher_ctx) = env.init_lp_with_matcher(&lp, &matcher_prog);
+    env.deposit(&lp, lp_idx, 100_000_000_000);
+
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 1_500_000_000);
+
+    let size: i128 = 100_000_000;
+    env.try_trade_cpi(
+        &user,
+        &lp.pubkey(),
+        lp_idx,
+        user_idx,
+        size,
+        &matcher_prog,
+        &matcher_ctx,
+    )
+    .expect("opening trade failed");
+    assert_eq!(env.read_account_position(lp_idx), -size);
+
+    let (lp_pda, _) = Pubkey::find_program_address(
+        &[b"lp", env.slab.as_ref(), &lp_idx.to_le_bytes()],
+        &env.program_id,
+    );
+    let liquidate_cpi = |env: &mut TradeCpiTestEnv| {
+        let caller = Keypair::new();
+        env.svm.airdrop(&caller.pubkey(), 1_000_000_000).unwrap();
+        let ix = Instruction {
+            program_id: env.program_id,
+            accounts: vec![
+                AccountMeta::new(caller.pubkey(), true),
+                AccountMeta::new(env.slab, false),
+                AccountMeta::new_readonly(sysvar::clock::ID, false),
+                AccountMeta::new_readonly(env.pyth_index, false),
+                AccountMeta::new_readonly(matcher_prog, false),
+                AccountMeta::new(matcher_ctx, false),
+                AccountMeta::new_readonly(lp_pda, false),
+            ],
+            data: encode_liquidate_cpi(user_idx, lp_idx),
+        };
+        let tx = Transaction::new_signed_with_payer(
+            &[ix],
+            Some(&caller.pubkey()),
+            &[&caller],
+            env.svm.latest_blockhash(),
+        );
+        env.svm
+            .send_transaction(tx)
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e))
+    };
+
+    // Healthy at entry: a no-op
+    liquidate_cpi(&mut env).expect("healthy LiquidateCpi must succeed as a no-op");
+    assert_eq!(env.read_account_position(user_idx), size);
+
+    // $138 -> $128: the 100-unit long is now below 5% maintenance
+    env.set_slot(200);
+    env.svm
+        .set_account(
+            env.pyth_index,
+            Account {
+                lamports: 1_000_000,
+                data: make_pyth_data(&TEST_FEED_ID, 128_000_000, -6, 1, 200),
+                owner: PYTH_RECEIVER_PROGRAM_ID,
+                executable: false,
+                rent_epoch: 0,
+            },
+        )
+        .unwrap();
+
+    let insurance_before = env.read_insurance_balance();
+    liquidate_cpi(&mut env).expect("LiquidateCpi failed");
+
+    // Sized like the engine's liquidation, which may keep part of the long
+    let user_pos = env.read_account_position(user_idx);
+    assert!(
+        (0..size).contains(&user_pos),
+        "expected the long to shrink, got {}",
+        user_pos
+    );
+    assert_eq!(
+        env.read_account_position(lp_idx),
+        -user_pos,
+        "the LP must absorb the liquidated slice through its matcher"
+    );
+    assert!(
+        env.read_insurance_balance() > insurance_before,
+        "liquidation fee must be paid to insurance"
+    );
+}
+
+// ============================================================================
+// MigrateSlab
+// ============================================================================
+
+fn encode_migrate_slab(from_version: u32) -> Vec<u8> {
+    let mut data = vec![60u8]; // MigrateSlab instruction tag
+    data.extend_from_slice(&from_version.to_le_bytes());
+    data
+}
+
+const VERSION_OFF: usize = 8; // SlabHeader: magic u64, then version u32
+const SLAB_LEN_V1: usize = SLAB_LEN - 704; // version 1: no MarketConfig tail
+
+fn slab_version(env: &TestEnv) -> u32 {
+    let data = env.svm.get_account(&env.slab).unwrap().data;
+    u32::from_le_bytes(data[VERSION_OFF..VERSION_OFF + 4].try_into().unwrap())
+}
+
+fn try_migrate_slab(env: &mut TestEnv, from_version: u32) -> Result<(), String> {
+    let admin = env.payer.insecure_clone();
+    env.svm.expire_blockhash();
+    let ix = Instruction {
+        program_id: env.program_id,
+        accounts: vec![
+            AccountMeta::new(admin.pubkey(), true),
+            AccountMeta::new(env.slab, false),
+        ],
+        data: encode_migrate_slab(from_version),
+    };
+    let tx = Transaction::new_signed_with_payer(
+        &[ix],
+        Some(&admin.pubkey()),
+        &[&admin],
+        env.svm.latest_blockhash(),
+    );
+    env.svm
+        .send_transaction(tx)
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+/// Rewrite the slab as version 1 held it: its first `len` bytes (no config
+/// tail), stamped with version 1. Returns the rewritten data.
+fn rewrite_slab_as_v1(env: &mut TestEnv, len: usize) -> Vec<u8> {
+    let cur = env.svm.get_account(&env.slab).unwrap();
+    let mut old = cur.data[..len].to_vec();
+    old[VERSION_OFF..VERSION_OFF + 4].copy_from_slice(&1u32.to_le_bytes());
+    env.svm
+        .set_account(
+            env.slab,
+            Account {
+                // Rent for the grown account is funded up front
+                lamports: 10_000_000_000,
+                data: old.clone(),
+                ..cur
+            },
+        )
+        .unwrap();
+    old
+}
+
+/// A version-1 slab (no config tail) is upgraded in place in one call: the
+/// header, config head and engine stay put, the tail is added zeroed and the
+/// header version is bumped; the market then works as usual.
+#[test]
+fn test_migrate_slab_v1_preserves_config_and_accounts() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 1_000_000_000);
+
+    let v1 = rewrite_slab_as_v1(&mut env, SLAB_LEN_V1);
+    // Unreadable until migrated
+    assert!(env.try_withdraw(&user, user_idx, 1).is_err());
+
+    // Only upgrades from the version actually on the slab
+    assert!(try_migrate_slab(&mut env, 2).is_err());
+    try_migrate_slab(&mut env, 1).expect("MigrateSlab failed");
+    assert_eq!(slab_version(&env), 2);
+
+    let data = env.svm.get_account(&env.slab).unwrap().data;
+    assert_eq!(data.len(), SLAB_LEN);
+    assert_eq!(&data[..VERSION_OFF], &v1[..VERSION_OFF]);
+    assert_eq!(
+        &data[VERSION_OFF + 4..SLAB_LEN_V1],
+        &v1[VERSION_OFF + 4..],
+        "header, config head and engine must be kept"
+    );
+    assert!(
+        data[SLAB_LEN_V1..].iter().all(|&b| b == 0),
+        "config fields added since version 1 start disabled"
+    );
+
+    // Already current: a repeat is rejected
+    assert!(try_migrate_slab(&mut env, 1).is_err());
+
+    env.try_withdraw(&user, user_idx, 100_000_000)
+        .expect("migrated account must be usable");
+}
+
+/// Open positions and capital survive a version-1 migration, and the market
+/// keeps trading on them afterwards.
+#[test]
+fn test_migrate_slab_v1_preserves_open_positions() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+    let lp = Keypair::new();
+    let lp_idx = env.init_lp(&lp);
+    env.deposit(&lp, lp_idx, 100_000_000_000);
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 10_000_000_000);
+    env.try_trade(&user, &lp, lp_idx, user_idx, 10_000_000)
+        .expect("trade before migration");
+
+    let positions = (
+        env.read_account_position(user_idx),
+        env.read_account_position(lp_idx),
+    );
+    let capitals = (
+        env.read_account_capital(user_idx),
+        env.read_account_capital(lp_idx),
+    );
+    let used = env.read_num_used_accounts();
+    assert_eq!(positions.0, 10_000_000);
+
+    rewrite_slab_as_v1(&mut env, SLAB_LEN_V1);
+    try_migrate_slab(&mut env, 1).expect("MigrateSlab failed");
+
+    assert_eq!(
+        (
+            env.read_account_position(user_idx),
+            env.read_account_position(lp_idx)
+        ),
+        positions,
+        "positions must survive the migration"
+    );
+    assert_eq!(
+        (
+            env.read_account_capital(user_idx),
+            env.read_account_capital(lp_idx)
+        ),
+        capitals,
+        "capital must survive the migration"
+    );
+    assert_eq!(env.read_num_used_accounts(), used);
+
+    env.try_trade(&user, &lp, lp_idx, user_idx, -10_000_000)
+        .expect("migrated position must be closable");
+    assert_eq!(env.read_account_position(user_idx), 0);
+}
+
+/// A legacy version-1 slab (8 bytes shorter, see slab_guard) grows to the
+/// full current length, its engine padded with zeros.
+#[test]
+fn test_migrate_slab_legacy_v1_grows_to_full_length() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+    let user = Keypair::new();
+    let user_idx = env.init_user(&user);
+    env.deposit(&user, user_idx, 1_000_000_000);
+    let capital = env.read_account_capital(user_idx);
+
+    rewrite_slab_as_v1(&mut env, SLAB_LEN_V1 - 8);
+    try_migrate_slab(&mut env, 1).expect("MigrateSlab failed");
+
+    let data = env.svm.get_account(&env.slab).unwrap().data;
+    assert_eq!(data.len(), SLAB_LEN);
+    assert!(data[SLAB_LEN_V1 - 8..].iter().all(|&b| b == 0));
+    assert_eq!(env.read_account_capital(user_idx), capital);
+    env.try_withdraw(&user, user_idx, 100_000_000)
+        .expect("migrated account must be usable");
+}
+
+/// MigrateSlab refuses a slab larger than the current layout, which the
+/// upgrade would truncate.
+#[test]
+fn test_migrate_slab_rejects_truncating_layout() {
+    let path = program_path();
+    if !path.exists() {
+        println!("SKIP: BPF not found. Run: cargo build-sbf");
+        return;
+    }
+
+    let mut env = TestEnv::new();
+    env.init_market_with_invert(0);
+
+    let acct = env.svm.get_account(&env.slab).unwrap();
+    let mut data = acct.data.clone();
+    data[VERSION_OFF..VERSION_OFF + 4].copy_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&[0u8; 4096]);
+    env.svm
+        .set_account(env.slab, Account { data, ..acct })
+        .unwrap();
+
+    assert!(try_migrate_slab(&mut env, 1).is_err());
+    assert_eq!(
+        env.svm.get_account(&env.slab).unwrap().data.len(),
+        SLAB_LEN + 4096
+    );
+}
@@ -31,9 +31,12 @@ use percolator_prog::verify::{
     // New: Dust math
     accumulate_dust,
     admin_ok,
+    admin_withdraw_allowed,
     // New: Unit scale conversion math
     base_to_units,
+    conf_adjusted_price,
     cpi_trade_size,
+    crank_reward,
     decide_admin_op,
     decide_crank,
     // New: allow_panic crank decision
@@ -43,19 +46,33 @@ use percolator_prog::verify::{
     decide_trade_cpi_from_ret,
     decide_trade_nocpi,
     decision_nonce,
+    engine_vault_covers,
+    equity_meets_margin,
+    feature_flags_update,
     gate_active,
+    haircut_ratio_e6,
     // New: InitMarket scale validation
     init_market_scale_ok,
+    insurance_floor_ok,
     // New: Oracle inversion math
     invert_price_e6,
+    // Position direction classification
+    is_reducing_fill,
     len_ok,
+    leverage_ok,
+    limit_price_ok,
+    liquidation_fill_ok,
     lp_pda_shape_ok,
     matcher_identity_ok,
     matcher_shape_ok,
+    median_price,
+    median_quorum_ok,
     nonce_on_failure,
     nonce_on_success,
+    open_interest_ok,
     oracle_feed_id_ok,
     owner_ok,
+    partial_liquidation_target_bps,
     pda_key_matches,
     // New: Oracle unit scale math
     scale_price_e6,
@@ -63,10 +80,19 @@ use percolator_prog::verify::{
     signer_ok,
     // Decision helpers for program-level coupling proofs
     single_owner_authorized,
+    slab_migration_ok,
     slab_shape_ok,
     sweep_dust,
     trade_authorized,
+    trade_signers_ok,
+    trade_size_in_range,
+    transfer_position_ok,
+    twap_accumulate,
+    twap_price,
     units_to_base,
+    vault_balance_matches,
+    vault_rent_buffer_ok,
+    weighted_entry_price,
     // New: Withdraw alignment
     withdraw_amount_aligned,
     writable_ok,
@@ -79,6 +105,7 @@ use percolator_prog::verify::{
     TradeCpiDecision,
     TradeNoCpiDecision,
     INVERSION_CONSTANT,
+    MIN_INVERTIBLE_PRICE_E6,
 };
 
 // Kani-specific bounds to avoid SAT explosion on division/modulo.
@@ -639,7 +666,7 @@ fn kani_gate_active_when_conditions_met() {
 }
 
 // =============================================================================
-// J. PER-INSTRUCTION AUTHORIZATION (4 proofs)
+// J. PER-INSTRUCTION AUTHORIZATION (5 proofs)
 // =============================================================================
 
 /// Prove: single-owner instruction rejects on mismatch
@@ -694,6 +721,26 @@ fn kani_trade_rejects_lp_mismatch() {
     );
 }
 
+/// Prove: trade signer policy per mode (universal). The user must always sign;
+/// the LP must sign unless the trade is routed through its bound matcher.
+#[kani::proof]
+fn kani_trade_signer_policy_per_mode() {
+    let via_matcher: bool = kani::any();
+    let user_signed: bool = kani::any();
+    let lp_signed: bool = kani::any();
+
+    let ok = trade_signers_ok(via_matcher, user_signed, lp_signed);
+    if !user_signed {
+        assert!(!ok, "missing user signer must be rejected in every mode");
+    }
+    if !via_matcher && !lp_signed {
+        assert!(!ok, "TradeNoCpi must require the LP signer");
+    }
+    if via_matcher && user_signed {
+        assert!(ok, "TradeCpi must not require the LP signer");
+    }
+}
+
 // =============================================================================
 // L. TRADECPI DECISION COUPLING (12 proofs) - CRITICAL
 // These prove program-level policies, not just helper semantics
@@ -2001,13 +2048,13 @@ fn kani_invert_zero_returns_raw() {
 fn kani_invert_nonzero_computes_correctly() {
     let raw: u64 = kani::any();
     // Constrain to valid range where inversion must succeed, capped for SAT solver
-    kani::assume(raw > 0);
-    kani::assume(raw <= KANI_MAX_QUOTIENT); // also ensures result >= 1 since 1e12/4096 >> 1
+    kani::assume(raw >= MIN_INVERTIBLE_PRICE_E6);
+    kani::assume(raw <= MIN_INVERTIBLE_PRICE_E6 + KANI_MAX_QUOTIENT); // result >= 1 since 1e12/5096 >> 1
 
     let result = invert_price_e6(raw, 1);
 
     // Force success - must not be None in valid range
-    let inverted = result.expect("inversion must succeed for raw in (0, 1e12]");
+    let inverted = result.expect("inversion must succeed for raw in [floor, 1e12]");
 
     // Verify correctness
     let expected = INVERSION_CONSTANT / (raw as u128);
@@ -2024,6 +2071,17 @@ fn kani_invert_zero_raw_returns_none() {
     assert!(result.is_none(), "raw==0 must return None");
 }
 
+/// Prove: any raw below the inversion floor returns None
+#[kani::proof]
+fn kani_invert_below_floor_returns_none() {
+    let raw: u64 = kani::any();
+    kani::assume(raw < MIN_INVERTIBLE_PRICE_E6);
+    assert!(
+        invert_price_e6(raw, 1).is_none(),
+        "raw below floor must return None"
+    );
+}
+
 /// Prove: inverted==0 returns None (result too small)
 #[kani::proof]
 fn kani_invert_result_zero_returns_none() {
@@ -3428,3 +3486,480 @@ fn kani_clamp_toward_formula_concrete() {
         "result must equal mark.clamp(990_000, 1_010_000)"
     );
 }
+
+/// Prove: A reducing fill never flips or grows the position.
+/// If is_reducing_fill(old, delta) then |old + delta| < |old| and the sign is not flipped.
+#[kani::proof]
+fn kani_reducing_fill_never_grows_or_flips() {
+    let old_pos: i128 = kani::any();
+    let delta: i128 = kani::any();
+
+    if is_reducing_fill(old_pos, delta) {
+        // No overflow possible: delta has the opposite sign and |delta| <= |old_pos|
+        let new_pos = old_pos + delta;
+        assert!(new_pos.unsigned_abs() < old_pos.unsigned_abs());
+        assert!(new_pos == 0 || (new_pos > 0) == (old_pos > 0));
+    }
+}
+
+/// Prove: Fills on a flat position and zero-size fills are never reducing.
+#[kani::proof]
+fn kani_reducing_fill_rejects_flat_and_zero() {
+    let x: i128 = kani::any();
+    assert!(!is_reducing_fill(0, x));
+    assert!(!is_reducing_fill(x, 0));
+}
+
+/// Prove: An allowed admin withdrawal never dips into user capital.
+/// If admin_withdraw_allowed(vault, c_tot, insurance, amount) then
+/// amount <= insurance and vault - amount >= c_tot.
+#[kani::proof]
+fn kani_admin_withdraw_never_touches_user_capital() {
+    let vault: u128 = kani::any();
+    let user_capital_total: u128 = kani::any();
+    let insurance: u128 = kani::any();
+    let amount: u128 = kani::any();
+
+    if admin_withdraw_allowed(vault, user_capital_total, insurance, amount) {
+        assert!(amount <= insurance);
+        assert!(amount <= vault);
+        assert!(vault - amount >= user_capital_total);
+    }
+}
+
+/// Prove: A partial insurance withdrawal accepted by insurance_floor_ok
+/// fits inside the fund and leaves at least the floor.
+#[kani::proof]
+fn kani_insurance_withdraw_keeps_floor() {
+    let insurance: u128 = kani::any();
+    let amount: u128 = kani::any();
+    let floor: u128 = kani::any();
+
+    if insurance_floor_ok(insurance, amount, floor) {
+        assert!(amount <= insurance);
+        assert!(insurance - amount >= floor);
+    }
+}
+
+/// Prove: equity_meets_margin never accepts negative equity against a non-zero
+/// requirement, and a higher margin never accepts what a lower one rejects.
+#[kani::proof]
+fn kani_equity_meets_margin_monotone() {
+    let equity: i128 = kani::any();
+    let notional: u128 = kani::any();
+    let lo: u64 = kani::any();
+    let hi: u64 = kani::any();
+    kani::assume(lo <= hi);
+    kani::assume(notional <= u64::MAX as u128);
+
+    if equity < 0 && notional.saturating_mul(lo as u128) / 10_000 > 0 {
+        assert!(!equity_meets_margin(equity, notional, lo));
+    }
+    if equity_meets_margin(equity, notional, hi) {
+        assert!(equity_meets_margin(equity, notional, lo));
+    }
+}
+
+/// Prove: engine liquidation sizing at the partial-liquidation target margin
+/// never keeps more than the requested remainder.
+#[kani::proof]
+fn kani_partial_liquidation_target_keeps_at_most_remainder() {
+    let equity: i128 = kani::any::<u32>() as i128;
+    let price: u64 = kani::any::<u32>() as u64;
+    let remaining: u128 = kani::any::<u16>() as u128;
+
+    if let Some(bps) = partial_liquidation_target_bps(equity, price, remaining) {
+        assert!(bps > 0);
+        let kept = (equity as u128 * 10_000 * 1_000_000) / (price as u128 * bps as u128);
+        assert!(kept <= remaining);
+    }
+}
+
+/// Prove: weighted_entry_price is the size-weighted average of the open
+/// position's fills: an increase lands between the old entry and the fill
+/// price with a rounding error below one unit, a reduce keeps the entry, and a
+/// flip resets it to the fill price.
+#[kani::proof]
+fn kani_weighted_entry_price_is_size_weighted_average() {
+    let old_pos: i128 = kani::any::<i16>() as i128;
+    let fill: i128 = kani::any::<i16>() as i128;
+    let old_entry: u64 = kani::any::<u16>() as u64;
+    let fill_price: u64 = kani::any::<u16>() as u64;
+
+    let new_pos = old_pos + fill;
+    let e = weighted_entry_price(old_pos, old_entry, fill, fill_price);
+
+    if new_pos == 0 {
+        assert!(e == 0);
+    } else if old_pos == 0 || (old_pos > 0) != (new_pos > 0) {
+        assert!(e == fill_price);
+    } else if fill == 0 || (fill > 0) != (old_pos > 0) {
+        assert!(e == old_entry);
+    } else {
+        let a = old_pos.unsigned_abs();
+        let b = fill.unsigned_abs();
+        let cost = a * old_entry as u128 + b * fill_price as u128;
+        let basis = e as u128 * (a + b);
+        assert!(basis <= cost);
+        assert!(cost < basis + (a + b));
+        assert!(e >= old_entry.min(fill_price) && e <= old_entry.max(fill_price));
+    }
+}
+
+/// Prove: vault_rent_buffer_ok accepts exactly the withdrawals that leave the
+/// vault holding at least its rent-exempt reserve.
+#[kani::proof]
+fn kani_vault_rent_buffer_never_breaches_reserve() {
+    let lamports: u64 = kani::any();
+    let reserve: u64 = kani::any();
+    let amount: u64 = kani::any();
+
+    let ok = vault_rent_buffer_ok(lamports, reserve, amount);
+    assert!(ok == (amount as u128 + reserve as u128 <= lamports as u128));
+    if ok {
+        assert!(lamports - amount >= reserve);
+    }
+}
+
+/// Prove: feature_flags_update never sets a bit outside `known`, changes only
+/// the requested bits, and rejects overlapping masks.
+#[kani::proof]
+fn kani_feature_flags_update_only_touches_requested_known_bits() {
+    let current: u64 = kani::any();
+    let set: u64 = kani::any();
+    let clear: u64 = kani::any();
+    let known: u64 = kani::any();
+    kani::assume(current & !known == 0);
+
+    match feature_flags_update(current, set, clear, known) {
+        Some(next) => {
+            assert!(next & !known == 0);
+            assert!(next & set == set);
+            assert!(next & clear == 0);
+            assert!((next ^ current) & !(set | clear) == 0);
+        }
+        None => assert!((set | clear) & !known != 0 || set & clear != 0),
+    }
+}
+
+/// Prove: conf_adjusted_price moves the price against the position by exactly
+/// `conf` and never returns zero.
+#[kani::proof]
+fn kani_conf_adjusted_price_moves_against_position() {
+    let price: u64 = kani::any();
+    let conf: u64 = kani::any();
+    let long: bool = kani::any();
+
+    match conf_adjusted_price(price, conf, long) {
+        Some(p) => {
+            assert!(p > 0);
+            if long {
+                assert!(p == price - conf);
+            } else {
+                assert!(p == price + conf);
+            }
+        }
+        None if long => assert!(conf >= price),
+        None => assert!(price.checked_add(conf).is_none() || price == 0),
+    }
+}
+
+/// Prove: the median of three prices is one of them and lies between the
+/// other two, so a single outlier can never become the index price.
+#[kani::proof]
+fn kani_median_price_of_three_is_the_middle_input() {
+    let a: u64 = kani::any();
+    let b: u64 = kani::any();
+    let c: u64 = kani::any();
+
+    let m = median_price(&[a, b, c]).unwrap();
+    assert!(m == a || m == b || m == c);
+    let below = (a <= m) as u8 + (b <= m) as u8 + (c <= m) as u8;
+    let above = (a >= m) as u8 + (b >= m) as u8 + (c >= m) as u8;
+    assert!(below >= 2 && above >= 2);
+}
+
+/// Prove: the index median never rests on a minority of its configured feeds,
+/// and with extra feeds configured a single fresh feed is never enough.
+#[kani::proof]
+fn kani_median_quorum_needs_majority() {
+    let fresh: usize = kani::any();
+    let configured: usize = kani::any();
+    kani::assume(configured >= 1 && configured <= 3 && fresh <= configured);
+
+    let ok = median_quorum_ok(fresh, configured);
+    assert_eq!(ok, fresh * 2 > configured);
+    if configured > 1 && fresh <= 1 {
+        assert!(!ok);
+    }
+    if fresh == configured {
+        assert!(ok);
+    }
+}
+
+/// Prove: the funding TWAP window never spans more than the horizon, and a
+/// window of constant price averages to exactly that price.
+#[kani::proof]
+fn kani_twap_window_bounded_and_constant_price_exact() {
+    let dt_total: u64 = kani::any();
+    let last_slot: u64 = kani::any();
+    let slot: u64 = kani::any();
+    let horizon: u64 = kani::any();
+    let price: u64 = kani::any();
+    kani::assume(horizon > 0 && dt_total <= horizon);
+    kani::assume(price > 0);
+
+    let price_sum = price as u128 * dt_total as u128;
+    let (sum, dt) = twap_accumulate(price_sum, dt_total, last_slot, price, slot, horizon);
+    assert!(dt >= 1 && dt <= horizon);
+    assert_eq!(twap_price(sum, dt), Some(price));
+}
+
+/// Prove: an accepted transfer shrinks the source without flipping it and
+/// leaves total |position| (open interest) unchanged.
+#[kani::proof]
+fn kani_transfer_position_conserves_open_interest() {
+    let from_pos: i128 = kani::any();
+    let to_pos: i128 = kani::any();
+    let size: i128 = kani::any();
+    kani::assume(from_pos > i128::MIN / 2 && from_pos < i128::MAX / 2);
+    kani::assume(to_pos > i128::MIN / 2 && to_pos < i128::MAX / 2);
+
+    if transfer_position_ok(from_pos, to_pos, size) {
+        let from_after = from_pos - size;
+        let to_after = to_pos + size;
+        assert!(from_after.unsigned_abs() < from_pos.unsigned_abs());
+        assert!(from_after == 0 || (from_after > 0) == (from_pos > 0));
+        assert!(
+            from_after.unsigned_abs() + to_after.unsigned_abs()
+                == from_pos.unsigned_abs() + to_pos.unsigned_abs()
+        );
+    }
+}
+
+/// Prove: a deposit split into (units, dust) keeps the vault token balance
+/// equal to engine vault * scale + dust_base, and the engine vault still
+/// covers capital plus insurance once the units are credited to capital.
+#[kani::proof]
+fn kani_deposit_preserves_vault_balance_identity() {
+    let scale: u32 = kani::any();
+    let vault: u32 = kani::any();
+    let dust: u32 = kani::any();
+    let amount: u32 = kani::any();
+    let c_tot: u32 = kani::any();
+    let insurance: u32 = kani::any();
+    kani::assume(scale <= 16);
+    kani::assume((c_tot as u64) + (insurance as u64) <= vault as u64);
+
+    let s = scale.max(1) as u64;
+    let balance = vault as u64 * s + dust as u64;
+    assert!(vault_balance_matches(
+        balance,
+        vault as u128,
+        scale,
+        dust as u64
+    ));
+    assert!(engine_vault_covers(
+        vault as u128,
+        c_tot as u128,
+        insurance as u128
+    ));
+
+    let (units, added_dust) = base_to_units(amount as u64, scale);
+    let vault_after = vault as u128 + units as u128;
+    assert!(vault_balance_matches(
+        balance + amount as u64,
+        vault_after,
+        scale,
+        dust as u64 + added_dust,
+    ));
+    assert!(engine_vault_covers(
+        vault_after,
+        c_tot as u128 + units as u128,
+        insurance as u128,
+    ));
+}
+
+/// Prove: the slippage guard accepts every fill with no limit, and otherwise
+/// bounds buys from above and sells from below.
+#[kani::proof]
+fn kani_limit_price_bounds_fill_by_side() {
+    let size: i128 = kani::any();
+    let exec: u64 = kani::any();
+    let limit: u64 = kani::any();
+
+    assert!(limit_price_ok(size, exec, 0));
+
+    kani::assume(limit != 0);
+    let ok = limit_price_ok(size, exec, limit);
+    if size > 0 {
+        assert_eq!(ok, exec <= limit);
+    } else {
+        assert_eq!(ok, exec >= limit);
+    }
+}
+
+/// Prove: every size the trade guard admits can be negated and `.abs()`-ed,
+/// so the `size != i128::MIN` assumption in other proofs is enforced on-chain.
+#[kani::proof]
+fn kani_trade_size_in_range_is_negatable() {
+    let size: i128 = kani::any();
+    if trade_size_in_range(size) {
+        assert!(size.checked_neg().is_some());
+        assert!(size.checked_abs().is_some());
+    } else {
+        assert_eq!(size, i128::MIN);
+    }
+}
+
+/// Prove: the keeper reward is zero without work and never takes insurance
+/// below the floor.
+#[kani::proof]
+fn kani_crank_reward_bounded_by_work_and_floor() {
+    let liquidations: u8 = kani::any();
+    let sweep: bool = kani::any();
+    let per_liq: u64 = kani::any();
+    let per_sweep: u64 = kani::any();
+    let insurance: u64 = kani::any();
+    let floor: u64 = kani::any();
+
+    let reward = crank_reward(
+        liquidations as u64,
+        sweep,
+        per_liq as u128,
+        per_sweep as u128,
+        insurance as u128,
+        floor as u128,
+    );
+    if liquidations == 0 && !sweep {
+        assert_eq!(reward, 0);
+    }
+    assert!(reward <= (insurance as u128).saturating_sub(floor as u128));
+    assert!(reward <= per_liq as u128 * liquidations as u128 + per_sweep as u128);
+}
+
+/// Prove: the haircut ratio never exceeds 1e6 and is exactly 1e6 whenever
+/// the reserve above capital and insurance covers all positive PnL.
+#[kani::proof]
+fn kani_haircut_ratio_bounded_and_full_when_backed() {
+    let vault: u64 = kani::any();
+    let c_tot: u64 = kani::any();
+    let insurance: u64 = kani::any();
+    let pnl_pos_tot: u64 = kani::any();
+
+    let ratio = haircut_ratio_e6(
+        vault as u128,
+        c_tot as u128,
+        insurance as u128,
+        pnl_pos_tot as u128,
+    );
+    assert!(ratio <= 1_000_000);
+    let reserve = (vault as u128).saturating_sub(c_tot as u128 + insurance as u128);
+    if reserve >= pnl_pos_tot as u128 {
+        assert_eq!(ratio, 1_000_000);
+    }
+}
+
+/// Prove: fills that do not grow open interest always pass the cap, and a
+/// growing fill passes only if the resulting total stays within it.
+#[kani::proof]
+fn kani_open_interest_cap_only_blocks_growth() {
+    let user_pos: i64 = kani::any();
+    let lp_pos: i64 = kani::any();
+    let size: i64 = kani::any();
+    let other_oi: u64 = kani::any();
+    let max_oi: u64 = kani::any();
+    kani::assume(max_oi > 0);
+
+    let (u, l, s) = (user_pos as i128, lp_pos as i128, size as i128);
+    let before = u.unsigned_abs() + l.unsigned_abs();
+    let after = (u + s).unsigned_abs() + (l - s).unsigned_abs();
+    let total = other_oi as u128 + before;
+
+    let ok = open_interest_ok(total, u, l, s, max_oi as u128);
+    if after <= before {
+        assert!(ok);
+    } else {
+        assert_eq!(ok, other_oi as u128 + after <= max_oi as u128);
+    }
+}
+
+/// Prove: LiquidateCpi only takes a matcher fill that closes the whole
+/// position, and never at a price worse for the target than the liquidation
+/// price moved by `slippage_bps`.
+#[kani::proof]
+fn kani_liquidation_fill_full_close_within_slippage() {
+    let close_size: i64 = kani::any();
+    let exec_size: i64 = kani::any();
+    let exec_price: u32 = kani::any();
+    let bound: u32 = kani::any();
+    let slippage_bps: u16 = kani::any();
+    kani::assume(slippage_bps <= 10_000);
+
+    let ok = liquidation_fill_ok(
+        close_size as i128,
+        exec_size as i128,
+        exec_price as u64,
+        bound as u64,
+        slippage_bps as u64,
+    );
+    if ok {
+        assert_eq!(exec_size, close_size);
+        assert!(close_size != 0 && bound > 0);
+        let slack = bound as u128 * slippage_bps as u128 / 10_000;
+        if close_size > 0 {
+            // Buying back a short: no higher than bound + slack
+            assert!(exec_price as u128 <= bound as u128 + slack);
+        } else {
+            // Selling out a long: no lower than bound - slack
+            assert!(exec_price as u128 + slack >= bound as u128);
+        }
+    }
+    let full_close = exec_size == close_size && close_size != 0;
+    if slippage_bps == 0 && full_close && exec_price > 0 && bound > 0 {
+        assert_eq!(
+            ok,
+            exec_price == bound || (close_size > 0) == (exec_price < bound)
+        );
+    }
+}
+
+/// Prove: MigrateSlab never downgrades, and never accepts a slab longer than
+/// the target layout (which the upgrade would truncate).
+#[kani::proof]
+fn kani_slab_migration_never_truncates() {
+    let from_version: u32 = kani::any();
+    let to_version: u32 = kani::any();
+    let slab_len: usize = kani::any();
+    let target_len: usize = kani::any();
+
+    let ok = slab_migration_ok(from_version, to_version, slab_len, target_len);
+    if ok {
+        assert!(from_version < to_version);
+        assert!(slab_len <= target_len);
+    }
+    if from_version < to_version && slab_len <= target_len {
+        assert!(ok);
+    }
+}
+
+/// Prove: reducing fills always pass the leverage cap, and a growing fill
+/// passes only if its notional stays within capital * max_leverage_x.
+#[kani::proof]
+fn kani_leverage_cap_only_blocks_growth() {
+    let pos: i64 = kani::any();
+    let delta: i64 = kani::any();
+    let price: u32 = kani::any();
+    let capital: u64 = kani::any();
+    let max_x: u8 = kani::any();
+    kani::assume(max_x > 0);
+
+    let (p, d) = (pos as i128, delta as i128);
+    let ok = leverage_ok(p, d, price as u64, capital as u128, max_x as u64);
+    if is_reducing_fill(p, d) {
+        assert!(ok);
+    } else {
+        let notional = (p + d).unsigned_abs() * price as u128 / 1_000_000;
+        assert_eq!(ok, notional <= capital as u128 * max_x as u128);
+    }
+}
@@ -8,14 +8,19 @@ use percolator_prog::{
     constants::{MAGIC, VERSION},
     error::PercolatorError,
     oracle,
-    processor::process_instruction,
-    state, units, zc,
+    processor::{self, process_instruction},
+    return_data::{
+        AccountIndex, AccountState, CrankBatch, CrankOutcome, FeatureFlags, InvariantReport,
+        LiquidationPreview, LiquidationScan, MarketStats, TopDonors, VaultInfo, WarmupStatus,
+        ORACLE_MODE_EXTERNAL, ORACLE_MODE_HYPERP,
+    },
+    state, units, view as slab_view, zc,
 };
 use solana_program::{
     account_info::AccountInfo, clock::Clock, program_error::ProgramError, program_pack::Pack,
     pubkey::Pubkey,
 };
-use spl_token::state::{Account as TokenAccount, AccountState};
+use spl_token::state::{Account as TokenAccount, AccountState as TokenAccountState};
 
 // --- Harness ---
 
@@ -76,7 +81,7 @@ fn make_token_account(mint: Pubkey, owner: Pubkey, amount: u64) -> Vec<u8> {
     account.mint = mint;
     account.owner = owner;
     account.amount = amount;
-    account.state = AccountState::Initialized;
+    account.state = TokenAccountState::Initialized;
     TokenAccount::pack(account, &mut data).unwrap();
     data
 }
@@ -95,6 +100,22 @@ fn make_mint_account() -> Vec<u8> {
     data
 }
 
+/// Token-2022 mint carrying a TransferFeeConfig extension whose newer schedule
+/// withholds `bps` of each transfer, capped at `max_fee`.
+fn make_transfer_fee_mint_account(bps: u16, max_fee: u64) -> Vec<u8> {
+    let mut data = make_mint_account();
+    data.resize(TokenAccount::LEN, 0);
+    data.push(1); // AccountType::Mint
+    let mut config = vec![0u8; 108];
+    // newer_transfer_fee: epoch, maximum_fee, transfer_fee_basis_points
+    config[98..106].copy_from_slice(&max_fee.to_le_bytes());
+    config[106..108].copy_from_slice(&bps.to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes()); // ExtensionType::TransferFeeConfig
+    data.extend_from_slice(&(config.len() as u16).to_le_bytes());
+    data.extend_from_slice(&config);
+    data
+}
+
 /// PYTH_RECEIVER_PROGRAM_ID bytes (rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ)
 const PYTH_RECEIVER_BYTES: [u8; 32] = [
     0x0c, 0xb7, 0xfa, 0xbb, 0x52, 0xf7, 0xa6, 0x48, 0xbb, 0x5b, 0x31, 0x7d, 0x9a, 0x01, 0x8b, 0x90,
@@ -288,6 +309,14 @@ fn encode_init_market_invert(
     data
 }
 
+/// Hyperp market: zero feed id, mark price seeded at init.
+fn encode_init_market_hyperp(fixture: &MarketFixture, initial_mark_price_e6: u64) -> Vec<u8> {
+    let mut data = encode_init_market(fixture, 100);
+    data[65..97].copy_from_slice(&[0u8; 32]); // index_feed_id
+    data[112..120].copy_from_slice(&initial_mark_price_e6.to_le_bytes());
+    data
+}
+
 fn encode_init_user(fee: u64) -> Vec<u8> {
     let mut data = vec![1u8];
     encode_u64(fee, &mut data);
@@ -302,6 +331,35 @@ fn encode_init_lp(matcher: Pubkey, ctx: Pubkey, fee: u64) -> Vec<u8> {
     data
 }
 
+fn encode_set_lp_matcher(lp_idx: u16, matcher: Pubkey, ctx: Pubkey) -> Vec<u8> {
+    let mut data = vec![52u8];
+    encode_u16(lp_idx, &mut data);
+    encode_pubkey(&matcher, &mut data);
+    encode_pubkey(&ctx, &mut data);
+    data
+}
+
+fn encode_get_account_index(owner: &Pubkey) -> Vec<u8> {
+    let mut data = vec![53u8];
+    encode_pubkey(owner, &mut data);
+    data
+}
+
+fn encode_set_funding_params(max_premium_bps: i64, max_bps_per_slot: i64, horizon: u64) -> Vec<u8> {
+    let mut data = vec![54u8];
+    data.extend_from_slice(&max_premium_bps.to_le_bytes());
+    data.extend_from_slice(&max_bps_per_slot.to_le_bytes());
+    encode_u64(horizon, &mut data);
+    data
+}
+
+fn encode_set_crank_rewards(per_liquidation: u128, per_sweep: u128) -> Vec<u8> {
+    let mut data = vec![55u8];
+    encode_u128(per_liquidation, &mut data);
+    encode_u128(per_sweep, &mut data);
+    data
+}
+
 fn encode_deposit(user_idx: u16, amount: u64) -> Vec<u8> {
     let mut data = vec![3u8];
     encode_u16(user_idx, &mut data);
@@ -309,6 +367,24 @@ fn encode_deposit(user_idx: u16, amount: u64) -> Vec<u8> {
     data
 }
 
+fn encode_deposit_to(target_idx: u16, amount: u64) -> Vec<u8> {
+    let mut data = vec![50u8];
+    encode_u16(target_idx, &mut data);
+    encode_u64(amount, &mut data);
+    data
+}
+
+fn encode_deposit_wrapped(user_idx: u16, lamports: u64) -> Vec<u8> {
+    let mut data = vec![49u8];
+    encode_u16(user_idx, &mut data);
+    encode_u64(lamports, &mut data);
+    data
+}
+
+fn encode_verify_invariants() -> Vec<u8> {
+    vec![51u8]
+}
+
 fn encode_withdraw(user_idx: u16, amount: u64) -> Vec<u8> {
     let mut data = vec![4u8];
     encode_u16(user_idx, &mut data);
@@ -316,6 +392,18 @@ fn encode_withdraw(user_idx: u16, amount: u64) -> Vec<u8> {
     data
 }
 
+fn encode_close_account(user_idx: u16) -> Vec<u8> {
+    let mut data = vec![8u8];
+    encode_u16(user_idx, &mut data);
+    data
+}
+
+fn encode_close_lp(lp_idx: u16) -> Vec<u8> {
+    let mut data = vec![57u8];
+    encode_u16(lp_idx, &mut data);
+    data
+}
+
 fn encode_crank(caller: u16, panic: u8) -> Vec<u8> {
     let mut data = vec![5u8];
     encode_u16(caller, &mut data);
@@ -327,6 +415,30 @@ fn encode_crank_permissionless(panic: u8) -> Vec<u8> {
     encode_crank(u16::MAX, panic)
 }
 
+fn encode_crank_batch(caller: u16, up_to_slot: u64, max_iters: u16, panic: u8) -> Vec<u8> {
+    let mut data = vec![42u8];
+    encode_u16(caller, &mut data);
+    encode_u64(up_to_slot, &mut data);
+    encode_u16(max_iters, &mut data);
+    data.push(panic);
+    data
+}
+
+fn encode_crank_window(caller: u16, start: u16, count: u16) -> Vec<u8> {
+    let mut data = vec![46u8];
+    encode_u16(caller, &mut data);
+    encode_u16(start, &mut data);
+    encode_u16(count, &mut data);
+    data
+}
+
+fn encode_liquidate_partial(target_idx: u16, close_bps: u16) -> Vec<u8> {
+    let mut data = vec![47u8];
+    encode_u16(target_idx, &mut data);
+    encode_u16(close_bps, &mut data);
+    data
+}
+
 fn encode_trade(lp: u16, user: u16, size: i128) -> Vec<u8> {
     let mut data = vec![6u8];
     encode_u16(lp, &mut data);
@@ -335,6 +447,18 @@ fn encode_trade(lp: u16, user: u16, size: i128) -> Vec<u8> {
     data
 }
 
+fn encode_trade_with_limit(lp: u16, user: u16, size: i128, limit_price_e6: u64) -> Vec<u8> {
+    let mut data = encode_trade(lp, user, size);
+    encode_u64(limit_price_e6, &mut data);
+    data
+}
+
+fn encode_trade_with_req_id(lp: u16, user: u16, size: i128, client_req_id: u64) -> Vec<u8> {
+    let mut data = encode_trade_with_limit(lp, user, size, 0);
+    encode_u64(client_req_id, &mut data);
+    data
+}
+
 fn encode_trade_cpi(lp: u16, user: u16, size: i128) -> Vec<u8> {
     let mut data = vec![10u8];
     encode_u16(lp, &mut data);
@@ -365,6 +489,216 @@ fn encode_topup_insurance(amount: u64) -> Vec<u8> {
     data
 }
 
+fn encode_set_emergency_exit(enabled: u8) -> Vec<u8> {
+    vec![22u8, enabled]
+}
+
+fn encode_set_max_trade_notional(max_notional_e6: u64) -> Vec<u8> {
+    let mut data = vec![23u8];
+    encode_u64(max_notional_e6, &mut data);
+    data
+}
+
+fn encode_set_open_interest_cap(max_open_interest_abs: u128) -> Vec<u8> {
+    let mut data = vec![58u8];
+    encode_u128(max_open_interest_abs, &mut data);
+    data
+}
+
+fn encode_set_max_leverage(max_leverage_x: u64) -> Vec<u8> {
+    let mut data = vec![63u8];
+    encode_u64(max_leverage_x, &mut data);
+    data
+}
+
+fn encode_set_liquidation_params(
+    liquidation_fee_bps: u64,
+    liquidation_fee_cap: u128,
+    liquidation_buffer_bps: u64,
+    min_liquidation_abs: u128,
+) -> Vec<u8> {
+    let mut data = vec![61u8];
+    encode_u64(liquidation_fee_bps, &mut data);
+    encode_u128(liquidation_fee_cap, &mut data);
+    encode_u64(liquidation_buffer_bps, &mut data);
+    encode_u128(min_liquidation_abs, &mut data);
+    data
+}
+
+fn encode_get_funding_index() -> Vec<u8> {
+    vec![24u8]
+}
+
+fn encode_withdraw_insurance(amount: Option<u64>) -> Vec<u8> {
+    let mut data = vec![20u8];
+    if let Some(amount) = amount {
+        encode_u64(amount, &mut data);
+    }
+    data
+}
+
+fn encode_set_maintenance_fee(fee_per_slot: u128) -> Vec<u8> {
+    let mut data = vec![15u8];
+    encode_u128(fee_per_slot, &mut data);
+    data
+}
+
+fn encode_set_insurance_floor(floor: u128) -> Vec<u8> {
+    let mut data = vec![48u8];
+    encode_u128(floor, &mut data);
+    data
+}
+
+fn encode_set_max_sweep_lag(slots: u64) -> Vec<u8> {
+    let mut data = vec![25u8];
+    encode_u64(slots, &mut data);
+    data
+}
+
+fn encode_set_oracle_price_cap(max_change_e2bps: u64) -> Vec<u8> {
+    let mut data = vec![18u8];
+    encode_u64(max_change_e2bps, &mut data);
+    data
+}
+
+fn encode_set_backstop_params(penalty_bps: u64, max_notional_per_slot_e6: u64) -> Vec<u8> {
+    let mut data = vec![26u8];
+    encode_u64(penalty_bps, &mut data);
+    encode_u64(max_notional_per_slot_e6, &mut data);
+    data
+}
+
+fn encode_close_with_backstop(user_idx: u16) -> Vec<u8> {
+    let mut data = vec![27u8];
+    encode_u16(user_idx, &mut data);
+    data
+}
+
+fn encode_get_vault_info() -> Vec<u8> {
+    vec![28u8]
+}
+
+/// SetRiskParams carries a full RiskParams; only margins and fees are applied.
+fn encode_set_risk_params(
+    maintenance_margin_bps: u64,
+    initial_margin_bps: u64,
+    trading_fee_bps: u64,
+    liquidation_fee_bps: u64,
+    allow_force: u8,
+) -> Vec<u8> {
+    let mut data = vec![30u8];
+    encode_u64(0, &mut data); // warmup_period_slots
+    encode_u64(maintenance_margin_bps, &mut data);
+    encode_u64(initial_margin_bps, &mut data);
+    encode_u64(trading_fee_bps, &mut data);
+    encode_u64(MAX_ACCOUNTS as u64, &mut data);
+    encode_u128(0, &mut data); // new_account_fee
+    encode_u128(0, &mut data); // risk_reduction_threshold
+    encode_u128(0, &mut data); // maintenance_fee_per_slot
+    encode_u64(100, &mut data); // max_crank_staleness_slots
+    encode_u64(liquidation_fee_bps, &mut data);
+    encode_u128(0, &mut data); // liquidation_fee_cap
+    encode_u64(0, &mut data); // liquidation_buffer_bps
+    encode_u128(0, &mut data); // min_liquidation_abs
+    data.push(allow_force);
+    data
+}
+
+fn encode_simulate_liquidation(target_idx: u16) -> Vec<u8> {
+    let mut data = vec![31u8];
+    encode_u16(target_idx, &mut data);
+    data
+}
+
+fn encode_topup_insurance_from(amount: u64, donor: &Pubkey) -> Vec<u8> {
+    let mut data = encode_topup_insurance(amount);
+    encode_pubkey(donor, &mut data);
+    data
+}
+
+fn encode_get_top_donors() -> Vec<u8> {
+    vec![34u8]
+}
+
+fn encode_get_warmup_status(user_idx: u16) -> Vec<u8> {
+    let mut data = vec![36u8];
+    encode_u16(user_idx, &mut data);
+    data
+}
+
+fn encode_reduce_position(lp: u16, user: u16, target_abs: i128) -> Vec<u8> {
+    let mut data = vec![37u8];
+    encode_u16(lp, &mut data);
+    encode_u16(user, &mut data);
+    encode_i128(target_abs, &mut data);
+    data
+}
+
+fn encode_multi_trade(user: u16, legs: &[(u16, i128)]) -> Vec<u8> {
+    let mut data = vec![38u8];
+    encode_u16(user, &mut data);
+    data.push(legs.len() as u8);
+    for &(lp, size) in legs {
+        encode_u16(lp, &mut data);
+        encode_i128(size, &mut data);
+    }
+    data
+}
+
+fn encode_set_feature_flags(set: u64, clear: u64) -> Vec<u8> {
+    let mut data = vec![39u8];
+    encode_u64(set, &mut data);
+    encode_u64(clear, &mut data);
+    data
+}
+
+fn encode_get_feature_flags() -> Vec<u8> {
+    vec![40u8]
+}
+
+fn encode_set_market_mode(mode: u8) -> Vec<u8> {
+    vec![41u8, mode]
+}
+
+fn encode_set_oracle_params(conf_filter_bps: u16, max_staleness_secs: u64) -> Vec<u8> {
+    let mut data = vec![44u8];
+    encode_u16(conf_filter_bps, &mut data);
+    encode_u64(max_staleness_secs, &mut data);
+    data
+}
+
+fn encode_set_index_feeds(secondary: &[u8; 32], tertiary: &[u8; 32]) -> Vec<u8> {
+    let mut data = vec![45u8];
+    encode_bytes32(secondary, &mut data);
+    encode_bytes32(tertiary, &mut data);
+    data
+}
+
+fn encode_transfer_position(from_idx: u16, to_idx: u16, size: i128) -> Vec<u8> {
+    let mut data = vec![43u8];
+    encode_u16(from_idx, &mut data);
+    encode_u16(to_idx, &mut data);
+    encode_i128(size, &mut data);
+    data
+}
+
+fn encode_liquidate_eligible(cursor: u16, max_scan: u16) -> Vec<u8> {
+    let mut data = vec![35u8];
+    encode_u16(cursor, &mut data);
+    encode_u16(max_scan, &mut data);
+    data
+}
+
+fn encode_get_account_state(user_idx: u16) -> Vec<u8> {
+    let mut data = vec![32u8];
+    encode_u16(user_idx, &mut data);
+    data
+}
+
+fn encode_get_market_stats() -> Vec<u8> {
+    vec![56u8]
+}
+
 fn find_idx_by_owner(data: &[u8], owner: Pubkey) -> Option<u16> {
     let engine = zc::engine_ref(data).ok()?;
     for i in 0..MAX_ACCOUNTS {
@@ -375,101 +709,388 @@ fn find_idx_by_owner(data: &[u8], owner: Pubkey) -> Option<u16> {
     None
 }
 
-// --- Tests ---
+// --- Return data capture ---
+//
+// The default native syscall stubs drop return data. These stubs keep it per
+// thread (tests run in parallel) and otherwise defer to the default behavior.
 
-#[test]
-fn test_struct_sizes() {
-    extern crate std;
-    use core::mem::{offset_of, size_of};
-    use percolator::{Account, RiskEngine, MAX_ACCOUNTS};
-    use std::println;
+thread_local! {
+    static RETURN_DATA: std::cell::RefCell<Option<Vec<u8>>> = std::cell::RefCell::new(None);
+}
 
-    println!("Size of Account: {}", size_of::<Account>());
-    println!("Offset of Account.kind: {}", offset_of!(Account, kind));
-    println!("Offset of Account.owner: {}", offset_of!(Account, owner));
-    println!("Size of RiskEngine: {}", size_of::<RiskEngine>());
-    println!("MAX_ACCOUNTS: {}", MAX_ACCOUNTS);
+struct ReturnDataStubs;
 
-    let account_array_size = MAX_ACCOUNTS * size_of::<Account>();
-    println!("Account array size: {}", account_array_size);
+impl solana_program::program_stubs::SyscallStubs for ReturnDataStubs {
+    fn sol_set_return_data(&self, data: &[u8]) {
+        RETURN_DATA.with(|r| *r.borrow_mut() = Some(data.to_vec()));
+    }
 
-    // Print offset of accounts array within RiskEngine
-    println!(
-        "Offset of RiskEngine.accounts: {}",
-        offset_of!(RiskEngine, accounts)
-    );
-    println!(
-        "Offset of RiskEngine.vault: {}",
-        offset_of!(RiskEngine, vault)
-    );
-    println!(
-        "Offset of RiskEngine.insurance_fund: {}",
-        offset_of!(RiskEngine, insurance_fund)
-    );
-    println!(
-        "Offset of RiskEngine.params: {}",
-        offset_of!(RiskEngine, params)
-    );
-    println!(
-        "Offset of RiskEngine.used: {}",
-        offset_of!(RiskEngine, used)
-    );
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        RETURN_DATA.with(|r| r.borrow().clone().map(|d| (Pubkey::default(), d)))
+    }
+}
 
-    // Print the SBF constant (note: this is x86_64 value when run as native test)
-    println!(
-        "ACCOUNTS_OFFSET (this test is x86_64): {}",
-        percolator_prog::zc::ACCOUNTS_OFFSET
-    );
+fn install_return_data_stubs() {
+    static ONCE: std::sync::Once = std::sync::Once::new();
+    ONCE.call_once(|| {
+        solana_program::program_stubs::set_syscall_stubs(Box::new(ReturnDataStubs));
+    });
+}
 
-    // Print SLAB_LEN
-    println!("ENGINE_OFF: {}", percolator_prog::constants::ENGINE_OFF);
-    println!("ENGINE_LEN: {}", percolator_prog::constants::ENGINE_LEN);
-    println!("SLAB_LEN: {}", percolator_prog::constants::SLAB_LEN);
+// --- Market helpers ---
+
+/// Run InitMarket on the fixture slab with the given instruction data.
+fn init_market_with(f: &mut MarketFixture, data: &[u8]) -> Result<(), ProgramError> {
+    let mut dummy_ata = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+    let accounts = vec![
+        f.admin.to_info(),
+        f.slab.to_info(),
+        f.mint.to_info(),
+        f.vault.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+        f.rent.to_info(),
+        dummy_ata.to_info(),
+        f.system.to_info(),
+    ];
+    process_instruction(&f.program_id, &accounts, data)
 }
 
-#[test]
-fn test_init_market() {
-    let mut f = setup_market();
-    let data = encode_init_market(&f, 100);
+/// A registered user or LP together with its owner and token account.
+struct Participant {
+    owner: TestAccount,
+    ata: TestAccount,
+    idx: u16,
+}
+
+fn new_owner_and_ata(f: &MarketFixture, balance: u64) -> (TestAccount, TestAccount) {
+    let owner = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    )
+    .signer();
+    let ata = TestAccount::new(
+        Pubkey::new_unique(),
+        spl_token::ID,
+        0,
+        make_token_account(f.mint.key, owner.key, balance),
+    )
+    .writable();
+    (owner, ata)
+}
 
+/// Register a user and deposit `amount` into it.
+fn add_user(f: &mut MarketFixture, amount: u64) -> Participant {
+    let (mut owner, mut ata) = new_owner_and_ata(f, amount);
     {
-        let mut dummy_ata = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
         let accounts = vec![
-            f.admin.to_info(),
+            owner.to_info(),
             f.slab.to_info(),
-            f.mint.to_info(),
+            ata.to_info(),
             f.vault.to_info(),
             f.token_prog.to_info(),
-            f.clock.to_info(),
-            f.rent.to_info(),
-            dummy_ata.to_info(),
-            f.system.to_info(),
         ];
-        process_instruction(&f.program_id, &accounts, &data).unwrap();
+        process_instruction(&f.program_id, &accounts, &encode_init_user(0)).unwrap();
     }
-
-    let header = state::read_header(&f.slab.data);
-    assert_eq!(header.magic, MAGIC);
-    assert_eq!(header.version, VERSION);
-
-    let engine = zc::engine_ref(&f.slab.data).unwrap();
-    assert_eq!(engine.params.max_accounts, MAX_ACCOUNTS as u64);
+    let idx = find_idx_by_owner(&f.slab.data, owner.key).unwrap();
+    let mut p = Participant { owner, ata, idx };
+    try_deposit(f, &mut p, amount).unwrap();
+    p
 }
 
-#[test]
-#[cfg(feature = "test")]
-fn test_init_user() {
-    let mut f = setup_market();
-    let init_data = encode_init_market(&f, 100);
+/// Register an LP (with dummy matcher accounts) and deposit `amount` into it.
+fn add_lp(f: &mut MarketFixture, amount: u64) -> Participant {
+    let (mut owner, mut ata) = new_owner_and_ata(f, amount);
     {
-        let mut dummy_ata = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
-        let init_accounts = vec![
-            f.admin.to_info(),
+        let accounts = vec![
+            owner.to_info(),
             f.slab.to_info(),
-            f.mint.to_info(),
+            ata.to_info(),
             f.vault.to_info(),
             f.token_prog.to_info(),
-            f.clock.to_info(),
+        ];
+        let data = encode_init_lp(Pubkey::new_unique(), Pubkey::new_unique(), 0);
+        process_instruction(&f.program_id, &accounts, &data).unwrap();
+    }
+    let idx = find_idx_by_owner(&f.slab.data, owner.key).unwrap();
+    let mut p = Participant { owner, ata, idx };
+    try_deposit(f, &mut p, amount).unwrap();
+    p
+}
+
+fn try_deposit(
+    f: &mut MarketFixture,
+    p: &mut Participant,
+    amount: u64,
+) -> Result<(), ProgramError> {
+    let accounts = vec![
+        p.owner.to_info(),
+        f.slab.to_info(),
+        p.ata.to_info(),
+        f.vault.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+    ];
+    process_instruction(&f.program_id, &accounts, &encode_deposit(p.idx, amount))
+}
+
+fn try_deposit_wrapped(
+    f: &mut MarketFixture,
+    p: &mut Participant,
+    lamports: u64,
+) -> Result<(), ProgramError> {
+    let accounts = vec![
+        p.owner.to_info(),
+        f.slab.to_info(),
+        p.ata.to_info(),
+        f.vault.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+        f.system.to_info(),
+    ];
+    let data = encode_deposit_wrapped(p.idx, lamports);
+    process_instruction(&f.program_id, &accounts, &data)
+}
+
+fn try_withdraw(
+    f: &mut MarketFixture,
+    p: &mut Participant,
+    amount: u64,
+) -> Result<(), ProgramError> {
+    let mut vault_pda =
+        TestAccount::new(f.vault_pda, solana_program::system_program::id(), 0, vec![]);
+    let accounts = vec![
+        p.owner.to_info(),
+        f.slab.to_info(),
+        f.vault.to_info(),
+        p.ata.to_info(),
+        vault_pda.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    process_instruction(&f.program_id, &accounts, &encode_withdraw(p.idx, amount))
+}
+
+fn try_close_account(f: &mut MarketFixture, p: &mut Participant) -> Result<(), ProgramError> {
+    let mut vault_pda =
+        TestAccount::new(f.vault_pda, solana_program::system_program::id(), 0, vec![]);
+    let accounts = vec![
+        p.owner.to_info(),
+        f.slab.to_info(),
+        f.vault.to_info(),
+        p.ata.to_info(),
+        vault_pda.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    process_instruction(&f.program_id, &accounts, &encode_close_account(p.idx))
+}
+
+/// TradeNoCpi between `user` and `lp` at the fixture oracle price.
+fn try_trade(
+    f: &mut MarketFixture,
+    user: &mut Participant,
+    lp: &mut Participant,
+    size: i128,
+) -> Result<(), ProgramError> {
+    let accounts = vec![
+        user.owner.to_info(),
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    process_instruction(
+        &f.program_id,
+        &accounts,
+        &encode_trade(lp.idx, user.idx, size),
+    )
+}
+
+fn try_reduce(
+    f: &mut MarketFixture,
+    user: &mut Participant,
+    lp: &mut Participant,
+    target_abs: i128,
+) -> Result<(), ProgramError> {
+    let accounts = vec![
+        user.owner.to_info(),
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    process_instruction(
+        &f.program_id,
+        &accounts,
+        &encode_reduce_position(lp.idx, user.idx, target_abs),
+    )
+}
+
+/// Send a two-account [admin, slab] instruction signed by the fixture admin.
+fn try_admin_ix(f: &mut MarketFixture, data: &[u8]) -> Result<(), ProgramError> {
+    let accounts = vec![f.admin.to_info(), f.slab.to_info()];
+    process_instruction(&f.program_id, &accounts, data)
+}
+
+fn try_crank_permissionless(f: &mut MarketFixture) -> Result<(), ProgramError> {
+    let mut keeper = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    );
+    let accounts = vec![
+        keeper.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    process_instruction(&f.program_id, &accounts, &encode_crank_permissionless(0))
+}
+
+/// Permissionless crank with the standard [caller, slab, clock, oracle] layout;
+/// returns the decoded CrankOutcome.
+fn crank_outcome(f: &mut MarketFixture) -> CrankOutcome {
+    install_return_data_stubs();
+    RETURN_DATA.with(|r| r.borrow_mut().take());
+    try_crank_permissionless(f).unwrap();
+    let bytes = RETURN_DATA
+        .with(|r| r.borrow_mut().take())
+        .expect("crank must set return data");
+    assert_eq!(bytes.len(), CrankOutcome::LEN);
+    CrankOutcome::from_bytes(&bytes).unwrap()
+}
+
+/// Run a read-only view instruction against the slab and return its return data.
+fn view(f: &mut MarketFixture, data: &[u8]) -> Vec<u8> {
+    install_return_data_stubs();
+    RETURN_DATA.with(|r| r.borrow_mut().take());
+    let accounts = vec![f.slab.to_info()];
+    process_instruction(&f.program_id, &accounts, data).unwrap();
+    RETURN_DATA
+        .with(|r| r.borrow_mut().take())
+        .expect("view instruction must set return data")
+}
+
+fn verify_invariants(f: &mut MarketFixture) -> InvariantReport {
+    install_return_data_stubs();
+    RETURN_DATA.with(|r| r.borrow_mut().take());
+    let accounts = vec![f.slab.to_info(), f.vault.to_info()];
+    process_instruction(&f.program_id, &accounts, &encode_verify_invariants()).unwrap();
+    let bytes = RETURN_DATA
+        .with(|r| r.borrow_mut().take())
+        .expect("VerifyInvariants must set return data");
+    InvariantReport::from_bytes(&bytes).unwrap()
+}
+
+fn insurance_balance(f: &MarketFixture) -> u128 {
+    zc::engine_ref(&f.slab.data)
+        .unwrap()
+        .insurance_fund
+        .balance
+        .get()
+}
+
+// --- Tests ---
+
+#[test]
+fn test_struct_sizes() {
+    extern crate std;
+    use core::mem::{offset_of, size_of};
+    use percolator::{Account, RiskEngine, MAX_ACCOUNTS};
+    use std::println;
+
+    println!("Size of Account: {}", size_of::<Account>());
+    println!("Offset of Account.kind: {}", offset_of!(Account, kind));
+    println!("Offset of Account.owner: {}", offset_of!(Account, owner));
+    println!("Size of RiskEngine: {}", size_of::<RiskEngine>());
+    println!("MAX_ACCOUNTS: {}", MAX_ACCOUNTS);
+
+    let account_array_size = MAX_ACCOUNTS * size_of::<Account>();
+    println!("Account array size: {}", account_array_size);
+
+    // Print offset of accounts array within RiskEngine
+    println!(
+        "Offset of RiskEngine.accounts: {}",
+        offset_of!(RiskEngine, accounts)
+    );
+    println!(
+        "Offset of RiskEngine.vault: {}",
+        offset_of!(RiskEngine, vault)
+    );
+    println!(
+        "Offset of RiskEngine.insurance_fund: {}",
+        offset_of!(RiskEngine, insurance_fund)
+    );
+    println!(
+        "Offset of RiskEngine.params: {}",
+        offset_of!(RiskEngine, params)
+    );
+    println!(
+        "Offset of RiskEngine.used: {}",
+        offset_of!(RiskEngine, used)
+    );
+
+    // Print the SBF constant (note: this is x86_64 value when run as native test)
+    println!(
+        "ACCOUNTS_OFFSET (this test is x86_64): {}",
+        percolator_prog::zc::ACCOUNTS_OFFSET
+    );
+
+    // Print SLAB_LEN
+    println!("ENGINE_OFF: {}", percolator_prog::constants::ENGINE_OFF);
+    println!("ENGINE_LEN: {}", percolator_prog::constants::ENGINE_LEN);
+    println!("SLAB_LEN: {}", percolator_prog::constants::SLAB_LEN);
+}
+
+#[test]
+fn test_init_market() {
+    let mut f = setup_market();
+    let data = encode_init_market(&f, 100);
+
+    {
+        let mut dummy_ata = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+        let accounts = vec![
+            f.admin.to_info(),
+            f.slab.to_info(),
+            f.mint.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.rent.to_info(),
+            dummy_ata.to_info(),
+            f.system.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &data).unwrap();
+    }
+
+    let header = state::read_header(&f.slab.data);
+    assert_eq!(header.magic, MAGIC);
+    assert_eq!(header.version, VERSION);
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.params.max_accounts, MAX_ACCOUNTS as u64);
+}
+
+#[test]
+#[cfg(feature = "test")]
+fn test_init_user() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    {
+        let mut dummy_ata = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+        let init_accounts = vec![
+            f.admin.to_info(),
+            f.slab.to_info(),
+            f.mint.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
             f.rent.to_info(),
             dummy_ata.to_info(),
             f.system.to_info(),
@@ -598,6 +1219,50 @@ fn test_deposit_withdraw() {
     assert_eq!(vault_state.amount, 300);
 }
 
+#[test]
+fn test_handle_deposit_called_directly() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 100);
+    user.ata.data = make_token_account(f.mint.key, user.owner.key, 500);
+    let capital = |f: &MarketFixture| {
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .capital
+            .get()
+    };
+    let before = capital(&f);
+
+    // Same accounts as DepositCollateral, no instruction decoding
+    {
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            user.ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+        ];
+        processor::handle_deposit(&f.program_id, &accounts, user.idx, 500).unwrap();
+    }
+
+    assert_eq!(capital(&f), before + 500);
+    assert_eq!(TokenAccount::unpack(&f.vault.data).unwrap().amount, 600);
+    assert_eq!(TokenAccount::unpack(&user.ata.data).unwrap().amount, 0);
+
+    // Handlers keep their own checks: a stranger cannot deposit into the account
+    let (mut stranger, mut stranger_ata) = new_owner_and_ata(&f, 10);
+    let accounts = vec![
+        stranger.to_info(),
+        f.slab.to_info(),
+        stranger_ata.to_info(),
+        f.vault.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+    ];
+    assert!(processor::handle_deposit(&f.program_id, &accounts, user.idx, 10).is_err());
+}
+
 #[test]
 fn test_vault_validation() {
     let mut f = setup_market();
@@ -1315,6 +1980,46 @@ fn test_trade_cpi_wrong_oracle_key_rejected() {
     assert_eq!(res, Err(PercolatorError::InvalidOracleKey.into()));
 }
 
+#[test]
+fn test_price_paths_reject_wrong_oracle_or_clock() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+
+    // A receiver-owned account for another feed: the embedded feed id decides
+    let good_oracle = f.pyth_index.data.clone();
+    f.pyth_index.data = make_pyth(&[0xFFu8; 32], 50_000_000, -6, 1, 100);
+    let wrong_key: Result<(), ProgramError> = Err(PercolatorError::InvalidOracleKey.into());
+    assert_eq!(try_crank_permissionless(&mut f), wrong_key);
+    assert_eq!(try_trade(&mut f, &mut user, &mut lp, 100), wrong_key);
+    assert_eq!(try_withdraw(&mut f, &mut user, 100), wrong_key);
+    assert_eq!(try_close_account(&mut f, &mut user), wrong_key);
+    let mut liquidate_data = vec![7u8];
+    encode_u16(user.idx, &mut liquidate_data);
+    let liquidate = |f: &mut MarketFixture, lp: &mut Participant| {
+        let accounts = vec![
+            lp.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &liquidate_data)
+    };
+    assert_eq!(liquidate(&mut f, &mut lp), wrong_key);
+
+    // A clock account that is not the Clock sysvar
+    f.pyth_index.data = good_oracle;
+    f.clock.key = Pubkey::new_unique();
+    let wrong_clock: Result<(), ProgramError> = Err(ProgramError::InvalidArgument);
+    assert_eq!(try_crank_permissionless(&mut f), wrong_clock);
+    assert_eq!(try_trade(&mut f, &mut user, &mut lp, 100), wrong_clock);
+    assert_eq!(try_withdraw(&mut f, &mut user, 100), wrong_clock);
+    assert_eq!(try_close_account(&mut f, &mut user), wrong_clock);
+    assert_eq!(liquidate(&mut f, &mut lp), wrong_clock);
+}
+
 #[test]
 fn test_set_risk_threshold() {
     let mut f = setup_market();
@@ -1678,6 +2383,33 @@ fn test_crank_updates_threshold_from_risk_metric() {
             "First update from 0 should be EWMA-smoothed raw target"
         );
     }
+
+    // Later updates keep climbing by the proportional step clamp, not by
+    // thresh_min_step, and never overshoot the target
+    let mut prev = 5625u128;
+    for slot in [110u64, 120, 130] {
+        f.clock.data = make_clock(slot, 100);
+        {
+            let accs = vec![
+                user.to_info(),
+                f.slab.to_info(),
+                f.clock.to_info(),
+                f.pyth_index.to_info(),
+            ];
+            process_instruction(&f.program_id, &accs, &encode_crank(user_idx, 0)).unwrap();
+        }
+        let threshold = zc::engine_ref(&f.slab.data)
+            .unwrap()
+            .risk_reduction_threshold();
+        assert!(
+            threshold >= prev + prev * DEFAULT_THRESH_STEP_BPS as u128 / 10_000,
+            "threshold crawled from {} to {}",
+            prev,
+            threshold
+        );
+        assert!(threshold <= 56_250);
+        prev = threshold;
+    }
 }
 
 #[test]
@@ -2332,6 +3064,31 @@ fn test_oracle_inversion() {
     );
 }
 
+#[test]
+fn test_oracle_inversion_rejects_price_below_floor() {
+    use percolator_prog::oracle::read_engine_price_e6;
+    use percolator_prog::verify::MIN_INVERTIBLE_PRICE_E6;
+
+    let feed_id = [0xCDu8; 32];
+    let pyth_receiver_id = Pubkey::new_from_array(PYTH_RECEIVER_BYTES);
+    let read = |raw: i64, invert: u8| {
+        let pyth_data = make_pyth(&feed_id, raw, -6, 0, 100);
+        let mut oracle = TestAccount::new(Pubkey::new_unique(), pyth_receiver_id, 0, pyth_data);
+        read_engine_price_e6(&oracle.to_info(), &feed_id, 100, 100, 500, invert, 0)
+    };
+
+    // Raw $0.000001 would invert to 1e12: rejected cleanly instead
+    assert_eq!(read(1, 1), Err(PercolatorError::OracleInvalid.into()));
+    assert_eq!(
+        read(MIN_INVERTIBLE_PRICE_E6 as i64 - 1, 1),
+        Err(PercolatorError::OracleInvalid.into())
+    );
+    assert_eq!(read(MIN_INVERTIBLE_PRICE_E6 as i64, 1), Ok(1_000_000_000));
+
+    // The floor only applies to inverted markets
+    assert_eq!(read(1, 0), Ok(1));
+}
+
 #[test]
 fn test_unit_scale_conversion() {
     // Test base_to_units and units_to_base with unit_scale
@@ -2645,37 +3402,140 @@ fn test_vault_amount_matches_engine_vault_plus_dust() {
 }
 
 #[test]
-fn test_engine_vault_equals_insurance_plus_capital_when_no_fees() {
-    // INVARIANT #2: engine.vault = insurance_fund.balance + sum(account.capital)
-    //
-    // This holds when:
-    // - new_account_fee = 0
-    // - no trades (no trading fees, no PnL)
-    // - no topups
-    //
-    // The existing encode_init_market_invert already uses all-zero fees.
+fn test_close_account_returns_sub_unit_dust_to_last_account_out() {
     let mut f = setup_market();
-    let unit_scale: u32 = 10;
+    let init_data = encode_init_market_invert(&f, 100, 0, 1000);
+    init_market_with(&mut f, &init_data).unwrap();
+    let balance = |ata: &TestAccount| TokenAccount::unpack(&ata.data).unwrap().amount;
+    let vault_balance = |f: &MarketFixture| {
+        TokenAccount::unpack(&f.vault.data[..TokenAccount::LEN])
+            .unwrap()
+            .amount
+    };
 
-    // Init market with unit_scale=10, all fees=0
-    {
-        let data = encode_init_market_invert(&f, 100, 0, unit_scale);
-        let mut dummy_ata = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
-        let accounts = vec![
-            f.admin.to_info(),
-            f.slab.to_info(),
-            f.mint.to_info(),
-            f.vault.to_info(),
-            f.token_prog.to_info(),
-            f.clock.to_info(),
-            f.rent.to_info(),
-            dummy_ata.to_info(),
-            f.system.to_info(),
-        ];
-        process_instruction(&f.program_id, &accounts, &data).unwrap();
-    }
+    // 500 + 700 base of sub-unit remainders; the crank sweeps one whole unit
+    // of it to insurance and leaves 200
+    let mut a = add_user(&mut f, 10_000_500);
+    let mut b = add_user(&mut f, 2_000_700);
+    assert_eq!(state::read_dust_base(&f.slab.data).unwrap(), 1_200);
+    try_crank_permissionless(&mut f).unwrap();
+    assert_eq!(state::read_dust_base(&f.slab.data).unwrap(), 200);
+
+    // While another account is open the remainder stays pooled
+    try_close_account(&mut f, &mut a).unwrap();
+    assert_eq!(balance(&a.ata), 10_000_000);
+    assert_eq!(state::read_dust_base(&f.slab.data).unwrap(), 200);
+
+    // The last account out takes it
+    try_close_account(&mut f, &mut b).unwrap();
+    assert_eq!(balance(&b.ata), 2_000_200);
+    assert_eq!(state::read_dust_base(&f.slab.data).unwrap(), 0);
+
+    // Conservation: returned base plus insurance is every deposited token
+    let insurance_units = zc::engine_ref(&f.slab.data).unwrap().insurance_fund.balance;
+    let insurance_base = insurance_units.get() as u64 * 1000;
+    assert_eq!(insurance_base, 1000);
+    assert_eq!(vault_balance(&f), insurance_base);
+    assert_eq!(
+        balance(&a.ata) + balance(&b.ata) + insurance_base,
+        10_000_500 + 2_000_700
+    );
+}
 
-    // Create and fund two users
+#[test]
+fn test_close_account_rejects_attacker_vault_and_wrong_pda() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000);
+
+    // An attacker-owned token account of the right mint in the vault slot
+    let attacker = Pubkey::new_unique();
+    let mut attacker_vault = TestAccount::new(
+        Pubkey::new_unique(),
+        spl_token::ID,
+        0,
+        make_token_account(f.mint.key, attacker, 0),
+    )
+    .writable();
+    let mut vault_pda =
+        TestAccount::new(f.vault_pda, solana_program::system_program::id(), 0, vec![]);
+    let accounts = vec![
+        user.owner.to_info(),
+        f.slab.to_info(),
+        attacker_vault.to_info(),
+        user.ata.to_info(),
+        vault_pda.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &encode_close_account(user.idx)),
+        Err(PercolatorError::InvalidVaultAta.into())
+    );
+
+    // The real vault with a non-derived authority at index 4
+    let mut wrong_pda = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    );
+    let accounts = vec![
+        user.owner.to_info(),
+        f.slab.to_info(),
+        f.vault.to_info(),
+        user.ata.to_info(),
+        wrong_pda.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &encode_close_account(user.idx)),
+        Err(ProgramError::InvalidArgument)
+    );
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].capital.get(), 1_000);
+
+    try_close_account(&mut f, &mut user).unwrap();
+    assert_eq!(TokenAccount::unpack(&user.ata.data).unwrap().amount, 1_000);
+}
+
+#[test]
+fn test_engine_vault_equals_insurance_plus_capital_when_no_fees() {
+    // INVARIANT #2: engine.vault = insurance_fund.balance + sum(account.capital)
+    //
+    // This holds when:
+    // - new_account_fee = 0
+    // - no trades (no trading fees, no PnL)
+    // - no topups
+    //
+    // The existing encode_init_market_invert already uses all-zero fees.
+    let mut f = setup_market();
+    let unit_scale: u32 = 10;
+
+    // Init market with unit_scale=10, all fees=0
+    {
+        let data = encode_init_market_invert(&f, 100, 0, unit_scale);
+        let mut dummy_ata = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+        let accounts = vec![
+            f.admin.to_info(),
+            f.slab.to_info(),
+            f.mint.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.rent.to_info(),
+            dummy_ata.to_info(),
+            f.system.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &data).unwrap();
+    }
+
+    // Create and fund two users
     let mut user1 = TestAccount::new(
         Pubkey::new_unique(),
         solana_program::system_program::id(),
@@ -3233,3 +4093,4678 @@ fn test_close_slab_non_admin_rejected() {
         "Slab should still be initialized after failed close"
     );
 }
+
+#[test]
+fn test_emergency_exit_waives_fees_on_reducing_trades() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // 10 bps trading fee
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .params
+        .trading_fee_bps = 10;
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+
+    // Open: normal fee
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    // Normal-mode reduce charges the configured fee
+    let before = insurance_balance(&f);
+    try_trade(&mut f, &mut user, &mut lp, -500).unwrap();
+    assert!(
+        insurance_balance(&f) > before,
+        "normal-mode close must charge the trading fee"
+    );
+
+    // Declare emergency exit
+    try_admin_ix(&mut f, &encode_set_emergency_exit(1)).unwrap();
+    assert!(state::is_emergency_exit(&f.slab.data));
+
+    // Emergency close is fee-free
+    let before = insurance_balance(&f);
+    try_trade(&mut f, &mut user, &mut lp, -500).unwrap();
+    assert_eq!(
+        insurance_balance(&f),
+        before,
+        "emergency-exit close must not charge a fee"
+    );
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        0
+    );
+    // Configured fee is restored after the waived fill
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().params.trading_fee_bps,
+        10
+    );
+
+    // Opens still pay the normal fee during an emergency
+    let before = insurance_balance(&f);
+    try_trade(&mut f, &mut user, &mut lp, 500).unwrap();
+    assert!(insurance_balance(&f) > before);
+
+    // Lift emergency exit: reducing fills pay again
+    try_admin_ix(&mut f, &encode_set_emergency_exit(0)).unwrap();
+    assert!(!state::is_emergency_exit(&f.slab.data));
+    let before = insurance_balance(&f);
+    try_trade(&mut f, &mut user, &mut lp, -500).unwrap();
+    assert!(insurance_balance(&f) > before);
+}
+
+#[test]
+fn test_emergency_exit_charges_lp_side_that_extends_risk() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    try_admin_ix(&mut f, &encode_set_maintenance_fee(10)).unwrap();
+
+    let mut long = add_user(&mut f, 1_000_000);
+    let mut short = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut long, &mut lp, 1_000).unwrap();
+    try_trade(&mut f, &mut short, &mut lp, -2_000).unwrap();
+    crank_outcome(&mut f);
+    try_admin_ix(&mut f, &encode_set_emergency_exit(1)).unwrap();
+
+    let capital = |f: &MarketFixture, idx: u16| {
+        zc::engine_ref(&f.slab.data).unwrap().accounts[idx as usize]
+            .capital
+            .get()
+    };
+    let long_before = capital(&f, long.idx);
+    let lp_before = capital(&f, lp.idx);
+
+    // The user closes its long; the LP, already long, takes the other side
+    // and extends its position, so only the user's fees are waived
+    f.clock.data = make_clock(120, 100);
+    try_trade(&mut f, &mut long, &mut lp, -1_000).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[long.idx as usize].position_size.get(), 0);
+    assert_eq!(engine.accounts[lp.idx as usize].position_size.get(), 2_000);
+    assert_eq!(capital(&f, long.idx), long_before);
+    assert_eq!(lp_before - capital(&f, lp.idx), 200);
+}
+
+#[test]
+fn test_set_emergency_exit_non_admin_rejected() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut attacker = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    )
+    .signer();
+    let accounts = vec![attacker.to_info(), f.slab.to_info()];
+    let res = process_instruction(&f.program_id, &accounts, &encode_set_emergency_exit(1));
+    assert_eq!(res, Err(PercolatorError::EngineUnauthorized.into()));
+    assert!(!state::is_emergency_exit(&f.slab.data));
+}
+
+#[test]
+fn test_max_trade_notional_rejects_oversized_fill() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+
+    // Oracle price is $100: cap a single fill at 50_000 notional (500 contracts)
+    try_admin_ix(&mut f, &encode_set_max_trade_notional(50_000)).unwrap();
+    assert_eq!(
+        state::read_config(&f.slab.data).max_trade_notional_e6,
+        50_000
+    );
+
+    // 1_000 contracts = 100_000 notional: rejected in one fill
+    let res = try_trade(&mut f, &mut user, &mut lp, 1_000);
+    assert_eq!(res, Err(PercolatorError::TradeTooLarge.into()));
+
+    // Split into two fills of 500 each: both accepted
+    try_trade(&mut f, &mut user, &mut lp, 500).unwrap();
+    try_trade(&mut f, &mut user, &mut lp, 500).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        1_000
+    );
+
+    // Cap applies to either side
+    let res = try_trade(&mut f, &mut user, &mut lp, -1_000);
+    assert_eq!(res, Err(PercolatorError::TradeTooLarge.into()));
+
+    // Zero disables the cap
+    try_admin_ix(&mut f, &encode_set_max_trade_notional(0)).unwrap();
+    try_trade(&mut f, &mut user, &mut lp, -1_000).unwrap();
+}
+
+#[test]
+fn test_get_funding_index_matches_engine_settlement() {
+    use percolator_prog::return_data::FundingIndex;
+
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    try_crank_permissionless(&mut f).unwrap();
+
+    // View reflects engine state after the crank
+    let idx = FundingIndex::from_bytes(&view(&mut f, &encode_get_funding_index())).unwrap();
+    {
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        assert_eq!(idx.funding_index_qpb_e6, engine.funding_index_qpb_e6.get());
+        assert_eq!(idx.last_funding_slot, engine.last_funding_slot);
+        assert_eq!(idx.last_crank_slot, engine.last_crank_slot);
+    }
+
+    // Simulate accrued funding (the fixture's position is too small to move the
+    // index on its own), then compute the user's payment client-side.
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        let current = engine.funding_index_qpb_e6.get();
+        engine.funding_index_qpb_e6 = I128::new(current + 2_500_000);
+    }
+    let idx = FundingIndex::from_bytes(&view(&mut f, &encode_get_funding_index())).unwrap();
+    let (pos, acct_index, pnl_before) = {
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        let acc = &engine.accounts[user.idx as usize];
+        (
+            acc.position_size.get(),
+            acc.funding_index.get(),
+            acc.pnl.get(),
+        )
+    };
+    let expected_payment = idx.accrued_payment(pos, acct_index);
+    assert_eq!(expected_payment, 2_500);
+
+    // Same-slot crank settles funding without accruing more (dt = 0)
+    try_crank_permissionless(&mut f).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let acc = &engine.accounts[user.idx as usize];
+    assert_eq!(acc.funding_index.get(), idx.funding_index_qpb_e6);
+    assert_eq!(acc.pnl.get(), pnl_before - expected_payment);
+}
+
+#[test]
+fn test_funding_index_return_data_roundtrip() {
+    use percolator_prog::return_data::FundingIndex;
+
+    let v = FundingIndex {
+        funding_index_qpb_e6: -123_456_789_012_345,
+        last_funding_slot: 42,
+        last_crank_slot: u64::MAX,
+    };
+    let bytes = v.to_bytes();
+    assert_eq!(bytes.len(), FundingIndex::LEN);
+    assert_eq!(FundingIndex::from_bytes(&bytes), Some(v));
+    assert_eq!(
+        FundingIndex::from_bytes(&bytes[..FundingIndex::LEN - 1]),
+        None
+    );
+}
+
+#[test]
+fn test_event_payloads_roundtrip_through_log_line() {
+    use percolator_prog::events::{
+        decode_base64, decode_line, encode_base64, Cranked, Liquidated, TradeExecuted,
+        MAX_EVENT_LEN, MAX_LINE_LEN, PREFIX,
+    };
+
+    let trade = TradeExecuted {
+        user_idx: 3,
+        lp_idx: 0,
+        size: -1_000_000,
+        exec_price: 138_000_000,
+        fee: 138,
+    };
+    let mut b64 = [0u8; MAX_LINE_LEN];
+    let n = encode_base64(&trade.to_bytes(), &mut b64);
+    assert!(PREFIX.len() + n <= MAX_LINE_LEN);
+    let line = format!(
+        "Program log: {}{}",
+        PREFIX,
+        std::str::from_utf8(&b64[..n]).unwrap()
+    );
+    let mut out = [0u8; MAX_EVENT_LEN];
+    let len = decode_line(&line, &mut out).unwrap();
+    assert_eq!(len, TradeExecuted::LEN);
+    assert_eq!(TradeExecuted::from_bytes(&out[..len]), Some(trade));
+    // Kind byte distinguishes events of the same schema version
+    assert_eq!(Liquidated::from_bytes(&out[..len]), None);
+
+    let liq = Liquidated {
+        target_idx: 7,
+        penalty: u128::MAX,
+    };
+    let n = encode_base64(&liq.to_bytes(), &mut b64);
+    let len = decode_base64(&b64[..n], &mut out).unwrap();
+    assert_eq!(Liquidated::from_bytes(&out[..len]), Some(liq));
+
+    let crank = Cranked {
+        slot: 42,
+        funding_rate: -5,
+    };
+    let n = encode_base64(&crank.to_bytes(), &mut b64);
+    let len = decode_base64(&b64[..n], &mut out).unwrap();
+    assert_eq!(Cranked::from_bytes(&out[..len]), Some(crank));
+
+    // Padding (1 and 2 bytes) and malformed input
+    let mut small = [0u8; 8];
+    assert_eq!(encode_base64(b"ab", &mut small), 4);
+    assert_eq!(&small[..4], b"YWI=");
+    assert_eq!(encode_base64(b"a", &mut small), 4);
+    assert_eq!(&small[..4], b"YQ==");
+    assert_eq!(decode_base64(b"YWI=", &mut out), Some(2));
+    assert_eq!(&out[..2], b"ab");
+    assert_eq!(decode_base64(b"YQ==YQ==", &mut out), None);
+    assert_eq!(decode_base64(b"YW!=", &mut out), None);
+    assert_eq!(decode_base64(b"YWI", &mut out), None);
+}
+
+#[test]
+fn test_sweep_backlog_blocks_opens_until_full_sweep() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    try_admin_ix(&mut f, &encode_set_max_sweep_lag(10)).unwrap();
+
+    // Keepers idle for 50 slots (within engine crank staleness, beyond sweep lag)
+    f.clock.data = make_clock(150, 100);
+
+    // Opening (increase and flip) rejected
+    let res = try_trade(&mut f, &mut user, &mut lp, 100);
+    assert_eq!(res, Err(PercolatorError::KeeperSweepStale.into()));
+    let res = try_trade(&mut f, &mut user, &mut lp, -2_000);
+    assert_eq!(res, Err(PercolatorError::KeeperSweepStale.into()));
+
+    // Reducing still allowed
+    try_trade(&mut f, &mut user, &mut lp, -500).unwrap();
+
+    // Keepers catch up: crank until the cursor completes a full cycle
+    for _ in 0..64 {
+        try_crank_permissionless(&mut f).unwrap();
+        if state::read_config(&f.slab.data).last_full_sweep_slot == 150 {
+            break;
+        }
+    }
+    assert_eq!(state::read_config(&f.slab.data).last_full_sweep_slot, 150);
+
+    // Opens allowed again
+    try_trade(&mut f, &mut user, &mut lp, 100).unwrap();
+}
+
+#[test]
+fn test_read_return_from_ctx_roundtrip() {
+    use percolator_prog::constants::{
+        MATCHER_ABI_VERSION, MATCHER_CONTEXT_LEN, RET_OFF_ABI_VERSION, RET_OFF_EXEC_PRICE,
+        RET_OFF_EXEC_SIZE, RET_OFF_FLAGS, RET_OFF_LP_ACCOUNT_ID, RET_OFF_ORACLE_PRICE,
+        RET_OFF_REQ_ID, RET_OFF_RESERVED,
+    };
+    use percolator_prog::matcher_abi::{read_return_from_ctx, FLAG_PARTIAL_OK, FLAG_VALID};
+
+    // Write a context the way a matcher does
+    let mut ctx = vec![0u8; MATCHER_CONTEXT_LEN];
+    ctx[RET_OFF_ABI_VERSION..RET_OFF_ABI_VERSION + 4]
+        .copy_from_slice(&MATCHER_ABI_VERSION.to_le_bytes());
+    ctx[RET_OFF_FLAGS..RET_OFF_FLAGS + 4]
+        .copy_from_slice(&(FLAG_VALID | FLAG_PARTIAL_OK).to_le_bytes());
+    ctx[RET_OFF_EXEC_PRICE..RET_OFF_EXEC_PRICE + 8].copy_from_slice(&101_500_000u64.to_le_bytes());
+    ctx[RET_OFF_EXEC_SIZE..RET_OFF_EXEC_SIZE + 16].copy_from_slice(&(-750i128).to_le_bytes());
+    ctx[RET_OFF_REQ_ID..RET_OFF_REQ_ID + 8].copy_from_slice(&7u64.to_le_bytes());
+    ctx[RET_OFF_LP_ACCOUNT_ID..RET_OFF_LP_ACCOUNT_ID + 8].copy_from_slice(&3u64.to_le_bytes());
+    ctx[RET_OFF_ORACLE_PRICE..RET_OFF_ORACLE_PRICE + 8]
+        .copy_from_slice(&100_000_000u64.to_le_bytes());
+
+    let ret = read_return_from_ctx(&ctx).unwrap();
+    assert_eq!(ret.abi_version, MATCHER_ABI_VERSION);
+    assert_eq!(ret.flags, FLAG_VALID | FLAG_PARTIAL_OK);
+    assert_eq!(ret.exec_price_e6, 101_500_000);
+    assert_eq!(ret.exec_size, -750);
+    assert_eq!(ret.req_id, 7);
+    assert_eq!(ret.lp_account_id, 3);
+    assert_eq!(ret.oracle_price_e6, 100_000_000);
+    assert_eq!(ret.reserved, 0);
+
+    // Short context rejected
+    assert_eq!(
+        read_return_from_ctx(&ctx[..63]).unwrap_err(),
+        ProgramError::InvalidAccountData
+    );
+
+    // Wrong ABI version rejected
+    let mut bad = ctx.clone();
+    bad[RET_OFF_ABI_VERSION..RET_OFF_ABI_VERSION + 4]
+        .copy_from_slice(&(MATCHER_ABI_VERSION + 1).to_le_bytes());
+    assert!(read_return_from_ctx(&bad).is_err());
+
+    // Non-zero reserved rejected
+    let mut bad = ctx.clone();
+    bad[RET_OFF_RESERVED] = 1;
+    assert!(read_return_from_ctx(&bad).is_err());
+}
+
+#[test]
+fn test_lp_unwarmed_gains_not_withdrawable() {
+    // LPs receive no optimistic rebate credits: every LP gain is PnL, and PnL only
+    // becomes withdrawable capital through warmup. Simulate a freshly credited gain,
+    // check that only principal can leave, then let it vest and withdraw it.
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut lp = add_lp(&mut f, 1_000);
+    {
+        // 500 of backed PnL vesting at 10/slot from slot 100
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.warmup_period_slots = 50;
+        engine.vault = U128::new(engine.vault.get() + 500);
+        engine.set_pnl(lp.idx as usize, 500);
+        engine.accounts[lp.idx as usize].warmup_slope_per_step = U128::new(10);
+        engine.accounts[lp.idx as usize].warmup_started_at_slot = 100;
+    }
+
+    // Principal + unvested gain: blocked
+    assert!(try_withdraw(&mut f, &mut lp, 1_500).is_err());
+    assert!(try_withdraw(&mut f, &mut lp, 1_001).is_err());
+
+    // Principal alone: allowed, gain stays in PnL
+    try_withdraw(&mut f, &mut lp, 1_000).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[lp.idx as usize].capital.get(), 0);
+    assert_eq!(engine.accounts[lp.idx as usize].pnl.get(), 500);
+
+    // Past the warmup period the whole gain is capital and can leave
+    f.clock.data = make_clock(150, 150);
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 1, 150);
+    assert!(try_withdraw(&mut f, &mut lp, 501).is_err());
+    try_withdraw(&mut f, &mut lp, 500).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[lp.idx as usize].capital.get(), 0);
+    assert_eq!(engine.accounts[lp.idx as usize].pnl.get(), 0);
+}
+
+#[test]
+fn test_crank_outcome_same_shape_for_pyth_and_hyperp() {
+    // One keeper, one instruction shape: both oracle kinds take the same four
+    // accounts and return the same CrankOutcome schema.
+    let mut pyth = setup_market();
+    let init_data = encode_init_market(&pyth, 100);
+    init_market_with(&mut pyth, &init_data).unwrap();
+
+    let mut hyperp = setup_market();
+    let init_data = encode_init_market_hyperp(&hyperp, 2_000_000);
+    init_market_with(&mut hyperp, &init_data).unwrap();
+
+    let out = crank_outcome(&mut pyth);
+    assert_eq!(out.slot, 100);
+    assert!(out.price_e6 > 0);
+    assert_eq!(out.oracle_mode, ORACLE_MODE_EXTERNAL);
+    assert_eq!(out.resolved, 0);
+    assert_eq!(out.sweep_completed, 1);
+
+    let out = crank_outcome(&mut hyperp);
+    assert_eq!(out.slot, 100);
+    assert_eq!(out.price_e6, 2_000_000);
+    assert_eq!(out.oracle_mode, ORACLE_MODE_HYPERP);
+    assert_eq!(out.resolved, 0);
+    assert_eq!(out.sweep_completed, 1);
+    assert_eq!((out.liquidations, out.insurance_delta), (0, 0));
+
+    // Encoding round-trips
+    assert_eq!(CrankOutcome::from_bytes(&out.to_bytes()), Some(out));
+    assert!(CrankOutcome::from_bytes(&out.to_bytes()[..CrankOutcome::LEN - 1]).is_none());
+}
+
+#[test]
+fn test_crank_outcome_reports_liquidations_and_insurance_delta() {
+    // 10_000 equity against 1_000 @ $100: below 15% maintenance
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 1_500;
+        engine.params.liquidation_fee_bps = 100;
+        engine.params.liquidation_fee_cap = U128::new(u128::MAX);
+    }
+
+    let insurance_before = insurance_balance(&f);
+    let out = crank_outcome(&mut f);
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].position_size.get(), 0);
+    assert_eq!(out.liquidations, 1);
+    assert!(out.insurance_delta > 0);
+    assert_eq!(
+        out.insurance_delta,
+        insurance_balance(&f) as i128 - insurance_before as i128
+    );
+
+    // Nothing left to liquidate
+    f.clock.data = make_clock(101, 100);
+    let out = crank_outcome(&mut f);
+    assert_eq!(out.liquidations, 0);
+}
+
+#[test]
+fn test_crank_charges_maintenance_fee_to_idle_account() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    try_admin_ix(&mut f, &encode_set_maintenance_fee(10)).unwrap();
+    let user = add_user(&mut f, 10_000);
+    let capital = |f: &MarketFixture| {
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .capital
+            .get()
+    };
+
+    // Baseline crank settles everything up to slot 100
+    crank_outcome(&mut f);
+    let capital_before = capital(&f);
+    let insurance_before = insurance_balance(&f);
+
+    // The account never acts; three cranks over 30 slots charge it 10 per slot
+    let mut reported = 0i128;
+    for slot in [110, 120, 130] {
+        f.clock.data = make_clock(slot, 100);
+        reported += crank_outcome(&mut f).insurance_delta;
+    }
+    assert_eq!(capital_before - capital(&f), 300);
+    assert_eq!(insurance_balance(&f) - insurance_before, 300);
+    assert_eq!(reported, 300);
+}
+
+#[test]
+fn test_admin_withdraw_allowed_boundary() {
+    use percolator_prog::verify::admin_withdraw_allowed;
+
+    // vault 1_000 = user capital 700 + insurance 300
+    assert!(admin_withdraw_allowed(1_000, 700, 300, 0));
+    assert!(admin_withdraw_allowed(1_000, 700, 300, 300));
+    // One unit past insurance would come out of user capital
+    assert!(!admin_withdraw_allowed(1_000, 700, 300, 301));
+
+    // Under-collateralized vault: insurance on paper exceeds real surplus
+    assert!(admin_withdraw_allowed(900, 700, 300, 200));
+    assert!(!admin_withdraw_allowed(900, 700, 300, 201));
+
+    // Amount larger than the vault never passes
+    assert!(!admin_withdraw_allowed(100, 0, u128::MAX, 101));
+}
+
+#[test]
+fn test_close_with_backstop_at_oracle_plus_penalty() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    // 10% initial margin, so an LP without capital cannot take new risk
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .params
+        .initial_margin_bps = 1_000;
+
+    // The LP ends up flat: the long and the short offset each other
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut short = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    try_trade(&mut f, &mut short, &mut lp, -1_000).unwrap();
+
+    let close = |f: &mut MarketFixture, user: &mut Participant| {
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_close_with_backstop(user.idx),
+        )
+    };
+
+    // Disabled by default
+    assert_eq!(
+        close(&mut f, &mut user),
+        Err(PercolatorError::BackstopUnavailable.into())
+    );
+
+    // An LP with capital can take the other side: no backstop
+    try_admin_ix(&mut f, &encode_set_backstop_params(100, 150_000)).unwrap();
+    assert_eq!(
+        close(&mut f, &mut user),
+        Err(PercolatorError::BackstopLpAvailable.into())
+    );
+
+    // The LP pulls its capital: no LP can carry the close any more
+    try_withdraw(&mut f, &mut lp, 1_000_000).unwrap();
+
+    // 100 bps penalty, 50_000 notional per slot: a 100_000 notional close does not fit
+    try_admin_ix(&mut f, &encode_set_backstop_params(100, 50_000)).unwrap();
+    assert_eq!(
+        close(&mut f, &mut user),
+        Err(PercolatorError::BackstopCapacityExceeded.into())
+    );
+
+    // Enough capacity: user exits at oracle ($100) and pays 1% of notional
+    try_admin_ix(&mut f, &encode_set_backstop_params(100, 150_000)).unwrap();
+    let before = insurance_balance(&f);
+    close(&mut f, &mut user).unwrap();
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let position = |idx: u16| engine.accounts[idx as usize].position_size.get();
+    assert_eq!(position(user.idx), 0);
+    // The LP is not the counterparty: its net exposure stays flat and the
+    // short's remaining 1_000 is carried by insurance
+    assert_eq!(position(lp.idx), 0, "LP is not the counterparty");
+    assert_eq!(position(short.idx) + position(lp.idx), -1_000);
+    // notional = 1_000 * 100_000_000 / 1e6 = 100_000; penalty = 1%
+    assert_eq!(insurance_balance(&f) - before, 1_000);
+    // Configured liquidation params are restored
+    assert_eq!(engine.params.liquidation_fee_bps, 0);
+
+    // Capacity used this slot: a second 100_000 close would exceed 150_000
+    assert_eq!(
+        close(&mut f, &mut short),
+        Err(PercolatorError::BackstopCapacityExceeded.into())
+    );
+
+    // Next slot: capacity resets
+    f.clock.data = make_clock(101, 101);
+    close(&mut f, &mut short).unwrap();
+}
+
+#[test]
+fn test_set_lp_matcher_validates_owner_kind_and_shape() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000);
+    let mut lp = add_lp(&mut f, 1_000);
+
+    let mut prog = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+    prog.executable = true;
+    let mut ctx = TestAccount::new(Pubkey::new_unique(), prog.key, 0, vec![0u8; 320]);
+    let (prog_key, ctx_key) = (prog.key, ctx.key);
+    let mut set = |f: &mut MarketFixture, signer: &mut TestAccount, idx: u16, ctx_len: usize| {
+        ctx.data.resize(ctx_len, 0);
+        let accounts = vec![
+            signer.to_info(),
+            f.slab.to_info(),
+            prog.to_info(),
+            ctx.to_info(),
+        ];
+        let data = encode_set_lp_matcher(idx, prog_key, ctx_key);
+        process_instruction(&f.program_id, &accounts, &data)
+    };
+
+    assert_eq!(
+        set(&mut f, &mut user.owner, lp.idx, 320),
+        Err(PercolatorError::EngineUnauthorized.into())
+    );
+    assert_eq!(
+        set(&mut f, &mut user.owner, user.idx, 320),
+        Err(PercolatorError::EngineAccountKindMismatch.into())
+    );
+    assert_eq!(
+        set(&mut f, &mut lp.owner, lp.idx, 319),
+        Err(ProgramError::InvalidAccountData)
+    );
+    set(&mut f, &mut lp.owner, lp.idx, 320).unwrap();
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let acc = &engine.accounts[lp.idx as usize];
+    assert_eq!(acc.matcher_program, prog_key.to_bytes());
+    assert_eq!(acc.matcher_context, ctx_key.to_bytes());
+
+    // Accounts must be the ones named in the instruction data
+    let mut other = TestAccount::new(Pubkey::new_unique(), prog_key, 0, vec![0u8; 320]);
+    let mut prog = TestAccount::new(prog_key, Pubkey::default(), 0, vec![]);
+    prog.executable = true;
+    let accounts = vec![
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        prog.to_info(),
+        other.to_info(),
+    ];
+    let data = encode_set_lp_matcher(lp.idx, prog_key, ctx_key);
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &data),
+        Err(ProgramError::InvalidArgument)
+    );
+}
+
+#[test]
+fn test_init_returns_index_and_get_account_index_resolves_it() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    install_return_data_stubs();
+
+    let register = |f: &mut MarketFixture, data: &[u8]| {
+        let (mut owner, mut ata) = new_owner_and_ata(f, 0);
+        let accounts = vec![
+            owner.to_info(),
+            f.slab.to_info(),
+            ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+        ];
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        process_instruction(&f.program_id, &accounts, data).unwrap();
+        let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+        (owner.key, AccountIndex::from_bytes(&bytes).unwrap().idx)
+    };
+    let (user, user_idx) = register(&mut f, &encode_init_user(0));
+    let init_lp = encode_init_lp(Pubkey::new_unique(), Pubkey::new_unique(), 0);
+    let (lp, lp_idx) = register(&mut f, &init_lp);
+    assert_ne!(user_idx, lp_idx);
+    assert_eq!(find_idx_by_owner(&f.slab.data, user), Some(user_idx));
+    assert_eq!(find_idx_by_owner(&f.slab.data, lp), Some(lp_idx));
+
+    for (owner, idx) in [(user, user_idx), (lp, lp_idx)] {
+        let bytes = view(&mut f, &encode_get_account_index(&owner));
+        assert_eq!(bytes.len(), AccountIndex::LEN);
+        assert_eq!(AccountIndex::from_bytes(&bytes), Some(AccountIndex { idx }));
+    }
+
+    let accounts = vec![f.slab.to_info()];
+    let data = encode_get_account_index(&Pubkey::new_unique());
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &data),
+        Err(PercolatorError::EngineAccountNotFound.into())
+    );
+}
+
+#[test]
+fn test_get_vault_info_matches_local_derivation() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let bytes = view(&mut f, &encode_get_vault_info());
+    assert_eq!(bytes.len(), VaultInfo::LEN);
+    let info = VaultInfo::from_bytes(&bytes).unwrap();
+
+    let (pda, bump) = Pubkey::find_program_address(&[b"vault", f.slab.key.as_ref()], &f.program_id);
+    assert_eq!(info.vault_authority, pda.to_bytes());
+    assert_eq!(info.vault_authority, f.vault_pda.to_bytes());
+    assert_eq!(info.vault_authority_bump, bump);
+    assert_eq!(info.vault_pubkey, f.vault.key.to_bytes());
+    assert_eq!(VaultInfo::from_bytes(&info.to_bytes()), Some(info));
+}
+
+#[test]
+fn test_verify_invariants_reports_desync_without_failing() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let user = add_user(&mut f, 10_000);
+
+    let report = verify_invariants(&mut f);
+    assert_eq!(report.violations, 0);
+    assert_eq!(report.vault_balance, 10_000);
+    assert_eq!(
+        report.capital_sum,
+        zc::engine_ref(&f.slab.data).unwrap().c_tot.get()
+    );
+    assert_eq!(
+        InvariantReport::from_bytes(&report.to_bytes()),
+        Some(report)
+    );
+
+    // Tokens land in the vault without an engine credit
+    let mut tok = TokenAccount::unpack(&f.vault.data).unwrap();
+    tok.amount += 7;
+    TokenAccount::pack(tok, &mut f.vault.data).unwrap();
+    let report = verify_invariants(&mut f);
+    assert_eq!(report.violations, InvariantReport::VAULT_BALANCE);
+    assert_eq!(report.vault_balance, 10_007);
+
+    // Capital written behind c_tot's back
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        let capital = engine.accounts[user.idx as usize].capital.get();
+        engine.accounts[user.idx as usize].capital = U128::new(capital + 1);
+    }
+    let report = verify_invariants(&mut f);
+    assert_eq!(
+        report.violations,
+        InvariantReport::VAULT_BALANCE | InvariantReport::CAPITAL_SUM
+    );
+
+    // Only the market's own vault is accepted
+    let mut other = TestAccount::new(
+        Pubkey::new_unique(),
+        spl_token::ID,
+        0,
+        make_token_account(f.mint.key, f.vault_pda, 10_007),
+    );
+    let accounts = vec![f.slab.to_info(), other.to_info()];
+    assert!(process_instruction(&f.program_id, &accounts, &encode_verify_invariants()).is_err());
+}
+
+#[test]
+fn test_engine_mut_borrows_slab_in_place() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // Writes through engine_mut land directly in the slab bytes (no load/store copy)
+    zc::engine_mut(&mut f.slab.data).unwrap().crank_cursor = 42;
+    assert_eq!(zc::engine_ref(&f.slab.data).unwrap().crank_cursor, 42);
+
+    // Truncated and misaligned buffers are rejected
+    let short = percolator_prog::constants::ENGINE_OFF + 8;
+    assert!(zc::engine_mut(&mut f.slab.data[..short]).is_err());
+    assert!(zc::engine_mut(&mut f.slab.data[1..]).is_err());
+    assert!(zc::engine_ref(&f.slab.data[1..]).is_err());
+}
+
+#[test]
+fn test_read_pyth_price_e6_price_update_v2_layout() {
+    let pyth_receiver_id = Pubkey::new_from_array(PYTH_RECEIVER_BYTES);
+    // $138.50 with expo -8, published at t=100
+    let mut acc = TestAccount::new(
+        Pubkey::new_unique(),
+        pyth_receiver_id,
+        0,
+        make_pyth(&TEST_FEED_ID, 13_850_000_000, -8, 1_000, 100),
+    );
+
+    let price = oracle::read_pyth_price_e6(&acc.to_info(), &TEST_FEED_ID, 110, 60, 500).unwrap();
+    assert_eq!(price, 138_500_000);
+
+    // Embedded feed_id must match the configured one
+    let res = oracle::read_pyth_price_e6(&acc.to_info(), &[0x11u8; 32], 110, 60, 500);
+    assert_eq!(res, Err(PercolatorError::InvalidOracleKey.into()));
+
+    // Staleness uses publish_time against Clock.unix_timestamp
+    let res = oracle::read_pyth_price_e6(&acc.to_info(), &TEST_FEED_ID, 161, 60, 500);
+    assert_eq!(res, Err(PercolatorError::OracleStale.into()));
+
+    // Truncated accounts (shorter than the 134-byte PriceUpdateV2 layout) are rejected
+    acc.data.truncate(133);
+    let res = oracle::read_pyth_price_e6(&acc.to_info(), &TEST_FEED_ID, 110, 60, 500);
+    assert_eq!(res, Err(ProgramError::InvalidAccountData));
+}
+
+#[test]
+fn test_read_pyth_price_e6_conservative_moves_against_the_side() {
+    let pyth_receiver_id = Pubkey::new_from_array(PYTH_RECEIVER_BYTES);
+    // $138.50 ± $0.50 with expo -8
+    let mut acc = TestAccount::new(
+        Pubkey::new_unique(),
+        pyth_receiver_id,
+        0,
+        make_pyth(&TEST_FEED_ID, 13_850_000_000, -8, 50_000_000, 100),
+    );
+    let read = |acc: &mut TestAccount, side| {
+        oracle::read_pyth_price_e6_conservative(&acc.to_info(), &TEST_FEED_ID, 110, 60, 500, side)
+    };
+
+    let mid = oracle::read_pyth_price_e6(&acc.to_info(), &TEST_FEED_ID, 110, 60, 500).unwrap();
+    assert_eq!(mid, 138_500_000);
+    assert_eq!(read(&mut acc, oracle::PriceSide::Long), Ok(138_000_000));
+    assert_eq!(read(&mut acc, oracle::PriceSide::Short), Ok(139_000_000));
+
+    // Same validation as the mid reader
+    assert_eq!(
+        oracle::read_pyth_price_e6_conservative(
+            &acc.to_info(),
+            &TEST_FEED_ID,
+            110,
+            60,
+            10,
+            oracle::PriceSide::Long
+        ),
+        Err(PercolatorError::OracleConfTooWide.into())
+    );
+}
+
+#[test]
+fn test_read_oracle_parses_once_into_bid_mid_ask() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut config = state::read_config(&f.slab.data);
+    config.max_staleness_secs = 60;
+    config.conf_filter_bps = 500;
+    config.oracle_price_cap_e2bps = 0;
+
+    // $138.50 ± $0.50 with expo -8, published at t=100
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 13_850_000_000, -8, 50_000_000, 100);
+    let clock = Clock {
+        slot: 10,
+        unix_timestamp: 110,
+        ..Clock::default()
+    };
+    let reading = oracle::read_oracle(&f.pyth_index.to_info(), &mut config, &clock).unwrap();
+    assert!(reading.bid_e6 <= reading.mid_e6 && reading.mid_e6 <= reading.ask_e6);
+    assert_eq!(
+        (reading.bid_e6, reading.mid_e6, reading.ask_e6),
+        (138_000_000, 138_500_000, 139_000_000)
+    );
+    assert_eq!(reading.publish, 100);
+    // Same values the separate readers produce, and the breaker is updated once
+    assert_eq!(config.last_effective_price_e6, reading.mid_e6);
+    let width = oracle::read_engine_conf_width_e6(&config, &f.pyth_index.to_info(), 110).unwrap();
+    assert_eq!(reading.mid_e6 - reading.bid_e6, width);
+    assert_eq!(reading.ask_e6 - reading.mid_e6, width);
+    assert_eq!(reading.liquidation_price(1), Ok(reading.bid_e6));
+    assert_eq!(reading.liquidation_price(-1), Ok(reading.ask_e6));
+    assert_eq!(reading.liquidation_price(0), Ok(reading.mid_e6));
+
+    // Validation is shared: a too-wide band fails the whole read
+    config.conf_filter_bps = 10;
+    assert_eq!(
+        oracle::read_oracle(&f.pyth_index.to_info(), &mut config, &clock),
+        Err(PercolatorError::OracleConfTooWide.into())
+    );
+}
+
+#[test]
+fn test_liquidation_uses_conservative_price_and_trades_use_mid() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // $100 ± $1: trades still fill at the mid
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 1_000_000, 100);
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize].entry_price,
+        100_000_000
+    );
+
+    // 9.9% maintenance: 10_000 >= 9_900 at the mid, 9_000 < 9_801 at the long bound
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 990;
+        engine.params.min_liquidation_abs = U128::new(u128::MAX);
+    }
+    install_return_data_stubs();
+    let preview = |f: &mut MarketFixture| {
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        let accounts = vec![f.slab.to_info(), f.clock.to_info(), f.pyth_index.to_info()];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_simulate_liquidation(user.idx),
+        )
+        .unwrap();
+        let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+        LiquidationPreview::from_bytes(&bytes).unwrap()
+    };
+
+    let p = preview(&mut f);
+    assert_eq!(p.price_e6, 99_000_000);
+    assert_eq!(p.equity, 9_000);
+    assert_eq!(p.maintenance_requirement, 9_801);
+    assert_eq!(p.liquidatable, 1);
+
+    // Without confidence the same account is healthy
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+    let p = preview(&mut f);
+    assert_eq!(p.price_e6, 100_000_000);
+    assert_eq!(p.equity, 10_000);
+    assert_eq!(p.maintenance_requirement, 9_900);
+    assert_eq!(p.liquidatable, 0);
+    assert_eq!(p.fee_to_insurance, 0);
+
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 1_000_000, 100);
+    let accounts = vec![
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    let mut data = vec![7u8];
+    encode_u16(user.idx, &mut data);
+    process_instruction(&f.program_id, &accounts, &data).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].position_size.get(), 0);
+}
+
+#[test]
+fn test_simulate_liquidation_counts_unsettled_funding() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    // 9.5% maintenance: 10_000 >= 9_500 until the long owes 1_000 of funding
+    // that no crank or trade has settled into its PnL yet
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 950;
+        let current = engine.funding_index_qpb_e6.get();
+        engine.funding_index_qpb_e6 = I128::new(current + 1_000_000);
+    }
+    install_return_data_stubs();
+    RETURN_DATA.with(|r| r.borrow_mut().take());
+    let accounts = vec![f.slab.to_info(), f.clock.to_info(), f.pyth_index.to_info()];
+    process_instruction(
+        &f.program_id,
+        &accounts,
+        &encode_simulate_liquidation(user.idx),
+    )
+    .unwrap();
+    let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+    let p = LiquidationPreview::from_bytes(&bytes).unwrap();
+    assert_eq!(p.equity, 9_000);
+    assert_eq!(p.liquidatable, 1);
+    // Still a view: the funding stays unsettled
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .pnl
+            .get(),
+        0
+    );
+}
+
+#[test]
+fn test_withdraw_settles_vested_warmup_without_crank() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000);
+    {
+        // 1_000 of backed PnL vesting at 10/slot from slot 100
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.warmup_period_slots = 100;
+        engine.vault = U128::new(engine.vault.get() + 1_000);
+        engine.set_pnl(user.idx as usize, 1_000);
+        engine.accounts[user.idx as usize].warmup_slope_per_step = U128::new(10);
+        engine.accounts[user.idx as usize].warmup_started_at_slot = 100;
+    }
+
+    // Halfway through the period, no crank in between: 500 vested, 500 locked
+    f.clock.data = make_clock(150, 150);
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 1, 150);
+    assert!(try_withdraw(&mut f, &mut user, 1_501).is_err());
+    try_withdraw(&mut f, &mut user, 1_500).unwrap();
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].capital.get(), 0);
+    assert_eq!(engine.accounts[user.idx as usize].pnl.get(), 500);
+}
+
+#[test]
+fn test_read_pyth_price_e6_rejects_bad_expo_gracefully() {
+    let pyth_receiver_id = Pubkey::new_from_array(PYTH_RECEIVER_BYTES);
+    let read = |price: i64, expo: i32| {
+        let mut acc = TestAccount::new(
+            Pubkey::new_unique(),
+            pyth_receiver_id,
+            0,
+            make_pyth(&TEST_FEED_ID, price, expo, 0, 100),
+        );
+        oracle::read_pyth_price_e6(&acc.to_info(), &TEST_FEED_ID, 100, 60, 500)
+    };
+
+    // Malformed feed: expo far outside the supported range
+    assert_eq!(
+        read(100, -30),
+        Err(PercolatorError::OracleInvalidExpo.into())
+    );
+    assert_eq!(
+        read(100, i32::MIN),
+        Err(PercolatorError::OracleInvalidExpo.into())
+    );
+    assert_eq!(
+        read(100, 19),
+        Err(PercolatorError::OracleInvalidExpo.into())
+    );
+
+    // expo = 0: plain integer price
+    assert_eq!(read(100, 0), Ok(100_000_000));
+
+    // expo = +12: in range, but 100 * 10^18 does not fit u64 => clean overflow error
+    assert_eq!(read(100, 12), Err(PercolatorError::EngineOverflow.into()));
+    assert_eq!(read(1, 12), Ok(1_000_000_000_000_000_000));
+}
+
+#[test]
+fn test_read_pyth_price_e6_scales_each_expo_to_e6() {
+    let pyth_receiver_id = Pubkey::new_from_array(PYTH_RECEIVER_BYTES);
+    let read = |price: i64, expo: i32| {
+        let mut acc = TestAccount::new(
+            Pubkey::new_unique(),
+            pyth_receiver_id,
+            0,
+            make_pyth(&TEST_FEED_ID, price, expo, 0, 100),
+        );
+        oracle::read_pyth_price_e6(&acc.to_info(), &TEST_FEED_ID, 100, 60, 500)
+    };
+
+    // $138.50 in each exponent: more decimals than e6 divide, fewer multiply
+    assert_eq!(read(13_850_000_000, -8), Ok(138_500_000));
+    assert_eq!(read(138_500_000, -6), Ok(138_500_000));
+    assert_eq!(read(138_500, -3), Ok(138_500_000));
+    assert_eq!(read(1, 2), Ok(100_000_000));
+
+    // Dividing truncates toward zero; a price below one e6 unit is invalid
+    assert_eq!(read(13_850_000_099, -8), Ok(138_500_000));
+    assert_eq!(read(99, -8), Err(PercolatorError::OracleInvalid.into()));
+}
+
+#[test]
+fn test_set_risk_params_guards_existing_positions() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // User equity 10_000 against 100_000 notional (1_000 @ $100) = 10%
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    let set = |f: &mut MarketFixture, data: &[u8]| {
+        let accounts = vec![
+            f.admin.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, data)
+    };
+
+    // maintenance > initial, or initial above 100%, is rejected
+    assert_eq!(
+        set(&mut f, &encode_set_risk_params(600, 500, 10, 50, 0)),
+        Err(PercolatorError::InvalidRiskParams.into())
+    );
+    assert_eq!(
+        set(&mut f, &encode_set_risk_params(500, 10_001, 10, 50, 0)),
+        Err(PercolatorError::InvalidRiskParams.into())
+    );
+
+    // 5% maintenance: position still covered
+    set(&mut f, &encode_set_risk_params(500, 1_000, 10, 50, 0)).unwrap();
+    let params = &zc::engine_ref(&f.slab.data).unwrap().params;
+    assert_eq!(params.maintenance_margin_bps, 500);
+    assert_eq!(params.initial_margin_bps, 1_000);
+    assert_eq!(params.trading_fee_bps, 10);
+    assert_eq!(params.liquidation_fee_bps, 50);
+    // Fields outside margins/fees are untouched
+    assert_eq!(params.max_crank_staleness_slots, 100);
+
+    // 20% maintenance would put the open position under water
+    assert_eq!(
+        set(&mut f, &encode_set_risk_params(2_000, 2_000, 10, 50, 0)),
+        Err(PercolatorError::EngineUndercollateralized.into())
+    );
+    assert_eq!(
+        zc::engine_ref(&f.slab.data)
+            .unwrap()
+            .params
+            .maintenance_margin_bps,
+        500
+    );
+
+    // ...unless forced
+    set(&mut f, &encode_set_risk_params(2_000, 2_000, 10, 50, 1)).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data)
+            .unwrap()
+            .params
+            .maintenance_margin_bps,
+        2_000
+    );
+
+    // Non-admin rejected
+    let mut attacker = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    )
+    .signer();
+    let accounts = vec![
+        attacker.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    let res = process_instruction(
+        &f.program_id,
+        &accounts,
+        &encode_set_risk_params(500, 1_000, 0, 0, 1),
+    );
+    assert_eq!(res, Err(PercolatorError::EngineUnauthorized.into()));
+}
+
+#[test]
+fn test_self_trade_rejected() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    let self_trade: Result<(), ProgramError> = Err(PercolatorError::SelfTrade.into());
+
+    // TradeNoCpi with both sides on the LP, and on the user
+    for idx in [lp.idx, user.idx] {
+        let accounts = vec![
+            user.owner.to_info(),
+            lp.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        let data = encode_trade(idx, idx, 100);
+        assert_eq!(
+            process_instruction(&f.program_id, &accounts, &data),
+            self_trade
+        );
+    }
+
+    // MultiTrade with a leg against the user itself
+    let accounts = vec![
+        user.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+        lp.owner.to_info(),
+    ];
+    let data = encode_multi_trade(user.idx, &[(user.idx, 100)]);
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &data),
+        self_trade
+    );
+
+    // TradeCpi is rejected before any matcher CPI
+    let mut matcher_prog = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+    let mut matcher_ctx =
+        TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]).writable();
+    let mut lp_pda = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    );
+    let accounts = vec![
+        user.owner.to_info(),
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+        matcher_prog.to_info(),
+        matcher_ctx.to_info(),
+        lp_pda.to_info(),
+    ];
+    let data = encode_trade_cpi(lp.idx, lp.idx, 100);
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &data),
+        self_trade
+    );
+
+    // TransferPosition onto the source itself
+    let accounts = vec![
+        user.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    let data = encode_transfer_position(user.idx, user.idx, 100);
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &data),
+        self_trade
+    );
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    for idx in [user.idx, lp.idx] {
+        assert_eq!(engine.accounts[idx as usize].position_size.get(), 0);
+    }
+}
+
+#[test]
+fn test_zero_size_trade_rejected() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+
+    let res = try_trade(&mut f, &mut user, &mut lp, 0);
+    assert_eq!(res, Err(PercolatorError::ZeroSizeTrade.into()));
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        0
+    );
+
+    // Non-zero sizes still trade
+    try_trade(&mut f, &mut user, &mut lp, 1).unwrap();
+}
+
+#[test]
+fn test_simulate_liquidation_matches_real_liquidation() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    // Zero confidence: the conservative liquidation price is the mid
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+
+    // 20% maintenance on 100_000 notional > 10_000 equity; 1% fee; force a full close
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 2_000;
+        engine.params.liquidation_fee_bps = 100;
+        engine.params.liquidation_fee_cap = U128::new(u128::MAX);
+        engine.params.min_liquidation_abs = U128::new(u128::MAX);
+    }
+
+    install_return_data_stubs();
+    RETURN_DATA.with(|r| r.borrow_mut().take());
+    let accounts = vec![f.slab.to_info(), f.clock.to_info(), f.pyth_index.to_info()];
+    let slab_before = f.slab.data.clone();
+    process_instruction(
+        &f.program_id,
+        &accounts,
+        &encode_simulate_liquidation(user.idx),
+    )
+    .unwrap();
+    assert_eq!(
+        f.slab.data, slab_before,
+        "simulation must not mutate the slab"
+    );
+    let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+    assert_eq!(bytes.len(), LiquidationPreview::LEN);
+    let preview = LiquidationPreview::from_bytes(&bytes).unwrap();
+    assert_eq!(
+        LiquidationPreview::from_bytes(&preview.to_bytes()),
+        Some(preview)
+    );
+
+    assert_eq!(preview.liquidatable, 1);
+    assert_eq!(preview.price_e6, 100_000_000);
+    assert_eq!(preview.position_size, 1_000);
+    assert_eq!(preview.equity, 10_000);
+    assert_eq!(preview.fee_to_liquidator, 0);
+    assert_eq!(preview.fee_to_insurance, 1_000);
+    assert_eq!(preview.socialized_loss, 0);
+    assert_eq!(preview.refund_to_account, 9_000);
+
+    // Real liquidation lands on the same split
+    let insurance_before = insurance_balance(&f);
+    let accounts = vec![
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    let mut data = vec![7u8];
+    encode_u16(user.idx, &mut data);
+    process_instruction(&f.program_id, &accounts, &data).unwrap();
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let acc = &engine.accounts[user.idx as usize];
+    assert_eq!(acc.position_size.get(), 0);
+    assert_eq!(
+        insurance_balance(&f) - insurance_before,
+        preview.fee_to_insurance
+    );
+    assert_eq!(
+        (acc.capital.get() as i128 + acc.pnl.get()) as u128,
+        preview.refund_to_account
+    );
+}
+
+#[test]
+fn test_simulate_liquidation_reports_partial_close() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+
+    // 20% maintenance plus a 5% buffer and no minimum: the engine closes only
+    // enough of the position to restore margin
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 2_000;
+        engine.params.liquidation_fee_bps = 100;
+        engine.params.liquidation_fee_cap = U128::new(u128::MAX);
+        engine.params.liquidation_buffer_bps = 500;
+        engine.params.min_liquidation_abs = U128::ZERO;
+    }
+
+    install_return_data_stubs();
+    RETURN_DATA.with(|r| r.borrow_mut().take());
+    let accounts = vec![f.slab.to_info(), f.clock.to_info(), f.pyth_index.to_info()];
+    let slab_before = f.slab.data.clone();
+    process_instruction(
+        &f.program_id,
+        &accounts,
+        &encode_simulate_liquidation(user.idx),
+    )
+    .unwrap();
+    assert_eq!(f.slab.data, slab_before);
+    let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+    let preview = LiquidationPreview::from_bytes(&bytes).unwrap();
+    assert_eq!(preview.liquidatable, 1);
+    assert!((1..1_000).contains(&preview.position_size));
+
+    // The real liquidation closes exactly the previewed part for the previewed fee
+    let insurance_before = insurance_balance(&f);
+    let accounts = vec![
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    let mut data = vec![7u8];
+    encode_u16(user.idx, &mut data);
+    process_instruction(&f.program_id, &accounts, &data).unwrap();
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let acc = &engine.accounts[user.idx as usize];
+    assert_eq!(acc.position_size.get(), 1_000 - preview.position_size);
+    assert_eq!(
+        insurance_balance(&f) - insurance_before,
+        preview.fee_to_insurance
+    );
+    assert_eq!(
+        (acc.capital.get() as i128 + acc.pnl.get()) as u128,
+        preview.refund_to_account
+    );
+}
+
+#[test]
+fn test_liquidate_partial_closes_fraction_and_charges_fee_on_it() {
+    // 10_000 equity against 1_000 @ $100 (100_000 notional): below 15%
+    // maintenance; 1% liquidation fee, no cap
+    let setup = || {
+        let mut f = setup_market();
+        let init_data = encode_init_market(&f, 100);
+        init_market_with(&mut f, &init_data).unwrap();
+        f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+        let mut user = add_user(&mut f, 10_000);
+        let mut lp = add_lp(&mut f, 1_000_000);
+        try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+        {
+            let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+            engine.params.maintenance_margin_bps = 1_500;
+            engine.params.liquidation_fee_bps = 100;
+            engine.params.liquidation_fee_cap = U128::new(u128::MAX);
+        }
+        (f, user.idx)
+    };
+    let liquidate = |f: &mut MarketFixture, idx: u16, close_bps: u16| {
+        let mut keeper = TestAccount::new(
+            Pubkey::new_unique(),
+            solana_program::system_program::id(),
+            0,
+            vec![],
+        );
+        let accounts = vec![
+            keeper.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_liquidate_partial(idx, close_bps),
+        )
+    };
+
+    let (mut f, idx) = setup();
+    for close_bps in [0, 10_001] {
+        assert_eq!(
+            liquidate(&mut f, idx, close_bps),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    // 50%: 500 left on 9_500 equity clears 15% of 50_000; fee is 1% of the
+    // 50_000 closed
+    let insurance_before = insurance_balance(&f);
+    liquidate(&mut f, idx, 5_000).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let acc = &engine.accounts[idx as usize];
+    assert_eq!(acc.position_size.get(), 500);
+    assert_eq!(insurance_balance(&f) - insurance_before, 500);
+    assert_eq!(acc.capital.get() as i128 + acc.pnl.get(), 9_500);
+    // Configured sizing params are restored
+    assert_eq!(engine.params.maintenance_margin_bps, 1_500);
+
+    // Now healthy: a second call is a no-op
+    liquidate(&mut f, idx, 5_000).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[idx as usize].position_size.get(), 500);
+
+    // 10% would leave 900 (13_500 required) on 9_900: falls back to a full close
+    let (mut f, idx) = setup();
+    let insurance_before = insurance_balance(&f);
+    liquidate(&mut f, idx, 1_000).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[idx as usize].position_size.get(), 0);
+    assert_eq!(insurance_balance(&f) - insurance_before, 1_000);
+}
+
+#[test]
+fn test_set_liquidation_params_updates_fee_used_by_next_liquidation() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+    // 10_000 equity against 1_000 @ $100: healthy at 5% maintenance
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .params
+        .maintenance_margin_bps = 500;
+
+    let set = |f: &mut MarketFixture, data: &[u8]| {
+        let accounts = vec![f.admin.to_info(), f.slab.to_info()];
+        process_instruction(&f.program_id, &accounts, data)
+    };
+    let liquidate = |f: &mut MarketFixture, data: &[u8]| {
+        let mut keeper = TestAccount::new(
+            Pubkey::new_unique(),
+            solana_program::system_program::id(),
+            0,
+            vec![],
+        );
+        let accounts = vec![
+            keeper.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, data)
+    };
+
+    // Fee above 100%, or maintenance + buffer above 100%
+    assert_eq!(
+        set(&mut f, &encode_set_liquidation_params(10_001, 0, 0, 0)),
+        Err(PercolatorError::InvalidConfigParam.into())
+    );
+    assert_eq!(
+        set(&mut f, &encode_set_liquidation_params(100, 0, 9_501, 0)),
+        Err(PercolatorError::InvalidConfigParam.into())
+    );
+
+    // Non-admin is rejected
+    let mut attacker = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    )
+    .signer();
+    let accounts = vec![attacker.to_info(), f.slab.to_info()];
+    assert_eq!(
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_set_liquidation_params(200, u128::MAX, 100, 7),
+        ),
+        Err(PercolatorError::EngineUnauthorized.into())
+    );
+
+    set(
+        &mut f,
+        &encode_set_liquidation_params(200, u128::MAX, 100, 7),
+    )
+    .unwrap();
+    let params = &zc::engine_ref(&f.slab.data).unwrap().params;
+    assert_eq!(params.liquidation_fee_bps, 200);
+    assert_eq!(params.liquidation_fee_cap.get(), u128::MAX);
+    assert_eq!(params.liquidation_buffer_bps, 100);
+    assert_eq!(params.min_liquidation_abs.get(), 7);
+    assert_eq!(params.maintenance_margin_bps, 500);
+
+    // Not retroactive: the healthy account is still not liquidatable
+    let mut data = vec![7u8];
+    encode_u16(user.idx, &mut data);
+    liquidate(&mut f, &data).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        1_000
+    );
+
+    // Below 15% maintenance: half closed, charged the new 2% on the 50_000 closed
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .params
+        .maintenance_margin_bps = 1_500;
+    let insurance_before = insurance_balance(&f);
+    liquidate(&mut f, &encode_liquidate_partial(user.idx, 5_000)).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        500
+    );
+    assert_eq!(insurance_balance(&f) - insurance_before, 1_000);
+}
+
+#[test]
+fn test_get_account_state_round_trip() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .set_pnl(user.idx as usize, -250);
+
+    let bytes = view(&mut f, &encode_get_account_state(user.idx));
+    assert_eq!(bytes.len(), AccountState::LEN);
+    let state = AccountState::from_bytes(&bytes).unwrap();
+    assert_eq!(state.to_bytes().as_slice(), bytes.as_slice());
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let acc = &engine.accounts[user.idx as usize];
+    assert_eq!(state.idx, user.idx);
+    assert_eq!(state.capital, acc.capital.get());
+    assert_eq!(state.position_size, 1_000);
+    assert_eq!(state.entry_price, 100_000_000);
+    assert_eq!(state.pnl, -250);
+    assert_eq!(state.pnl_pos, 0);
+    assert_eq!(state.pnl_warmed, 0);
+
+    // Unused slot is rejected
+    install_return_data_stubs();
+    let accounts = vec![f.slab.to_info()];
+    let res = process_instruction(
+        &f.program_id,
+        &accounts,
+        &encode_get_account_state(MAX_ACCOUNTS as u16 - 1),
+    );
+    assert_eq!(res, Err(PercolatorError::EngineAccountNotFound.into()));
+}
+
+#[test]
+fn test_weighted_entry_price_increase_reduce_flip() {
+    use percolator_prog::verify::weighted_entry_price;
+
+    // User long path; the LP takes every fill with the opposite sign and must
+    // get the same basis.
+    for sign in [1i128, -1] {
+        // Open 100 @ 100
+        let e = weighted_entry_price(0, 0, sign * 100, 100);
+        assert_eq!(e, 100);
+        // Increase by 300 @ 200: (100*100 + 300*200) / 400 = 175
+        let e = weighted_entry_price(sign * 100, e, sign * 300, 200);
+        assert_eq!(e, 175);
+        // Reduce by 250 @ 50: unchanged
+        let e = weighted_entry_price(sign * 400, e, -sign * 250, 50);
+        assert_eq!(e, 175);
+        // Flip 150 -> -50 @ 90: reset to fill price
+        let e = weighted_entry_price(sign * 150, e, -sign * 200, 90);
+        assert_eq!(e, 90);
+        // Close to flat
+        assert_eq!(weighted_entry_price(-sign * 50, e, sign * 50, 120), 0);
+        // Rounds down
+        assert_eq!(weighted_entry_price(sign * 2, 10, sign, 11), 10);
+    }
+
+    // Engine fills at a single oracle price: user and LP bases are the fill price
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+
+    let mut expected = [(0i128, 0u64); 2];
+    for size in [1_000i128, 500, -700, -1_300] {
+        try_trade(&mut f, &mut user, &mut lp, size).unwrap();
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        for (k, (idx, fill)) in [(user.idx, size), (lp.idx, -size)].into_iter().enumerate() {
+            let (pos, entry) = expected[k];
+            let e = weighted_entry_price(pos, entry, fill, 100_000_000);
+            expected[k] = (pos + fill, e);
+            let acc = &engine.accounts[idx as usize];
+            assert_eq!(acc.position_size.get(), pos + fill);
+            assert_eq!(acc.entry_price, e);
+        }
+    }
+    assert_eq!(expected[0].0, -500);
+    assert_eq!(expected[1].0, 500);
+}
+
+#[test]
+fn test_opens_require_initial_margin_reduces_maintenance() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 500;
+        engine.params.initial_margin_bps = 1_000;
+    }
+
+    // 10_000 equity covers exactly 10% of 100_000 notional (1_000 @ $100)
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 1_001),
+        Err(PercolatorError::EngineUndercollateralized.into())
+    );
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    // Equity 6_000 on 100_000 notional: above maintenance, below initial
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .set_pnl(user.idx as usize, -4_000);
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 1),
+        Err(PercolatorError::EngineUndercollateralized.into())
+    );
+    try_trade(&mut f, &mut user, &mut lp, -100).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].position_size.get(), 900);
+}
+
+#[test]
+fn test_init_market_validates_optional_oracle() {
+    let init_with_oracle = |f: &mut MarketFixture, data: &[u8], oracle: &mut TestAccount| {
+        let mut dummy_ata = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+        let accounts = vec![
+            f.admin.to_info(),
+            f.slab.to_info(),
+            f.mint.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.rent.to_info(),
+            dummy_ata.to_info(),
+            f.system.to_info(),
+            oracle.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, data)
+    };
+
+    // Oracle for a different feed: rejected, slab left uninitialized
+    let mut f = setup_market();
+    let data = encode_init_market(&f, 100);
+    let pyth_owner = f.pyth_index.owner;
+    let mut oracle = TestAccount::new(
+        Pubkey::new_unique(),
+        pyth_owner,
+        0,
+        make_pyth(&[0xCDu8; 32], 100_000_000, -6, 1, 100),
+    );
+    assert_eq!(
+        init_with_oracle(&mut f, &data, &mut oracle),
+        Err(PercolatorError::InvalidOracleKey.into())
+    );
+    assert_ne!(state::read_header(&f.slab.data).magic, MAGIC);
+
+    // Zero price: rejected
+    oracle.data = make_pyth(&TEST_FEED_ID, 0, -6, 1, 100);
+    assert_eq!(
+        init_with_oracle(&mut f, &data, &mut oracle),
+        Err(PercolatorError::OracleInvalid.into())
+    );
+
+    // Readable index oracle: accepted
+    oracle.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 1, 100);
+    init_with_oracle(&mut f, &data, &mut oracle).unwrap();
+    assert_eq!(state::read_header(&f.slab.data).magic, MAGIC);
+
+    // Hyperp: no external oracle is read, only initial_mark_price_e6 > 0 matters
+    let mut f = setup_market();
+    let mut garbage = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+    let no_mark = encode_init_market_hyperp(&f, 0);
+    assert_eq!(
+        init_with_oracle(&mut f, &no_mark, &mut garbage),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    let data = encode_init_market_hyperp(&f, 100_000_000);
+    init_with_oracle(&mut f, &data, &mut garbage).unwrap();
+}
+
+#[test]
+fn test_crank_ranges_settle_idle_warmup() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // Idle profitable accounts: 1_000 of backed PnL each, vesting at 10/slot from slot 100
+    let users: Vec<Participant> = (0..8).map(|_| add_user(&mut f, 1_000)).collect();
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.warmup_period_slots = 100;
+        for u in &users {
+            engine.vault = U128::new(engine.vault.get() + 1_000);
+            engine.set_pnl(u.idx as usize, 1_000);
+            engine.accounts[u.idx as usize].warmup_slope_per_step = U128::new(10);
+            engine.accounts[u.idx as usize].warmup_started_at_slot = 100;
+        }
+    }
+
+    // Fully vested; nobody touches their account. Crank until the cursor wraps.
+    f.clock.data = make_clock(300, 300);
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 1, 300);
+    let mut calls = 0;
+    loop {
+        calls += 1;
+        assert!(calls <= MAX_ACCOUNTS, "sweep must complete");
+        if crank_outcome(&mut f).sweep_completed == 1 {
+            break;
+        }
+    }
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    for u in &users {
+        let acc = &engine.accounts[u.idx as usize];
+        assert_eq!(acc.pnl.get(), 0, "idle account {} not settled", u.idx);
+        assert_eq!(acc.capital.get(), 2_000);
+    }
+
+    // Idempotent: another full cycle converts nothing further
+    let snapshot = f.slab.data.clone();
+    loop {
+        if crank_outcome(&mut f).sweep_completed == 1 {
+            break;
+        }
+    }
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let before = zc::engine_ref(&snapshot).unwrap();
+    for u in &users {
+        let (a, b) = (
+            &engine.accounts[u.idx as usize],
+            &before.accounts[u.idx as usize],
+        );
+        assert_eq!(a.capital.get(), b.capital.get());
+        assert_eq!(a.pnl.get(), b.pnl.get());
+    }
+}
+
+#[test]
+fn test_top_donors_track_attributed_top_ups() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let top_up = |f: &mut MarketFixture, data: &[u8]| {
+        let (mut funder, mut funder_ata) = new_owner_and_ata(f, 1_000_000);
+        let accounts = vec![
+            funder.to_info(),
+            f.slab.to_info(),
+            funder_ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, data).unwrap();
+    };
+
+    let alice = Pubkey::new_unique();
+    let bob = Pubkey::new_unique();
+    top_up(&mut f, &encode_topup_insurance_from(300, &alice));
+    top_up(&mut f, &encode_topup_insurance_from(500, &bob));
+    top_up(&mut f, &encode_topup_insurance_from(400, &alice));
+    // Anonymous top-ups still fund insurance but are not attributed
+    top_up(&mut f, &encode_topup_insurance(1_000));
+    assert_eq!(insurance_balance(&f), 2_200);
+
+    let bytes = view(&mut f, &encode_get_top_donors());
+    assert_eq!(bytes.len(), TopDonors::LEN);
+    let donors = TopDonors::from_bytes(&bytes).unwrap();
+    assert_eq!(donors.count, 2);
+    assert_eq!(donors.entries[0], (alice.to_bytes(), 700));
+    assert_eq!(donors.entries[1], (bob.to_bytes(), 500));
+    assert_eq!(TopDonors::from_bytes(&donors.to_bytes()), Some(donors));
+}
+
+#[test]
+fn test_withdraw_insurance_amount_respects_floor() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let user = add_user(&mut f, 2_000);
+    {
+        let (mut funder, mut funder_ata) = new_owner_and_ata(&f, 5_000);
+        let accounts = vec![
+            funder.to_info(),
+            f.slab.to_info(),
+            funder_ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_topup_insurance(5_000)).unwrap();
+    }
+    let insurance_before = insurance_balance(&f);
+    let engine_vault_before = zc::engine_ref(&f.slab.data).unwrap().vault.get();
+    let token_vault_before = TokenAccount::unpack(&f.vault.data).unwrap().amount;
+
+    let mut admin_ata = TestAccount::new(
+        Pubkey::new_unique(),
+        spl_token::ID,
+        0,
+        make_token_account(f.mint.key, f.admin.key, 0),
+    )
+    .writable();
+    let mut vault_pda =
+        TestAccount::new(f.vault_pda, solana_program::system_program::id(), 0, vec![]);
+    let mut withdraw = |f: &mut MarketFixture, data: &[u8]| {
+        let accounts = vec![
+            f.admin.to_info(),
+            f.slab.to_info(),
+            admin_ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            vault_pda.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, data)
+    };
+
+    // The whole-fund form still requires a resolved market
+    assert_eq!(
+        withdraw(&mut f, &encode_withdraw_insurance(None)),
+        Err(ProgramError::InvalidAccountData)
+    );
+
+    try_admin_ix(&mut f, &encode_set_insurance_floor(1_000)).unwrap();
+    let surplus = (insurance_before - 1_000) as u64;
+    assert_eq!(
+        withdraw(&mut f, &encode_withdraw_insurance(Some(surplus + 1))),
+        Err(PercolatorError::EngineInsufficientBalance.into())
+    );
+    assert_eq!(
+        withdraw(&mut f, &encode_withdraw_insurance(Some(0))),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    withdraw(&mut f, &encode_withdraw_insurance(Some(surplus))).unwrap();
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.insurance_fund.balance.get(), 1_000);
+    assert_eq!(engine.vault.get(), engine_vault_before - surplus as u128);
+    assert_eq!(engine.accounts[user.idx as usize].capital.get(), 2_000);
+    assert_eq!(
+        TokenAccount::unpack(&f.vault.data).unwrap().amount,
+        token_vault_before - surplus
+    );
+    assert_eq!(
+        TokenAccount::unpack(&admin_ata.data).unwrap().amount,
+        surplus
+    );
+}
+
+#[test]
+fn test_init_fee_overpayment_credited_to_capital() {
+    use percolator_prog::verify::split_account_fee;
+
+    assert_eq!(split_account_fee(5_000, 1_000), (1_000, 4_000));
+    assert_eq!(split_account_fee(1_000, 1_000), (1_000, 0));
+    // Underpayment passes through so the engine rejects it
+    assert_eq!(split_account_fee(500, 1_000), (500, 0));
+
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .params
+        .new_account_fee = U128::new(1_000);
+
+    for init in [
+        encode_init_user(5_000),
+        encode_init_lp(Pubkey::new_unique(), Pubkey::new_unique(), 5_000),
+    ] {
+        let vault_before = zc::engine_ref(&f.slab.data).unwrap().vault.get();
+        let (mut owner, mut ata) = new_owner_and_ata(&f, 5_000);
+        let accounts = vec![
+            owner.to_info(),
+            f.slab.to_info(),
+            ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &init).unwrap();
+
+        let idx = find_idx_by_owner(&f.slab.data, owner.key).unwrap();
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        assert_eq!(engine.accounts[idx as usize].capital.get(), 4_000);
+        assert_eq!(engine.vault.get() - vault_before, 5_000);
+    }
+}
+
+#[test]
+fn test_zero_amount_deposit_and_withdraw_rejected() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000);
+    let vault_before = zc::engine_ref(&f.slab.data).unwrap().vault.get();
+
+    assert_eq!(
+        try_deposit(&mut f, &mut user, 0),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    assert_eq!(
+        try_withdraw(&mut f, &mut user, 0),
+        Err(ProgramError::InvalidInstructionData)
+    );
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.vault.get(), vault_before);
+    assert_eq!(engine.accounts[user.idx as usize].capital.get(), 1_000);
+
+    // Non-zero amounts still work
+    try_deposit(&mut f, &mut user, 1).unwrap();
+    try_withdraw(&mut f, &mut user, 1).unwrap();
+}
+
+#[test]
+fn test_deposit_to_funds_another_owners_account() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let beneficiary = add_user(&mut f, 500);
+    let idx = beneficiary.idx;
+
+    // The sponsor holds no account in this market
+    let (mut sponsor, mut ata) = new_owner_and_ata(&f, 301);
+    let mut fund = |f: &mut MarketFixture, ata: &mut TestAccount, data: &[u8]| {
+        let accounts = vec![
+            sponsor.to_info(),
+            f.slab.to_info(),
+            ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, data)
+    };
+
+    assert_eq!(
+        fund(&mut f, &mut ata, &encode_deposit_to(idx, 0)),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    fund(&mut f, &mut ata, &encode_deposit_to(idx, 300)).unwrap();
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[idx as usize].capital.get(), 800);
+    assert_eq!(
+        engine.accounts[idx as usize].owner,
+        beneficiary.owner.key.to_bytes()
+    );
+    assert_eq!(TokenAccount::unpack(&ata.data).unwrap().amount, 1);
+
+    // DepositCollateral still requires ownership
+    assert_eq!(
+        fund(&mut f, &mut ata, &encode_deposit(idx, 1)),
+        Err(PercolatorError::EngineUnauthorized.into())
+    );
+}
+
+#[test]
+fn test_withdraw_rejects_wrong_vault_pda_at_index_4() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000);
+    let vault_before = TokenAccount::unpack(&f.vault.data).unwrap().amount;
+
+    let mut wrong_pda = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    );
+    let accounts = vec![
+        user.owner.to_info(),
+        f.slab.to_info(),
+        f.vault.to_info(),
+        user.ata.to_info(),
+        wrong_pda.to_info(),
+        f.token_prog.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &encode_withdraw(user.idx, 100)),
+        Err(ProgramError::InvalidArgument)
+    );
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].capital.get(), 1_000);
+    assert_eq!(
+        TokenAccount::unpack(&f.vault.data).unwrap().amount,
+        vault_before
+    );
+
+    // The derived authority at index 4 succeeds
+    try_withdraw(&mut f, &mut user, 100).unwrap();
+}
+
+#[test]
+fn test_withdraw_keeps_native_vault_rent_exempt() {
+    const RENT_RESERVE: u64 = 2_039_280;
+
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+
+    // Native-SOL vault whose lamports only cover 500_000 above its rent reserve
+    let mut tok = TokenAccount::unpack(&f.vault.data).unwrap();
+    tok.is_native = solana_program::program_option::COption::Some(RENT_RESERVE);
+    TokenAccount::pack(tok, &mut f.vault.data).unwrap();
+    f.vault.lamports = RENT_RESERVE + 500_000;
+
+    assert_eq!(
+        try_withdraw(&mut f, &mut user, 500_001),
+        Err(PercolatorError::VaultRentExemptBreach.into())
+    );
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].capital.get(), 1_000_000);
+
+    // Down to exactly the reserve is fine
+    try_withdraw(&mut f, &mut user, 500_000).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].capital.get(), 500_000);
+}
+
+#[test]
+fn test_token_2022_collateral_deposit_and_withdraw() {
+    use percolator_prog::collateral::TOKEN_2022_PROGRAM_ID;
+
+    let mut f = setup_market();
+    // Token-2022 mint and vault; the vault carries an extension (AccountType
+    // byte after the base layout)
+    f.mint.owner = TOKEN_2022_PROGRAM_ID;
+    f.vault.owner = TOKEN_2022_PROGRAM_ID;
+    f.vault.data.push(2);
+    f.token_prog.key = TOKEN_2022_PROGRAM_ID;
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let (mut owner, mut ata) = new_owner_and_ata(&f, 1_000_000);
+    ata.owner = TOKEN_2022_PROGRAM_ID;
+    {
+        let accounts = vec![
+            owner.to_info(),
+            f.slab.to_info(),
+            ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.mint.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_init_user(0)).unwrap();
+    }
+    let idx = find_idx_by_owner(&f.slab.data, owner.key).unwrap();
+    let mut user = Participant { owner, ata, idx };
+
+    // Token-2022 transfers need the trailing mint
+    assert_eq!(
+        try_deposit(&mut f, &mut user, 1_000),
+        Err(ProgramError::NotEnoughAccountKeys)
+    );
+    let mut other_mint = TestAccount::new(
+        Pubkey::new_unique(),
+        TOKEN_2022_PROGRAM_ID,
+        0,
+        make_mint_account(),
+    );
+    {
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            user.ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            other_mint.to_info(),
+        ];
+        let res = process_instruction(&f.program_id, &accounts, &encode_deposit(idx, 1_000));
+        assert_eq!(res, Err(PercolatorError::InvalidMint.into()));
+    }
+
+    {
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            user.ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.mint.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_deposit(idx, 1_000_000)).unwrap();
+    }
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[idx as usize].capital.get(), 1_000_000);
+
+    {
+        let mut vault_pda =
+            TestAccount::new(f.vault_pda, solana_program::system_program::id(), 0, vec![]);
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            f.vault.to_info(),
+            user.ata.to_info(),
+            vault_pda.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+            f.mint.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_withdraw(idx, 400_000)).unwrap();
+    }
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[idx as usize].capital.get(), 600_000);
+    let vault = TokenAccount::unpack(&f.vault.data[..TokenAccount::LEN]).unwrap();
+    assert_eq!(vault.amount, 600_000);
+    let ata = TokenAccount::unpack(&user.ata.data).unwrap();
+    assert_eq!(ata.amount, 400_000);
+}
+
+#[test]
+fn test_transfer_fee_mint_credits_received_amount() {
+    use percolator_prog::collateral::TOKEN_2022_PROGRAM_ID;
+
+    let mut f = setup_market();
+    // 1% transfer fee on the collateral mint
+    f.mint.owner = TOKEN_2022_PROGRAM_ID;
+    f.mint.data = make_transfer_fee_mint_account(100, u64::MAX);
+    f.vault.owner = TOKEN_2022_PROGRAM_ID;
+    f.vault.data.push(2);
+    f.token_prog.key = TOKEN_2022_PROGRAM_ID;
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let vault_amount = |f: &MarketFixture| {
+        TokenAccount::unpack(&f.vault.data[..TokenAccount::LEN])
+            .unwrap()
+            .amount
+    };
+
+    // InitUser: 10_000 paid, 9_900 arrive and become capital (no account fee)
+    let (mut owner, mut ata) = new_owner_and_ata(&f, 1_010_000);
+    ata.owner = TOKEN_2022_PROGRAM_ID;
+    {
+        let accounts = vec![
+            owner.to_info(),
+            f.slab.to_info(),
+            ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.mint.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_init_user(10_000)).unwrap();
+    }
+    let idx = find_idx_by_owner(&f.slab.data, owner.key).unwrap();
+    let mut user = Participant { owner, ata, idx };
+    assert_eq!(vault_amount(&f), 9_900);
+    let capital = |f: &MarketFixture| {
+        zc::engine_ref(&f.slab.data).unwrap().accounts[idx as usize]
+            .capital
+            .get()
+    };
+    assert_eq!(capital(&f), 9_900);
+
+    // Deposit: 1_000_000 requested, the vault sees 990_000 and so does the engine
+    {
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            user.ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.mint.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_deposit(idx, 1_000_000)).unwrap();
+    }
+    assert_eq!(vault_amount(&f), 999_900);
+    assert_eq!(capital(&f), 999_900);
+    assert_eq!(TokenAccount::unpack(&user.ata.data).unwrap().amount, 0);
+
+    // TopUpInsurance credits the delta too
+    let insurance_before = insurance_balance(&f);
+    let (mut funder, mut funder_ata) = new_owner_and_ata(&f, 50_000);
+    funder_ata.owner = TOKEN_2022_PROGRAM_ID;
+    {
+        let accounts = vec![
+            funder.to_info(),
+            f.slab.to_info(),
+            funder_ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.mint.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_topup_insurance(50_000)).unwrap();
+    }
+    assert_eq!(insurance_balance(&f) - insurance_before, 49_500);
+    assert_eq!(vault_amount(&f), 999_900 + 49_500);
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().vault.get(),
+        vault_amount(&f) as u128
+    );
+}
+
+#[test]
+fn test_deposit_collateral_wrapped_native_mint_only() {
+    const RENT_RESERVE: u64 = 2_039_280;
+
+    // Non-native collateral: the wrapped variant is refused
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000);
+    user.owner.lamports = 1_000_000;
+    assert_eq!(
+        try_deposit_wrapped(&mut f, &mut user, 1_000),
+        Err(PercolatorError::InvalidMint.into())
+    );
+
+    // Native-SOL collateral
+    let mut f = setup_market();
+    let native = spl_token::native_mint::id();
+    f.mint.key = native;
+    f.vault.data = make_token_account(native, f.vault_pda, 0);
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let (mut owner, mut ata) = new_owner_and_ata(&f, 0);
+    owner.lamports = 5_000_000;
+    let mut tok = TokenAccount::unpack(&ata.data).unwrap();
+    tok.is_native = solana_program::program_option::COption::Some(RENT_RESERVE);
+    TokenAccount::pack(tok, &mut ata.data).unwrap();
+    ata.lamports = RENT_RESERVE;
+    {
+        let accounts = vec![
+            owner.to_info(),
+            f.slab.to_info(),
+            ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_init_user(0)).unwrap();
+    }
+    let idx = find_idx_by_owner(&f.slab.data, owner.key).unwrap();
+    let mut user = Participant { owner, ata, idx };
+
+    // Lamports go straight from the wallet to capital, no separate wrap step
+    try_deposit_wrapped(&mut f, &mut user, 3_000_000).unwrap();
+    assert_eq!(user.owner.lamports, 2_000_000);
+    assert_eq!(TokenAccount::unpack(&user.ata.data).unwrap().amount, 0);
+    assert_eq!(
+        TokenAccount::unpack(&f.vault.data).unwrap().amount,
+        3_000_000
+    );
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[idx as usize].capital.get(), 3_000_000);
+
+    // The system program must be the real one
+    f.system.key = Pubkey::new_unique();
+    assert_eq!(
+        try_deposit_wrapped(&mut f, &mut user, 1_000),
+        Err(ProgramError::IncorrectProgramId)
+    );
+}
+
+#[test]
+fn test_lp_entry_resets_on_flip_to_smaller_size() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+
+    // LP short 100 @ 100
+    try_trade(&mut f, &mut user, &mut lp, 100).unwrap();
+    let state = AccountState::from_bytes(&view(&mut f, &encode_get_account_state(lp.idx))).unwrap();
+    assert_eq!(state.position_size, -100);
+    assert_eq!(state.entry_price, 100_000_000);
+
+    // Flip -100 -> +50 @ 110: |new| < |old|, entry must still move to the fill price
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 110_000_000, -6, 1, 100);
+    try_trade(&mut f, &mut user, &mut lp, -150).unwrap();
+    let state = AccountState::from_bytes(&view(&mut f, &encode_get_account_state(lp.idx))).unwrap();
+    assert_eq!(state.position_size, 50);
+    assert_eq!(state.entry_price, 110_000_000);
+    let equity_before = state.capital as i128 + state.pnl;
+
+    // Crank @ 120 in the same slot (no funding): LP gains 50 * (120 - 110), not
+    // the 50 * (120 - 100) a stale entry would report.
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 120_000_000, -6, 1, 100);
+    try_crank_permissionless(&mut f).unwrap();
+    let state = AccountState::from_bytes(&view(&mut f, &encode_get_account_state(lp.idx))).unwrap();
+    assert_eq!(state.position_size, 50);
+    let unrealized = state.position_size * (120_000_000 - state.entry_price as i128) / 1_000_000;
+    let equity_after = state.capital as i128 + state.pnl + unrealized;
+    assert_eq!(equity_after - equity_before, 500);
+}
+
+#[test]
+fn test_liquidate_eligible_paginates_to_cover_the_book() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // Every third user is thin and goes underwater once maintenance is raised
+    let mut lp = add_lp(&mut f, 100_000_000);
+    let mut users = Vec::new();
+    for i in 0..16 {
+        let capital = if i % 3 == 1 { 10_000 } else { 1_000_000 };
+        let mut user = add_user(&mut f, capital);
+        try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+        users.push((user, capital));
+    }
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 2_000;
+        engine.params.min_liquidation_abs = U128::new(u128::MAX);
+    }
+
+    install_return_data_stubs();
+    let mut caller = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    );
+    let mut cursor = 0u16;
+    let mut pages = 0;
+    let mut scanned = 0usize;
+    let mut liquidated = 0u16;
+    loop {
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        let accounts = vec![
+            caller.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_liquidate_eligible(cursor, 5),
+        )
+        .unwrap();
+        let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+        assert_eq!(bytes.len(), LiquidationScan::LEN);
+        let page = LiquidationScan::from_bytes(&bytes).unwrap();
+        assert!(page.scanned <= 5);
+        pages += 1;
+        scanned += page.scanned as usize;
+        liquidated += page.liquidated;
+        cursor = page.next_cursor;
+        if cursor == 0 {
+            break;
+        }
+    }
+
+    assert!(pages > 1, "a 5-slot page cannot cover the book");
+    assert_eq!(scanned, MAX_ACCOUNTS);
+    let thin = users.iter().filter(|(_, c)| *c == 10_000).count();
+    assert_eq!(liquidated as usize, thin);
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    for (user, capital) in &users {
+        let pos = engine.accounts[user.idx as usize].position_size.get();
+        if *capital == 10_000 {
+            assert_eq!(pos, 0, "underwater user {} not liquidated", user.idx);
+        } else {
+            assert_eq!(pos, 1_000, "healthy user {} touched", user.idx);
+        }
+    }
+
+    // Zero-width page and out-of-range cursor are rejected
+    for data in [
+        encode_liquidate_eligible(0, 0),
+        encode_liquidate_eligible(MAX_ACCOUNTS as u16, 5),
+    ] {
+        let accounts = vec![
+            caller.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        assert_eq!(
+            process_instruction(&f.program_id, &accounts, &data),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+}
+
+#[test]
+fn test_trade_in_funding_advance_slot_charges_pre_trade_position_once() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .params
+        .trading_fee_bps = 0;
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    try_crank_permissionless(&mut f).unwrap();
+
+    // A crank in this slot just advanced the index (simulated, as the fixture's
+    // inventory is too small to move it); the user has not settled yet.
+    let equity_before = {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        let current = engine.funding_index_qpb_e6.get();
+        engine.funding_index_qpb_e6 = I128::new(current + 2_500_000);
+        let acc = &engine.accounts[user.idx as usize];
+        acc.capital.get() as i128 + acc.pnl.get()
+    };
+
+    // Trade then crank again in the same slot: funding is charged on the
+    // 1_000 held across the advance (2_500), not on the post-trade 1_500 (3_750).
+    // Compare equity, as the engine may realize the loss against capital.
+    try_trade(&mut f, &mut user, &mut lp, 500).unwrap();
+    {
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        let acc = &engine.accounts[user.idx as usize];
+        assert_eq!(acc.funding_index.get(), engine.funding_index_qpb_e6.get());
+        assert_eq!(
+            acc.capital.get() as i128 + acc.pnl.get(),
+            equity_before - 2_500
+        );
+    }
+    try_crank_permissionless(&mut f).unwrap();
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let acc = &engine.accounts[user.idx as usize];
+    assert_eq!(acc.position_size.get(), 1_500);
+    assert_eq!(
+        acc.capital.get() as i128 + acc.pnl.get(),
+        equity_before - 2_500
+    );
+}
+
+#[test]
+fn test_get_warmup_status_follows_linear_curve() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    // Position closed in profit: 1_000 of backed PnL warming at 10/slot from slot 100
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.warmup_period_slots = 100;
+        engine.vault = U128::new(engine.vault.get() + 1_000);
+        engine.set_pnl(user.idx as usize, 1_000);
+        engine.accounts[user.idx as usize].warmup_slope_per_step = U128::new(10);
+        engine.accounts[user.idx as usize].warmup_started_at_slot = 100;
+    }
+
+    let status_at = |f: &mut MarketFixture, slot: u64| {
+        f.clock.data = make_clock(slot, 100);
+        install_return_data_stubs();
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        let accounts = vec![f.slab.to_info(), f.clock.to_info()];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_get_warmup_status(user.idx),
+        )
+        .unwrap();
+        let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+        assert_eq!(bytes.len(), WarmupStatus::LEN);
+        WarmupStatus::from_bytes(&bytes).unwrap()
+    };
+
+    for (slot, vested) in [(100, 0), (130, 300), (199, 990), (200, 1_000), (500, 1_000)] {
+        let status = status_at(&mut f, slot);
+        assert_eq!(
+            status,
+            WarmupStatus {
+                total_warming: 1_000,
+                vested_now: vested,
+                full_vest_slot: 200,
+            },
+            "slot {}",
+            slot
+        );
+    }
+
+    // Nothing warming: empty schedule
+    zc::engine_mut(&mut f.slab.data)
+        .unwrap()
+        .set_pnl(user.idx as usize, -50);
+    let status = status_at(&mut f, 130);
+    assert_eq!(status.total_warming, 0);
+    assert_eq!(status.vested_now, 0);
+
+    // Instant warmup: all of it is vested now
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.warmup_period_slots = 0;
+        engine.set_pnl(user.idx as usize, 1_000);
+    }
+    let status = status_at(&mut f, 130);
+    assert_eq!(status.total_warming, 1_000);
+    assert_eq!(status.vested_now, 1_000);
+    assert_eq!(status.full_vest_slot, 130);
+}
+
+#[test]
+fn test_hyperp_requires_nonzero_price_cap() {
+    // Hyperp starts with the default cap and cannot drop it to 0
+    let mut f = setup_market();
+    let init_data = encode_init_market_hyperp(&f, 100_000_000);
+    init_market_with(&mut f, &init_data).unwrap();
+    assert_eq!(
+        state::read_config(&f.slab.data).oracle_price_cap_e2bps,
+        percolator_prog::constants::DEFAULT_HYPERP_PRICE_CAP_E2BPS
+    );
+    assert_eq!(
+        try_admin_ix(&mut f, &encode_set_oracle_price_cap(0)),
+        Err(PercolatorError::HyperpCapRequired.into())
+    );
+    try_admin_ix(&mut f, &encode_set_oracle_price_cap(1_000)).unwrap();
+    assert_eq!(
+        state::read_config(&f.slab.data).oracle_price_cap_e2bps,
+        1_000
+    );
+
+    // Standard oracle markets may keep the circuit breaker disabled
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    try_admin_ix(&mut f, &encode_set_oracle_price_cap(1_000)).unwrap();
+    try_admin_ix(&mut f, &encode_set_oracle_price_cap(0)).unwrap();
+    assert_eq!(state::read_config(&f.slab.data).oracle_price_cap_e2bps, 0);
+}
+
+#[test]
+fn test_reduce_position_to_target_size() {
+    use percolator_prog::verify::reduce_to_target_delta;
+
+    assert_eq!(reduce_to_target_delta(100, 30), -70);
+    assert_eq!(reduce_to_target_delta(-100, 30), 70);
+    assert_eq!(reduce_to_target_delta(100, 0), -100);
+    assert_eq!(reduce_to_target_delta(100, 100), 0);
+    assert_eq!(reduce_to_target_delta(-100, 500), 0);
+    assert_eq!(reduce_to_target_delta(i128::MIN, 0), i128::MAX);
+
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 5_000_000_000);
+    let mut lp = add_lp(&mut f, 5_000_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 100_000_000).unwrap();
+
+    let (user_idx, lp_idx) = (user.idx, lp.idx);
+    let positions = |f: &MarketFixture| {
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        (
+            engine.accounts[user_idx as usize].position_size.get(),
+            engine.accounts[lp_idx as usize].position_size.get(),
+        )
+    };
+
+    try_reduce(&mut f, &mut user, &mut lp, 30_000_000).unwrap();
+    assert_eq!(positions(&f), (30_000_000, -30_000_000));
+
+    // At or above the current size: no-op success
+    let snapshot = f.slab.data.clone();
+    try_reduce(&mut f, &mut user, &mut lp, 30_000_000).unwrap();
+    try_reduce(&mut f, &mut user, &mut lp, 50_000_000).unwrap();
+    assert_eq!(f.slab.data, snapshot);
+
+    // Negative target is malformed
+    assert_eq!(
+        try_reduce(&mut f, &mut user, &mut lp, -1),
+        Err(ProgramError::InvalidInstructionData)
+    );
+
+    // Full close never flips
+    try_reduce(&mut f, &mut user, &mut lp, 0).unwrap();
+    assert_eq!(positions(&f), (0, 0));
+}
+
+#[test]
+fn test_trade_signer_policy_per_mode() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+
+    // LP bound to a real-looking matcher program/context pair
+    let mut matcher_program = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+    matcher_program.executable = true;
+    let mut matcher_ctx =
+        TestAccount::new(Pubkey::new_unique(), matcher_program.key, 0, vec![0u8; 320]).writable();
+    let (mut owner, mut ata) = new_owner_and_ata(&f, 1_000_000);
+    {
+        let accounts = vec![
+            owner.to_info(),
+            f.slab.to_info(),
+            ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+        ];
+        let data = encode_init_lp(matcher_program.key, matcher_ctx.key, 0);
+        process_instruction(&f.program_id, &accounts, &data).unwrap();
+    }
+    let idx = find_idx_by_owner(&f.slab.data, owner.key).unwrap();
+    let mut lp = Participant { owner, ata, idx };
+    try_deposit(&mut f, &mut lp, 1_000_000).unwrap();
+
+    // TradeNoCpi: both counterparties must sign
+    lp.owner.is_signer = false;
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 100),
+        Err(PercolatorError::ExpectedSigner.into())
+    );
+    lp.owner.is_signer = true;
+    user.owner.is_signer = false;
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 100),
+        Err(PercolatorError::ExpectedSigner.into())
+    );
+    user.owner.is_signer = true;
+
+    // TradeCpi: the LP owner need not sign, but the matcher must be the bound one
+    let (lp_pda_key, _) = Pubkey::find_program_address(
+        &[b"lp", f.slab.key.as_ref(), &lp.idx.to_le_bytes()],
+        &f.program_id,
+    );
+    let mut lp_pda = TestAccount::new(lp_pda_key, solana_program::system_program::id(), 0, vec![]);
+    let mut other_program = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+    other_program.executable = true;
+    let mut other_ctx =
+        TestAccount::new(Pubkey::new_unique(), other_program.key, 0, vec![0u8; 320]).writable();
+    lp.owner.is_signer = false;
+    {
+        let accounts = vec![
+            user.owner.to_info(),
+            lp.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+            other_program.to_info(),
+            other_ctx.to_info(),
+            lp_pda.to_info(),
+        ];
+        let res = process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_trade_cpi(lp.idx, user.idx, 100),
+        );
+        // Past the signer check; rejected only for the unbound matcher
+        assert_eq!(
+            res,
+            Err(PercolatorError::EngineInvalidMatchingEngine.into())
+        );
+    }
+
+    // TradeCpi still requires the user signer
+    user.owner.is_signer = false;
+    let accounts = vec![
+        user.owner.to_info(),
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+        matcher_program.to_info(),
+        matcher_ctx.to_info(),
+        lp_pda.to_info(),
+    ];
+    let res = process_instruction(
+        &f.program_id,
+        &accounts,
+        &encode_trade_cpi(lp.idx, user.idx, 100),
+    );
+    assert_eq!(res, Err(PercolatorError::ExpectedSigner.into()));
+}
+
+#[test]
+fn test_multi_trade_fills_all_legs_or_none() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp_a = add_lp(&mut f, 1_000_000);
+    let mut lp_b = add_lp(&mut f, 1_000_000);
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 500;
+        engine.params.initial_margin_bps = 1_000;
+    }
+
+    let multi_trade = |f: &mut MarketFixture,
+                       user: &mut Participant,
+                       lps: [&mut TestAccount; 2],
+                       legs: &[(u16, i128)]| {
+        let [a, b] = lps;
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+            a.to_info(),
+            b.to_info(),
+        ];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_multi_trade(user.idx, legs),
+        )
+    };
+    let positions = |f: &MarketFixture, idxs: [u16; 3]| {
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        idxs.map(|i| engine.accounts[i as usize].position_size.get())
+    };
+    let idxs = [user.idx, lp_a.idx, lp_b.idx];
+
+    // Two legs, 900 @ $100 combined: within 10% initial margin of 10_000
+    multi_trade(
+        &mut f,
+        &mut user,
+        [&mut lp_a.owner, &mut lp_b.owner],
+        &[(lp_a.idx, 400), (lp_b.idx, 500)],
+    )
+    .unwrap();
+    assert_eq!(positions(&f, idxs), [900, -400, -500]);
+
+    // Hedged legs net to a reduction: only the net fill is margined (legs still
+    // fill in order, each within the engine's maintenance check)
+    multi_trade(
+        &mut f,
+        &mut user,
+        [&mut lp_a.owner, &mut lp_b.owner],
+        &[(lp_b.idx, -2_100), (lp_a.idx, 2_000)],
+    )
+    .unwrap();
+    assert_eq!(positions(&f, idxs), [800, -2_400, 1_600]);
+
+    // Combined fill over initial margin, and a second leg signed by the wrong
+    // owner: rejected before the first leg fills
+    let snapshot = f.slab.data.clone();
+    assert_eq!(
+        multi_trade(
+            &mut f,
+            &mut user,
+            [&mut lp_a.owner, &mut lp_b.owner],
+            &[(lp_a.idx, 100), (lp_b.idx, 200)],
+        ),
+        Err(PercolatorError::EngineUndercollateralized.into())
+    );
+    let mut impostor = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    )
+    .signer();
+    assert_eq!(
+        multi_trade(
+            &mut f,
+            &mut user,
+            [&mut lp_a.owner, &mut impostor],
+            &[(lp_a.idx, -100), (lp_b.idx, -100)],
+        ),
+        Err(PercolatorError::EngineUnauthorized.into())
+    );
+    assert_eq!(f.slab.data, snapshot);
+
+    // Missing LP signature, zero-size leg, and too many legs
+    lp_b.owner.is_signer = false;
+    assert_eq!(
+        multi_trade(
+            &mut f,
+            &mut user,
+            [&mut lp_a.owner, &mut lp_b.owner],
+            &[(lp_a.idx, -100), (lp_b.idx, -100)],
+        ),
+        Err(PercolatorError::ExpectedSigner.into())
+    );
+    lp_b.owner.is_signer = true;
+    assert_eq!(
+        multi_trade(
+            &mut f,
+            &mut user,
+            [&mut lp_a.owner, &mut lp_b.owner],
+            &[(lp_a.idx, -100), (lp_b.idx, 0)],
+        ),
+        Err(PercolatorError::ZeroSizeTrade.into())
+    );
+    let legs = [(lp_a.idx, -1i128); percolator_prog::constants::MAX_TRADE_LEGS + 1];
+    assert_eq!(
+        multi_trade(&mut f, &mut user, [&mut lp_a.owner, &mut lp_b.owner], &legs),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    assert_eq!(f.slab.data, snapshot);
+}
+
+#[test]
+fn test_feature_flags_toggle_pause_and_cpi_only() {
+    use percolator_prog::state::{FEATURE_CPI_ONLY, FEATURE_PAUSED, FLAG_EMERGENCY_EXIT};
+
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+
+    let flags = |f: &mut MarketFixture| {
+        FeatureFlags::from_bytes(&view(f, &encode_get_feature_flags())).unwrap()
+    };
+    assert_eq!(flags(&mut f), FeatureFlags::default());
+
+    // Paused: no trades or deposits, withdrawals still work
+    try_admin_ix(&mut f, &encode_set_feature_flags(FEATURE_PAUSED, 0)).unwrap();
+    assert_eq!(flags(&mut f).feature_flags, FEATURE_PAUSED);
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 100),
+        Err(PercolatorError::MarketPaused.into())
+    );
+    assert_eq!(
+        try_deposit(&mut f, &mut user, 1_000),
+        Err(PercolatorError::MarketPaused.into())
+    );
+    try_withdraw(&mut f, &mut user, 1_000).unwrap();
+
+    // Unpause and go CPI-only in one call: deposits reopen, TradeNoCpi stays off
+    try_admin_ix(
+        &mut f,
+        &encode_set_feature_flags(FEATURE_CPI_ONLY, FEATURE_PAUSED),
+    )
+    .unwrap();
+    assert_eq!(flags(&mut f).feature_flags, FEATURE_CPI_ONLY);
+    try_deposit(&mut f, &mut user, 1_000).unwrap();
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 100),
+        Err(PercolatorError::NoCpiTradingDisabled.into())
+    );
+
+    try_admin_ix(&mut f, &encode_set_feature_flags(0, FEATURE_CPI_ONLY)).unwrap();
+    try_trade(&mut f, &mut user, &mut lp, 100).unwrap();
+
+    // Unknown bits and overlapping masks are rejected without touching the flags
+    assert_eq!(
+        try_admin_ix(&mut f, &encode_set_feature_flags(1 << 63, 0)),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    assert_eq!(
+        try_admin_ix(
+            &mut f,
+            &encode_set_feature_flags(FEATURE_PAUSED, FEATURE_PAUSED)
+        ),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    assert_eq!(flags(&mut f).feature_flags, 0);
+
+    // Lifecycle flags are reported alongside
+    try_admin_ix(&mut f, &encode_set_emergency_exit(1)).unwrap();
+    assert_eq!(flags(&mut f).market_flags, FLAG_EMERGENCY_EXIT);
+}
+
+#[test]
+fn test_market_mode_reduce_only_and_halted() {
+    use percolator_prog::state::{MARKET_MODE_HALTED, MARKET_MODE_NORMAL, MARKET_MODE_REDUCE_ONLY};
+
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    // Reduce-only: growing or flipping the position is rejected, reducing fills pass
+    try_admin_ix(&mut f, &encode_set_market_mode(MARKET_MODE_REDUCE_ONLY)).unwrap();
+    let mode = FeatureFlags::from_bytes(&view(&mut f, &encode_get_feature_flags()))
+        .unwrap()
+        .market_mode;
+    assert_eq!(mode, MARKET_MODE_REDUCE_ONLY);
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 100),
+        Err(PercolatorError::EngineRiskReductionOnlyMode.into())
+    );
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, -1_500),
+        Err(PercolatorError::EngineRiskReductionOnlyMode.into())
+    );
+    try_trade(&mut f, &mut user, &mut lp, -400).unwrap();
+    let user_idx = user.idx as usize;
+    let pos = |f: &MarketFixture| {
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user_idx]
+            .position_size
+            .get()
+    };
+    assert_eq!(pos(&f), 600);
+
+    // Halted: trades and collateral moves stop, the crank keeps running
+    try_admin_ix(&mut f, &encode_set_market_mode(MARKET_MODE_HALTED)).unwrap();
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, -100),
+        Err(PercolatorError::MarketHalted.into())
+    );
+    assert_eq!(
+        try_deposit(&mut f, &mut user, 1_000),
+        Err(PercolatorError::MarketHalted.into())
+    );
+    assert_eq!(
+        try_withdraw(&mut f, &mut user, 1_000),
+        Err(PercolatorError::MarketHalted.into())
+    );
+    try_crank_permissionless(&mut f).unwrap();
+
+    // Unknown modes are rejected; back to normal reopens opening trades
+    assert_eq!(
+        try_admin_ix(&mut f, &encode_set_market_mode(3)),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    try_admin_ix(&mut f, &encode_set_market_mode(MARKET_MODE_NORMAL)).unwrap();
+    try_trade(&mut f, &mut user, &mut lp, 100).unwrap();
+    assert_eq!(pos(&f), 700);
+}
+
+#[test]
+fn test_keeper_crank_batch_covers_a_slot_range() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let batch = |f: &mut MarketFixture, up_to_slot: u64, max_iters: u16| {
+        install_return_data_stubs();
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        let mut keeper = TestAccount::new(
+            Pubkey::new_unique(),
+            solana_program::system_program::id(),
+            0,
+            vec![],
+        );
+        let accounts = vec![
+            keeper.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        let data = encode_crank_batch(u16::MAX, up_to_slot, max_iters, 0);
+        process_instruction(&f.program_id, &accounts, &data)?;
+        let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+        assert_eq!(bytes.len(), CrankBatch::LEN);
+        Ok::<_, ProgramError>(CrankBatch::from_bytes(&bytes).unwrap())
+    };
+
+    // Ten slots behind: one call cranks all of them
+    f.clock.data = make_clock(110, 100);
+    assert_eq!(
+        batch(&mut f, u64::MAX, 16),
+        Ok(CrankBatch {
+            processed: 10,
+            last_slot: 110
+        })
+    );
+    assert_eq!(zc::engine_ref(&f.slab.data).unwrap().last_crank_slot, 110);
+    assert_eq!(batch(&mut f, u64::MAX, 16).unwrap().processed, 0);
+
+    // up_to_slot and max_iters both bound the range
+    f.clock.data = make_clock(130, 100);
+    assert_eq!(
+        batch(&mut f, 112, 16),
+        Ok(CrankBatch {
+            processed: 2,
+            last_slot: 112
+        })
+    );
+    assert_eq!(
+        batch(&mut f, u64::MAX, 4),
+        Ok(CrankBatch {
+            processed: 4,
+            last_slot: 116
+        })
+    );
+
+    // Iteration count must be within 1..=MAX_CRANK_BATCH_ITERS
+    for max_iters in [0, percolator_prog::constants::MAX_CRANK_BATCH_ITERS + 1] {
+        assert_eq!(
+            batch(&mut f, u64::MAX, max_iters),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+}
+
+#[test]
+fn test_transfer_position_moves_half_a_long_between_sub_accounts() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut main = add_user(&mut f, 100_000);
+    let sub = add_user(&mut f, 10_000);
+    let thin = add_user(&mut f, 1_000);
+    let stranger = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut main, &mut lp, 1_000).unwrap();
+    {
+        // One wallet owns main, sub and thin; 10% initial margin
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        for idx in [sub.idx, thin.idx] {
+            engine.accounts[idx as usize].owner = main.owner.key.to_bytes();
+        }
+        engine.params.initial_margin_bps = 1_000;
+    }
+
+    let mut transfer = |f: &mut MarketFixture, from: u16, to: u16, size: i128| {
+        let accounts = vec![
+            main.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_transfer_position(from, to, size),
+        )
+    };
+
+    // Price up 10%: the long carries 10_000 of mark PnL, half of which moves with half the size
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 110_000_000, -6, 1, 100);
+    transfer(&mut f, main.idx, sub.idx, 500).unwrap();
+    {
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        for (idx, capital) in [(main.idx, 100_000i128), (sub.idx, 10_000)] {
+            let acc = &engine.accounts[idx as usize];
+            assert_eq!(acc.position_size.get(), 500);
+            assert_eq!(acc.entry_price, 110_000_000);
+            assert_eq!(acc.pnl.get(), 5_000);
+            // Marked at the entry: equity is capital + PnL, above 10% of 55_000
+            let equity = acc.capital.get() as i128 + acc.pnl.get();
+            assert_eq!(equity, capital + 5_000);
+            assert!(equity >= 5_500);
+        }
+    }
+
+    // Destination below initial margin: 1_000 equity against 4_400 required
+    assert_eq!(
+        transfer(&mut f, main.idx, thin.idx, 400),
+        Err(PercolatorError::EngineUndercollateralized.into())
+    );
+    // More than the source holds, or a flip, is rejected
+    assert_eq!(
+        transfer(&mut f, main.idx, sub.idx, 501),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    assert_eq!(
+        transfer(&mut f, main.idx, sub.idx, -100),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    // Accounts of another wallet are off limits
+    assert_eq!(
+        transfer(&mut f, main.idx, stranger.idx, 100),
+        Err(PercolatorError::EngineUnauthorized.into())
+    );
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[main.idx as usize].position_size.get(), 500);
+    assert_eq!(engine.accounts[thin.idx as usize].position_size.get(), 0);
+}
+
+#[test]
+fn test_set_oracle_params_tightens_conf_filter_and_staleness() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // 3% confidence passes the 5% filter set at init
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 3_000_000, 100);
+    try_crank_permissionless(&mut f).unwrap();
+
+    // Tightened to 1%: the same feed is now too wide
+    try_admin_ix(&mut f, &encode_set_oracle_params(100, 100)).unwrap();
+    assert_eq!(
+        try_crank_permissionless(&mut f),
+        Err(PercolatorError::OracleConfTooWide.into())
+    );
+    let config = state::read_config(&f.slab.data);
+    assert_eq!(config.conf_filter_bps, 100);
+    assert_eq!(config.max_staleness_secs, 100);
+
+    // Staleness follows the new bound too: 20s old against a 10s limit
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 1, 100);
+    f.clock.data = make_clock(101, 120);
+    try_crank_permissionless(&mut f).unwrap();
+    try_admin_ix(&mut f, &encode_set_oracle_params(100, 10)).unwrap();
+    assert_eq!(
+        try_crank_permissionless(&mut f),
+        Err(PercolatorError::OracleStale.into())
+    );
+
+    // A filter above 100% is rejected
+    assert_eq!(
+        try_admin_ix(&mut f, &encode_set_oracle_params(10_001, 100)),
+        Err(ProgramError::InvalidInstructionData)
+    );
+}
+
+#[test]
+fn test_index_price_median_discards_stale_feed_and_outlier() {
+    const FEED_B: [u8; 32] = [0xB0; 32];
+    const FEED_C: [u8; 32] = [0xC0; 32];
+
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    try_admin_ix(&mut f, &encode_set_index_feeds(&FEED_B, &FEED_C)).unwrap();
+
+    let pyth_id = f.pyth_index.owner;
+    let mut feed_b = TestAccount::new(Pubkey::new_unique(), pyth_id, 0, vec![]);
+    let mut feed_c = TestAccount::new(Pubkey::new_unique(), pyth_id, 0, vec![]);
+    let mut crank_with_feeds = |f: &mut MarketFixture, b: Vec<u8>, c: Vec<u8>| {
+        feed_b.data = b;
+        feed_c.data = c;
+        let mut keeper = TestAccount::new(
+            Pubkey::new_unique(),
+            solana_program::system_program::id(),
+            0,
+            vec![],
+        );
+        let accounts = vec![
+            keeper.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+            feed_b.to_info(),
+            feed_c.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_crank_permissionless(0))?;
+        Ok::<u64, ProgramError>(state::read_config(&f.slab.data).last_effective_price_e6)
+    };
+    let fresh = |feed: &[u8; 32], price: i64| make_pyth(feed, price, -6, 1, 100);
+    let stale = |feed: &[u8; 32], price: i64| make_pyth(feed, price, -6, 1, -1);
+
+    // Three fresh feeds: the 150 outlier is ignored
+    let price = crank_with_feeds(
+        &mut f,
+        fresh(&FEED_B, 101_000_000),
+        fresh(&FEED_C, 150_000_000),
+    );
+    assert_eq!(price.unwrap(), 101_000_000);
+
+    // One stale feed is discarded: median of the remaining two is their mean
+    let price = crank_with_feeds(
+        &mut f,
+        stale(&FEED_B, 101_000_000),
+        fresh(&FEED_C, 104_000_000),
+    );
+    assert_eq!(price.unwrap(), 102_000_000);
+
+    // Two stale feeds: the lone fresh primary is not a quorum of three
+    assert_eq!(
+        crank_with_feeds(
+            &mut f,
+            stale(&FEED_B, 101_000_000),
+            stale(&FEED_C, 150_000_000)
+        ),
+        Err(PercolatorError::OracleStale.into())
+    );
+
+    // A feed account for the wrong feed is an error, not a silent discard
+    assert_eq!(
+        crank_with_feeds(
+            &mut f,
+            fresh(&FEED_C, 101_000_000),
+            fresh(&FEED_B, 150_000_000)
+        ),
+        Err(PercolatorError::InvalidOracleKey.into())
+    );
+
+    // All configured feed accounts are required
+    assert_eq!(
+        try_crank_permissionless(&mut f),
+        Err(ProgramError::NotEnoughAccountKeys)
+    );
+
+    // Feeds may not repeat the primary, and the tertiary needs a secondary
+    assert_eq!(
+        try_admin_ix(&mut f, &encode_set_index_feeds(&TEST_FEED_ID, &FEED_C)),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    assert_eq!(
+        try_admin_ix(&mut f, &encode_set_index_feeds(&[0; 32], &FEED_C)),
+        Err(ProgramError::InvalidInstructionData)
+    );
+
+    // Clearing both restores the single-feed read
+    try_admin_ix(&mut f, &encode_set_index_feeds(&[0; 32], &[0; 32])).unwrap();
+    try_crank_permissionless(&mut f).unwrap();
+}
+
+#[test]
+fn test_token_2022_withdraw_with_extra_index_feeds() {
+    use percolator_prog::collateral::TOKEN_2022_PROGRAM_ID;
+    const FEED_B: [u8; 32] = [0xB0; 32];
+    const FEED_C: [u8; 32] = [0xC0; 32];
+
+    let mut f = setup_market();
+    f.mint.owner = TOKEN_2022_PROGRAM_ID;
+    f.vault.owner = TOKEN_2022_PROGRAM_ID;
+    f.vault.data.push(2);
+    f.token_prog.key = TOKEN_2022_PROGRAM_ID;
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    try_admin_ix(&mut f, &encode_set_index_feeds(&FEED_B, &FEED_C)).unwrap();
+
+    let (mut owner, mut ata) = new_owner_and_ata(&f, 1_000_000);
+    ata.owner = TOKEN_2022_PROGRAM_ID;
+    {
+        let accounts = vec![
+            owner.to_info(),
+            f.slab.to_info(),
+            ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.mint.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_init_user(0)).unwrap();
+    }
+    let idx = find_idx_by_owner(&f.slab.data, owner.key).unwrap();
+    let mut user = Participant { owner, ata, idx };
+    {
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            user.ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.mint.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_deposit(idx, 1_000_000)).unwrap();
+    }
+
+    let pyth_id = f.pyth_index.owner;
+    let mut feed_b = TestAccount::new(
+        Pubkey::new_unique(),
+        pyth_id,
+        0,
+        make_pyth(&FEED_B, 101_000_000, -6, 1, 100),
+    );
+    let mut feed_c = TestAccount::new(
+        Pubkey::new_unique(),
+        pyth_id,
+        0,
+        make_pyth(&FEED_C, 150_000_000, -6, 1, 100),
+    );
+    let mut vault_pda =
+        TestAccount::new(f.vault_pda, solana_program::system_program::id(), 0, vec![]);
+    let mut withdraw = |f: &mut MarketFixture, trailing: Vec<&mut TestAccount>| {
+        let mut accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            f.vault.to_info(),
+            user.ata.to_info(),
+            vault_pda.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        accounts.extend(trailing.into_iter().map(|a| a.to_info()));
+        process_instruction(&f.program_id, &accounts, &encode_withdraw(idx, 100_000))
+    };
+
+    // The mint holds accounts[8]: feeds in its place are not a mint
+    assert_eq!(
+        withdraw(&mut f, vec![&mut feed_b, &mut feed_c]),
+        Err(PercolatorError::InvalidMint.into())
+    );
+    // The feeds follow the mint and are all required
+    let mut mint = TestAccount::new(f.mint.key, f.mint.owner, 0, f.mint.data.clone());
+    assert_eq!(
+        withdraw(&mut f, vec![&mut mint, &mut feed_b]),
+        Err(ProgramError::NotEnoughAccountKeys)
+    );
+
+    // Mint, then both feeds: priced off the median and paid out with transfer_checked
+    withdraw(&mut f, vec![&mut mint, &mut feed_b, &mut feed_c]).unwrap();
+    assert_eq!(
+        state::read_config(&f.slab.data).last_effective_price_e6,
+        101_000_000
+    );
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[idx as usize].capital.get(), 900_000);
+    let ata = TokenAccount::unpack(&user.ata.data).unwrap();
+    assert_eq!(ata.amount, 100_000);
+}
+
+#[test]
+fn test_two_crank_windows_match_one_full_crank() {
+    // Two identical markets with an open position, 100 slots of funding and
+    // warmup pending; one is cranked in full, the other in two windows.
+    let setup = || {
+        let mut f = setup_market();
+        let init_data = encode_init_market(&f, 1_000);
+        init_market_with(&mut f, &init_data).unwrap();
+        let mut user = add_user(&mut f, 1_000_000);
+        let mut lp = add_lp(&mut f, 1_000_000);
+        try_trade(&mut f, &mut user, &mut lp, 5_000).unwrap();
+        f.clock.data = make_clock(200, 100);
+        (f, user.idx, lp.idx)
+    };
+    let window = |f: &mut MarketFixture, start: u16, count: u16| {
+        install_return_data_stubs();
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        let mut keeper = TestAccount::new(
+            Pubkey::new_unique(),
+            solana_program::system_program::id(),
+            0,
+            vec![],
+        );
+        let accounts = vec![
+            keeper.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        let data = encode_crank_window(u16::MAX, start, count);
+        process_instruction(&f.program_id, &accounts, &data)?;
+        let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+        Ok::<_, ProgramError>(CrankOutcome::from_bytes(&bytes).unwrap())
+    };
+
+    let (mut full, user_idx, lp_idx) = setup();
+    try_crank_permissionless(&mut full).unwrap();
+
+    let (mut windowed, _, _) = setup();
+    let half = (MAX_ACCOUNTS / 2) as u16;
+    // Windows must continue from the cursor
+    assert_eq!(
+        window(&mut windowed, half, half),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    let first = window(&mut windowed, 0, half).unwrap();
+    assert_eq!((first.crank_cursor, first.sweep_completed), (half, 0));
+    let second = window(&mut windowed, half, half).unwrap();
+    assert_eq!((second.crank_cursor, second.sweep_completed), (0, 1));
+
+    let full_engine = zc::engine_ref(&full.slab.data).unwrap();
+    let win_engine = zc::engine_ref(&windowed.slab.data).unwrap();
+    assert_eq!(
+        full_engine.funding_index_qpb_e6.get(),
+        win_engine.funding_index_qpb_e6.get()
+    );
+    for idx in [user_idx, lp_idx] {
+        let a = &full_engine.accounts[idx as usize];
+        let b = &win_engine.accounts[idx as usize];
+        assert_eq!(a.capital.get(), b.capital.get(), "capital of {idx}");
+        assert_eq!(a.pnl.get(), b.pnl.get(), "pnl of {idx}");
+        assert_eq!(a.position_size.get(), b.position_size.get());
+    }
+    assert_eq!(
+        state::read_config(&windowed.slab.data).last_full_sweep_slot,
+        200
+    );
+}
+
+#[test]
+fn test_funding_twap_bounds_one_slot_price_spike() {
+    use percolator_prog::state::FEATURE_FUNDING_TWAP;
+
+    // Same market twice, spot vs TWAP funding: LP net short 3e10 units, so
+    // the premium is 300 bps at price 100 (rate 0 over the 500-slot horizon)
+    // and saturates at 500 bps (rate -1) at price 200.
+    let spike_rate = |twap: bool| {
+        let mut f = setup_market();
+        let init_data = encode_init_market(&f, 100);
+        init_market_with(&mut f, &init_data).unwrap();
+        if twap {
+            try_admin_ix(&mut f, &encode_set_feature_flags(FEATURE_FUNDING_TWAP, 0)).unwrap();
+        }
+        let mut user = add_user(&mut f, 1_000_000_000_000);
+        let mut lp = add_lp(&mut f, 1_000_000_000_000);
+        try_trade(&mut f, &mut user, &mut lp, 30_000_000_000).unwrap();
+
+        for slot in 101..=110 {
+            f.clock.data = make_clock(slot, 100);
+            assert_eq!(crank_outcome(&mut f).funding_rate_bps_per_slot, 0);
+        }
+        f.clock.data = make_clock(111, 100);
+        f.pyth_index.data = make_pyth(&TEST_FEED_ID, 200_000_000, -6, 1, 100);
+        let outcome = crank_outcome(&mut f);
+        assert_eq!(outcome.price_e6, 200_000_000);
+        let config = state::read_config(&f.slab.data);
+        assert_eq!(
+            (config.funding_twap_price_sum, config.funding_twap_dt_total),
+            (1_200_000_000, 11)
+        );
+        outcome.funding_rate_bps_per_slot
+    };
+
+    assert_eq!(spike_rate(false), -1);
+    // TWAP of ten slots at 100 and one at 200 is ~109: premium stays below a
+    // full bps per slot
+    assert_eq!(spike_rate(true), 0);
+}
+
+#[test]
+fn test_view_decodes_header_config_and_accounts() {
+    let mut f = setup_market();
+
+    // An uninitialized slab does not decode
+    assert_eq!(
+        slab_view::decode_header(&f.slab.data).err(),
+        Some(PercolatorError::NotInitialized.into())
+    );
+
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    let header = slab_view::decode_header(&f.slab.data).unwrap();
+    assert_eq!(header.magic, MAGIC);
+    assert_eq!(header.version, slab_view::VIEW_VERSION);
+    assert_eq!(header.admin, f.admin.key.to_bytes());
+
+    let config = slab_view::decode_config(&f.slab.data).unwrap();
+    assert_eq!(config.collateral_mint, f.mint.key.to_bytes());
+    assert_eq!(config.index_feed_id, TEST_FEED_ID);
+    assert_eq!(config.max_staleness_secs, 100);
+    assert_eq!(config.conf_filter_bps, 500);
+    assert_eq!(
+        bytemuck::bytes_of(&config),
+        bytemuck::bytes_of(&state::read_config(&f.slab.data))
+    );
+
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    let acc = slab_view::decode_account(&f.slab.data, user.idx).unwrap();
+    assert_eq!(acc.idx, user.idx);
+    assert!(!acc.is_lp);
+    assert_eq!(acc.owner, user.owner.key.to_bytes());
+    assert_eq!(acc.position_size, 1_000);
+    assert_eq!(
+        acc.capital,
+        engine.accounts[user.idx as usize].capital.get()
+    );
+    assert!(
+        slab_view::decode_account(&f.slab.data, lp.idx)
+            .unwrap()
+            .is_lp
+    );
+
+    // Unused and out-of-range slots are not accounts
+    for idx in [user.idx.max(lp.idx) + 1, MAX_ACCOUNTS as u16] {
+        assert_eq!(
+            slab_view::decode_account(&f.slab.data, idx).err(),
+            Some(PercolatorError::EngineAccountNotFound.into())
+        );
+    }
+}
+
+#[test]
+fn test_client_builders_round_trip_through_decode() {
+    use percolator::RiskParams;
+    use percolator_prog::client::{self, InitMarketArgs};
+    use percolator_prog::ix::Instruction;
+    use solana_program::sysvar;
+
+    let f = setup_market();
+    let m = client::Market {
+        program_id: f.program_id,
+        slab: f.slab.key,
+        mint: f.mint.key,
+        vault: f.vault.key,
+        oracle: f.pyth_index.key,
+        token_program: spl_token::ID,
+    };
+    let (user, user_ata, lp_owner) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+
+    // Same bytes as the hand-rolled test encoder
+    let args = InitMarketArgs {
+        admin: f.admin.key,
+        index_feed_id: f.index_feed_id,
+        max_staleness_secs: 100,
+        conf_filter_bps: 500,
+        invert: 0,
+        unit_scale: 0,
+        initial_mark_price_e6: 0,
+        risk_params: RiskParams {
+            warmup_period_slots: 0,
+            maintenance_margin_bps: 0,
+            initial_margin_bps: 0,
+            trading_fee_bps: 0,
+            max_accounts: MAX_ACCOUNTS as u64,
+            new_account_fee: U128::ZERO,
+            risk_reduction_threshold: U128::ZERO,
+            maintenance_fee_per_slot: U128::ZERO,
+            max_crank_staleness_slots: 100,
+            liquidation_fee_bps: 0,
+            liquidation_fee_cap: U128::ZERO,
+            liquidation_buffer_bps: 0,
+            min_liquidation_abs: U128::ZERO,
+        },
+    };
+    let ix = client::init_market_ix(&m, &args);
+    assert_eq!(ix.data, encode_init_market(&f, 100));
+    assert_eq!(ix.accounts.len(), 9);
+    assert!(ix.accounts[0].is_signer && ix.accounts[1].is_writable);
+    match Instruction::decode(&ix.data).unwrap() {
+        Instruction::InitMarket {
+            admin,
+            collateral_mint,
+            index_feed_id,
+            risk_params,
+            ..
+        } => {
+            assert_eq!((admin, collateral_mint), (f.admin.key, f.mint.key));
+            assert_eq!(index_feed_id, TEST_FEED_ID);
+            assert_eq!(risk_params.max_crank_staleness_slots, 100);
+        }
+        other => panic!("decoded {other:?}"),
+    }
+
+    let (matcher_prog, matcher_ctx) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let ix = client::init_lp_ix(&m, &lp_owner, &user_ata, &matcher_prog, &matcher_ctx, 7);
+    assert_eq!(ix.accounts.len(), 5);
+    assert!(matches!(
+        Instruction::decode(&ix.data).unwrap(),
+        Instruction::InitLP { matcher_program, matcher_context, fee_payment: 7 }
+            if matcher_program == matcher_prog && matcher_context == matcher_ctx
+    ));
+
+    // Payout paths share the withdraw layout, vault authority at [4], oracle at [7]
+    for ix in [
+        client::withdraw_ix(&m, &user, &user_ata, 3, 1_000),
+        client::close_account_ix(&m, &user, &user_ata, 3),
+    ] {
+        assert_eq!(ix.accounts.len(), 8);
+        assert_eq!(ix.accounts[4].pubkey, f.vault_pda);
+        assert_eq!(ix.accounts[6].pubkey, sysvar::clock::ID);
+        assert_eq!(ix.accounts[7].pubkey, f.pyth_index.key);
+    }
+    assert!(matches!(
+        Instruction::decode(&client::withdraw_ix(&m, &user, &user_ata, 3, 1_000).data).unwrap(),
+        Instruction::WithdrawCollateral {
+            user_idx: 3,
+            amount: 1_000
+        }
+    ));
+    assert!(matches!(
+        Instruction::decode(&client::close_account_ix(&m, &user, &user_ata, 3).data).unwrap(),
+        Instruction::CloseAccount { user_idx: 3 }
+    ));
+    assert!(matches!(
+        Instruction::decode(&client::init_user_ix(&m, &user, &user_ata, 5).data).unwrap(),
+        Instruction::InitUser { fee_payment: 5 }
+    ));
+    let ix = client::deposit_ix(&m, &user, &user_ata, 3, 500);
+    assert_eq!(ix.accounts.len(), 6);
+    assert!(matches!(
+        Instruction::decode(&ix.data).unwrap(),
+        Instruction::DepositCollateral {
+            user_idx: 3,
+            amount: 500
+        }
+    ));
+
+    let ix = client::keeper_crank_ix(&m, &user, u16::MAX, 0);
+    assert_eq!(ix.data, encode_crank_permissionless(0));
+    assert!(matches!(
+        Instruction::decode(&client::liquidate_at_oracle_ix(&m, &user, 4).data).unwrap(),
+        Instruction::LiquidateAtOracle { target_idx: 4 }
+    ));
+
+    let ix = client::trade_no_cpi_ix(&m, &user, &lp_owner, 1, 2, -300);
+    assert_eq!(ix.data, encode_trade(1, 2, -300));
+    assert!(ix.accounts[0].is_signer && ix.accounts[1].is_signer);
+
+    let ix = client::trade_cpi_ix(&m, &user, &lp_owner, 1, 2, 300, &matcher_prog, &matcher_ctx);
+    assert_eq!(ix.data, encode_trade_cpi(1, 2, 300));
+    assert_eq!(ix.accounts.len(), 8);
+    assert!(!ix.accounts[1].is_signer);
+    assert!(ix.accounts[6].is_writable);
+    let (lp_pda, _) = Pubkey::find_program_address(
+        &[b"lp", f.slab.key.as_ref(), &1u16.to_le_bytes()],
+        &f.program_id,
+    );
+    assert_eq!(ix.accounts[7].pubkey, lp_pda);
+}
+
+#[test]
+fn test_slab_of_wrong_length_reports_invalid_slab_len() {
+    use percolator_prog::constants::{expected_slab_len, LEGACY_SLAB_LEN, SLAB_LEN};
+
+    assert_eq!(expected_slab_len(), SLAB_LEN);
+
+    // Too short for this build, e.g. allocated for a smaller MAX_ACCOUNTS
+    let mut f = setup_market();
+    f.slab.data = vec![0u8; expected_slab_len() / 2];
+    let init_data = encode_init_market(&f, 100);
+    assert_eq!(
+        init_market_with(&mut f, &init_data),
+        Err(PercolatorError::InvalidSlabLen.into())
+    );
+    assert_eq!(
+        slab_view::decode_header(&f.slab.data).err(),
+        Some(PercolatorError::InvalidSlabLen.into())
+    );
+
+    // Between the legacy and current lengths is neither
+    f.slab.data = vec![0u8; LEGACY_SLAB_LEN + 1];
+    assert_eq!(
+        init_market_with(&mut f, &init_data),
+        Err(PercolatorError::InvalidSlabLen.into())
+    );
+}
+
+#[test]
+fn test_trade_cpi_rejects_stale_matcher_return_after_nonce_advances() {
+    use percolator_prog::constants::{
+        CTX_OFF_MAGIC, MATCHER_ABI_VERSION, MATCHER_CONTEXT_LEN, MATCHER_CONTEXT_MAGIC,
+        RET_OFF_ABI_VERSION, RET_OFF_EXEC_PRICE, RET_OFF_EXEC_SIZE, RET_OFF_FLAGS,
+        RET_OFF_LP_ACCOUNT_ID, RET_OFF_ORACLE_PRICE, RET_OFF_REQ_ID,
+    };
+    use percolator_prog::matcher_abi::FLAG_VALID;
+
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+
+    let mut matcher_program = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+    matcher_program.executable = true;
+    let mut matcher_ctx = TestAccount::new(
+        Pubkey::new_unique(),
+        matcher_program.key,
+        0,
+        vec![0u8; MATCHER_CONTEXT_LEN],
+    )
+    .writable();
+    matcher_ctx.data[CTX_OFF_MAGIC..CTX_OFF_MAGIC + 8]
+        .copy_from_slice(&MATCHER_CONTEXT_MAGIC.to_le_bytes());
+    let (mut lp_owner, mut lp_ata) = new_owner_and_ata(&f, 1_000_000);
+    {
+        let accounts = vec![
+            lp_owner.to_info(),
+            f.slab.to_info(),
+            lp_ata.to_info(),
+            f.vault.to_info(),
+            f.token_prog.to_info(),
+        ];
+        let data = encode_init_lp(matcher_program.key, matcher_ctx.key, 0);
+        process_instruction(&f.program_id, &accounts, &data).unwrap();
+    }
+    let lp_idx = find_idx_by_owner(&f.slab.data, lp_owner.key).unwrap();
+    let mut lp = Participant {
+        owner: lp_owner,
+        ata: lp_ata,
+        idx: lp_idx,
+    };
+    try_deposit(&mut f, &mut lp, 1_000_000).unwrap();
+    let lp_account_id = zc::engine_ref(&f.slab.data).unwrap().accounts[lp_idx as usize].account_id;
+
+    let (lp_pda_key, _) = Pubkey::find_program_address(
+        &[b"lp", f.slab.key.as_ref(), &lp_idx.to_le_bytes()],
+        &f.program_id,
+    );
+    let mut lp_pda = TestAccount::new(lp_pda_key, solana_program::system_program::id(), 0, vec![]);
+
+    // The test runtime's CPI is a no-op, so the context keeps whatever return
+    // was written last: exactly what a replayed matcher response looks like.
+    let write_return = |ctx: &mut TestAccount, req_id: u64| {
+        let d = &mut ctx.data;
+        d[RET_OFF_ABI_VERSION..RET_OFF_ABI_VERSION + 4]
+            .copy_from_slice(&MATCHER_ABI_VERSION.to_le_bytes());
+        d[RET_OFF_FLAGS..RET_OFF_FLAGS + 4].copy_from_slice(&FLAG_VALID.to_le_bytes());
+        d[RET_OFF_EXEC_PRICE..RET_OFF_EXEC_PRICE + 8]
+            .copy_from_slice(&100_000_000u64.to_le_bytes());
+        d[RET_OFF_EXEC_SIZE..RET_OFF_EXEC_SIZE + 16].copy_from_slice(&100i128.to_le_bytes());
+        d[RET_OFF_REQ_ID..RET_OFF_REQ_ID + 8].copy_from_slice(&req_id.to_le_bytes());
+        d[RET_OFF_LP_ACCOUNT_ID..RET_OFF_LP_ACCOUNT_ID + 8]
+            .copy_from_slice(&lp_account_id.to_le_bytes());
+        d[RET_OFF_ORACLE_PRICE..RET_OFF_ORACLE_PRICE + 8]
+            .copy_from_slice(&100_000_000u64.to_le_bytes());
+    };
+    let mut trade_cpi = |f: &mut MarketFixture, ctx: &mut TestAccount| {
+        let accounts = vec![
+            user.owner.to_info(),
+            lp.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+            matcher_program.to_info(),
+            ctx.to_info(),
+            lp_pda.to_info(),
+        ];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_trade_cpi(lp_idx, user.idx, 100),
+        )
+    };
+    let position = |f: &MarketFixture| {
+        zc::engine_ref(&f.slab.data).unwrap().accounts[lp_idx as usize]
+            .position_size
+            .get()
+    };
+
+    // Response for the next request id (nonce 0 -> req_id 1) fills
+    write_return(&mut matcher_ctx, 1);
+    trade_cpi(&mut f, &mut matcher_ctx).unwrap();
+    assert_eq!(state::read_req_nonce(&f.slab.data).unwrap(), 1);
+    assert_eq!(position(&f), -100);
+
+    // Replaying the same response: the request now carries req_id 2
+    assert_eq!(
+        trade_cpi(&mut f, &mut matcher_ctx),
+        Err(ProgramError::InvalidAccountData)
+    );
+    // Failure leaves the nonce and the book untouched
+    assert_eq!(state::read_req_nonce(&f.slab.data).unwrap(), 1);
+    assert_eq!(position(&f), -100);
+
+    // A fresh response echoing req_id 2 is accepted
+    write_return(&mut matcher_ctx, 2);
+    trade_cpi(&mut f, &mut matcher_ctx).unwrap();
+    assert_eq!(state::read_req_nonce(&f.slab.data).unwrap(), 2);
+    assert_eq!(position(&f), -200);
+}
+
+#[test]
+fn test_trade_limit_price_rejects_slippage() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    let mut trade = |f: &mut MarketFixture, size: i128, limit: u64| {
+        let accounts = vec![
+            user.owner.to_info(),
+            lp.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        let data = encode_trade_with_limit(lp.idx, user.idx, size, limit);
+        process_instruction(&f.program_id, &accounts, &data)
+    };
+    let slippage: Result<(), ProgramError> = Err(PercolatorError::SlippageExceeded.into());
+
+    // Oracle fills at $100: a buy capped at $99 or a sell floored at $101 is rejected
+    assert_eq!(trade(&mut f, 100, 99_000_000), slippage);
+    assert_eq!(trade(&mut f, -100, 101_000_000), slippage);
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        0
+    );
+
+    // Loose limits (and the exact fill price) pass
+    trade(&mut f, 100, 101_000_000).unwrap();
+    trade(&mut f, -50, 100_000_000).unwrap();
+    // 0 disables the check
+    trade(&mut f, 50, 0).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        100
+    );
+
+    // Legacy payload without the trailing field still decodes as "no limit"
+    try_trade(&mut f, &mut user, &mut lp, -100).unwrap();
+}
+
+#[test]
+fn test_trade_size_i128_min_rejected() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    let overflow: Result<(), ProgramError> = Err(PercolatorError::EngineOverflow.into());
+
+    // TradeNoCpi
+    assert_eq!(try_trade(&mut f, &mut user, &mut lp, i128::MIN), overflow);
+
+    // MultiTrade leg
+    let accounts = vec![
+        user.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+        lp.owner.to_info(),
+    ];
+    let data = encode_multi_trade(user.idx, &[(lp.idx, i128::MIN)]);
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &data),
+        overflow
+    );
+
+    // TradeCpi is rejected before any matcher CPI
+    let mut matcher_prog = TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]);
+    let mut matcher_ctx =
+        TestAccount::new(Pubkey::new_unique(), Pubkey::default(), 0, vec![]).writable();
+    let mut lp_pda = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    );
+    let accounts = vec![
+        user.owner.to_info(),
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+        matcher_prog.to_info(),
+        matcher_ctx.to_info(),
+        lp_pda.to_info(),
+    ];
+    let data = encode_trade_cpi(lp.idx, user.idx, i128::MIN);
+    assert_eq!(
+        process_instruction(&f.program_id, &accounts, &data),
+        overflow
+    );
+
+    // Nothing was filled
+    let engine = zc::engine_ref(&f.slab.data).unwrap();
+    assert_eq!(engine.accounts[user.idx as usize].position_size.get(), 0);
+    assert_eq!(engine.accounts[lp.idx as usize].position_size.get(), 0);
+}
+
+#[test]
+fn test_get_account_state_reports_warmup_progress() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    // Profitable position: 1_000 of backed PnL warming at 10/slot from slot 100
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.warmup_period_slots = 100;
+        engine.vault = U128::new(engine.vault.get() + 1_000);
+        engine.set_pnl(user.idx as usize, 1_000);
+        engine.accounts[user.idx as usize].warmup_slope_per_step = U128::new(10);
+        engine.accounts[user.idx as usize].warmup_started_at_slot = 100;
+    }
+
+    let state_at = |f: &mut MarketFixture, slot: u64| {
+        f.clock.data = make_clock(slot, 100);
+        install_return_data_stubs();
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        let accounts = vec![f.slab.to_info(), f.clock.to_info()];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_get_account_state(user.idx),
+        )
+        .unwrap();
+        let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+        AccountState::from_bytes(&bytes).unwrap()
+    };
+
+    let state = state_at(&mut f, 100);
+    assert_eq!(state.pnl_pos, 1_000);
+    assert_eq!(state.pnl_warmed, 0);
+    assert_eq!(state.warmup_remaining_slots, 100);
+
+    // Crank partway through warmup, then watch the countdown run
+    f.clock.data = make_clock(130, 100);
+    try_crank_permissionless(&mut f).unwrap();
+    let mut last = state_at(&mut f, 130);
+    assert!(last.warmup_remaining_slots > 0 && last.warmup_remaining_slots != u64::MAX);
+    for slot in [140, 150, 160] {
+        let state = state_at(&mut f, slot);
+        assert!(
+            state.warmup_remaining_slots < last.warmup_remaining_slots,
+            "slot {}",
+            slot
+        );
+        assert!(state.pnl_warmed > last.pnl_warmed, "slot {}", slot);
+        assert_eq!(state.pnl_pos, last.pnl_pos);
+        last = state;
+    }
+
+    // Without a clock the engine's last observed slot (the crank's) is used
+    let state =
+        AccountState::from_bytes(&view(&mut f, &encode_get_account_state(user.idx))).unwrap();
+    assert_eq!(state, state_at(&mut f, 130));
+}
+
+#[test]
+fn test_set_funding_params_clamps_crank_funding_rate() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // LP net short 3e10 units at price 100: a 300 bps premium, which is
+    // below one bps per slot over the default 500-slot horizon
+    let mut user = add_user(&mut f, 1_000_000_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 30_000_000_000).unwrap();
+    f.clock.data = make_clock(101, 100);
+    assert_eq!(crank_outcome(&mut f).funding_rate_bps_per_slot, 0);
+
+    // A 10-slot horizon spreads it to -30 bps per slot, within a 100 bps cap
+    try_admin_ix(&mut f, &encode_set_funding_params(500, 100, 10)).unwrap();
+    let config = state::read_config(&f.slab.data);
+    assert_eq!(
+        (
+            config.funding_max_premium_bps,
+            config.funding_max_bps_per_slot,
+            config.funding_horizon_slots
+        ),
+        (500, 100, 10)
+    );
+    f.clock.data = make_clock(102, 100);
+    assert_eq!(crank_outcome(&mut f).funding_rate_bps_per_slot, -30);
+
+    // Lowering the per-slot cap clamps the same premium
+    try_admin_ix(&mut f, &encode_set_funding_params(500, 2, 10)).unwrap();
+    f.clock.data = make_clock(103, 100);
+    assert_eq!(crank_outcome(&mut f).funding_rate_bps_per_slot, -2);
+
+    // Lowering the premium cap clamps before the horizon split: 100 bps / 10
+    try_admin_ix(&mut f, &encode_set_funding_params(100, 100, 10)).unwrap();
+    f.clock.data = make_clock(104, 100);
+    assert_eq!(crank_outcome(&mut f).funding_rate_bps_per_slot, -10);
+
+    // Out-of-bounds values are rejected and leave the config untouched
+    for data in [
+        encode_set_funding_params(500, 5, 0),
+        encode_set_funding_params(10_001, 5, 500),
+        encode_set_funding_params(-1, 5, 500),
+        encode_set_funding_params(500, 101, 500),
+        encode_set_funding_params(500, -1, 500),
+    ] {
+        assert_eq!(
+            try_admin_ix(&mut f, &data),
+            Err(PercolatorError::InvalidConfigParam.into())
+        );
+    }
+    assert_eq!(
+        state::read_config(&f.slab.data).funding_max_premium_bps,
+        100
+    );
+}
+
+#[test]
+fn test_init_market_rejects_admin_field_mismatch() {
+    let mut f = setup_market();
+    let mut data = encode_init_market(&f, 100);
+    // The admin pubkey follows the tag; point it at someone other than the signer
+    data[1..33].copy_from_slice(&Pubkey::new_unique().to_bytes());
+    assert_eq!(
+        init_market_with(&mut f, &data),
+        Err(ProgramError::InvalidInstructionData)
+    );
+    assert!(
+        f.slab.data.iter().all(|&b| b == 0),
+        "slab must stay untouched"
+    );
+
+    // Matching field and signer initializes, storing the signer as admin
+    let data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &data).unwrap();
+    assert_eq!(
+        state::read_header(&f.slab.data).admin,
+        f.admin.key.to_bytes()
+    );
+}
+
+#[test]
+fn test_init_market_rejects_inverted_or_oversized_margins() {
+    // maintenance_margin_bps at 128..136, initial_margin_bps at 136..144
+    let init = |maintenance_bps: u64, initial_bps: u64| {
+        let mut f = setup_market();
+        let mut data = encode_init_market(&f, 100);
+        data[128..136].copy_from_slice(&maintenance_bps.to_le_bytes());
+        data[136..144].copy_from_slice(&initial_bps.to_le_bytes());
+        let res = init_market_with(&mut f, &data);
+        if res.is_err() {
+            assert!(
+                f.slab.data.iter().all(|&b| b == 0),
+                "slab must stay untouched"
+            );
+        }
+        res
+    };
+
+    // Equal margins are accepted
+    init(1_000, 1_000).unwrap();
+    init(500, 1_000).unwrap();
+    assert_eq!(
+        init(1_000, 500),
+        Err(PercolatorError::InvalidRiskParams.into())
+    );
+    assert_eq!(
+        init(500, 10_001),
+        Err(PercolatorError::InvalidRiskParams.into())
+    );
+    assert_eq!(
+        init(10_001, 10_001),
+        Err(PercolatorError::InvalidRiskParams.into())
+    );
+}
+
+#[test]
+fn test_validate_init_params_reports_failures_without_writing() {
+    use percolator_prog::return_data::InitParamsReport;
+
+    let mut f = setup_market();
+    // InitMarket's payload under tag 62, no accounts
+    let validate = |data: &[u8]| {
+        install_return_data_stubs();
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        process_instruction(&f.program_id, &[], data).unwrap();
+        let bytes = RETURN_DATA
+            .with(|r| r.borrow_mut().take())
+            .expect("ValidateInitParams must set return data");
+        InitParamsReport::from_bytes(&bytes).unwrap().failures
+    };
+    let mut data = encode_init_market(&f, 100);
+    data[0] = 62;
+    assert_eq!(validate(&data), 0);
+
+    // initial_margin_bps (400) below maintenance_margin_bps (500)
+    data[128..136].copy_from_slice(&500u64.to_le_bytes());
+    data[136..144].copy_from_slice(&400u64.to_le_bytes());
+    assert_eq!(validate(&data), InitParamsReport::MARGINS);
+
+    // Hyperp feed without a mark price and a conf filter above 100%, on top
+    data[65..97].copy_from_slice(&[0u8; 32]);
+    data[105..107].copy_from_slice(&10_001u16.to_le_bytes());
+    assert_eq!(
+        validate(&data),
+        InitParamsReport::MARGINS | InitParamsReport::HYPERP_MARK | InitParamsReport::CONF_FILTER
+    );
+    assert!(
+        f.slab.data.iter().all(|&b| b == 0),
+        "slab must stay untouched"
+    );
+
+    // The payload that passed initializes for real
+    let data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &data).unwrap();
+}
+
+#[test]
+fn test_liquidation_respects_fee_cap_buffer_and_dust_threshold() {
+    // 10_000 equity against 1_000 @ $100 (100_000 notional), 1% liquidation fee.
+    // Returns the user's position and the insurance gain after LiquidateAtOracle.
+    let liquidate = |maintenance_bps: u64, fee_cap: u128, buffer_bps: u64, min_abs: u128| {
+        let mut f = setup_market();
+        let init_data = encode_init_market(&f, 100);
+        init_market_with(&mut f, &init_data).unwrap();
+        f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+        let mut user = add_user(&mut f, 10_000);
+        let mut lp = add_lp(&mut f, 1_000_000);
+        try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+        {
+            let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+            engine.params.maintenance_margin_bps = maintenance_bps;
+            engine.params.liquidation_fee_bps = 100;
+            engine.params.liquidation_fee_cap = U128::new(fee_cap);
+            engine.params.liquidation_buffer_bps = buffer_bps;
+            engine.params.min_liquidation_abs = U128::new(min_abs);
+        }
+        let insurance_before = insurance_balance(&f);
+        let accounts = vec![
+            lp.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        let mut data = vec![7u8];
+        encode_u16(user.idx, &mut data);
+        process_instruction(&f.program_id, &accounts, &data).unwrap();
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        (
+            engine.accounts[user.idx as usize].position_size.get(),
+            insurance_balance(&f) - insurance_before,
+        )
+    };
+
+    // Fee cap: a full close owes 1_000 (1% of 100_000) but pays at most the cap
+    assert_eq!(liquidate(2_000, u128::MAX, 0, u128::MAX), (0, 1_000));
+    assert_eq!(liquidate(2_000, 250, 0, u128::MAX), (0, 250));
+
+    // Without a dust threshold the engine closes only what maintenance needs...
+    let (partial, _) = liquidate(2_000, u128::MAX, 0, 0);
+    assert!(partial > 0 && partial < 1_000, "remainder {}", partial);
+    // ...and a remainder below min_liquidation_abs is closed out instead of left as dust
+    let (dust, _) = liquidate(2_000, u128::MAX, 0, partial as u128 + 1);
+    assert_eq!(dust, 0);
+
+    // The buffer sizes the close toward maintenance + buffer: a smaller remainder
+    let (buffered, _) = liquidate(2_000, u128::MAX, 500, 0);
+    assert!(buffered < partial, "{} vs {}", buffered, partial);
+
+    // A marginally healthy account (10_000 equity >= 9.9% of 100_000) is left
+    // alone whatever the buffer
+    assert_eq!(liquidate(990, u128::MAX, 500, 0), (1_000, 0));
+}
+
+#[test]
+fn test_crank_reward_pays_keeper_for_liquidation() {
+    // 10_000 equity against 1_000 @ $100: below 15% maintenance, 1% fee to insurance
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 0, 100);
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    let mut keeper = add_user(&mut f, 1_000);
+    let keeper_idx = keeper.idx;
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    try_admin_ix(&mut f, &encode_set_crank_rewards(300, 50)).unwrap();
+    {
+        let config = state::read_config(&f.slab.data);
+        assert_eq!(
+            (
+                config.crank_reward_per_liquidation,
+                config.crank_reward_per_sweep
+            ),
+            (300, 50)
+        );
+    }
+
+    let mut self_crank = |f: &mut MarketFixture| {
+        install_return_data_stubs();
+        RETURN_DATA.with(|r| r.borrow_mut().take());
+        let accounts = vec![
+            keeper.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_crank(keeper_idx, 0)).unwrap();
+        let bytes = RETURN_DATA.with(|r| r.borrow_mut().take()).unwrap();
+        CrankOutcome::from_bytes(&bytes).unwrap()
+    };
+    let capital = |f: &MarketFixture, idx: u16| {
+        zc::engine_ref(&f.slab.data).unwrap().accounts[idx as usize]
+            .capital
+            .get()
+    };
+
+    // Healthy book: no work, no reward (the sweep in the init slot pays nothing)
+    let keeper_before = capital(&f, keeper_idx);
+    let out = self_crank(&mut f);
+    assert_eq!(out.liquidations, 0);
+    assert_eq!(capital(&f, keeper_idx), keeper_before);
+
+    // One liquidation in a fresh slot: per-liquidation plus per-sweep reward,
+    // paid out of insurance (which nets fee minus reward)
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.params.maintenance_margin_bps = 1_500;
+        engine.params.liquidation_fee_bps = 100;
+        engine.params.liquidation_fee_cap = U128::new(u128::MAX);
+        engine.params.min_liquidation_abs = U128::new(u128::MAX);
+    }
+    f.clock.data = make_clock(101, 100);
+    let insurance_before = insurance_balance(&f);
+    let out = self_crank(&mut f);
+    assert_eq!(out.liquidations, 1);
+    assert_eq!(capital(&f, keeper_idx), keeper_before + 350);
+    assert_eq!(insurance_balance(&f) - insurance_before, 1_000 - 350);
+    assert_eq!(out.insurance_delta, 650);
+
+    // Same slot again: nothing left to liquidate and the sweep was already paid
+    let out = self_crank(&mut f);
+    assert_eq!(out.liquidations, 0);
+    assert_eq!(capital(&f, keeper_idx), keeper_before + 350);
+
+    // Permissionless cranks earn nothing
+    f.clock.data = make_clock(102, 100);
+    let insurance_before = insurance_balance(&f);
+    crank_outcome(&mut f);
+    assert_eq!(insurance_balance(&f), insurance_before);
+
+    let report = verify_invariants(&mut f);
+    assert_eq!(report.violations, 0);
+}
+
+#[test]
+fn test_get_market_stats_reports_pnl_pos_tot_and_haircut() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+
+    let stats = MarketStats::from_bytes(&view(&mut f, &encode_get_market_stats())).unwrap();
+    {
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        assert_eq!(stats.c_tot, engine.c_tot.get());
+        assert_eq!(stats.insurance, engine.insurance_fund.balance.get());
+    }
+    assert_eq!((stats.long_oi, stats.short_oi), (1_000, 1_000));
+    assert_eq!(stats.num_used_accounts, 2);
+    assert_eq!(stats.pnl_pos_tot, 0);
+    assert_eq!(stats.haircut_ratio_e6, 1_000_000);
+
+    // Profitable position fully backed by a matching vault reserve
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.vault = U128::new(engine.vault.get() + 1_000);
+        engine.set_pnl(user.idx as usize, 1_000);
+    }
+    let stats = MarketStats::from_bytes(&view(&mut f, &encode_get_market_stats())).unwrap();
+    assert_eq!(stats.pnl_pos_tot, 1_000);
+    assert_eq!(stats.haircut_ratio_e6, 1_000_000);
+
+    // Only half of the profit is backed: a 50% haircut
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.vault = U128::new(engine.vault.get() - 500);
+    }
+    let stats = MarketStats::from_bytes(&view(&mut f, &encode_get_market_stats())).unwrap();
+    assert_eq!(stats.pnl_pos_tot, 1_000);
+    assert_eq!(stats.haircut_ratio_e6, 500_000);
+}
+
+#[test]
+fn test_trade_duplicate_client_req_id_rejected() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    let user_idx = user.idx;
+    let mut trade = |f: &mut MarketFixture, size: i128, client_req_id: u64| {
+        let accounts = vec![
+            user.owner.to_info(),
+            lp.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        let data = encode_trade_with_req_id(lp.idx, user.idx, size, client_req_id);
+        process_instruction(&f.program_id, &accounts, &data)
+    };
+    let position = |f: &MarketFixture| {
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user_idx as usize]
+            .position_size
+            .get()
+    };
+    // Kept in the first 8 bytes of the user account's matcher_context
+    let last_req_id = |f: &MarketFixture| {
+        let ctx = zc::engine_ref(&f.slab.data).unwrap().accounts[user_idx as usize].matcher_context;
+        u64::from_le_bytes(ctx[..8].try_into().unwrap())
+    };
+
+    trade(&mut f, 100, 7).unwrap();
+    assert_eq!(last_req_id(&f), 7);
+
+    // The resubmission is rejected and the position is untouched
+    assert_eq!(
+        trade(&mut f, 100, 7),
+        Err(PercolatorError::DuplicateRequest.into())
+    );
+    assert_eq!(position(&f), 100);
+
+    // A fresh id fills; 0 opts out of the check
+    trade(&mut f, 100, 8).unwrap();
+    trade(&mut f, 100, 0).unwrap();
+    trade(&mut f, 100, 0).unwrap();
+    assert_eq!(position(&f), 400);
+    assert_eq!(last_req_id(&f), 8);
+}
+
+#[test]
+fn test_trade_client_req_id_rejected_for_lp_user_side() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    let mut lp = add_lp(&mut f, 1_000_000);
+    let mut lp_as_user = add_lp(&mut f, 1_000_000);
+    let ctx_before =
+        zc::engine_ref(&f.slab.data).unwrap().accounts[lp_as_user.idx as usize].matcher_context;
+
+    // An LP's matcher_context binds its matcher, so it cannot hold the id
+    let accounts = vec![
+        lp_as_user.owner.to_info(),
+        lp.owner.to_info(),
+        f.slab.to_info(),
+        f.clock.to_info(),
+        f.pyth_index.to_info(),
+    ];
+    let data = encode_trade_with_req_id(lp.idx, lp_as_user.idx, 100, 9);
+    let res = process_instruction(&f.program_id, &accounts, &data);
+    assert_eq!(res, Err(PercolatorError::EngineAccountKindMismatch.into()));
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[lp_as_user.idx as usize].matcher_context,
+        ctx_before
+    );
+}
+
+#[test]
+fn test_close_lp_requires_flat_inventory_and_frees_slot() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    let close_lp = |f: &mut MarketFixture, p: &mut Participant, idx: u16| {
+        let mut vault_pda =
+            TestAccount::new(f.vault_pda, solana_program::system_program::id(), 0, vec![]);
+        let accounts = vec![
+            p.owner.to_info(),
+            f.slab.to_info(),
+            f.vault.to_info(),
+            p.ata.to_info(),
+            vault_pda.to_info(),
+            f.token_prog.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(&f.program_id, &accounts, &encode_close_lp(idx))
+    };
+
+    // Only LP accounts
+    let user_idx = user.idx;
+    assert_eq!(
+        close_lp(&mut f, &mut user, user_idx),
+        Err(PercolatorError::EngineAccountKindMismatch.into())
+    );
+
+    // Open inventory blocks the close
+    try_trade(&mut f, &mut user, &mut lp, 100).unwrap();
+    let lp_idx = lp.idx;
+    assert_eq!(
+        close_lp(&mut f, &mut lp, lp_idx),
+        Err(PercolatorError::EnginePositionSizeMismatch.into())
+    );
+
+    // Flat again: capital is refunded and the slot freed
+    try_trade(&mut f, &mut user, &mut lp, -100).unwrap();
+    let used_before = zc::engine_ref(&f.slab.data).unwrap().num_used_accounts;
+    let ata_before = TokenAccount::unpack(&lp.ata.data).unwrap().amount;
+    close_lp(&mut f, &mut lp, lp_idx).unwrap();
+    assert!(TokenAccount::unpack(&lp.ata.data).unwrap().amount > ata_before);
+    {
+        let engine = zc::engine_ref(&f.slab.data).unwrap();
+        assert!(!engine.is_used(lp_idx as usize));
+        assert_eq!(engine.num_used_accounts, used_before - 1);
+    }
+
+    // The freed index is handed to the next account
+    let lp2 = add_lp(&mut f, 1_000);
+    assert_eq!(lp2.idx, lp_idx);
+}
+
+#[test]
+fn test_closed_account_index_is_reused() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let _lp = add_lp(&mut f, 1_000_000);
+    let used = |f: &MarketFixture| zc::engine_ref(&f.slab.data).unwrap().num_used_accounts;
+
+    // Churn one slot well past the test build's MAX_ACCOUNTS (64)
+    let mut user = add_user(&mut f, 1_000);
+    let idx = user.idx;
+    let used_open = used(&f);
+    for _ in 0..100 {
+        try_close_account(&mut f, &mut user).unwrap();
+        assert_eq!(used(&f), used_open - 1);
+        user = add_user(&mut f, 1_000);
+        assert_eq!(user.idx, idx);
+        assert_eq!(used(&f), used_open);
+    }
+}
+
+#[test]
+fn test_open_interest_cap_blocks_only_growing_trades() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    let mut user = add_user(&mut f, 1_000_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+
+    // 400 of total open interest: 200 long (user) + 200 short (LP)
+    {
+        let accounts = vec![f.admin.to_info(), f.slab.to_info()];
+        process_instruction(&f.program_id, &accounts, &encode_set_open_interest_cap(400)).unwrap();
+    }
+    try_trade(&mut f, &mut user, &mut lp, 150).unwrap();
+    try_trade(&mut f, &mut user, &mut lp, 50).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data)
+            .unwrap()
+            .total_open_interest
+            .get(),
+        400
+    );
+
+    // At the cap: any growth is rejected, reducing always passes
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 1),
+        Err(PercolatorError::OpenInterestCap.into())
+    );
+    try_trade(&mut f, &mut user, &mut lp, -50).unwrap();
+    try_trade(&mut f, &mut user, &mut lp, 50).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        200
+    );
+
+    // Non-admin cannot lift the cap
+    let mut attacker = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    )
+    .signer();
+    let accounts = vec![attacker.to_info(), f.slab.to_info()];
+    assert!(
+        process_instruction(&f.program_id, &accounts, &encode_set_open_interest_cap(0)).is_err()
+    );
+}
+
+#[test]
+fn test_leverage_cap_allows_exactly_the_cap_and_rejects_above() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+    // 10_000 capital at 20x: up to 200_000 notional, 2_000 @ $100
+    let mut user = add_user(&mut f, 10_000);
+    let mut lp = add_lp(&mut f, 1_000_000);
+    {
+        let accounts = vec![f.admin.to_info(), f.slab.to_info()];
+        process_instruction(&f.program_id, &accounts, &encode_set_max_leverage(20)).unwrap();
+    }
+
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    try_trade(&mut f, &mut user, &mut lp, 1_000).unwrap();
+    assert_eq!(
+        zc::engine_ref(&f.slab.data).unwrap().accounts[user.idx as usize]
+            .position_size
+            .get(),
+        2_000
+    );
+
+    // One unit past the cap is rejected; reducing always passes
+    assert_eq!(
+        try_trade(&mut f, &mut user, &mut lp, 1),
+        Err(PercolatorError::LeverageCap.into())
+    );
+    try_trade(&mut f, &mut user, &mut lp, -500).unwrap();
+
+    // A fresh 2_001 short from flat is over the cap too
+    let mut short = add_user(&mut f, 10_000);
+    assert_eq!(
+        try_trade(&mut f, &mut short, &mut lp, -2_001),
+        Err(PercolatorError::LeverageCap.into())
+    );
+
+    // TransferPosition is held to the cap on the destination: 1_000 capital
+    // carries at most 200 @ $100 (1% initial margin, so only the cap binds)
+    let sub = add_user(&mut f, 1_000);
+    {
+        let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+        engine.accounts[sub.idx as usize].owner = user.owner.key.to_bytes();
+        engine.params.initial_margin_bps = 100;
+    }
+    let mut transfer = |f: &mut MarketFixture, size: i128| {
+        let accounts = vec![
+            user.owner.to_info(),
+            f.slab.to_info(),
+            f.clock.to_info(),
+            f.pyth_index.to_info(),
+        ];
+        process_instruction(
+            &f.program_id,
+            &accounts,
+            &encode_transfer_position(user.idx, sub.idx, size),
+        )
+    };
+    assert_eq!(
+        transfer(&mut f, 201),
+        Err(PercolatorError::LeverageCap.into())
+    );
+    transfer(&mut f, 200).unwrap();
+
+    // Non-admin cannot lift the cap
+    let mut attacker = TestAccount::new(
+        Pubkey::new_unique(),
+        solana_program::system_program::id(),
+        0,
+        vec![],
+    )
+    .signer();
+    let accounts = vec![attacker.to_info(), f.slab.to_info()];
+    assert!(process_instruction(&f.program_id, &accounts, &encode_set_max_leverage(0)).is_err());
+}
+
+#[test]
+fn test_oracle_staleness_uses_unix_timestamp_not_slot() {
+    let mut f = setup_market();
+    let init_data = encode_init_market(&f, 100);
+    init_market_with(&mut f, &init_data).unwrap();
+
+    // The feed was published at t=100 with max_staleness_secs = 100. An early
+    // slot does not make it fresh once 101 seconds have passed...
+    f.clock.data = make_clock(100, 201);
+    assert_eq!(
+        try_crank_permissionless(&mut f),
+        Err(PercolatorError::OracleStale.into())
+    );
+
+    // ...and a far later slot does not make it stale while seconds are in range
+    f.clock.data = make_clock(10_000, 150);
+    try_crank_permissionless(&mut f).unwrap();
+}
+
+#[test]
+fn test_crank_vests_warmup_regardless_of_empty_slots() {
+    // Same warming account, with `churn` freed slots below it
+    let run = |churn: usize| {
+        let mut f = setup_market();
+        let init_data = encode_init_market(&f, 100);
+        init_market_with(&mut f, &init_data).unwrap();
+        let mut closed = Vec::new();
+        for _ in 0..churn {
+            closed.push(add_user(&mut f, 1_000));
+        }
+        let user = add_user(&mut f, 1_000);
+        for mut p in closed {
+            try_close_account(&mut f, &mut p).unwrap();
+        }
+        assert_eq!(zc::engine_ref(&f.slab.data).unwrap().num_used_accounts, 1);
+
+        // Backed PnL vesting at 10/slot from `start`
+        let vest = |f: &mut MarketFixture, pnl: i128, start: u64| {
+            let engine = zc::engine_mut(&mut f.slab.data).unwrap();
+            engine.params.warmup_period_slots = 100;
+            engine.vault = U128::new(engine.vault.get() + pnl as u128);
+            engine.set_pnl(user.idx as usize, pnl);
+            engine.accounts[user.idx as usize].warmup_slope_per_step = U128::new(10);
+            engine.accounts[user.idx as usize].warmup_started_at_slot = start;
+        };
+        let crank_at = |f: &mut MarketFixture, slot: u64| {
+            f.clock.data = make_clock(slot, slot as i64);
+            f.pyth_index.data = make_pyth(&TEST_FEED_ID, 100_000_000, -6, 1, slot as i64);
+            try_crank_permissionless(f).unwrap();
+            let engine = zc::engine_ref(&f.slab.data).unwrap();
+            let acc = &engine.accounts[user.idx as usize];
+            (acc.capital.get(), acc.pnl.get())
+        };
+
+        vest(&mut f, 1_000, 100);
+        let halfway = crank_at(&mut f, 150);
+        let done = crank_at(&mut f, 250);
+        // PnL gained between cranks is still visited
+        vest(&mut f, 1_000, 250);
+        let again = crank_at(&mut f, 400);
+        (user.idx, [halfway, done, again])
+    };
+
+    let (idx, base) = run(0);
+    let (churned_idx, churned) = run(40);
+    assert!(churned_idx >= 40 && idx < churned_idx);
+    assert_eq!(churned, base);
+    assert!(base[0].1 > 0 && base[0].1 < 1_000);
+    assert_eq!(base[1].1, 0);
+    assert_eq!(base[2].1, 0);
+    assert!(base[2].0 > base[1].0);
+}